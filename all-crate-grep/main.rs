@@ -2,9 +2,15 @@ extern crate all_crate_storage;
 extern crate grep;
 
 use std::env;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::process;
 use all_crate_storage::registry::registry;
-use all_crate_storage::crate_storage::{CrateSource, FileTreeStorage};
+use all_crate_storage::blob_crate_storage::BlobCrateStorage;
+use all_crate_storage::crate_storage::{CrateSource, DynCrateSource, FileTreeStorage};
+use all_crate_storage::dir_blob_storage::DirBlobStorage;
+use all_crate_storage::extracted_dir_storage::ExtractedDirStorage;
+use all_crate_storage::error::CrateErrorKind;
+use all_crate_storage::volumes::VolumeSet;
 use self::registry::{Registry, AllCratesJson};
 
 use std::thread;
@@ -13,9 +19,38 @@ use std::sync::mpsc::{sync_channel, SyncSender};
 use grep::regex::RegexMatcher;
 use grep::matcher::Matcher;
 
-fn run(tx :SyncSender<(usize, usize, String)>, acj :&AllCratesJson,
+const USAGE :&str = "Usage: all-crate-grep <regex> [archive-tree|storage-file|blob-dir|extracted-dir] [<path>]";
+
+#[derive(Clone, Copy)]
+enum SourceKind {
+	ArchiveTree,
+	StorageFile,
+	BlobDir,
+	ExtractedDir,
+}
+
+fn open_source(kind :SourceKind, path :&Path) -> DynCrateSource<VolumeSet> {
+	match kind {
+		SourceKind::ArchiveTree => {
+			DynCrateSource::FileTreeStorage(FileTreeStorage::new(path))
+		},
+		SourceKind::StorageFile => {
+			let f = VolumeSet::open(path).unwrap();
+			DynCrateSource::BlobCrateStorage(Box::new(BlobCrateStorage::new(f).unwrap()))
+		},
+		SourceKind::BlobDir => {
+			let dbs = DirBlobStorage::open(path).unwrap();
+			DynCrateSource::BlobDirectory(Box::new(BlobCrateStorage::from_blob_store(dbs)))
+		},
+		SourceKind::ExtractedDir => {
+			DynCrateSource::ExtractedDirStorage(ExtractedDirStorage::new(path))
+		},
+	}
+}
+
+fn run<S :CrateSource>(tx :SyncSender<(usize, usize, String)>, acj :&AllCratesJson,
 		total_file_count :usize, t :usize, tc :usize,
-		grepper :&RegexMatcher, storage_base :&Path) {
+		grepper :&RegexMatcher, crate_source :&mut S) {
 	let mut ctr = 0;
 
 	macro_rules! pln {
@@ -24,8 +59,6 @@ fn run(tx :SyncSender<(usize, usize, String)>, acj :&AllCratesJson,
 		}
 	}
 
-	let mut crate_source = FileTreeStorage::new(storage_base);
-
 	for &(ref name, ref versions) in acj.iter() {
 
 		for ref v in versions.iter() {
@@ -38,15 +71,19 @@ fn run(tx :SyncSender<(usize, usize, String)>, acj :&AllCratesJson,
 			}
 
 			let fh = match crate_source.get_crate_handle_nv(name.to_owned(), v.version.clone()) {
-				Some(f) => f,
-				None => {
+				Ok(f) => f,
+				Err(ref e) if *e.get_context() == CrateErrorKind::NotFound => {
 					pln!("Version {} of crate {} not mirrored", v.version, name);
 					continue
 				},
+				Err(e) => {
+					pln!("Couldn't obtain {} v {}: {}", name, v.version, e);
+					continue
+				},
 			};
 
 			let mut match_found = false;
-			fh.crate_file_handle.map_all_files(|file_path, file| {
+			let res = fh.map_all_files(|file_path, file| {
 				if let (Some(file_path), Some(file)) = (file_path, file) {
 					if let Ok(Some(_)) = grepper.find(&file) {
 						pln!("Match found in {} v {} file {}", name, v.version, file_path);
@@ -54,6 +91,9 @@ fn run(tx :SyncSender<(usize, usize, String)>, acj :&AllCratesJson,
 					}
 				}
 			});
+			if let Err(e) = res {
+				pln!("Stopped searching {} v {}: {}", name, v.version, e);
+			}
 			if !match_found {
 				pln!("No match in {} v {}", name, v.version);
 			}
@@ -69,11 +109,26 @@ fn main() {
 	let total_file_count :usize = acj.iter().map(|&(_, ref v)| v.len()).sum();
 
 	println!("The target is {} files.", total_file_count);
-	let storage_base = env::current_dir().unwrap().join("crate-archives");
-	println!("Using directory {} to load the files from.",
+	let mut args = env::args().skip(1);
+	let needle = args.next().unwrap_or_else(|| {
+		eprintln!("{}", USAGE);
+		process::exit(2);
+	});
+	let (kind, default_path) = match args.next().as_ref().map(|k| &k[..]) {
+		None | Some("archive-tree") => (SourceKind::ArchiveTree, "crate-archives"),
+		Some("storage-file") => (SourceKind::StorageFile, "crate-constr-archives/crate_storage"),
+		Some("blob-dir") => (SourceKind::BlobDir, "crate-constr-archives/blobs"),
+		Some("extracted-dir") => (SourceKind::ExtractedDir, "vendor"),
+		Some(_) => {
+			eprintln!("{}", USAGE);
+			process::exit(2);
+		},
+	};
+	let storage_base = args.next().map(PathBuf::from)
+		.unwrap_or_else(|| env::current_dir().unwrap().join(default_path));
+	println!("Using {} to load the files from.",
 		storage_base.to_str().unwrap());
 
-	let needle = env::args().nth(1).expect("expected search term");
 	println!("Search term '{}'", needle);
 	let grepper = RegexMatcher::new_line_matcher(&needle).unwrap();
 
@@ -86,8 +141,9 @@ fn main() {
 		let grepper = grepper.clone();
 		let storage_base = storage_base.clone();
 		thread::spawn(move || {
+			let mut crate_source = open_source(kind, &storage_base);
 			run(tx, &acj, total_file_count, v, thread_count,
-				&grepper, &storage_base);
+				&grepper, &mut crate_source);
 		});
 	}
 	std::mem::drop(tx);
@@ -60,12 +60,12 @@ fn run(tx :SyncSender<Message>, acj :&AllCratesJson,
 			tx.send(Message {
 				t, ctr, total_file_count,
 				msg : $msg,
-			}).unwrap();
+			}).unwrap()
 		}
 	}
 	macro_rules! pln {
 		($($v:expr),*) => {
-			msg!(Msg::Text(format!($($v),*)));
+			msg!(Msg::Text(format!($($v),*)))
 		}
 	}
 
@@ -0,0 +1,81 @@
+extern crate all_crate_storage;
+
+use std::fs::{self, OpenOptions};
+use std::env;
+use std::path::Path;
+use std::process;
+use all_crate_storage::registry::registry;
+use all_crate_storage::blob_crate_storage::BlobCrateStorage;
+use all_crate_storage::compaction::{self, RetentionPolicy};
+use all_crate_storage::volumes::VolumeSet;
+use self::registry::{Registry, AllCratesJson};
+
+const USAGE :&str = "Usage: compact-crate-storage <src> <dst> [--deny <deny-list>]... [--drop-yanked]
+
+Writes the crates of the storage file <src> into the new storage file <dst>,
+leaving out the denied crates, and all blobs only they reference.
+
+The deny list contains one crate name per line, optionally followed
+by a version to only remove that version of the crate.";
+
+fn usage() -> ! {
+	eprintln!("{}", USAGE);
+	process::exit(2);
+}
+
+fn main() {
+	let mut args = env::args().skip(1);
+	let src_path = args.next().unwrap_or_else(|| usage());
+	let dst_path = args.next().unwrap_or_else(|| usage());
+	let mut policy = RetentionPolicy::default();
+	while let Some(arg) = args.next() {
+		match &arg[..] {
+			"--deny" => {
+				let path = args.next().unwrap_or_else(|| usage());
+				let list = fs::read_to_string(&path).unwrap_or_else(|e| {
+					eprintln!("Couldn't read deny list {}: {}", path, e);
+					process::exit(2);
+				});
+				policy.add_deny_list(&list).unwrap_or_else(|e| {
+					eprintln!("Invalid deny list {}: {}", path, e);
+					process::exit(2);
+				});
+			},
+			"--drop-yanked" => {
+				eprintln!("Loading all crates json...");
+				let registry = Registry::from_name("github.com-1ecc6299db9ec823").unwrap();
+				let acj :AllCratesJson = registry.get_all_crates_json().unwrap();
+				policy.deny_yanked(&acj);
+			},
+			_ => usage(),
+		}
+	}
+
+	let src_f = VolumeSet::open(Path::new(&src_path)).unwrap_or_else(|e| {
+		eprintln!("Couldn't open storage file {}: {}", src_path, e);
+		process::exit(2);
+	});
+	let mut src = BlobCrateStorage::new(src_f).unwrap_or_else(|e| {
+		eprintln!("Couldn't open storage file {}: {}", src_path, e);
+		process::exit(2);
+	});
+	// Never write into an existing file, we don't want to
+	// mix the blobs we are removing into another storage
+	let dst_f = OpenOptions::new()
+		.read(true)
+		.write(true)
+		.create_new(true)
+		.open(&dst_path).unwrap_or_else(|e| {
+			eprintln!("Couldn't create storage file {}: {}", dst_path, e);
+			process::exit(2);
+		});
+	let mut dst = BlobCrateStorage::empty(dst_f);
+
+	let stats = compaction::compact(&mut src, &mut dst, |spec| policy.keeps(spec))
+		.unwrap_or_else(|e| {
+			eprintln!("Compaction failed: {}", e);
+			process::exit(1);
+		});
+	eprintln!("Kept {} crates, removed {}.", stats.crates_kept, stats.crates_removed);
+	eprintln!("Kept {} blobs, removed {}.", stats.blobs_kept, stats.blobs_removed);
+}
@@ -3,11 +3,47 @@ extern crate all_crate_storage;
 use std::fs::{self, OpenOptions};
 use std::env;
 use all_crate_storage::registry::registry;
+use std::path::PathBuf;
 use all_crate_storage::blob_crate_storage::BlobCrateStorage;
+use all_crate_storage::blob_store::BlobStoreMut;
+use all_crate_storage::codec::Codec;
 use all_crate_storage::crate_storage::{FileTreeStorage, CrateStorage};
+use all_crate_storage::dir_blob_storage::DirBlobStorage;
+use all_crate_storage::volumes::VolumeSet;
 use self::registry::{Registry, AllCratesJson};
 
+fn fill_storage<B :BlobStoreMut>(mut cst :BlobCrateStorage<B>, codec :Codec,
+		acj :&AllCratesJson, fts :&mut FileTreeStorage) {
+	let thread_count = 8;
+
+	cst.set_codec(codec);
+	cst.fill_crate_storage_from_source(thread_count, acj, fts,
+		|n, v| println!("Storing {} v {}", n, v.version)).unwrap();
+
+	cst.store().unwrap();
+}
+
 fn main() {
+	let mut codec = Codec::Gzip;
+	let mut volume_size = None;
+	let mut blob_dir = None;
+	let mut args = env::args().skip(1);
+	while let Some(arg) = args.next() {
+		if arg == "--volume-size" {
+			let size = args.next().and_then(|s| s.parse::<u64>().ok())
+				.unwrap_or_else(|| panic!("Expected the volume size in bytes"));
+			volume_size = Some(size);
+			continue;
+		}
+		if arg == "--blob-dir" {
+			let dir = args.next()
+				.unwrap_or_else(|| panic!("Expected the path of the blob directory"));
+			blob_dir = Some(PathBuf::from(dir));
+			continue;
+		}
+		codec = Codec::from_name(&arg)
+			.unwrap_or_else(|| panic!("Unknown codec '{}', expected gzip or zstd", arg));
+	}
 	println!("Loading all crates json...");
 	let registry = Registry::from_name("github.com-1ecc6299db9ec823").unwrap();
 	let acj :AllCratesJson = registry.get_all_crates_json().unwrap();
@@ -21,17 +57,20 @@ fn main() {
 
 	fs::create_dir_all(&storage_con_base).unwrap();
 
-	let thread_count = 8;
-
 	let mut fts = FileTreeStorage::new(&storage_base);
-	let f = OpenOptions::new()
-		.read(true)
-		.write(true)
-		.create(true)
-		.open(storage_con_base.join("crate_storage")).unwrap();
-	let mut cst = BlobCrateStorage::new(f).unwrap();
-	cst.fill_crate_storage_from_source(thread_count, &acj, &mut fts,
-		|n, v| println!("Storing {} v {}", n, v.version));
-
-	cst.store().unwrap();
+	let storage_path = storage_con_base.join("crate_storage");
+	if let Some(blob_dir) = blob_dir {
+		let dbs = DirBlobStorage::create(&blob_dir).unwrap();
+		fill_storage(BlobCrateStorage::from_blob_store(dbs), codec, &acj, &mut fts);
+	} else if let Some(volume_size) = volume_size {
+		let volumes = VolumeSet::open_writable(&storage_path, volume_size).unwrap();
+		fill_storage(BlobCrateStorage::new(volumes).unwrap(), codec, &acj, &mut fts);
+	} else {
+		let f = OpenOptions::new()
+			.read(true)
+			.write(true)
+			.create(true)
+			.open(storage_path).unwrap();
+		fill_storage(BlobCrateStorage::new(f).unwrap(), codec, &acj, &mut fts);
+	}
 }
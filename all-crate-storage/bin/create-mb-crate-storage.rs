@@ -5,10 +5,19 @@ use std::fs::{self, OpenOptions};
 use std::env;
 use all_crate_storage::registry::registry;
 use all_crate_storage::blob_crate_storage::BlobCrateStorage;
+use all_crate_storage::codec::Codec;
 use self::registry::{Registry, AllCratesJson};
 use all_crate_storage::multi_blob_crate_storage::GraphOfBlobs;
 
+/// The maximum number of diffs that need to be applied to obtain a blob
+const MAX_CHAIN_DEPTH :usize = 8;
+
 fn main() {
+	let codec = match env::args().nth(1) {
+		Some(name) => Codec::from_name(&name)
+			.unwrap_or_else(|| panic!("Unknown codec '{}', expected gzip or zstd", name)),
+		None => Codec::Gzip,
+	};
 	println!("Loading all crates json...");
 	let registry = Registry::from_name("github.com-1ecc6299db9ec823").unwrap();
 	let acj :AllCratesJson = registry.get_all_crates_json().unwrap();
@@ -30,9 +39,11 @@ fn main() {
 		.open(storage_con_base.join("crate_storage_mb")).unwrap();
 	let mut src = BlobCrateStorage::new(src_f).unwrap();
 	let mut dst = BlobCrateStorage::new(dst_f).unwrap();
+	dst.set_codec(codec);
 
 	println!("Obtaining graph...");
 	let graph = GraphOfBlobs::from_blob_crate_storage(&acj, &mut src);
 	println!("root number {}", graph.roots.len());
-	dst.store_parallel_mb(&mut src, &graph, 8);
+	dst.store_parallel_mb(&mut src, &graph, 8, MAX_CHAIN_DEPTH).unwrap();
+	dst.store().unwrap();
 }
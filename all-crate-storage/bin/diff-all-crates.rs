@@ -50,7 +50,7 @@ fn run(tx :SyncSender<(usize, usize, String)>, acj :&AllCratesJson,
 		}
 	}
 
-	let mut crate_source = FileTreeStorage::new(storage_base);
+	let crate_source = FileTreeStorage::new(storage_base);
 
 	for &(ref name, ref versions) in acj.iter() {
 
@@ -68,7 +68,7 @@ fn run(tx :SyncSender<(usize, usize, String)>, acj :&AllCratesJson,
 				continue;
 			}
 			let crate_blob = crate_source.get_crate_nv(name.clone(), v.version.clone());
-			let crate_blob = if let Some(crate_blob) = crate_blob {
+			let crate_blob = if let Ok(crate_blob) = crate_blob {
 				// verify the checksum
 				let mut ring_ctx = HashCtx::new();
 				io::copy(&mut crate_blob.as_slice(), &mut ring_ctx).unwrap();
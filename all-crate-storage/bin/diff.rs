@@ -29,16 +29,16 @@ fn main() {
 	let f = OpenOptions::new()
 		.read(true)
 		.open(storage_con_base.join("crate_storage")).unwrap();
-	let mut cst = BlobCrateStorage::new(f).unwrap();
+	let cst = BlobCrateStorage::new(f).unwrap();
 
 	let lib_062 = {
-		let mut ch = cst.get_crate_handle_nv("lewton".to_owned(),
+		let ch = cst.get_crate_handle_nv("lewton".to_owned(),
 			Version::parse("0.6.2").unwrap()).unwrap();
 		ch.get_file("lewton-0.6.2/src/imdct.rs").unwrap()
 	};
 	let lib_062_str = str::from_utf8(&lib_062).unwrap();
 	let lib_070 = {
-		let mut ch = cst.get_crate_handle_nv("lewton".to_owned(),
+		let ch = cst.get_crate_handle_nv("lewton".to_owned(),
 			Version::parse("0.7.0").unwrap()).unwrap();
 		ch.get_file("lewton-0.7.0/src/imdct.rs").unwrap()
 	};
@@ -50,7 +50,7 @@ fn main() {
 	diff.serialize(&mut v).unwrap();
 	println!("{:?}", diff);
 	println!("{} {} {}", lib_062.len(), lib_070.len(), v.len());
-	assert_eq!(diff.reconstruct_new(lib_062_str), lib_070_str);
+	assert_eq!(diff.reconstruct_new(lib_062_str).unwrap(), lib_070_str);
 	/*for i in diff.inserts() {
 		println!("{:?}", i);
 	}
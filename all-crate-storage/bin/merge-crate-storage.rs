@@ -0,0 +1,76 @@
+extern crate all_crate_storage;
+extern crate semver;
+
+use std::fs::OpenOptions;
+use std::env;
+use std::path::Path;
+use std::process;
+use std::collections::HashMap;
+use semver::Version;
+use all_crate_storage::registry::registry;
+use all_crate_storage::blob_crate_storage::BlobCrateStorage;
+use all_crate_storage::hash_ctx::{Digest, digest_from_hex};
+use all_crate_storage::merge;
+use all_crate_storage::volumes::VolumeSet;
+use self::registry::{Registry, AllCratesJson};
+
+const USAGE :&str = "Usage: merge-crate-storage <dst> <src>...
+
+Merges the storage files <src> into the new storage file <dst>.
+If the storage files disagree about a crate, the copy that
+matches the checksum in the registry index is used.";
+
+/// Loads the checksums of all crates from the registry index
+fn load_checksums() -> HashMap<(String, Version), Digest> {
+	eprintln!("Loading all crates json...");
+	let registry = Registry::from_name("github.com-1ecc6299db9ec823").unwrap();
+	let acj :AllCratesJson = registry.get_all_crates_json().unwrap();
+	acj.into_iter()
+		.flat_map(|(_, versions)| versions)
+		.filter_map(|v| {
+			let d = digest_from_hex(&v.checksum)?;
+			Some(((v.name, v.version), d))
+		})
+		.collect()
+}
+
+fn main() {
+	let args = env::args().skip(1).collect::<Vec<_>>();
+	if args.len() < 2 {
+		eprintln!("{}", USAGE);
+		process::exit(2);
+	}
+	let mut srcs = args[1 ..].iter()
+		.map(|path| {
+			let f = VolumeSet::open(Path::new(path)).unwrap_or_else(|e| {
+				eprintln!("Couldn't open storage file {}: {}", path, e);
+				process::exit(2);
+			});
+			BlobCrateStorage::new(f).unwrap_or_else(|e| {
+				eprintln!("Couldn't open storage file {}: {}", path, e);
+				process::exit(2);
+			})
+		})
+		.collect::<Vec<_>>();
+	let dst_f = OpenOptions::new()
+		.read(true)
+		.write(true)
+		.create_new(true)
+		.open(&args[0]).unwrap_or_else(|e| {
+			eprintln!("Couldn't create storage file {}: {}", args[0], e);
+			process::exit(2);
+		});
+	let mut dst = BlobCrateStorage::empty(dst_f);
+
+	// The index is only needed if there are conflicts
+	let mut checksums = None;
+	let stats = merge::merge(&mut dst, &mut srcs, |spec| {
+		let checksums = checksums.get_or_insert_with(load_checksums);
+		checksums.get(&(spec.name.clone(), spec.version.clone())).cloned()
+	}).unwrap_or_else(|e| {
+		eprintln!("Merging failed: {}", e);
+		process::exit(1);
+	});
+	eprintln!("Merged {} crates and {} blobs.", stats.crates, stats.blobs);
+	eprintln!("{} conflicts, {} of them unresolved.", stats.conflicts, stats.unresolved);
+}
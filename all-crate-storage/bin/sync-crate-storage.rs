@@ -0,0 +1,95 @@
+extern crate all_crate_storage;
+extern crate reqwest;
+
+use std::fs::{self, File, OpenOptions};
+use std::env;
+use std::path::Path;
+use std::process;
+use reqwest::Client;
+use all_crate_storage::blob_crate_storage::BlobCrateStorage;
+use all_crate_storage::blob_storage::BlobStorage;
+use all_crate_storage::sync::Inventory;
+use all_crate_storage::update_pack;
+
+const USAGE :&str = "Usage: sync-crate-storage <url> <storage>
+
+Fetches the crates that the storage file <storage> lacks from the
+cargo-local-serve instance at <url> and adds them to <storage>, in place.
+If <storage> doesn't exist, it is created.";
+
+fn usage() -> ! {
+	eprintln!("{}", USAGE);
+	process::exit(2);
+}
+
+fn fail<E :std::fmt::Display>(msg :&str, e :E) -> ! {
+	eprintln!("{}: {}", msg, e);
+	process::exit(1);
+}
+
+fn open_storage(path :&str) -> BlobCrateStorage<BlobStorage<File>> {
+	let exists = Path::new(path).exists();
+	let f = OpenOptions::new()
+		.read(true)
+		.write(true)
+		.create(true)
+		.truncate(false)
+		.open(path).unwrap_or_else(|e| fail(&format!("Couldn't open {}", path), e));
+	let res = if exists {
+		BlobCrateStorage::new(f)
+	} else {
+		Ok(BlobCrateStorage::empty(f))
+	};
+	res.unwrap_or_else(|e| fail(&format!("Couldn't open storage file {}", path), e))
+}
+
+fn main() {
+	let args = env::args().skip(1).collect::<Vec<_>>();
+	if args.len() != 2 {
+		usage();
+	}
+	let url = format!("{}/api/v1/sync", args[0].trim_end_matches('/'));
+	let mut storage = open_storage(&args[1]);
+
+	let inventory = Inventory::of_storage(&mut storage)
+		.unwrap_or_else(|e| fail("Couldn't read the storage", e));
+	eprintln!("Local storage has {} crates.", inventory.crate_count());
+	let mut request = Vec::new();
+	inventory.serialize(&mut request).unwrap();
+
+	// Creating the pack can take a while on the other end
+	let client = Client::builder()
+		.timeout(None)
+		.build()
+		.unwrap_or_else(|e| fail("Couldn't create HTTP client", e));
+	let mut resp = client.post(&url)
+		.body(request)
+		.send()
+		.unwrap_or_else(|e| fail(&format!("Request to {} failed", url), e));
+	if !resp.status().is_success() {
+		fail(&format!("Request to {} failed", url), resp.status());
+	}
+	let pack_path = format!("{}.sync-pack", args[1]);
+	// A pack left over from an interrupted run gets overwritten
+	let mut pack_f = OpenOptions::new()
+		.read(true)
+		.write(true)
+		.create(true)
+		.truncate(true)
+		.open(&pack_path)
+		.unwrap_or_else(|e| fail(&format!("Couldn't create {}", pack_path), e));
+	if let Err(e) = resp.copy_to(&mut pack_f) {
+		let _ = fs::remove_file(&pack_path);
+		fail("Couldn't download the update pack", e);
+	}
+
+	let res = BlobCrateStorage::new(pack_f)
+		.and_then(|mut pack| update_pack::apply_update_pack(&mut storage, &mut pack));
+	let _ = fs::remove_file(&pack_path);
+	match res {
+		Ok(stats) => {
+			eprintln!("Added {} crates, {} blobs.", stats.crates, stats.blobs);
+		},
+		Err(e) => fail("Failed", e),
+	}
+}
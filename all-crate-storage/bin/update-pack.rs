@@ -0,0 +1,69 @@
+extern crate all_crate_storage;
+
+use std::fs::{File, OpenOptions};
+use std::env;
+use std::process;
+use all_crate_storage::blob_crate_storage::BlobCrateStorage;
+use all_crate_storage::blob_storage::BlobStorage;
+use all_crate_storage::update_pack;
+
+const USAGE :&str = "Usage: update-pack create <old> <new> <pack>
+       update-pack apply <storage> <pack>
+
+create: writes the blobs and index entries that the storage file <new>
+        has and <old> lacks into the new file <pack>.
+apply:  adds the content of <pack> to <storage>, in place.";
+
+fn usage() -> ! {
+	eprintln!("{}", USAGE);
+	process::exit(2);
+}
+
+fn open_storage(path :&str, write :bool) -> BlobCrateStorage<BlobStorage<File>> {
+	let f = OpenOptions::new()
+		.read(true)
+		.write(write)
+		.open(path).unwrap_or_else(|e| {
+			eprintln!("Couldn't open {}: {}", path, e);
+			process::exit(2);
+		});
+	BlobCrateStorage::new(f).unwrap_or_else(|e| {
+		eprintln!("Couldn't open storage file {}: {}", path, e);
+		process::exit(2);
+	})
+}
+
+fn main() {
+	let args = env::args().skip(1).collect::<Vec<_>>();
+	let res = match (args.first().map(|s| &s[..]), args.len()) {
+		(Some("create"), 4) => {
+			let mut old = open_storage(&args[1], false);
+			let new = open_storage(&args[2], false);
+			let pack_f = OpenOptions::new()
+				.read(true)
+				.write(true)
+				.create_new(true)
+				.open(&args[3]).unwrap_or_else(|e| {
+					eprintln!("Couldn't create {}: {}", args[3], e);
+					process::exit(2);
+				});
+			let mut pack = BlobCrateStorage::empty(pack_f);
+			update_pack::create_update_pack(&mut old, &new, &mut pack)
+		},
+		(Some("apply"), 3) => {
+			let mut storage = open_storage(&args[1], true);
+			let mut pack = open_storage(&args[2], false);
+			update_pack::apply_update_pack(&mut storage, &mut pack)
+		},
+		_ => usage(),
+	};
+	match res {
+		Ok(stats) => {
+			eprintln!("{} crates, {} blobs.", stats.crates, stats.blobs);
+		},
+		Err(e) => {
+			eprintln!("Failed: {}", e);
+			process::exit(1);
+		},
+	}
+}
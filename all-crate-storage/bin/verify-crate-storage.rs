@@ -0,0 +1,104 @@
+extern crate all_crate_storage;
+extern crate serde_json;
+
+use std::env;
+use std::path::Path;
+use std::process;
+use all_crate_storage::registry::registry;
+use all_crate_storage::blob_crate_storage::BlobCrateStorage;
+use all_crate_storage::crate_storage::{CrateSource, CrateSpec, FileTreeStorage};
+use all_crate_storage::dir_blob_storage::DirBlobStorage;
+use all_crate_storage::verify::{self, Finding, Problem};
+use all_crate_storage::volumes::VolumeSet;
+use self::registry::{Registry, AllCratesJson};
+
+const USAGE :&str = "Usage: verify-crate-storage (storage-file|blob-dir|archive-tree) [<path>]
+
+Checks all crates of the registry index against the given storage.
+Problems are printed to stdout, as one JSON object per line.";
+
+/// Walks all crates and prints the problems found
+///
+/// Returns the number of crates checked and the number of problems found.
+fn verify_all<S :CrateSource, F :FnMut(&mut S, &CrateSpec) -> Vec<Problem>>(
+		source :&mut S, acj :&AllCratesJson, mut verify_blobs :F) -> (usize, usize) {
+	let mut checked = 0;
+	let mut problem_count = 0;
+	for (_, versions) in acj.iter() {
+		for v in versions.iter() {
+			checked += 1;
+			let spec = CrateSpec {
+				name : v.name.clone(),
+				version : v.version.clone(),
+			};
+			let mut problems = verify::verify_crate(source, v)
+				.into_iter()
+				.collect::<Vec<_>>();
+			// Crates that are present get their blobs checked even if
+			// they couldn't be obtained, to find the blobs at fault
+			if problems != [Problem::Missing] {
+				problems.extend(verify_blobs(source, &spec));
+			}
+			for problem in problems {
+				problem_count += 1;
+				let finding = Finding {
+					name : v.name.clone(),
+					version : v.version.to_string(),
+					problem,
+				};
+				println!("{}", serde_json::to_string(&finding).unwrap());
+			}
+		}
+	}
+	(checked, problem_count)
+}
+
+fn main() {
+	let mut args = env::args().skip(1);
+	let kind = args.next().unwrap_or_else(|| {
+		eprintln!("{}", USAGE);
+		process::exit(2);
+	});
+	let path = args.next();
+
+	eprintln!("Loading all crates json...");
+	let registry = Registry::from_name("github.com-1ecc6299db9ec823").unwrap();
+	let acj :AllCratesJson = registry.get_all_crates_json().unwrap();
+
+	let (checked, problems) = match &kind[..] {
+		"storage-file" => {
+			let path = path.unwrap_or_else(|| "crate-constr-archives/crate_storage".to_owned());
+			let f = VolumeSet::open(Path::new(&path)).unwrap_or_else(|e| {
+				eprintln!("Couldn't open storage file {}: {}", path, e);
+				process::exit(2);
+			});
+			let mut bcs = BlobCrateStorage::new(f).unwrap_or_else(|e| {
+				eprintln!("Couldn't open storage file {}: {}", path, e);
+				process::exit(2);
+			});
+			verify_all(&mut bcs, &acj, verify::verify_crate_blobs)
+		},
+		"blob-dir" => {
+			let path = path.unwrap_or_else(|| "crate-constr-archives/blobs".to_owned());
+			let dbs = DirBlobStorage::open(Path::new(&path)).unwrap_or_else(|e| {
+				eprintln!("Couldn't open blob directory {}: {}", path, e);
+				process::exit(2);
+			});
+			let mut bcs = BlobCrateStorage::from_blob_store(dbs);
+			verify_all(&mut bcs, &acj, verify::verify_crate_blobs)
+		},
+		"archive-tree" => {
+			let path = path.unwrap_or_else(|| "crate-archives".to_owned());
+			let mut fts = FileTreeStorage::new(path.as_ref());
+			verify_all(&mut fts, &acj, |_, _| Vec::new())
+		},
+		_ => {
+			eprintln!("{}", USAGE);
+			process::exit(2);
+		},
+	};
+	eprintln!("Checked {} crates, found {} problems.", checked, problems);
+	if problems > 0 {
+		process::exit(1);
+	}
+}
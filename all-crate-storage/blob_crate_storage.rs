@@ -1,235 +1,475 @@
-use super::blob_storage::BlobStorage;
-use super::hash_ctx::{HashCtx, Digest};
+use super::blob_storage::{BlobStorage, StorageError, StorageErrorKind,
+	FEATURE_ZSTD, FEATURE_DIFF_KINDS};
+use super::blob_store::{BlobStore, BlobStoreMut};
+use super::hash_ctx::{HashCtx, Digest, VerifyingReader, digest_of};
 use super::reconstruction::{CrateContentBlobs, CrateRecMetadata,
-	CrateRecMetaWithBlobs, hdr_from_ptr};
-use super::crate_storage::{CrateStorage, CrateSpec, CrateSource,
-	CrateHandle, CrateFileHandle};
+	CrateRecMetaWithBlobs, EntryMetadata, hdr_from_ptr, archive_gz_builder};
+use super::crate_storage::{CrateStorage, CrateSpec, CrateSource, RepairableCrateSource,
+	CrateHandle, CrateFileHandle, FileMetadata, sort_specs};
 use super::multi_blob::MultiBlob;
-use super::diff::Diff;
-use super::multi_blob_crate_storage::GraphOfBlobs;
+use super::multi_blob_crate_storage::{GraphOfBlobs, BlobComponent};
+use super::codec::{self, Codec, BlobCompressor, Dictionary};
+use super::read_at::ReadAt;
+use super::cache::{LruCache, CacheStats};
+use super::extraction::ExtractionLimits;
+use super::error::{self, CrateError, CrateErrorKind};
 
 use semver::Version;
-use flate2::{Compression, GzBuilder};
-use flate2::read::GzDecoder;
-use std::io::{self, Read, Seek, Write, Result as IoResult};
-use std::collections::HashSet;
+use failure::{Fail, ResultExt};
+use flate2::Compression;
+use flate2::write::GzEncoder;
+use std::io::{self, Read, Write, Cursor};
+use std::mem;
+use std::vec;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex, MutexGuard};
 
-pub struct BlobCrateStorage<S :Read + Seek> {
-	pub(crate) b :BlobStorage<S>,
-}
+/// Number of crates whose files are used to train the zstd dictionary
+const DICT_SAMPLE_CRATES :usize = 200;
+/// Number of blobs used to train the zstd dictionary when creating multi blobs
+const DICT_SAMPLE_BLOBS :usize = 2000;
+/// Maximum length of a single sample for dictionary training
+const DICT_SAMPLE_MAX_LEN :usize = 16 * 1024;
 
-macro_rules! optry {
-	($e:expr) => {
-		match $e {
-			Some(d) => d,
-			None => return None,
-		}
-	};
+pub struct BlobCrateStorage<B :BlobStore> {
+	pub(crate) b :B,
+	/// The codec used for newly written blobs
+	codec :Codec,
+	/// Cache of the dictionaries we already loaded
+	dictionaries :Mutex<HashMap<u32, Arc<Vec<u8>>>>,
+	/// Cache of deserialized crate metadata, by digest of the metadata blob
+	meta_cache :LruCache<Digest, CrateRecMetadata>,
+	/// Whether the digests of blobs are checked when reading them
+	verify_digests :bool,
+	/// Limits for the .crate files that get stored
+	limits :ExtractionLimits,
 }
 
-macro_rules! decompress {
-	($e:expr) => {{
-		let mut gz_dec = GzDecoder::new($e.as_slice());
-		let mut r = Vec::new();
-		io::copy(&mut gz_dec, &mut r).unwrap();
-		r
-	}}
+impl<S :ReadAt> BlobCrateStorage<BlobStorage<S>> {
+	pub fn empty(storage :S) -> Self {
+		BlobCrateStorage::from_blob_store(BlobStorage::empty(storage))
+	}
+	pub fn new(storage :S) -> Result<Self, StorageError> {
+		Ok(BlobCrateStorage::from_blob_store(try!(BlobStorage::new(storage))))
+	}
+	pub fn load(storage :S) -> Result<Self, StorageError> {
+		Ok(BlobCrateStorage::from_blob_store(try!(BlobStorage::load(storage))))
+	}
 }
 
-impl<S :Read + Seek> BlobCrateStorage<S> {
-	pub fn empty(storage :S) -> Self {
+impl<B :BlobStore> BlobCrateStorage<B> {
+	/// Creates a crate storage that keeps its blobs in the given store
+	pub fn from_blob_store(b :B) -> Self {
 		BlobCrateStorage {
-			b : BlobStorage::empty(storage),
+			b,
+			codec : Codec::Gzip,
+			dictionaries : Mutex::new(HashMap::new()),
+			meta_cache : LruCache::new(0),
+			verify_digests : true,
+			limits : ExtractionLimits::default(),
 		}
 	}
-	pub fn new(storage :S) -> IoResult<Self> {
-		Ok(BlobCrateStorage {
-			b : try!(BlobStorage::new(storage)),
-		})
+	/// Sets the codec that newly written blobs get compressed with
+	///
+	/// The default is gzip. For zstd, a dictionary is trained
+	/// on the first stored crates, unless the storage
+	/// already contains one.
+	pub fn set_codec(&mut self, codec :Codec) {
+		self.codec = codec;
 	}
-	pub fn load(storage :S) -> IoResult<Self> {
-		Ok(BlobCrateStorage {
-			b : try!(BlobStorage::load(storage)),
-		})
+	/// Sets whether the digests of blobs are checked when reading them
+	///
+	/// This is enabled by default. Blobs whose content doesn't
+	/// match their digest result in a `ChecksumMismatch` error.
+	/// Disabling the check saves a bit of time when reading.
+	pub fn set_verify_digests(&mut self, verify :bool) {
+		self.verify_digests = verify;
+	}
+	/// Sets the limits for the .crate files that get stored or repaired
+	///
+	/// Crates exceeding them are stored as they are,
+	/// without splitting them up into blobs.
+	pub fn set_extraction_limits(&mut self, limits :ExtractionLimits) {
+		self.limits = limits;
+	}
+	/// Sets how many crates the metadata is kept in memory for
+	///
+	/// The metadata contains the list of files of a crate
+	/// and where their content can be found. Keeping it
+	/// in memory saves decompressing it for each access.
+	/// The default is zero, which disables the cache.
+	pub fn set_metadata_cache_capacity(&mut self, capacity :usize) {
+		self.meta_cache.set_capacity(capacity);
+	}
+	/// Returns the statistics of the metadata cache
+	pub fn metadata_cache_stats(&self) -> CacheStats {
+		self.meta_cache.stats()
+	}
+	fn loaded_dictionaries(&self) -> MutexGuard<'_, HashMap<u32, Arc<Vec<u8>>>> {
+		// The cache is valid even if a thread has panicked while holding the lock
+		self.dictionaries.lock().unwrap_or_else(|e| e.into_inner())
+	}
+	fn load_dictionary(&self, id :u32) -> Result<Arc<Vec<u8>>, StorageError> {
+		if let Some(dict) = self.loaded_dictionaries().get(&id) {
+			return Ok(dict.clone());
+		}
+		let dict_d = *try!(self.b.dictionaries().get(&id).ok_or(StorageErrorKind::MissingBlob));
+		let dict = try!(self.b.get(&dict_d));
+		let dict = Arc::new(try!(dict.ok_or(StorageErrorKind::MissingBlob)));
+		self.loaded_dictionaries().insert(id, dict.clone());
+		Ok(dict)
+	}
+	fn get_dictionary(&self, id :u32) -> Result<Arc<Vec<u8>>, CrateError> {
+		self.load_dictionary(id).map_err(error::from_storage)
+	}
+	/// Returns the most recently added dictionary, if there is any
+	fn latest_dictionary(&self) -> Result<Option<Dictionary>, StorageError> {
+		let id = match self.b.dictionaries().keys().max() {
+			Some(id) => *id,
+			None => return Ok(None),
+		};
+		let content = try!(self.load_dictionary(id));
+		Ok(Some(Dictionary {
+			id,
+			content,
+		}))
+	}
+	fn decompress(&self, blob :&[u8]) -> Result<Vec<u8>, CrateError> {
+		let dict = match codec::needed_dictionary(blob) {
+			Some(id) => Some(try!(self.get_dictionary(id))),
+			None => None,
+		};
+		codec::decompress(blob, dict.as_ref().map(|d| &d[..]))
+			.map_err(|e| e.context(CrateErrorKind::Corrupt))
+	}
+	/// Checks the content obtained for the given digest
+	fn check_content(&self, d :&Digest, content :Vec<u8>,
+			verify :bool) -> Result<Vec<u8>, CrateError> {
+		if verify && digest_of(&content) != *d {
+			return Err(CrateErrorKind::ChecksumMismatch.into());
+		}
+		Ok(content)
 	}
-	pub(crate) fn get_crate_rec_meta(&mut self, s :&CrateSpec) -> Option<CrateRecMetadata> {
-		let meta_d = optry!(self.b.name_index.get(&s.file_name())).clone();
+	/// Returns the file names of all crates in the storage
+	fn crate_file_names(&self) -> Result<HashSet<String>, CrateError> {
+		let names = try!(self.b.names().map_err(error::from_storage));
+		let raw_names = try!(self.b.raw_crates().map_err(error::from_storage));
+		Ok(names.into_iter()
+			.chain(raw_names)
+			.map(|(name, _)| name)
+			.collect())
+	}
+	pub(crate) fn get_crate_rec_meta(&self, s :&CrateSpec) -> Result<CrateRecMetadata, CrateError> {
+		let meta_d = try!(self.b.get_named(&s.file_name()).map_err(error::from_storage));
+		let meta_d = try!(meta_d.ok_or(CrateErrorKind::NotFound));
 
-		let cmeta = optry!(optry!(self.b.get(&meta_d).ok()));
-		let dmeta = decompress!(cmeta);
-		let meta = optry!(CrateRecMetadata::deserialize(dmeta.as_slice()).ok());
-		Some(meta)
+		if let Some(meta) = self.meta_cache.get(&meta_d) {
+			return Ok(meta);
+		}
+		let dmeta = try!(self.get_blob(&meta_d));
+		let mut meta = try!(CrateRecMetadata::deserialize(dmeta.as_slice())
+			.map_err(|e| e.context(CrateErrorKind::Corrupt)));
+		// Older storages lack the path table, so build it here
+		let resolved = meta.resolve_path_table(|d| self.get_blob(d).ok());
+		try!(resolved.ok_or(CrateErrorKind::Corrupt));
+		self.meta_cache.insert(meta_d, meta.clone());
+		Ok(meta)
+	}
+	/// Obtains the decompressed content of the blob with the given digest
+	///
+	/// The blob can either be stored directly, or as part of a multi blob.
+	/// Blobs are only requested if something refers to them,
+	/// so missing blobs are reported as corruption.
+	pub(crate) fn get_blob(&self, d :&Digest) -> Result<Vec<u8>, CrateError> {
+		let verify = self.verify_digests;
+		self.get_blob_verify(d, verify)
+	}
+	/// Returns a reader for the decompressed content of the blob with the given digest
+	///
+	/// Blobs that are stored directly are decompressed while being read,
+	/// and their digest is checked once the end is reached.
+	/// Blobs inside multi blobs are reconstructed as a whole first.
+	pub(crate) fn get_blob_reader<'a>(&'a self, d :&Digest) -> Result<Box<dyn Read + 'a>, CrateError> {
+		let blob = match try!(self.b.get(d).map_err(error::from_storage)) {
+			Some(blob) => blob,
+			None => return Ok(Box::new(Cursor::new(try!(self.get_blob(d))))),
+		};
+		let dict = match codec::needed_dictionary(&blob) {
+			Some(id) => Some(try!(self.get_dictionary(id))),
+			None => None,
+		};
+		let rdr = try!(codec::decompressing_reader(Cursor::new(blob), dict.as_ref().map(|d| &d[..]))
+			.map_err(|e| e.context(CrateErrorKind::Corrupt)));
+		if !self.verify_digests {
+			return Ok(rdr);
+		}
+		Ok(Box::new(VerifyingReader::new(rdr, *d)))
+	}
+	/// Whether the diffs in the multi blobs are prefixed with their kind
+	pub(crate) fn diff_kinds(&self) -> bool {
+		self.b.features() & FEATURE_DIFF_KINDS != 0
+	}
+	pub(crate) fn get_blob_verify(&self, d :&Digest, verify :bool) -> Result<Vec<u8>, CrateError> {
+		if let Some(blob) = try!(self.b.get(d).map_err(error::from_storage)) {
+			let content = try!(self.decompress(&blob));
+			return self.check_content(d, content, verify);
+		}
+		let mblob_d = try!(self.b.get_multi_blob(d).map_err(error::from_storage));
+		let mblob_d = try!(mblob_d.ok_or(CrateErrorKind::Corrupt));
+		let mblob = try!(self.b.get(&mblob_d).map_err(error::from_storage));
+		let mblob = try!(mblob.ok_or(CrateErrorKind::Corrupt));
+		let dmblob = try!(self.decompress(&mblob));
+		let mb = try!(MultiBlob::deserialize(dmblob.as_slice(), self.diff_kinds())
+			.map_err(|e| e.context(CrateErrorKind::Corrupt)));
+		let content = try!(mb.get_blob(*d).ok_or(CrateErrorKind::Corrupt));
+		self.check_content(d, content, verify)
 	}
 }
 
-impl<S :Read + Seek + Write> BlobCrateStorage<S> {
-	pub fn store(&mut self) -> io::Result<()> {
-		try!(self.b.write_header_and_index());
+impl<B :BlobStoreMut> BlobCrateStorage<B> {
+	pub fn store(&mut self) -> Result<(), StorageError> {
+		try!(self.b.write_index());
 		Ok(())
 	}
-}
+	/// Re-stores the corrupt blobs of a crate from an intact copy of its .crate file
+	///
+	/// The copy is only used if it matches what has been stored for the crate.
+	/// Returns whether any blob has been repaired.
+	/// The repaired blobs are written as a checkpoint.
+	pub fn restore_crate_blobs(&mut self, spec :&CrateSpec,
+			crate_file :&[u8]) -> Result<bool, StorageError> {
+		let file_name = spec.file_name();
+		if let Some(d) = try!(self.b.get_raw_crate(&file_name)) {
+			// Raw crates are stored uncompressed
+			if digest_of(crate_file) == d && !self.raw_blob_intact(&d) {
+				try!(self.b.replace(d, crate_file));
+				try!(self.b.checkpoint());
+				return Ok(true);
+			}
+			return Ok(false);
+		}
+		let meta_d = match try!(self.b.get_named(&file_name)) {
+			Some(d) => d,
+			None => return Ok(false),
+		};
+		let ccb = match CrateContentBlobs::from_archive_file_limited(crate_file, &self.limits) {
+			Ok(ccb) => ccb,
+			Err(_) => return Ok(false),
+		};
+		let CrateRecMetaWithBlobs { mut meta, blobs: content_blobs } = ccb.into_meta_with_blobs();
+		let mut meta_blob = Vec::new();
+		try!(meta.serialize(&mut meta_blob).context(StorageErrorKind::Io));
+		if digest_of(&meta_blob) != meta_d {
+			// The crate might have been stored without the path table
+			meta.path_table = None;
+			meta_blob.clear();
+			try!(meta.serialize(&mut meta_blob).context(StorageErrorKind::Io));
+		}
+		if digest_of(&meta_blob) != meta_d {
+			// The copy differs from the stored crate
+			return Ok(false);
+		}
+		let blobs = Some((meta_d, meta_blob)).into_iter().chain(content_blobs);
 
-fn get_digest_lists(blob_graph :&GraphOfBlobs) -> Vec<Vec<Digest>> {
-	let mut res = Vec::with_capacity(blob_graph.roots.len());
-	let graph = &blob_graph.graph;
-	let mut visited = HashSet::new();
-	for root in blob_graph.roots.iter() {
-		let mut mblob_digests = Vec::new();
-		let mut to_walk = Vec::new();
-		to_walk.push(*root);
-		// TODO perform in-order traversal
-		// TODO also actually return a tree not a traversal.
-		// traversals perform pretty crappily if we have a tree present :).
-		while let Some(n) = to_walk.pop() {
-			if !visited.insert(n) {
+		let compressor = try!(self.obtain_compressor(&[]));
+		let mut repaired = false;
+		for (d, blob) in blobs {
+			if self.get_blob_verify(&d, true).is_ok() {
 				continue;
 			}
-			let digest = graph.node_weight(n).unwrap();
-			mblob_digests.push(*digest);
-			let n = graph.neighbors(n);
-			for neigh in n {
-				to_walk.push(neigh);
+			let compressed = try!(compressor.compress(&blob).context(StorageErrorKind::Io));
+			try!(self.b.replace(d, &compressed));
+			repaired = true;
+		}
+		if repaired {
+			try!(self.b.checkpoint());
+		}
+		Ok(repaired)
+	}
+	fn raw_blob_intact(&self, d :&Digest) -> bool {
+		match self.b.get(d) {
+			Ok(Some(blob)) => digest_of(&blob) == *d,
+			_ => false,
+		}
+	}
+	/// Adds a dictionary to the storage
+	fn insert_dictionary(&mut self, dict :&Dictionary) -> Result<(), StorageError> {
+		let mut hctx = HashCtx::new();
+		try!(io::copy(&mut dict.content.as_slice(), &mut hctx).context(StorageErrorKind::Io));
+		let digest = hctx.finish_and_get_digest();
+		try!(self.b.insert(digest, &dict.content));
+		self.b.insert_dictionary(dict.id, digest);
+		self.loaded_dictionaries().insert(dict.id, dict.content.clone());
+		Ok(())
+	}
+	/// Copies the dictionaries of another storage over to this one
+	///
+	/// This is needed before compressed blobs can be copied over.
+	pub(crate) fn copy_dictionaries_from<C :BlobStore>(&mut self,
+			src :&mut BlobCrateStorage<C>) -> Result<(), StorageError> {
+		let ids = src.b.dictionaries().keys().cloned().collect::<Vec<_>>();
+		for id in ids {
+			if let Some(d) = self.b.dictionaries().get(&id) {
+				if Some(d) != src.b.dictionaries().get(&id) {
+					return Err(StorageErrorKind::ConflictingDictionary(id).into());
+				}
+				continue;
 			}
+			let content = match src.get_dictionary(id) {
+				Ok(c) => c,
+				Err(_) => return Err(StorageErrorKind::MissingBlob.into()),
+			};
+			try!(self.insert_dictionary(&Dictionary { id, content }));
+		}
+		Ok(())
+	}
+	/// Obtains the compressor for newly written blobs
+	///
+	/// If zstd is used and the storage doesn't have a dictionary yet,
+	/// one is trained on the given samples. Without samples,
+	/// blobs are compressed without a dictionary.
+	pub(crate) fn obtain_compressor(&mut self, samples :&[Vec<u8>]) -> Result<BlobCompressor, StorageError> {
+		if self.codec != Codec::Zstd {
+			return Ok(BlobCompressor::new(self.codec, None));
+		}
+		self.b.add_features(FEATURE_ZSTD);
+		if let Some(dict) = try!(self.latest_dictionary()) {
+			return Ok(BlobCompressor::new(self.codec, Some(dict)));
+		}
+		let id = self.b.dictionaries().keys().max().unwrap_or(&0) + 1;
+		let dict = Dictionary::train(id, samples);
+		if let Some(ref dict) = dict {
+			try!(self.insert_dictionary(dict));
+		}
+		Ok(BlobCompressor::new(self.codec, dict))
+	}
+	/// Makes sure that multi blobs with the given diff format can be added
+	///
+	/// A storage that has no multi blobs yet adopts the format.
+	pub(crate) fn use_diff_format(&mut self, diff_kinds :bool) -> Result<(), StorageError> {
+		if self.diff_kinds() == diff_kinds {
+			return Ok(());
+		}
+		if self.diff_kinds() || !try!(self.b.multi_blob_entries()).is_empty() {
+			return Err(StorageErrorKind::MixedDiffFormats.into());
+		}
+		self.b.add_features(FEATURE_DIFF_KINDS);
+		Ok(())
+	}
+	/// Takes over the feature flags of a storage whose blobs are copied over
+	pub(crate) fn add_features_of<C :BlobStore>(&mut self,
+			src :&BlobCrateStorage<C>) -> Result<(), StorageError> {
+		if !try!(src.b.multi_blob_entries()).is_empty() {
+			try!(self.use_diff_format(src.diff_kinds()));
 		}
-		res.push(mblob_digests);
+		self.b.add_features(src.b.features() & !FEATURE_DIFF_KINDS);
+		Ok(())
 	}
-	res
 }
 
-impl<S :Read + Seek + Write> BlobCrateStorage<S> {
-	pub fn store_parallel_mb<T :Read + Seek + Write>(
-			&mut self, src :&mut BlobCrateStorage<T>,
-			blob_graph :&GraphOfBlobs, thread_count :u16) {
-		use std::sync::mpsc::{sync_channel, TrySendError};
-		use multiqueue::mpmc_queue;
-		use std::time::Duration;
-		use std::thread;
-
-		// TODO somehow also store the metadata -- without it we can't provide reading functionality to anyone
-
-		let digest_lists = get_digest_lists(blob_graph);
-		let mut digests_iter = digest_lists.iter();
-		let mut to_go = digest_lists.len();
-
-		let (bt_tx, bt_rx) = sync_channel(3 * thread_count as usize);
-		let (pt_tx, pt_rx) = mpmc_queue(3 * thread_count as u64);
-		for _ in 0 .. thread_count {
-			let bt_tx = bt_tx.clone();
-			let pt_rx = pt_rx.clone();
-			thread::spawn(move || {
-				while let Ok(task) = pt_rx.recv() {
-					handle_parallel_task(task, |bt| bt_tx.send(bt).unwrap());
-				}
-			});
-		}
-		drop(bt_tx);
-		pt_rx.unsubscribe();
-		let mut par_task_backlog = Vec::new();
-		let mut blobs_to_store = HashSet::new();
-		loop {
-			let mut done_something = false;
-			if let Ok(task) = bt_rx.recv_timeout(Duration::new(0, 50_000)) {
-				handle_blocking_task(task, &mut self.b,
-					&mut blobs_to_store, |tsk| par_task_backlog.push(tsk));
-				done_something = true;
-			}
-			if par_task_backlog.is_empty() {
-				for digests in (&mut digests_iter).take(10) {
-					to_go -= 1;
-					println!("{} {}", to_go, digests.len());
-					let blobs = digests.iter()
-						// TODO don't use unwrap here
-						// TODO instead of filter_map and skipping report an error or something something
-						.filter_map(|digest| src.b.get(digest).unwrap().map(|b|(*digest, b)))
-						.collect::<Vec<_>>();
-					par_task_backlog.push(ParallelTask::CreateMultiBlob(blobs));
-					done_something = true;
-				}
-			}
-			loop {
-				let mut removed_something = false;
-				if let Some(t) = par_task_backlog.pop() {
-					if let Err(e) = pt_tx.try_send(t) {
-						let t = match e {
-							TrySendError::Full(t) => t,
-							TrySendError::Disconnected(t) => t,
-						};
-						par_task_backlog.push(t);
-					} else {
-						removed_something = true;
-						done_something = true;
-					}
-				}
-				if !removed_something {
+impl<B :BlobStoreMut> BlobCrateStorage<B> {
+	/// Stores the blobs of the given storage as multi blobs
+	///
+	/// No chain of diffs inside a multi blob will be longer
+	/// than `max_chain_depth`.
+	pub fn store_parallel_mb<C :BlobStore>(
+			&mut self, src :&mut BlobCrateStorage<C>,
+			blob_graph :&GraphOfBlobs, thread_count :u16,
+			max_chain_depth :usize) -> Result<(), StorageError> {
+		// Copy over the crate metadata so that the crates can be reconstructed
+		try!(self.copy_dictionaries_from(src));
+		try!(self.use_diff_format(true));
+		// Components whose blobs are all present from an earlier run are skipped
+		let mut components = Vec::new();
+		for component in blob_graph.components() {
+			let mut all_present = true;
+			for d in component.digests.iter() {
+				if !try!(blob_present(&self.b, d)) {
+					all_present = false;
 					break;
 				}
 			}
-			if !done_something && par_task_backlog.is_empty() {
-				break;
+			if !all_present {
+				components.push(component);
 			}
 		}
+		// Train the dictionary on the blobs of the first components
+		// unless the source storage brought one along
+		let samples = if self.codec == Codec::Zstd && self.b.dictionaries().is_empty() {
+			components.iter()
+				.flat_map(|c| c.digests.iter())
+				.take(DICT_SAMPLE_BLOBS)
+				.filter_map(|d| src.get_blob(d).ok())
+				.map(|mut blob| {
+					blob.truncate(DICT_SAMPLE_MAX_LEN);
+					blob
+				})
+				.collect::<Vec<_>>()
+		} else {
+			Vec::new()
+		};
+		let compressor = try!(self.obtain_compressor(&samples));
+		let names = try!(src.b.names());
+		for (name, meta_d) in names {
+			if !try!(self.b.has(&meta_d)) {
+				let meta_blob = try!(src.b.get(&meta_d));
+				let meta_blob = try!(meta_blob.ok_or(StorageErrorKind::MissingBlob));
+				try!(self.b.insert(meta_d, &meta_blob));
+			}
+			self.b.insert_name(name, meta_d);
+		}
+
+		let mut components_iter = components.iter();
+
+		run_tasks(&mut self.b, &compressor, thread_count, || {
+			let mut tasks = Vec::new();
+			for BlobComponent { digests, edges } in (&mut components_iter).take(10) {
+				let mut blobs = Vec::with_capacity(digests.len());
+				for digest in digests.iter() {
+					let blob = try!(src.get_blob(digest).context(StorageErrorKind::MissingBlob));
+					blobs.push((*digest, blob));
+				}
+				tasks.push(ParallelTask::CreateMultiBlob(blobs,
+					edges.clone(), max_chain_depth, compressor.clone()));
+			}
+			Ok(tasks)
+		})
 	}
 }
 
-impl<S :Read + Seek + Write> CrateStorage for BlobCrateStorage<S> {
+impl<B :BlobStoreMut> CrateStorage for BlobCrateStorage<B> {
 	fn store_parallel_iter<I :Iterator<Item = (CrateSpec, Vec<u8>, Digest)>>(
-			&mut self, thread_count :u16, mut crate_iter :I) {
-		use std::sync::mpsc::{sync_channel, TrySendError};
-		use multiqueue::mpmc_queue;
-		use std::time::Duration;
-		use std::thread;
-
-		let (bt_tx, bt_rx) = sync_channel(3 * thread_count as usize);
-		let (pt_tx, pt_rx) = mpmc_queue(3 * thread_count as u64);
-		for _ in 0 .. thread_count {
-			let bt_tx = bt_tx.clone();
-			let pt_rx = pt_rx.clone();
-			thread::spawn(move || {
-				while let Ok(task) = pt_rx.recv() {
-					handle_parallel_task(task, |bt| bt_tx.send(bt).unwrap());
-				}
-			});
-		}
-		drop(bt_tx);
-		pt_rx.unsubscribe();
-		let mut par_task_backlog = Vec::new();
-		let mut blobs_to_store = HashSet::new();
-		loop {
-			let mut done_something = false;
-			if let Ok(task) = bt_rx.recv_timeout(Duration::new(0, 50_000)) {
-				handle_blocking_task(task, &mut self.b,
-					&mut blobs_to_store, |tsk| par_task_backlog.push(tsk));
-				done_something = true;
-			}
-			if par_task_backlog.is_empty() {
-				for (sp, b, d) in (&mut crate_iter).take(10) {
-					let name = sp.file_name();
-					par_task_backlog.push(ParallelTask::ObtainCrateContentBlobs(name, b, d));
-					done_something = true;
-				}
-			}
-			loop {
-				let mut removed_something = false;
-				if let Some(t) = par_task_backlog.pop() {
-					if let Err(e) = pt_tx.try_send(t) {
-						let t = match e {
-							TrySendError::Full(t) => t,
-							TrySendError::Disconnected(t) => t,
-						};
-						par_task_backlog.push(t);
-					} else {
-						removed_something = true;
-						done_something = true;
-					}
-				}
-				if !removed_something {
-					break;
-				}
-			}
-			if !done_something && par_task_backlog.is_empty() {
-				break;
-			}
-		}
+			&mut self, thread_count :u16, mut crate_iter :I) -> Result<(), CrateError> {
+		// Take the first few crates to train the zstd dictionary on.
+		// They are stored like all the other crates afterwards.
+		let sample_crates = if self.codec == Codec::Zstd && self.b.dictionaries().is_empty() {
+			(&mut crate_iter).take(DICT_SAMPLE_CRATES).collect::<Vec<_>>()
+		} else {
+			Vec::new()
+		};
+		let samples = sample_crates.iter()
+			.filter_map(|(_, b, _)| {
+				CrateContentBlobs::from_archive_file_limited(&b[..], &self.limits).ok()
+			})
+			.flat_map(|ccb| ccb.into_meta_with_blobs().blobs)
+			.map(|(_, mut blob)| {
+				blob.truncate(DICT_SAMPLE_MAX_LEN);
+				blob
+			})
+			.collect::<Vec<_>>();
+		let compressor = try!(self.obtain_compressor(&samples).map_err(error::from_storage));
+		let mut crate_iter = sample_crates.into_iter().chain(crate_iter);
+		let limits = self.limits;
+
+		let res = run_tasks(&mut self.b, &compressor, thread_count, || {
+			Ok((&mut crate_iter).take(10)
+				.map(|(sp, b, d)| ParallelTask::ObtainCrateContentBlobs(sp.file_name(), b, d, limits))
+				.collect())
+		});
+		res.map_err(error::from_storage)
+	}
+	fn stored_crate_names(&self) -> Result<HashSet<String>, CrateError> {
+		self.crate_file_names()
 	}
 }
 
@@ -237,206 +477,401 @@ pub struct StorageFileHandle {
 	meta :CrateRecMetadata,
 }
 
-impl<S :Read + Seek> CrateFileHandle<BlobCrateStorage<S>> for StorageFileHandle {
-	fn get_file_list(&self, _source :&mut BlobCrateStorage<S>) -> Vec<String> {
-		self.meta.get_file_list()
-	}
-	fn get_file(&self, source :&mut BlobCrateStorage<S>,
-			path :&str) -> Option<Vec<u8>> {
-		for &(ref hdr, ref d) in self.meta.entry_metadata.iter() {
-			let hdr = hdr_from_ptr(hdr);
-			let p = hdr.path().unwrap();
-			let s = p.to_str().unwrap();
-			if s != path {
-				continue;
-			}
-			let blob = optry!(optry!(source.b.get(d).ok()));
-			let decompressed = decompress!(blob);
-			return Some(decompressed);
-		}
-		None
+impl<B :BlobStore> CrateFileHandle<BlobCrateStorage<B>> for StorageFileHandle {
+	fn get_file_list(&self, _source :&BlobCrateStorage<B>) -> Result<Vec<String>, CrateError> {
+		Ok(self.meta.get_file_list())
+	}
+	fn get_file(&self, source :&BlobCrateStorage<B>,
+			path :&str) -> Result<Vec<u8>, CrateError> {
+		let (_, d) = try!(self.meta.find_entry(path).ok_or(CrateErrorKind::NotFound));
+		source.get_blob(d)
+	}
+	fn get_file_reader<'a>(&'a self, source :&'a BlobCrateStorage<B>,
+			path :&str) -> Result<Box<dyn Read + 'a>, CrateError> {
+		let entry = try!(self.meta.find_entry(path).ok_or(CrateErrorKind::NotFound));
+		source.get_blob_reader(&entry.1)
+	}
+	fn has_file(&self, _source :&BlobCrateStorage<B>, path :&str) -> Result<bool, CrateError> {
+		Ok(self.meta.find_entry(path).is_some())
+	}
+	fn get_file_metadata_list(&self, _source :&BlobCrateStorage<B>) -> Result<Vec<FileMetadata>, CrateError> {
+		// The headers contain everything, so no blob needs to be read
+		Ok(self.meta.files().into_iter()
+			.filter_map(|(path, &(ref hdr, d))| {
+				FileMetadata::from_header(hdr_from_ptr(hdr), path.to_owned(), d)
+			})
+			.collect())
 	}
 }
 
-impl<S :Read + Seek> CrateSource for BlobCrateStorage<S> {
+impl<B :BlobStore> CrateSource for BlobCrateStorage<B> {
 
 	type CrateHandle = StorageFileHandle;
-	fn get_crate_handle_nv(&mut self,
-			name :String, version :Version) -> Option<CrateHandle<Self, Self::CrateHandle>> {
+	fn get_crate_handle_nv(&self,
+			name :String, version :Version) -> Result<CrateHandle<'_, Self, Self::CrateHandle>, CrateError> {
 		let s = CrateSpec {
 			name,
 			version,
 		};
-		let meta = optry!(self.get_crate_rec_meta(&s));
+		let meta = try!(self.get_crate_rec_meta(&s));
 
-		Some(CrateHandle {
+		Ok(CrateHandle {
 			source : self,
 			crate_file_handle : StorageFileHandle {
 				meta
 			},
 		})
 	}
-	fn get_crate(&mut self, s :&CrateSpec) -> Option<Vec<u8>> {
-		let meta = optry!(self.get_crate_rec_meta(s));
+	fn get_crate(&self, s :&CrateSpec) -> Result<Vec<u8>, CrateError> {
+		let raw_d = try!(self.b.get_raw_crate(&s.file_name()).map_err(error::from_storage));
+		if let Some(d) = raw_d {
+			let content = try!(self.b.get(&d).map_err(error::from_storage));
+			let content = try!(content.ok_or(CrateErrorKind::Corrupt));
+			let verify = self.verify_digests;
+			return self.check_content(&d, content, verify);
+		}
+		let meta = try!(self.get_crate_rec_meta(s));
 		let mut blobs = Vec::with_capacity(meta.entry_metadata.len());
-		for &(ref _hdr, ref d) in meta.entry_metadata.iter() {
-			let blob = optry!(optry!(self.b.get(d).ok()));
-			let decompressed = decompress!(blob);
-			blobs.push((*d, decompressed));
+		for (_hdr, d) in meta.entry_metadata.iter() {
+			let blob = try!(self.get_blob(d));
+			blobs.push((*d, blob));
 		}
 		let crmb = CrateRecMetaWithBlobs {
 			meta,
 			blobs
 		};
 		let ccb = CrateContentBlobs::from_meta_with_blobs(crmb);
-		Some(ccb.to_archive_file())
+		Ok(ccb.to_archive_file())
+	}
+	fn has_crate(&self, s :&CrateSpec) -> Result<bool, CrateError> {
+		let file_name = s.file_name();
+		let raw_d = try!(self.b.get_raw_crate(&file_name).map_err(error::from_storage));
+		if raw_d.is_some() {
+			return Ok(true);
+		}
+		let meta_d = try!(self.b.get_named(&file_name).map_err(error::from_storage));
+		Ok(meta_d.is_some())
+	}
+	fn get_crate_reader<'a>(&'a self, s :&CrateSpec) -> Result<Box<dyn Read + 'a>, CrateError> {
+		let raw_d = try!(self.b.get_raw_crate(&s.file_name()).map_err(error::from_storage));
+		if raw_d.is_some() {
+			let content = try!(self.get_crate(s));
+			return Ok(Box::new(Cursor::new(content)));
+		}
+		let meta = try!(self.get_crate_rec_meta(s));
+		Ok(Box::new(ReconstructingReader::new(self, meta)))
+	}
+	fn get_crate_versions(&self, name :&str) -> Result<Vec<Version>, CrateError> {
+		// Only the range of the name is read from the indices.
+		// It can contain other crates, like foo-bar for foo.
+		let prefix = format!("{}-", name);
+		let names = try!(self.b.names_with_prefix(&prefix).map_err(error::from_storage));
+		let raw_names = try!(self.b.raw_crates_with_prefix(&prefix).map_err(error::from_storage));
+		let mut versions = names.into_iter()
+			.chain(raw_names)
+			.filter_map(|(n, _)| CrateSpec::from_file_name(&n))
+			.filter(|s| s.name == name)
+			.map(|s| s.version)
+			.collect::<Vec<_>>();
+		versions.sort();
+		versions.dedup();
+		Ok(versions)
+	}
+	fn get_crate_list(&self) -> Result<Vec<CrateSpec>, CrateError> {
+		let mut specs = try!(self.crate_file_names()).iter()
+			.filter_map(|n| CrateSpec::from_file_name(n))
+			.collect::<Vec<_>>();
+		sort_specs(&mut specs);
+		Ok(specs)
+	}
+}
+
+impl<B :BlobStoreMut> RepairableCrateSource for BlobCrateStorage<B> {
+	fn repair_crate(&mut self, spec :&CrateSpec, crate_file :&[u8]) -> Result<bool, CrateError> {
+		self.restore_crate_blobs(spec, crate_file).map_err(error::from_storage)
+	}
+}
+
+/// Reconstructs a .crate file while it is being read
+///
+/// Only the blob of a single file is held in memory at a time.
+/// Missing or corrupt blobs result in an `InvalidData` error.
+struct ReconstructingReader<'a, B :BlobStore + 'a> {
+	storage :&'a BlobCrateStorage<B>,
+	entries :vec::IntoIter<EntryMetadata>,
+	/// The encoder, or `None` once the archive is complete
+	encoder :Option<GzEncoder<Vec<u8>>>,
+	/// Compressed output that hasn't been read yet
+	out :Vec<u8>,
+	out_pos :usize,
+}
+
+impl<'a, B :BlobStore + 'a> ReconstructingReader<'a, B> {
+	fn new(storage :&'a BlobCrateStorage<B>, meta :CrateRecMetadata) -> Self {
+		let encoder = archive_gz_builder(meta.gz_file_name, meta.gz_os)
+			.write(Vec::new(), Compression::best());
+		ReconstructingReader {
+			storage,
+			entries : meta.entry_metadata.into_iter(),
+			encoder : Some(encoder),
+			out : Vec::new(),
+			out_pos : 0,
+		}
+	}
+	/// Feeds the next entry of the archive into the encoder
+	///
+	/// Returns false if there are no more entries.
+	fn encode_next_entry(&mut self) -> io::Result<bool> {
+		let encoder = match self.encoder {
+			Some(ref mut e) => e,
+			None => return Ok(false),
+		};
+		match self.entries.next() {
+			Some((hdr, d)) => {
+				let blob = try!(self.storage.get_blob(&d).map_err(|e| io::Error::new(
+					io::ErrorKind::InvalidData, e.to_string())));
+				try!(encoder.write_all(&hdr[..]));
+				try!(encoder.write_all(&blob));
+				// Pad the content to a whole number of tar blocks
+				let padding = (512 - blob.len() % 512) % 512;
+				try!(encoder.write_all(&[0; 512][.. padding]));
+				mem::swap(encoder.get_mut(), &mut self.out);
+			},
+			None => {
+				// The two zero blocks that end a tar archive
+				try!(encoder.write_all(&[0; 1024]));
+				try!(encoder.try_finish());
+				mem::swap(encoder.get_mut(), &mut self.out);
+				self.encoder = None;
+			},
+		}
+		Ok(true)
+	}
+}
+
+impl<'a, B :BlobStore + 'a> Read for ReconstructingReader<'a, B> {
+	fn read(&mut self, buf :&mut [u8]) -> io::Result<usize> {
+		while self.out_pos >= self.out.len() {
+			self.out.clear();
+			self.out_pos = 0;
+			if !try!(self.encode_next_entry()) {
+				return Ok(0);
+			}
+		}
+		let len = buf.len().min(self.out.len() - self.out_pos);
+		buf[.. len].copy_from_slice(&self.out[self.out_pos .. self.out_pos + len]);
+		self.out_pos += len;
+		Ok(len)
 	}
 }
 
 /// Tasks that can be executed in parallel
 enum ParallelTask {
-	ObtainCrateContentBlobs(String, Vec<u8>, Digest),
-	CompressBlob(Digest, Vec<u8>),
-	CreateMultiBlob(Vec<(Digest, Vec<u8>)>),
+	ObtainCrateContentBlobs(String, Vec<u8>, Digest, ExtractionLimits),
+	CompressBlob(Digest, Vec<u8>, BlobCompressor),
+	/// Creates multi blobs from the blobs and edges of a graph component
+	CreateMultiBlob(Vec<(Digest, Vec<u8>)>, Vec<(usize, usize)>, usize, BlobCompressor),
 }
 
 /// Tasks that need blocking access to the blob storage
 enum BlockingTask {
-	StoreCrateUndeduplicated(String, Vec<u8>),
+	/// Emitted after a parallel task has been completed
+	ParallelTaskDone,
+	/// Emitted if a parallel task has failed
+	ParallelTaskFailed(StorageError),
+	StoreCrateUndeduplicated(String, Digest, Vec<u8>),
 	StoreCrateContentBlobs(String, CrateContentBlobs),
 	StoreBlob(Digest, Vec<u8>),
 	StoreMultiBlob(Digest, Vec<Digest>, Vec<u8>),
 }
 
-fn handle_parallel_task<ET :FnMut(BlockingTask)>(task :ParallelTask, mut emit_task :ET) {
+/// Time between two checkpoints while storing
+const CHECKPOINT_INTERVAL_SECS :u64 = 300;
+
+/// Runs tasks on a pool of threads and stores the results
+///
+/// `next_tasks` is called to obtain new tasks until it returns
+/// an empty list. Regularly, when no tasks are in flight,
+/// a checkpoint of the storage is written.
+/// Stops at the first error of any task or of `next_tasks`.
+fn run_tasks<B :BlobStoreMut, NT :FnMut() -> Result<Vec<ParallelTask>, StorageError>>(
+		blob_store :&mut B, compressor :&BlobCompressor,
+		thread_count :u16, mut next_tasks :NT) -> Result<(), StorageError> {
+	use std::sync::mpsc::{sync_channel, TrySendError};
+	use multiqueue::mpmc_queue;
+	use std::time::{Duration, Instant};
+	use std::thread;
+
+	let (bt_tx, bt_rx) = sync_channel(3 * thread_count as usize);
+	let (pt_tx, pt_rx) = mpmc_queue(3 * thread_count as u64);
+	for _ in 0 .. thread_count {
+		let bt_tx = bt_tx.clone();
+		let pt_rx = pt_rx.clone();
+		thread::spawn(move || {
+			while let Ok(task) = pt_rx.recv() {
+				// Sending only fails if run_tasks has returned early
+				let res = handle_parallel_task(task, |bt| { let _ = bt_tx.send(bt); });
+				let done = match res {
+					Ok(()) => BlockingTask::ParallelTaskDone,
+					Err(e) => BlockingTask::ParallelTaskFailed(e),
+				};
+				if bt_tx.send(done).is_err() {
+					break;
+				}
+			}
+		});
+	}
+	drop(bt_tx);
+	pt_rx.unsubscribe();
+	let checkpoint_interval = Duration::from_secs(CHECKPOINT_INTERVAL_SECS);
+	let mut last_checkpoint = Instant::now();
+	let mut par_task_backlog = Vec::new();
+	// Number of parallel tasks sent to the threads that haven't been completed yet
+	let mut in_flight = 0;
+	let mut input_done = false;
+	let mut blobs_to_store = HashSet::new();
+	loop {
+		if let Ok(task) = bt_rx.recv_timeout(Duration::new(0, 50_000)) {
+			if let BlockingTask::ParallelTaskDone = task {
+				in_flight -= 1;
+			}
+			try!(handle_blocking_task(task, blob_store, compressor,
+				&mut blobs_to_store, |tsk| par_task_backlog.push(tsk)));
+		}
+		let checkpoint_due = last_checkpoint.elapsed() >= checkpoint_interval;
+		// Don't start new work if a checkpoint is due,
+		// so that we get to a point where nothing is in flight.
+		if par_task_backlog.is_empty() && !input_done && !checkpoint_due {
+			let tasks = try!(next_tasks());
+			input_done = tasks.is_empty();
+			par_task_backlog.extend(tasks);
+		}
+		while let Some(t) = par_task_backlog.pop() {
+			if let Err(e) = pt_tx.try_send(t) {
+				let t = match e {
+					TrySendError::Full(t) => t,
+					TrySendError::Disconnected(t) => t,
+				};
+				par_task_backlog.push(t);
+				break;
+			}
+			in_flight += 1;
+		}
+		if in_flight == 0 && par_task_backlog.is_empty() {
+			if input_done {
+				return Ok(());
+			}
+			if checkpoint_due {
+				try!(blob_store.checkpoint());
+				last_checkpoint = Instant::now();
+			}
+		}
+	}
+}
+
+fn handle_parallel_task<ET :FnMut(BlockingTask)>(task :ParallelTask,
+		mut emit_task :ET) -> Result<(), StorageError> {
 	match task {
-		ParallelTask::ObtainCrateContentBlobs(crate_file_name, crate_archive_file, digest) => {
-			match CrateContentBlobs::from_archive_file(&crate_archive_file[..]) {
+		ParallelTask::ObtainCrateContentBlobs(crate_file_name, crate_archive_file, digest, limits) => {
+			match CrateContentBlobs::from_archive_file_limited(&crate_archive_file[..], &limits) {
 				Ok(ccb) => {
 					if digest == ccb.digest_of_reconstructed() {
 						emit_task(BlockingTask::StoreCrateContentBlobs(crate_file_name, ccb));
 					} else {
 						// Digest mismatch
-						emit_task(BlockingTask::StoreCrateUndeduplicated(crate_file_name, crate_archive_file));
+						emit_task(BlockingTask::StoreCrateUndeduplicated(crate_file_name,
+							digest, crate_archive_file));
 					}
 				},
 				Err(_) => {
-					// Error during CrateContentBlobs creation... most likely invalid gz file or sth
-					emit_task(BlockingTask::StoreCrateUndeduplicated(crate_file_name, crate_archive_file));
+					// Invalid archive, or one exceeding the limits
+					emit_task(BlockingTask::StoreCrateUndeduplicated(crate_file_name,
+						digest, crate_archive_file));
 				},
 			};
 		},
-		ParallelTask::CompressBlob(d, blob) => {
-			let mut gz_enc = GzBuilder::new().read(blob.as_slice(), Compression::best());
-			let mut buffer_compressed = Vec::new();
-			io::copy(&mut gz_enc, &mut buffer_compressed).unwrap();
-
-			emit_task(BlockingTask::StoreBlob(d, buffer_compressed));
+		ParallelTask::CompressBlob(d, blob, compressor) => {
+			let compressed = try!(compressor.compress(&blob).context(StorageErrorKind::Io));
+			emit_task(BlockingTask::StoreBlob(d, compressed));
 		},
-		ParallelTask::CreateMultiBlob(blobs) => {
-			let mut root_blob = None;
-			let mut diff_list = Vec::new();
-			let mut digests = Vec::new();
-			let mut last :Option<(Digest, &str)> = None;
-			for (digest, blob) in blobs.iter() {
-				if let Ok(s) = ::std::str::from_utf8(&blob) {
-					digests.push(digest);
-					if let Some(l) = last.take() {
-						let diff = Diff::from_texts_nl(&l.1, &s);
-						diff_list.push((l.0, *digest, diff));
-					} else {
-						root_blob = Some((*digest, s.to_string()));
-					}
-					last = Some((*digest, s));
-				} else {
-					// UTF-8 decode error. Skip this one.
-					// TODO emit a compression parallel task here. one day.
-					let mut gz_enc = GzBuilder::new().read(blob.as_slice(), Compression::best());
-					let mut buffer_compressed = Vec::new();
-					io::copy(&mut gz_enc, &mut buffer_compressed).unwrap();
-
-					emit_task(BlockingTask::StoreBlob(*digest, buffer_compressed));
+		ParallelTask::CreateMultiBlob(blobs, edges, max_chain_depth, compressor) => {
+			for mb in MultiBlob::from_blobs(&blobs, &edges, max_chain_depth) {
+				if mb.diff_list.is_empty() {
+					// No need for a multi blob if there is only one blob
+					let (digest, blob) = mb.root_blob;
+					let compressed = try!(compressor.compress(&blob).context(StorageErrorKind::Io));
+					emit_task(BlockingTask::StoreBlob(digest, compressed));
+					continue;
 				}
-			}
-
-			if digests.len() > 0 {
-				let mb = MultiBlob {
-					root_blob : root_blob.unwrap(),
-					diff_list,
-				};
 				let mut mb_blob = Vec::new();
-				mb.serialize(&mut mb_blob).unwrap();
-
-				let mut hctx = HashCtx::new();
-				io::copy(&mut mb_blob.as_slice(), &mut hctx).unwrap();
-				let multi_blob_digest = hctx.finish_and_get_digest();
+				try!(mb.serialize(&mut mb_blob).context(StorageErrorKind::Io));
+				let multi_blob_digest = digest_of(&mb_blob);
 
-				let mut gz_enc = GzBuilder::new().read(mb_blob.as_slice(), Compression::best());
-				let mut buffer_compressed = Vec::new();
-				io::copy(&mut gz_enc, &mut buffer_compressed).unwrap();
-
-				let digests = blobs.iter()
-						.map(|(digest, _b)| *digest)
-						.collect::<Vec<_>>();
-
-				let task = BlockingTask::StoreMultiBlob(multi_blob_digest, digests, buffer_compressed);
+				let compressed = try!(compressor.compress(&mb_blob).context(StorageErrorKind::Io));
+				let task = BlockingTask::StoreMultiBlob(multi_blob_digest,
+					mb.digests(), compressed);
 				emit_task(task);
 			}
 		},
 	}
+	Ok(())
 }
 
-fn handle_blocking_task<ET :FnMut(ParallelTask), S :Read + Seek + Write>(task :BlockingTask,
-		blob_store :&mut BlobStorage<S>, blobs_to_store :&mut HashSet<Digest>,
-		mut emit_task :ET) {
+/// Returns whether the blob is present, either directly or inside a multi blob
+fn blob_present<B :BlobStore>(blob_store :&B, d :&Digest) -> Result<bool, StorageError> {
+	Ok(try!(blob_store.has(d)) || try!(blob_store.get_multi_blob(d)).is_some())
+}
+
+fn handle_blocking_task<ET :FnMut(ParallelTask), B :BlobStoreMut>(task :BlockingTask,
+		blob_store :&mut B, compressor :&BlobCompressor,
+		blobs_to_store :&mut HashSet<Digest>, mut emit_task :ET) -> Result<(), StorageError> {
 	match task {
-		BlockingTask::StoreCrateUndeduplicated(crate_file_name, crate_blob) => {
-			// TODO
+		BlockingTask::ParallelTaskDone => (),
+		BlockingTask::ParallelTaskFailed(e) => return Err(e),
+		BlockingTask::StoreCrateUndeduplicated(crate_file_name, digest, crate_blob) => {
+			// The .crate file is compressed already,
+			// so we store it as it is.
+			blob_store.insert_raw_crate(crate_file_name, digest);
+			try!(blob_store.insert(digest, &crate_blob));
 		},
 		BlockingTask::StoreCrateContentBlobs(crate_file_name, ccb) => {
 			let CrateRecMetaWithBlobs { meta, blobs } = ccb.into_meta_with_blobs();
+			// Blobs may be present from an earlier run already,
+			// e.g. license files that many crates share
 			for entry in blobs {
 				let entry_digest = entry.0;
-				if blobs_to_store.insert(entry_digest) {
-					emit_task(ParallelTask::CompressBlob(entry_digest, entry.1));
+				if blobs_to_store.insert(entry_digest) &&
+						!try!(blob_present(blob_store, &entry_digest)) {
+					emit_task(ParallelTask::CompressBlob(entry_digest, entry.1,
+						compressor.clone()));
 				}
 			}
 			// emit a blob for meta as well
 			let mut meta_blob = Vec::new();
-			meta.serialize(&mut meta_blob).unwrap();
-			let mut meta_blob_hctx = HashCtx::new();
-			io::copy(&mut meta_blob.as_slice(), &mut meta_blob_hctx).unwrap();
-			let meta_blob_digest = meta_blob_hctx.finish_and_get_digest();
-			// The blob digest may be already present, e.g. if
-			// we had been writing this particular crate into the
-			// BlobStorage previously. In order to be on the safe
-			// side, check for existence before inserting into
-			// the blob storage.
-			if blobs_to_store.insert(meta_blob_digest) {
-				emit_task(ParallelTask::CompressBlob(meta_blob_digest, meta_blob));
+			try!(meta.serialize(&mut meta_blob).context(StorageErrorKind::Io));
+			let meta_blob_digest = digest_of(&meta_blob);
+			if blobs_to_store.insert(meta_blob_digest) &&
+					!try!(blob_present(blob_store, &meta_blob_digest)) {
+				emit_task(ParallelTask::CompressBlob(meta_blob_digest, meta_blob,
+					compressor.clone()));
 			}
 			// enter the meta blob into the blob storage
-			blob_store.name_index.insert(crate_file_name, meta_blob_digest);
+			blob_store.insert_name(crate_file_name, meta_blob_digest);
 
 		},
 		BlockingTask::StoreBlob(d, blob) => {
-			let actually_added = blob_store.insert(d, &blob).unwrap();
-			// If the blob is already present, it indicates a bug because
-			// we are supposed to check for presence before we ask for the
-			// blob to be compressed. If we would just shrug this off, we'd
-			// waste cycles spent on compressing the blobs.
-			assert!(actually_added, "Tried to insert a blob into the storage that was already present");
+			// If the blob is present already, it is kept as it is
+			try!(blob_store.insert(d, &blob));
 		},
 		BlockingTask::StoreMultiBlob(mblob_digest, digests, buf_compressed) => {
-			let actually_added = blob_store.insert(mblob_digest, &buf_compressed).unwrap();
+			try!(blob_store.insert(mblob_digest, &buf_compressed));
+			// Blobs that are present already keep their place
 			for d in digests.iter() {
-				blob_store.digest_to_multi_blob.insert(*d, mblob_digest);
+				if !try!(blob_present(blob_store, d)) {
+					blob_store.insert_multi_blob(*d, mblob_digest);
+				}
 			}
-			// If the blob is already present, it indicates a bug because
-			// we are supposed to check for presence before we ask for the
-			// blob to be compressed. If we would just shrug this off, we'd
-			// waste cycles spent on compressing the blobs.
-			assert!(actually_added, "Tried to insert a blob into the storage that was already present");
 		},
 	}
+	Ok(())
 }
@@ -1,128 +1,705 @@
 
-use std::io::{Read, Write, Seek, SeekFrom, Result as IoResult, ErrorKind};
+use std::io::{self, Read, Write, Seek, SeekFrom, Cursor, Result as IoResult, ErrorKind};
 use std::collections::HashMap;
-use std::collections::hash_map::Entry;
+use std::fmt;
+use std::fs::File;
 use byteorder::{ReadBytesExt, WriteBytesExt, BigEndian};
+use failure::{Context, Fail, ResultExt};
+use flate2::Crc;
 use super::hash_ctx::Digest;
+use super::sorted_table::{SortedTable, write_table};
+use super::read_at::{ReadAt, ReadAtCursor};
+use super::extraction::MAX_PATH_LEN;
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum StorageErrorKind {
+	Io,
+	InvalidMagic,
+	UnsupportedVersion(u32),
+	UnsupportedFeatures(u64),
+	InvalidName,
+	MissingBlob,
+	CorruptHeader,
+	CorruptIndex,
+	InvalidUpdatePack,
+	InvalidInventory,
+	CorruptBlob,
+	ConflictingDictionary(u32),
+	MixedDiffFormats,
+}
+
+impl fmt::Display for StorageErrorKind {
+	fn fmt(&self, f :&mut fmt::Formatter) -> fmt::Result {
+		use self::StorageErrorKind::*;
+		match *self {
+			Io => write!(f, "I/O error while accessing the storage"),
+			InvalidMagic => write!(f, "Invalid header: not a storage file"),
+			UnsupportedVersion(v) => write!(f, "Unsupported storage format version {}", v),
+			UnsupportedFeatures(v) => write!(f, "Storage uses unsupported features (flags {:#x})", v),
+			InvalidName => write!(f, "Name in the name index is not valid UTF-8"),
+			MissingBlob => write!(f, "Blob referenced by the index is missing"),
+			CorruptHeader => write!(f, "Invalid header: no valid header slot found"),
+			CorruptIndex => write!(f, "Invalid index segment chain"),
+			InvalidUpdatePack => write!(f, "Update pack doesn't fit the storage"),
+			InvalidInventory => write!(f, "Invalid or oversized inventory"),
+			CorruptBlob => write!(f, "Invalid blob length"),
+			ConflictingDictionary(v) => write!(f, "Storages have different dictionaries with id {}", v),
+			MixedDiffFormats => write!(f, "Multi blobs with different diff formats can't be mixed"),
+		}
+	}
+}
+
+impl Fail for StorageErrorKind {}
+
+pub type StorageError = Context<StorageErrorKind>;
+
+/// The blobs in the storage may be compressed with zstd
+///
+/// If this flag is not set, all blobs are gzip compressed.
+pub const FEATURE_ZSTD :u64 = 1 << 0;
+/// The storage contains multi blobs
+pub const FEATURE_MULTI_BLOB :u64 = 1 << 1;
+/// The storage contains crates that are stored as they are
+///
+/// This happens for crates that couldn't be split up
+/// into blobs, e.g. because they were malformed.
+pub const FEATURE_RAW_CRATES :u64 = 1 << 2;
+/// The diffs in multi blobs are prefixed with their kind
+///
+/// Such multi blobs may contain byte oriented deltas that
+/// older readers can't parse. Without this flag, multi blobs
+/// only contain text diffs. The diffs don't say which format
+/// they use, so all multi blobs of a storage share one.
+pub const FEATURE_DIFF_KINDS :u64 = 1 << 3;
+
+const KNOWN_FEATURES :u64 = FEATURE_ZSTD | FEATURE_MULTI_BLOB | FEATURE_RAW_CRATES
+	| FEATURE_DIFF_KINDS;
 
 pub struct BlobStorage<S> {
-	blob_offsets :HashMap<Digest, u64>,
+	blob_offsets :SortedTable<Digest, u64>,
 	/// Index that maps (crate) names to digests
 	///
 	/// Note that not all blobs are present in this index, only those that represent
 	/// a crate.
-	pub name_index :HashMap<String, Digest>,
-	pub digest_to_multi_blob :HashMap<Digest, Digest>,
+	name_index :SortedTable<String, Digest>,
+	digest_to_multi_blob :SortedTable<Digest, Digest>,
+	/// Index that maps dictionary ids to the digests of the dictionaries
+	///
+	/// The dictionaries are used for compressing the blobs.
+	pub dictionaries :HashMap<u32, Digest>,
+	/// Index that maps crate names to crates stored as they are
+	raw_crates :SortedTable<String, Digest>,
+	/// Feature flags, see the `FEATURE_*` constants
+	///
+	/// The flags for multi blobs and raw crates are set
+	/// automatically when writing the index.
+	pub features :u64,
 	storage :S,
+	/// The offset where the next blob or index segment gets written
 	index_offset :u64,
+	/// The offset of the last written index segment, 0 if there is none
+	last_segment :u64,
+	/// The version of the header present in the storage, 0 if there is none
+	header_version :u32,
+	/// The header slot that was last written
+	header_slot :usize,
+}
+
+/// Storage that can make sure that written data is durable
+///
+/// Checkpoints rely on this, so that the header never
+/// points to an index that hasn't reached the disk yet.
+pub trait SyncAll {
+	/// Writes the data and metadata to the disk
+	fn sync_all(&mut self) -> IoResult<()>;
+}
+
+impl SyncAll for File {
+	fn sync_all(&mut self) -> IoResult<()> {
+		File::sync_all(self)
+	}
+}
+
+impl<T> SyncAll for Cursor<T> {
+	fn sync_all(&mut self) -> IoResult<()> {
+		Ok(())
+	}
+}
+
+impl<S :SyncAll + ?Sized> SyncAll for &mut S {
+	fn sync_all(&mut self) -> IoResult<()> {
+		(**self).sync_all()
+	}
 }
 
 pub(crate) fn write_delim_byte_slice<W :Write>(mut wtr :W, sl :&[u8]) -> IoResult<()> {
 	try!(wtr.write_u64::<BigEndian>(sl.len() as u64));
-	try!(wtr.write(sl));
+	try!(wtr.write_all(sl));
 	Ok(())
 }
-pub(crate) fn read_delim_byte_slice<R :Read>(mut rdr :R) -> IoResult<Vec<u8>> {
+pub(crate) fn read_delim_byte_slice<R :Read>(rdr :R) -> IoResult<Vec<u8>> {
+	read_delim_byte_slice_max(rdr, u64::MAX)
+}
+/// Reads a length delimited slice that is at most `max_len` bytes long
+///
+/// Use this for input that might be damaged or hostile,
+/// so that a bogus length can't make us allocate huge buffers.
+pub(crate) fn read_delim_byte_slice_max<R :Read>(mut rdr :R, max_len :u64) -> IoResult<Vec<u8>> {
 	let len = try!(rdr.read_u64::<BigEndian>());
+	if len > max_len {
+		return Err(io::Error::new(ErrorKind::InvalidData,
+			format!("Length {} exceeds the maximum of {}", len, max_len)));
+	}
 	let mut res = vec![0; len as usize];
 	try!(rdr.read_exact(&mut res));
 	Ok(res)
 }
+/// Reads a name of an index that is at most `max_len` bytes long
+///
+/// Names whose length is out of bounds indicate a damaged index.
+pub(crate) fn read_index_name<R :Read>(rdr :R, max_len :u64) -> Result<String, StorageError> {
+	let bytes = try!(read_delim_byte_slice_max(rdr, max_len).map_err(|e| {
+		match e.kind() {
+			ErrorKind::InvalidData | ErrorKind::UnexpectedEof => {
+				e.context(StorageErrorKind::CorruptIndex)
+			},
+			_ => e.context(StorageErrorKind::Io),
+		}
+	}));
+	Ok(try!(String::from_utf8(bytes).context(StorageErrorKind::InvalidName)))
+}
 
-impl<S :Read + Seek> BlobStorage<S> {
+impl<S :ReadAt> BlobStorage<S> {
 	pub fn empty(storage :S) -> Self {
 		BlobStorage {
-			name_index : HashMap::new(),
-			digest_to_multi_blob : HashMap::new(),
-			blob_offsets : HashMap::new(),
+			name_index : SortedTable::empty(),
+			digest_to_multi_blob : SortedTable::empty(),
+			dictionaries : HashMap::new(),
+			raw_crates : SortedTable::empty(),
+			features : 0,
+			blob_offsets : SortedTable::empty(),
 
 			storage,
-			// TODO don't hardcode this number somehow (get the length of the header)
-			index_offset : 64,
+			index_offset : HEADER_LEN,
+			last_segment : 0,
+			header_version : 0,
+			header_slot : 0,
 		}
 	}
-	pub fn new(mut storage :S) -> IoResult<Self> {
-		try!(storage.seek(SeekFrom::Start(0)));
-		match storage.read_u64::<BigEndian>() {
+	/// Opens the storage, or creates an empty one if it has no content
+	pub fn new(storage :S) -> Result<Self, StorageError> {
+		let mut rdr = ReadAtCursor::new(&storage);
+		match rdr.read_u64::<BigEndian>() {
 			Ok(v) if v == BLOB_MAGIC => BlobStorage::load(storage),
-			Ok(_) => panic!("Invalid header"),
+			Ok(_) => Err(StorageErrorKind::InvalidMagic.into()),
 			Err(ref e) if e.kind() == ErrorKind::UnexpectedEof => Ok(BlobStorage::empty(storage)),
-			Err(e) => Err(e),
+			Err(e) => Err(e).context(StorageErrorKind::Io),
+		}
+	}
+	/// Loads the storage
+	///
+	/// If the storage was not written completely, e.g. because
+	/// the writing process got killed, everything up to the
+	/// last checkpoint is loaded. Newly inserted blobs
+	/// are written after that checkpoint.
+	pub fn load(storage :S) -> Result<Self, StorageError> {
+		use self::StorageErrorKind::*;
+		let mut rdr = ReadAtCursor::new(&storage);
+		let hdr = try!(read_hdr(&mut rdr));
+		if hdr.version < 3 {
+			return BlobStorage::load_unsorted(storage, hdr);
 		}
+		// Follow the chain of index segments back to the first one
+		let mut segment_offsets = vec![hdr.index_offset];
+		if hdr.version >= 4 {
+			loop {
+				let last = segment_offsets[segment_offsets.len() - 1];
+				try!(rdr.seek(SeekFrom::Start(last)).context(Io));
+				let prev = try!(rdr.read_u64::<BigEndian>().context(Io));
+				if prev == 0 {
+					break;
+				}
+				if prev >= last {
+					return Err(CorruptIndex.into());
+				}
+				segment_offsets.push(prev);
+			}
+		}
+		// The first segment contains most entries,
+		// so only its tables are kept on disk.
+		// The entries of the remaining ones are loaded into memory.
+		let first = segment_offsets.pop().unwrap();
+		let content_len = try!(storage.content_len().context(Io));
+		let mut seg = try!(read_segment(&mut rdr, first, content_len, &hdr));
+		for offs in segment_offsets.into_iter().rev() {
+			let s = try!(read_segment(&mut rdr, offs, content_len, &hdr));
+			seg.blob_offsets.extend_saved(try!(s.blob_offsets.entries(&mut rdr)));
+			seg.name_index.extend_saved(try!(s.name_index.entries(&mut rdr)));
+			seg.digest_to_multi_blob.extend_saved(try!(s.digest_to_multi_blob.entries(&mut rdr)));
+			seg.raw_crates.extend_saved(try!(s.raw_crates.entries(&mut rdr)));
+			seg.dictionaries.extend(s.dictionaries);
+			seg.end = s.end;
+		}
+		Ok(BlobStorage {
+			blob_offsets : seg.blob_offsets,
+			name_index : seg.name_index,
+			digest_to_multi_blob : seg.digest_to_multi_blob,
+			dictionaries : seg.dictionaries,
+			raw_crates : seg.raw_crates,
+			features : hdr.features,
+
+			storage,
+			index_offset : seg.end,
+			last_segment : hdr.index_offset,
+			header_version : hdr.version,
+			header_slot : hdr.slot,
+		})
 	}
-	pub fn load(mut storage :S) -> IoResult<Self> {
-		try!(storage.seek(SeekFrom::Start(0)));
-		let index_offset = try!(read_hdr(&mut storage));
-		try!(storage.seek(SeekFrom::Start(index_offset)));
-		let blob_offsets = try!(read_offset_table(&mut storage));
-		let name_index = try!(read_name_idx(&mut storage));
-		let digest_to_multi_blob = try!(read_digest_to_multi_blob(&mut storage));
+	/// Loads a storage written before the index was sorted
+	///
+	/// The index is read into memory completely.
+	fn load_unsorted(storage :S, hdr :Header) -> Result<Self, StorageError> {
+		use self::StorageErrorKind::*;
+		let mut rdr = ReadAtCursor::new(&storage);
+		try!(rdr.seek(SeekFrom::Start(hdr.index_offset)).context(Io));
+		let blob_offsets = try!(read_offset_table(&mut rdr).context(Io));
+		let name_index = try!(read_name_idx(&mut rdr));
+		let digest_to_multi_blob = try!(read_digest_to_multi_blob(&mut rdr).context(Io));
+		// Version 1 storages end after the digest to multi blob table
+		let dictionaries = if hdr.version >= 2 {
+			try!(read_dictionaries(&mut rdr).context(Io))
+		} else {
+			HashMap::new()
+		};
+		let raw_crates = if hdr.features & FEATURE_RAW_CRATES != 0 {
+			try!(read_name_idx(&mut rdr))
+		} else {
+			HashMap::new()
+		};
+		let index_end = try!(rdr.stream_position().context(Io));
 		Ok(BlobStorage {
-			blob_offsets,
-			name_index,
-			digest_to_multi_blob,
+			blob_offsets : SortedTable::from_map(blob_offsets),
+			name_index : SortedTable::from_map(name_index),
+			digest_to_multi_blob : SortedTable::from_map(digest_to_multi_blob),
+			dictionaries,
+			raw_crates : SortedTable::from_map(raw_crates),
+			features : hdr.features,
 
 			storage,
-			index_offset,
+			index_offset : index_end,
+			last_segment : hdr.index_offset,
+			header_version : hdr.version,
+			header_slot : hdr.slot,
 		})
 	}
 
-	pub fn has(&self, digest :&Digest) -> bool {
-		self.blob_offsets.get(digest).is_some()
+	/// Returns a reader for the storage
+	///
+	/// Reads don't need mutable access, so that the
+	/// storage can be shared between threads.
+	fn rdr(&self) -> ReadAtCursor<'_, S> {
+		ReadAtCursor::new(&self.storage)
+	}
+	pub fn has(&self, digest :&Digest) -> Result<bool, StorageError> {
+		Ok(try!(self.blob_offsets.get(self.rdr(), digest)).is_some())
 	}
-	pub fn get(&mut self, digest :&Digest) -> IoResult<Option<Vec<u8>>> {
-		let blob_offs = match self.blob_offsets.get(digest) {
-			Some(d) => *d,
+	pub fn get(&self, digest :&Digest) -> Result<Option<Vec<u8>>, StorageError> {
+		let blob_offs = match try!(self.blob_offsets.get(self.rdr(), digest)) {
+			Some(d) => d,
 			None => return Ok(None),
 		};
-		try!(self.storage.seek(SeekFrom::Start(blob_offs)));
-		let content = try!(read_delim_byte_slice(&mut self.storage));
+		// A damaged length prefix must not make us allocate
+		// more than what is left of the storage
+		let len = try!(self.storage.content_len().context(StorageErrorKind::Io));
+		let max_len = len.saturating_sub(blob_offs).saturating_sub(8);
+		let mut rdr = self.rdr();
+		try!(rdr.seek(SeekFrom::Start(blob_offs)).context(StorageErrorKind::Io));
+		let content = try!(read_delim_byte_slice_max(&mut rdr, max_len).map_err(|e| {
+			match e.kind() {
+				ErrorKind::InvalidData | ErrorKind::UnexpectedEof => {
+					e.context(StorageErrorKind::CorruptBlob)
+				},
+				_ => e.context(StorageErrorKind::Io),
+			}
+		}));
 		Ok(Some(content))
 	}
+	/// Looks up the digest of the blob with the given (crate) name
+	pub fn get_named(&self, name :&str) -> Result<Option<Digest>, StorageError> {
+		self.name_index.get(self.rdr(), &name.to_owned())
+	}
+	/// Returns all entries of the name index, sorted by name
+	pub fn names(&self) -> Result<Vec<(String, Digest)>, StorageError> {
+		self.name_index.entries(self.rdr())
+	}
+	/// Returns the entries of the name index whose name starts with the prefix, sorted by name
+	pub fn names_with_prefix(&self, prefix :&str) -> Result<Vec<(String, Digest)>, StorageError> {
+		self.name_index.entries_with_prefix(self.rdr(), prefix)
+	}
+	/// Returns the digests of all blobs that are stored directly, sorted
+	pub fn digests(&self) -> Result<Vec<Digest>, StorageError> {
+		let entries = try!(self.blob_offsets.entries(self.rdr()));
+		Ok(entries.into_iter().map(|(d, _)| d).collect())
+	}
+	/// Returns all blobs stored inside multi blobs,
+	/// together with the digest of the multi blob, sorted
+	pub fn multi_blob_entries(&self) -> Result<Vec<(Digest, Digest)>, StorageError> {
+		self.digest_to_multi_blob.entries(self.rdr())
+	}
+	/// Looks up the digest of the multi blob that contains the given blob
+	pub fn get_multi_blob(&self, digest :&Digest) -> Result<Option<Digest>, StorageError> {
+		self.digest_to_multi_blob.get(self.rdr(), digest)
+	}
+	/// Looks up the digest of the crate with the given name that is stored as it is
+	pub fn get_raw_crate(&self, name :&str) -> Result<Option<Digest>, StorageError> {
+		self.raw_crates.get(self.rdr(), &name.to_owned())
+	}
+	/// Returns all crates that are stored as they are, sorted by name
+	pub fn raw_crates(&self) -> Result<Vec<(String, Digest)>, StorageError> {
+		self.raw_crates.entries(self.rdr())
+	}
+	/// Returns the crates stored as they are whose name starts with the prefix, sorted by name
+	pub fn raw_crates_with_prefix(&self, prefix :&str) -> Result<Vec<(String, Digest)>, StorageError> {
+		self.raw_crates.entries_with_prefix(self.rdr(), prefix)
+	}
 }
 
-impl<S :Seek + Write> BlobStorage<S> {
-	pub fn insert_named_blob(&mut self, name :Option<String>, digest :Digest, content :&[u8]) -> IoResult<()> {
+impl<S :ReadAt + Seek + Write + SyncAll> BlobStorage<S> {
+	pub fn insert_name(&mut self, name :String, digest :Digest) {
+		self.name_index.insert(name, digest);
+	}
+	pub fn insert_multi_blob(&mut self, digest :Digest, multi_blob_digest :Digest) {
+		self.digest_to_multi_blob.insert(digest, multi_blob_digest);
+	}
+	pub fn insert_raw_crate(&mut self, name :String, digest :Digest) {
+		self.raw_crates.insert(name, digest);
+	}
+	pub fn insert_named_blob(&mut self, name :Option<String>, digest :Digest,
+			content :&[u8]) -> Result<(), StorageError> {
 		if let Some(n) = name {
 			self.name_index.insert(n, digest);
 		}
-		try!(self.insert(digest, &content));
+		try!(self.insert(digest, content));
 		Ok(())
 	}
-	pub fn insert(&mut self, digest :Digest, content :&[u8]) -> IoResult<bool> {
-		let e = self.blob_offsets.entry(digest);
-		match e {
-			Entry::Occupied(_) => return Ok(false),
-			Entry::Vacant(v) => v.insert(self.index_offset),
-		};
-		try!(self.storage.seek(SeekFrom::Start(self.index_offset)));
-		try!(write_delim_byte_slice(&mut self.storage, content));
-		self.index_offset = try!(self.storage.seek(SeekFrom::Current(0)));
+	pub fn insert(&mut self, digest :Digest, content :&[u8]) -> Result<bool, StorageError> {
+		if try!(self.has(&digest)) {
+			return Ok(false);
+		}
+		try!(self.append_blob(digest, content));
 		Ok(true)
 	}
-	pub fn write_header_and_index(&mut self) -> IoResult<()> {
-		try!(self.storage.seek(SeekFrom::Start(0)));
-		try!(write_hdr(&mut self.storage, self.index_offset));
-		try!(self.storage.seek(SeekFrom::Start(self.index_offset)));
-		try!(write_offset_table(&mut self.storage, &self.blob_offsets));
-		try!(write_name_idx(&mut self.storage, &self.name_index));
-		try!(write_digest_to_multi_blob(&mut self.storage, &self.digest_to_multi_blob));
+	/// Stores the blob even if a blob with that digest is present already
+	///
+	/// This is used to repair blobs whose stored content is corrupt.
+	/// The corrupt content remains in the file, but isn't referenced any more.
+	pub fn replace(&mut self, digest :Digest, content :&[u8]) -> Result<(), StorageError> {
+		self.append_blob(digest, content)
+	}
+	fn append_blob(&mut self, digest :Digest, content :&[u8]) -> Result<(), StorageError> {
+		use self::StorageErrorKind::*;
+		self.blob_offsets.insert(digest, self.index_offset);
+		try!(self.storage.seek(SeekFrom::Start(self.index_offset)).context(Io));
+		try!(write_delim_byte_slice(&mut self.storage, content).context(Io));
+		self.index_offset = try!(self.storage.stream_position().context(Io));
+		Ok(())
+	}
+	fn update_features(&mut self) {
+		if !self.digest_to_multi_blob.is_empty() {
+			self.features |= FEATURE_MULTI_BLOB;
+		}
+		if !self.raw_crates.is_empty() {
+			self.features |= FEATURE_RAW_CRATES;
+		}
+	}
+	/// Writes the complete index and updates the header to point to it
+	///
+	/// The index is written as one segment, so that it
+	/// can be searched without loading it into memory.
+	pub fn write_header_and_index(&mut self) -> Result<(), StorageError> {
+		self.update_features();
+		let blob_offsets = try!(self.blob_offsets.entries(self.rdr()));
+		let name_index = try!(self.name_index.entries(self.rdr()));
+		let digest_to_multi_blob = try!(self.digest_to_multi_blob.entries(self.rdr()));
+		let raw_crates = try!(self.raw_crates.entries(self.rdr()));
+
+		let segment_offset = self.index_offset;
+		try!(self.write_segment(0, &blob_offsets, &name_index,
+			&digest_to_multi_blob, &raw_crates));
+		try!(self.write_header(segment_offset));
+
+		// Use the tables of the new segment so that
+		// we don't have to keep the entries in memory.
+		let hdr = Header {
+			version : FORMAT_VERSION,
+			features : self.features,
+			index_offset : segment_offset,
+			slot : self.header_slot,
+		};
+		let content_len = try!(self.storage.content_len().context(StorageErrorKind::Io));
+		let seg = try!(read_segment(self.rdr(), segment_offset, content_len, &hdr));
+		self.blob_offsets = seg.blob_offsets;
+		self.name_index = seg.name_index;
+		self.digest_to_multi_blob = seg.digest_to_multi_blob;
+		self.raw_crates = seg.raw_crates;
+		Ok(())
+	}
+	/// Writes a checkpoint
+	///
+	/// The index entries added since the last checkpoint are
+	/// appended as a new index segment that points to the previous
+	/// one, and then the header is updated to point to the new segment.
+	/// If the writing process gets killed, everything up to the last
+	/// checkpoint can be loaded again.
+	pub fn checkpoint(&mut self) -> Result<(), StorageError> {
+		if self.header_version < FORMAT_VERSION {
+			// Older index segments can't be chained to
+			return self.write_header_and_index();
+		}
+		let has_new = self.blob_offsets.has_new_entries() ||
+			self.name_index.has_new_entries() ||
+			self.digest_to_multi_blob.has_new_entries() ||
+			self.raw_crates.has_new_entries();
+		if !has_new {
+			return Ok(());
+		}
+		self.update_features();
+		let blob_offsets = self.blob_offsets.take_new_entries();
+		let name_index = self.name_index.take_new_entries();
+		let digest_to_multi_blob = self.digest_to_multi_blob.take_new_entries();
+		let raw_crates = self.raw_crates.take_new_entries();
+
+		let segment_offset = self.index_offset;
+		let prev = self.last_segment;
+		try!(self.write_segment(prev, &blob_offsets, &name_index,
+			&digest_to_multi_blob, &raw_crates));
+		try!(self.write_header(segment_offset));
 		Ok(())
 	}
+	/// Writes an index segment at the current position
+	///
+	/// New blobs will be written after the segment.
+	fn write_segment(&mut self, prev :u64,
+			blob_offsets :&[(Digest, u64)],
+			name_index :&[(String, Digest)],
+			digest_to_multi_blob :&[(Digest, Digest)],
+			raw_crates :&[(String, Digest)]) -> Result<(), StorageError> {
+		use self::StorageErrorKind::*;
+		let segment_offset = self.index_offset;
+		try!(self.storage.seek(SeekFrom::Start(segment_offset)).context(Io));
+		try!(self.storage.write_u64::<BigEndian>(prev).context(Io));
+		try!(write_table(&mut self.storage, blob_offsets).context(Io));
+		try!(write_table(&mut self.storage, name_index).context(Io));
+		try!(write_table(&mut self.storage, digest_to_multi_blob).context(Io));
+		try!(write_dictionaries(&mut self.storage, &self.dictionaries).context(Io));
+		try!(write_table(&mut self.storage, raw_crates).context(Io));
+		self.index_offset = try!(self.storage.stream_position().context(Io));
+		// The segment and the blobs before it have to be
+		// on the disk before the header points to it
+		try!(self.storage.flush().context(Io));
+		try!(self.storage.sync_all().context(Io));
+		self.last_segment = segment_offset;
+		Ok(())
+	}
+	/// Updates the header to point to the given index segment
+	///
+	/// The header has two slots that are written alternately,
+	/// so that there is always a valid slot, even if the
+	/// process got killed while writing the other one.
+	fn write_header(&mut self, index_offset :u64) -> Result<(), StorageError> {
+		use self::StorageErrorKind::*;
+		// Headers of older versions don't use the second slot, so it is
+		// written first, while the old header still points to the old index.
+		let slot = if self.header_version < FORMAT_VERSION {
+			1
+		} else {
+			1 - self.header_slot
+		};
+		try!(self.storage.seek(SeekFrom::Start(hdr_slot_offset(slot))).context(Io));
+		try!(write_hdr_slot(&mut self.storage, self.features, index_offset).context(Io));
+		try!(self.sync().context(Io));
+		if self.header_version < FORMAT_VERSION {
+			// Only now switch to the new version. Its first slot
+			// points to the old index at most, so the second one
+			// gets used. The start of the header lies within the
+			// first sector, so it can't be torn.
+			try!(self.storage.seek(SeekFrom::Start(0)).context(Io));
+			try!(write_hdr_start(&mut self.storage).context(Io));
+			try!(self.sync().context(Io));
+		}
+		self.header_version = FORMAT_VERSION;
+		self.header_slot = slot;
+		Ok(())
+	}
+	fn sync(&mut self) -> IoResult<()> {
+		try!(self.storage.flush());
+		self.storage.sync_all()
+	}
 }
 
 const BLOB_MAGIC :u64 = 0x42_4C_4F_42_53_54_52_45;
 
-fn read_hdr<R :Read>(mut rdr :R) -> IoResult<u64> {
-	let magic = try!(rdr.read_u64::<BigEndian>());
-	// TODO return Err instead of panicing
-	assert_eq!(magic, BLOB_MAGIC);
-	let index_offset = try!(rdr.read_u64::<BigEndian>());
-	Ok(index_offset)
+/// The length of the space reserved for the header
+///
+/// The blobs are stored after it.
+const HEADER_LEN :u64 = 64;
+
+/// The version of the storage format we write
+///
+/// Version 1 storages have a header that only consists of the
+/// magic and the index offset. Since version 2, the magic is
+/// followed by a zero (to tell the header apart from the version 1 one,
+/// as the index offset is never zero), the format version,
+/// the feature flags, and then the index offset.
+///
+/// Since version 3, the index sections are sorted tables
+/// that can be searched without loading them into memory.
+///
+/// Since version 4, the index can consist of multiple segments,
+/// each pointing to the previous one, and the header has two
+/// slots for the feature flags and the index offset, each
+/// with a checksum. The slot pointing to the later index
+/// segment is the valid one. The first slot is at the
+/// position of the feature flags and index offset
+/// of version 3.
+pub const FORMAT_VERSION :u32 = 4;
+
+/// Offset of the first header slot
+const HEADER_SLOTS_START :u64 = 20;
+/// Length of a header slot: the flags, the index offset and a checksum
+const HEADER_SLOT_LEN :u64 = 20;
+
+fn hdr_slot_offset(slot :usize) -> u64 {
+	HEADER_SLOTS_START + slot as u64 * HEADER_SLOT_LEN
+}
+
+struct Header {
+	version :u32,
+	features :u64,
+	index_offset :u64,
+	/// The slot the header was read from
+	slot :usize,
+}
+
+/// The index sections of an index segment
+struct Segment {
+	blob_offsets :SortedTable<Digest, u64>,
+	name_index :SortedTable<String, Digest>,
+	digest_to_multi_blob :SortedTable<Digest, Digest>,
+	dictionaries :HashMap<u32, Digest>,
+	raw_crates :SortedTable<String, Digest>,
+	/// The offset where the segment ends
+	end :u64,
+}
+
+fn read_segment<R :Read + Seek>(mut rdr :R, offset :u64,
+		content_len :u64, hdr :&Header) -> Result<Segment, StorageError> {
+	use self::StorageErrorKind::*;
+	// Since version 4, segments start with the offset of the previous one
+	let offs = if hdr.version >= 4 { offset + 8 } else { offset };
+	let (blob_offsets, offs) = try!(SortedTable::read_section(&mut rdr, offs, content_len));
+	let (name_index, offs) = try!(SortedTable::read_section(&mut rdr, offs, content_len));
+	let (digest_to_multi_blob, offs) = try!(SortedTable::read_section(&mut rdr, offs, content_len));
+	try!(rdr.seek(SeekFrom::Start(offs)).context(Io));
+	let dictionaries = try!(read_dictionaries(&mut rdr).context(Io));
+	let offs = try!(rdr.stream_position().context(Io));
+	let (raw_crates, end) = if hdr.version >= 4 || hdr.features & FEATURE_RAW_CRATES != 0 {
+		try!(SortedTable::read_section(&mut rdr, offs, content_len))
+	} else {
+		(SortedTable::empty(), offs)
+	};
+	Ok(Segment {
+		blob_offsets,
+		name_index,
+		digest_to_multi_blob,
+		dictionaries,
+		raw_crates,
+		end,
+	})
 }
-fn write_hdr<W :Write>(mut wtr :W, index_offset :u64) -> IoResult<()> {
+
+fn read_hdr<R :Read>(mut rdr :R) -> Result<Header, StorageError> {
+	use self::StorageErrorKind::*;
+	let magic = try!(rdr.read_u64::<BigEndian>().context(Io));
+	if magic != BLOB_MAGIC {
+		return Err(InvalidMagic.into());
+	}
+	let index_offset = try!(rdr.read_u64::<BigEndian>().context(Io));
+	if index_offset != 0 {
+		return Ok(Header {
+			version : 1,
+			features : 0,
+			index_offset,
+			slot : 0,
+		});
+	}
+	let version = try!(rdr.read_u32::<BigEndian>().context(Io));
+	if !(2 ..= FORMAT_VERSION).contains(&version) {
+		return Err(UnsupportedVersion(version).into());
+	}
+	let (features, index_offset, slot) = if version < 4 {
+		let features = try!(rdr.read_u64::<BigEndian>().context(Io));
+		let index_offset = try!(rdr.read_u64::<BigEndian>().context(Io));
+		(features, index_offset, 0)
+	} else {
+		let mut slots = Vec::new();
+		for slot in 0 .. 2 {
+			let mut buf = [0; HEADER_SLOT_LEN as usize];
+			try!(rdr.read_exact(&mut buf).context(Io));
+			if let Some((features, index_offset)) = parse_hdr_slot(&buf) {
+				slots.push((features, index_offset, slot));
+			}
+		}
+		// Use the slot that points to the latest index segment
+		match slots.into_iter().max_by_key(|s| s.1) {
+			Some(s) => s,
+			None => return Err(CorruptHeader.into()),
+		}
+	};
+	try!(check_features(features));
+	Ok(Header {
+		version,
+		features,
+		index_offset,
+		slot,
+	})
+}
+fn check_features(features :u64) -> Result<(), StorageError> {
+	let unknown = features & !KNOWN_FEATURES;
+	if unknown != 0 {
+		return Err(StorageErrorKind::UnsupportedFeatures(unknown).into());
+	}
+	Ok(())
+}
+/// Parses a header slot, returning `None` if it is invalid
+fn parse_hdr_slot(buf :&[u8]) -> Option<(u64, u64)> {
+	let mut rdr = buf;
+	let features = rdr.read_u64::<BigEndian>().ok()?;
+	let index_offset = rdr.read_u64::<BigEndian>().ok()?;
+	let checksum = rdr.read_u32::<BigEndian>().ok()?;
+	if index_offset == 0 || checksum != hdr_slot_checksum(&buf[.. 16]) {
+		return None;
+	}
+	Some((features, index_offset))
+}
+fn hdr_slot_checksum(buf :&[u8]) -> u32 {
+	let mut crc = Crc::new();
+	crc.update(buf);
+	crc.sum()
+}
+fn write_hdr_start<W :Write>(mut wtr :W) -> IoResult<()> {
 	try!(wtr.write_u64::<BigEndian>(BLOB_MAGIC));
-	try!(wtr.write_u64::<BigEndian>(index_offset));
+	try!(wtr.write_u64::<BigEndian>(0));
+	try!(wtr.write_u32::<BigEndian>(FORMAT_VERSION));
+	Ok(())
+}
+fn write_hdr_slot<W :Write>(mut wtr :W, features :u64, index_offset :u64) -> IoResult<()> {
+	let mut buf = Vec::with_capacity(HEADER_SLOT_LEN as usize);
+	try!(buf.write_u64::<BigEndian>(features));
+	try!(buf.write_u64::<BigEndian>(index_offset));
+	let checksum = hdr_slot_checksum(&buf);
+	try!(buf.write_u32::<BigEndian>(checksum));
+	try!(wtr.write_all(&buf));
 	Ok(())
 }
 fn read_offset_table<R :Read>(mut rdr :R) -> IoResult<HashMap<Digest, u64>> {
@@ -136,35 +713,18 @@ fn read_offset_table<R :Read>(mut rdr :R) -> IoResult<HashMap<Digest, u64>> {
 	}
 	Ok(tbl)
 }
-fn write_offset_table<W :Write>(mut wtr :W, tbl :&HashMap<Digest, u64>) -> IoResult<()> {
-	try!(wtr.write_u64::<BigEndian>(tbl.len() as u64));
-	for (d, o) in tbl.iter() {
-		try!(wtr.write(d));
-		try!(wtr.write_u64::<BigEndian>(*o));
-	}
-	Ok(())
-}
-fn read_name_idx<R :Read>(mut rdr :R) -> IoResult<HashMap<String, Digest>> {
-	let nidx_len = try!(rdr.read_u64::<BigEndian>());
+fn read_name_idx<R :Read>(mut rdr :R) -> Result<HashMap<String, Digest>, StorageError> {
+	use self::StorageErrorKind::*;
+	let nidx_len = try!(rdr.read_u64::<BigEndian>().context(Io));
 	let mut nidx = HashMap::new();
 	for _ in 0 .. nidx_len {
-		let s_bytes = try!(read_delim_byte_slice(&mut rdr));
+		let s = try!(read_index_name(&mut rdr, MAX_PATH_LEN));
 		let mut d :Digest = [0; 32];
-		try!(rdr.read_exact(&mut d));
-		let s = String::from_utf8(s_bytes).unwrap();
-		// TODO return Err instead of panicing
+		try!(rdr.read_exact(&mut d).context(Io));
 		nidx.insert(s, d);
 	}
 	Ok(nidx)
 }
-fn write_name_idx<W :Write>(mut wtr :W, nidx :&HashMap<String, Digest>) -> IoResult<()> {
-	try!(wtr.write_u64::<BigEndian>(nidx.len() as u64));
-	for (s,d) in nidx.iter() {
-		try!(write_delim_byte_slice(&mut wtr, s.as_bytes()));
-		try!(wtr.write(d));
-	}
-	Ok(())
-}
 fn read_digest_to_multi_blob<R :Read>(mut rdr :R) -> IoResult<HashMap<Digest, Digest>> {
 	let res_len = try!(rdr.read_u64::<BigEndian>());
 	let mut res = HashMap::new();
@@ -177,11 +737,22 @@ fn read_digest_to_multi_blob<R :Read>(mut rdr :R) -> IoResult<HashMap<Digest, Di
 	}
 	Ok(res)
 }
-fn write_digest_to_multi_blob<W :Write>(mut wtr :W, nidx :&HashMap<Digest, Digest>) -> IoResult<()> {
-	try!(wtr.write_u64::<BigEndian>(nidx.len() as u64));
-	for (d, d_multi) in nidx.iter() {
-		try!(wtr.write(d));
-		try!(wtr.write(d_multi));
+pub(crate) fn read_dictionaries<R :Read>(mut rdr :R) -> IoResult<HashMap<u32, Digest>> {
+	let res_len = try!(rdr.read_u64::<BigEndian>());
+	let mut res = HashMap::new();
+	for _ in 0 .. res_len {
+		let id = try!(rdr.read_u32::<BigEndian>());
+		let mut d :Digest = [0; 32];
+		try!(rdr.read_exact(&mut d));
+		res.insert(id, d);
+	}
+	Ok(res)
+}
+pub(crate) fn write_dictionaries<W :Write>(mut wtr :W, dicts :&HashMap<u32, Digest>) -> IoResult<()> {
+	try!(wtr.write_u64::<BigEndian>(dicts.len() as u64));
+	for (id, d) in dicts.iter() {
+		try!(wtr.write_u32::<BigEndian>(*id));
+		try!(wtr.write_all(d));
 	}
 	Ok(())
 }
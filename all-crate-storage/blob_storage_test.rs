@@ -1,5 +1,6 @@
 use std::io::Cursor;
-use super::blob_storage::{BlobStorage};
+use byteorder::{WriteBytesExt, BigEndian};
+use super::blob_storage::{BlobStorage, StorageErrorKind, FEATURE_MULTI_BLOB};
 
 #[test]
 fn store_and_load() {
@@ -18,9 +19,171 @@ fn store_and_load() {
 	}
 	println!("c {:?}", c);
 	{
-		let mut st = BlobStorage::load(&mut c).unwrap();
+		let st = BlobStorage::load(&mut c).unwrap();
 		for &(ref d, s) in test_data.iter() {
 			assert_eq!(st.get(d).unwrap().as_ref(), Some(s));
 		}
 	}
 }
+
+#[test]
+fn store_and_load_dictionaries() {
+	let mut c = Cursor::new(Vec::new());
+	{
+		let mut st = BlobStorage::empty(&mut c);
+		st.insert([4; 32], &[1, 2, 3]).unwrap();
+		st.dictionaries.insert(1, [4; 32]);
+		st.write_header_and_index().unwrap();
+	}
+	{
+		let st = BlobStorage::load(&mut c).unwrap();
+		assert_eq!(st.dictionaries.get(&1), Some(&[4; 32]));
+		assert_eq!(st.get(&[4; 32]).unwrap(), Some(vec![1, 2, 3]));
+	}
+}
+
+#[test]
+fn corrupt_blob_length() {
+	let mut c = Cursor::new(Vec::new());
+	{
+		let mut st = BlobStorage::empty(&mut c);
+		st.insert([1; 32], &[1, 2, 3]).unwrap();
+		st.write_header_and_index().unwrap();
+	}
+	// The length prefix of the first blob claims a huge blob
+	c.get_mut()[64] = 0x7f;
+	let st = BlobStorage::load(&mut c).unwrap();
+	let e = st.get(&[1; 32]).unwrap_err();
+	assert_eq!(*e.get_context(), StorageErrorKind::CorruptBlob);
+}
+
+#[test]
+fn load_version_1() {
+	// Header of a version 1 storage with an empty index at offset 64
+	let mut v = Vec::new();
+	v.extend_from_slice(b"BLOBSTRE");
+	v.write_u64::<BigEndian>(64).unwrap();
+	v.resize(64, 0);
+	for _ in 0 .. 3 {
+		v.write_u64::<BigEndian>(0).unwrap();
+	}
+	let st = BlobStorage::new(Cursor::new(v)).unwrap();
+	assert_eq!(st.features, 0);
+	assert!(st.dictionaries.is_empty());
+}
+
+#[test]
+fn reject_invalid_headers() {
+	let err = BlobStorage::new(Cursor::new(b"NOTABLOBSTORAGE!".to_vec())).err().unwrap();
+	assert_eq!(*err.get_context(), StorageErrorKind::InvalidMagic);
+
+	let mut v = Vec::new();
+	v.extend_from_slice(b"BLOBSTRE");
+	v.write_u64::<BigEndian>(0).unwrap();
+	v.write_u32::<BigEndian>(1000).unwrap();
+	let err = BlobStorage::new(Cursor::new(v)).err().unwrap();
+	assert_eq!(*err.get_context(), StorageErrorKind::UnsupportedVersion(1000));
+
+	let mut v = Vec::new();
+	v.extend_from_slice(b"BLOBSTRE");
+	v.write_u64::<BigEndian>(0).unwrap();
+	v.write_u32::<BigEndian>(2).unwrap();
+	v.write_u64::<BigEndian>(FEATURE_MULTI_BLOB | 1 << 40).unwrap();
+	// Index offset
+	v.write_u64::<BigEndian>(0).unwrap();
+	let err = BlobStorage::new(Cursor::new(v)).err().unwrap();
+	assert_eq!(*err.get_context(), StorageErrorKind::UnsupportedFeatures(1 << 40));
+}
+
+#[test]
+fn insert_after_load() {
+	let mut c = Cursor::new(Vec::new());
+	{
+		let mut st = BlobStorage::empty(&mut c);
+		st.insert_named_blob(Some("a".to_owned()), [1; 32], &[1, 1]).unwrap();
+		st.insert_named_blob(Some("b".to_owned()), [2; 32], &[2, 2]).unwrap();
+		st.write_header_and_index().unwrap();
+	}
+	let old = c.get_ref().clone();
+	{
+		// The new blob and the new index segment
+		// are written after the old index segment
+		let mut st = BlobStorage::load(&mut c).unwrap();
+		st.insert_named_blob(Some("c".to_owned()), [3; 32], &[3, 3]).unwrap();
+		st.insert_multi_blob([4; 32], [3; 32]);
+		st.write_header_and_index().unwrap();
+	}
+	{
+		// The old index segment is still intact, so with
+		// the old header, the old content can be loaded
+		let mut v = c.get_ref().clone();
+		v[.. 64].copy_from_slice(&old[.. 64]);
+		let st = BlobStorage::load(Cursor::new(v)).unwrap();
+		assert_eq!(st.names().unwrap(), vec![("a".to_owned(), [1; 32]),
+			("b".to_owned(), [2; 32])]);
+		assert_eq!(st.get(&[2; 32]).unwrap(), Some(vec![2, 2]));
+		assert!(!st.has(&[3; 32]).unwrap());
+	}
+	{
+		let st = BlobStorage::load(&mut c).unwrap();
+		assert_eq!(st.features, FEATURE_MULTI_BLOB);
+		let names = st.names().unwrap();
+		assert_eq!(names, vec![("a".to_owned(), [1; 32]),
+			("b".to_owned(), [2; 32]), ("c".to_owned(), [3; 32])]);
+		for (name, d) in names.iter() {
+			assert_eq!(st.get_named(name).unwrap(), Some(*d));
+			assert_eq!(st.get(d).unwrap(), Some(vec![d[0]; 2]));
+		}
+		assert_eq!(st.get_named("d").unwrap(), None);
+		assert_eq!(st.get_multi_blob(&[4; 32]).unwrap(), Some([3; 32]));
+		assert_eq!(st.get_multi_blob(&[3; 32]).unwrap(), None);
+	}
+}
+
+#[test]
+fn recover_from_checkpoint() {
+	let mut c = Cursor::new(Vec::new());
+	{
+		let mut st = BlobStorage::empty(&mut c);
+		st.insert_named_blob(Some("a".to_owned()), [1; 32], &[1, 1]).unwrap();
+		st.checkpoint().unwrap();
+		st.insert_named_blob(Some("b".to_owned()), [2; 32], &[2, 2]).unwrap();
+		st.checkpoint().unwrap();
+		// Not covered by a checkpoint, as if the process was killed
+		st.insert_named_blob(Some("c".to_owned()), [3; 32], &[3, 3]).unwrap();
+	}
+	{
+		let mut st = BlobStorage::load(&mut c).unwrap();
+		assert_eq!(st.names().unwrap(), vec![("a".to_owned(), [1; 32]),
+			("b".to_owned(), [2; 32])]);
+		assert_eq!(st.get(&[2; 32]).unwrap(), Some(vec![2, 2]));
+		assert!(!st.has(&[3; 32]).unwrap());
+		// Resume
+		st.insert_named_blob(Some("c".to_owned()), [3; 32], &[3, 3]).unwrap();
+		st.write_header_and_index().unwrap();
+	}
+	{
+		let st = BlobStorage::load(&mut c).unwrap();
+		assert_eq!(st.names().unwrap().len(), 3);
+		for d in [[1; 32], [2; 32], [3; 32]].iter() {
+			assert_eq!(st.get(d).unwrap(), Some(vec![d[0]; 2]));
+		}
+	}
+}
+
+#[test]
+fn recover_from_torn_header() {
+	let mut c = Cursor::new(Vec::new());
+	{
+		let mut st = BlobStorage::empty(&mut c);
+		st.insert_named_blob(Some("a".to_owned()), [1; 32], &[1, 1]).unwrap();
+		st.checkpoint().unwrap();
+		st.insert_named_blob(Some("b".to_owned()), [2; 32], &[2, 2]).unwrap();
+		st.checkpoint().unwrap();
+	}
+	// Corrupt the first header slot, which was written last
+	c.get_mut()[25] ^= 0xff;
+	let st = BlobStorage::load(&mut c).unwrap();
+	assert_eq!(st.names().unwrap(), vec![("a".to_owned(), [1; 32])]);
+	assert!(!st.has(&[2; 32]).unwrap());
+}
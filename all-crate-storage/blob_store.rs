@@ -0,0 +1,144 @@
+/*!
+Traits for stores of blobs
+
+A blob crate storage keeps its blobs and indices in a blob store.
+The store can be a single storage file (`BlobStorage`),
+or a directory with one file per blob (`DirBlobStorage`).
+*/
+
+use std::collections::HashMap;
+use std::io::{Seek, Write};
+use super::read_at::ReadAt;
+use super::blob_storage::{BlobStorage, StorageError, SyncAll};
+use super::hash_ctx::Digest;
+
+/// Read access to a store of blobs
+pub trait BlobStore {
+	fn has(&self, digest :&Digest) -> Result<bool, StorageError>;
+	fn get(&self, digest :&Digest) -> Result<Option<Vec<u8>>, StorageError>;
+	/// Returns the digests of all blobs that are stored directly, sorted
+	fn digests(&self) -> Result<Vec<Digest>, StorageError>;
+	/// Looks up the digest of the blob with the given (crate) name
+	fn get_named(&self, name :&str) -> Result<Option<Digest>, StorageError>;
+	/// Returns all entries of the name index, sorted by name
+	fn names(&self) -> Result<Vec<(String, Digest)>, StorageError>;
+	/// Returns the entries of the name index whose name starts with the prefix, sorted by name
+	fn names_with_prefix(&self, prefix :&str) -> Result<Vec<(String, Digest)>, StorageError> {
+		let mut names = try!(self.names());
+		names.retain(|(n, _)| n.starts_with(prefix));
+		Ok(names)
+	}
+	/// Looks up the digest of the multi blob that contains the given blob
+	fn get_multi_blob(&self, digest :&Digest) -> Result<Option<Digest>, StorageError>;
+	/// Returns all blobs stored inside multi blobs,
+	/// together with the digest of the multi blob, sorted
+	fn multi_blob_entries(&self) -> Result<Vec<(Digest, Digest)>, StorageError>;
+	/// Looks up the digest of a crate that is stored as it is
+	fn get_raw_crate(&self, name :&str) -> Result<Option<Digest>, StorageError>;
+	/// Returns all crates that are stored as they are, sorted by name
+	fn raw_crates(&self) -> Result<Vec<(String, Digest)>, StorageError>;
+	/// Returns the crates stored as they are whose name starts with the prefix, sorted by name
+	fn raw_crates_with_prefix(&self, prefix :&str) -> Result<Vec<(String, Digest)>, StorageError> {
+		let mut raw_crates = try!(self.raw_crates());
+		raw_crates.retain(|(n, _)| n.starts_with(prefix));
+		Ok(raw_crates)
+	}
+	/// Returns the digests of the zstd dictionaries, by id
+	fn dictionaries(&self) -> &HashMap<u32, Digest>;
+	/// Returns the feature flags of the store
+	fn features(&self) -> u64;
+}
+
+/// Write access to a store of blobs
+///
+/// Changes to the indices only become durable
+/// once `checkpoint` or `write_index` are called.
+pub trait BlobStoreMut :BlobStore {
+	/// Stores the blob, unless one with that digest is present already
+	///
+	/// Returns whether the blob has been added.
+	fn insert(&mut self, digest :Digest, content :&[u8]) -> Result<bool, StorageError>;
+	/// Stores the blob even if a blob with that digest is present already
+	fn replace(&mut self, digest :Digest, content :&[u8]) -> Result<(), StorageError>;
+	fn insert_name(&mut self, name :String, digest :Digest);
+	fn insert_multi_blob(&mut self, digest :Digest, multi_blob_digest :Digest);
+	fn insert_raw_crate(&mut self, name :String, digest :Digest);
+	fn insert_dictionary(&mut self, id :u32, digest :Digest);
+	fn add_features(&mut self, features :u64);
+	/// Writes the complete indices
+	fn write_index(&mut self) -> Result<(), StorageError>;
+	/// Writes the changes to the indices since the last checkpoint
+	fn checkpoint(&mut self) -> Result<(), StorageError>;
+}
+
+impl<S :ReadAt> BlobStore for BlobStorage<S> {
+	fn has(&self, digest :&Digest) -> Result<bool, StorageError> {
+		BlobStorage::has(self, digest)
+	}
+	fn get(&self, digest :&Digest) -> Result<Option<Vec<u8>>, StorageError> {
+		BlobStorage::get(self, digest)
+	}
+	fn digests(&self) -> Result<Vec<Digest>, StorageError> {
+		BlobStorage::digests(self)
+	}
+	fn get_named(&self, name :&str) -> Result<Option<Digest>, StorageError> {
+		BlobStorage::get_named(self, name)
+	}
+	fn names(&self) -> Result<Vec<(String, Digest)>, StorageError> {
+		BlobStorage::names(self)
+	}
+	fn names_with_prefix(&self, prefix :&str) -> Result<Vec<(String, Digest)>, StorageError> {
+		BlobStorage::names_with_prefix(self, prefix)
+	}
+	fn get_multi_blob(&self, digest :&Digest) -> Result<Option<Digest>, StorageError> {
+		BlobStorage::get_multi_blob(self, digest)
+	}
+	fn multi_blob_entries(&self) -> Result<Vec<(Digest, Digest)>, StorageError> {
+		BlobStorage::multi_blob_entries(self)
+	}
+	fn get_raw_crate(&self, name :&str) -> Result<Option<Digest>, StorageError> {
+		BlobStorage::get_raw_crate(self, name)
+	}
+	fn raw_crates(&self) -> Result<Vec<(String, Digest)>, StorageError> {
+		BlobStorage::raw_crates(self)
+	}
+	fn raw_crates_with_prefix(&self, prefix :&str) -> Result<Vec<(String, Digest)>, StorageError> {
+		BlobStorage::raw_crates_with_prefix(self, prefix)
+	}
+	fn dictionaries(&self) -> &HashMap<u32, Digest> {
+		&self.dictionaries
+	}
+	fn features(&self) -> u64 {
+		self.features
+	}
+}
+
+impl<S :ReadAt + Seek + Write + SyncAll> BlobStoreMut for BlobStorage<S> {
+	fn insert(&mut self, digest :Digest, content :&[u8]) -> Result<bool, StorageError> {
+		BlobStorage::insert(self, digest, content)
+	}
+	fn replace(&mut self, digest :Digest, content :&[u8]) -> Result<(), StorageError> {
+		BlobStorage::replace(self, digest, content)
+	}
+	fn insert_name(&mut self, name :String, digest :Digest) {
+		BlobStorage::insert_name(self, name, digest)
+	}
+	fn insert_multi_blob(&mut self, digest :Digest, multi_blob_digest :Digest) {
+		BlobStorage::insert_multi_blob(self, digest, multi_blob_digest)
+	}
+	fn insert_raw_crate(&mut self, name :String, digest :Digest) {
+		BlobStorage::insert_raw_crate(self, name, digest)
+	}
+	fn insert_dictionary(&mut self, id :u32, digest :Digest) {
+		self.dictionaries.insert(id, digest);
+	}
+	fn add_features(&mut self, features :u64) {
+		self.features |= features;
+	}
+	fn write_index(&mut self) -> Result<(), StorageError> {
+		self.write_header_and_index()
+	}
+	fn checkpoint(&mut self) -> Result<(), StorageError> {
+		BlobStorage::checkpoint(self)
+	}
+}
@@ -0,0 +1,119 @@
+/*!
+Bounded caches shared between threads
+
+A `LruCache` holds a limited number of entries and drops the
+least recently used one once it is full. It counts hits and
+misses, so that its effectiveness can be observed.
+A capacity of zero disables the cache.
+*/
+
+use lru_cache::LruCache as LruMap;
+use std::hash::Hash;
+use std::sync::{Mutex, MutexGuard};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+pub struct LruCache<K :Eq + Hash, V> {
+	entries :Mutex<LruMap<K, V>>,
+	hits :AtomicU64,
+	misses :AtomicU64,
+}
+
+#[derive(Serialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CacheStats {
+	pub hits :u64,
+	pub misses :u64,
+	/// Number of entries currently in the cache
+	pub entries :usize,
+	pub capacity :usize,
+}
+
+impl<K :Eq + Hash, V :Clone> LruCache<K, V> {
+	pub fn new(capacity :usize) -> Self {
+		LruCache {
+			entries : Mutex::new(LruMap::new(capacity)),
+			hits : AtomicU64::new(0),
+			misses : AtomicU64::new(0),
+		}
+	}
+	fn lock(&self) -> MutexGuard<'_, LruMap<K, V>> {
+		// The entries are valid even if a thread has panicked while holding the lock
+		self.entries.lock().unwrap_or_else(|e| e.into_inner())
+	}
+	/// Changes the capacity, dropping entries if there are too many
+	pub fn set_capacity(&self, capacity :usize) {
+		self.lock().set_capacity(capacity);
+	}
+	/// Returns the entry for `key`, counting a hit or a miss
+	pub fn get(&self, key :&K) -> Option<V> {
+		let mut entries = self.lock();
+		if entries.capacity() == 0 {
+			return None;
+		}
+		let v = entries.get_mut(key).map(|v| v.clone());
+		let ctr = if v.is_some() { &self.hits } else { &self.misses };
+		ctr.fetch_add(1, Ordering::Relaxed);
+		v
+	}
+	pub fn insert(&self, key :K, value :V) {
+		let mut entries = self.lock();
+		if entries.capacity() > 0 {
+			entries.insert(key, value);
+		}
+	}
+	/// Returns the entry for `key`, obtaining it via `f` if it is not present
+	///
+	/// Only values returned by `f` as `Some` are put into the cache.
+	/// The lock isn't held while `f` runs, so concurrent misses
+	/// for the same key might all call `f`.
+	pub fn get_or_insert_with<F :FnOnce() -> Option<V>>(&self,
+			key :K, f :F) -> Option<V> {
+		if let Some(v) = self.get(&key) {
+			return Some(v);
+		}
+		let v = f();
+		if let Some(ref v) = v {
+			self.insert(key, v.clone());
+		}
+		v
+	}
+	pub fn stats(&self) -> CacheStats {
+		let entries = self.lock();
+		CacheStats {
+			hits : self.hits.load(Ordering::Relaxed),
+			misses : self.misses.load(Ordering::Relaxed),
+			entries : entries.len(),
+			capacity : entries.capacity(),
+		}
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn eviction_and_stats() {
+		let cache = LruCache::new(2);
+		cache.insert(1, "one");
+		cache.insert(2, "two");
+		assert_eq!(cache.get(&1), Some("one"));
+		// 2 is now the least recently used entry
+		cache.insert(3, "three");
+		assert_eq!(cache.get(&2), None);
+		assert_eq!(cache.get_or_insert_with(2, || Some("two")), Some("two"));
+		assert_eq!(cache.get(&1), None);
+		assert_eq!(cache.get_or_insert_with(4, || None), None);
+		assert_eq!(cache.stats(), CacheStats {
+			hits : 1,
+			misses : 4,
+			entries : 2,
+			capacity : 2,
+		});
+
+		cache.set_capacity(0);
+		assert_eq!(cache.get_or_insert_with(1, || Some("one")), Some("one"));
+		assert_eq!(cache.get(&1), None);
+		assert_eq!(cache.stats().entries, 0);
+		assert_eq!(cache.stats().misses, 4);
+	}
+}
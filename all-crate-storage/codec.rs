@@ -0,0 +1,235 @@
+/*!
+Compression codecs for blobs
+
+Each compressed blob of a blob crate storage starts with
+a byte that specifies the codec it was compressed with.
+Blobs compressed with zstd additionally record the id of
+the dictionary they were compressed with, or 0 if none
+was used. The dictionaries are stored in the blob storage
+as well, see `BlobStorage::dictionaries`.
+
+Blobs written before codecs were recorded are plain gzip
+streams. As the gzip magic can't be confused with any
+codec byte, we can still read them.
+*/
+
+use flate2::{Compression, GzBuilder};
+use flate2::read::GzDecoder;
+use byteorder::{ByteOrder, ReadBytesExt, WriteBytesExt, BigEndian};
+use std::io::{self, Read, Write, BufReader};
+use std::sync::Arc;
+use zstd;
+
+const CODEC_GZIP :u8 = 1;
+const CODEC_ZSTD :u8 = 2;
+const GZIP_MAGIC :u8 = 0x1f;
+
+/// The zstd compression level we use
+///
+/// Blob storages are written once and read often,
+/// so we can afford spending time on compression.
+const ZSTD_LEVEL :i32 = 19;
+
+/// Maximum size of a trained dictionary
+const DICT_MAX_LEN :usize = 112_640;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Codec {
+	Gzip,
+	Zstd,
+}
+
+impl Codec {
+	pub fn from_name(name :&str) -> Option<Self> {
+		match name {
+			"gzip" => Some(Codec::Gzip),
+			"zstd" => Some(Codec::Zstd),
+			_ => None,
+		}
+	}
+	pub fn name(&self) -> &'static str {
+		match self {
+			Codec::Gzip => "gzip",
+			Codec::Zstd => "zstd",
+		}
+	}
+}
+
+/// A zstd dictionary together with its id
+#[derive(Clone)]
+pub struct Dictionary {
+	pub id :u32,
+	pub content :Arc<Vec<u8>>,
+}
+
+impl Dictionary {
+	/// Trains a dictionary on the given samples
+	///
+	/// Returns `None` if training was not possible,
+	/// e.g. because there were not enough samples.
+	pub fn train(id :u32, samples :&[Vec<u8>]) -> Option<Self> {
+		let content = zstd::dict::from_samples(samples, DICT_MAX_LEN).ok()?;
+		Some(Dictionary {
+			id,
+			content : Arc::new(content),
+		})
+	}
+}
+
+/// Compresses blobs with a given codec
+#[derive(Clone)]
+pub struct BlobCompressor {
+	codec :Codec,
+	dict :Option<Dictionary>,
+}
+
+impl BlobCompressor {
+	/// Creates a compressor
+	///
+	/// The dictionary is only used by the zstd codec.
+	pub fn new(codec :Codec, dict :Option<Dictionary>) -> Self {
+		BlobCompressor {
+			codec,
+			dict,
+		}
+	}
+	pub fn compress(&self, blob :&[u8]) -> io::Result<Vec<u8>> {
+		let mut res = Vec::new();
+		match self.codec {
+			Codec::Gzip => {
+				try!(res.write_u8(CODEC_GZIP));
+				let mut gz_enc = GzBuilder::new().read(blob, Compression::best());
+				try!(io::copy(&mut gz_enc, &mut res));
+			},
+			Codec::Zstd => {
+				try!(res.write_u8(CODEC_ZSTD));
+				let mut enc = if let Some(ref dict) = self.dict {
+					try!(res.write_u32::<BigEndian>(dict.id));
+					try!(zstd::stream::write::Encoder::with_dictionary(res,
+						ZSTD_LEVEL, &dict.content))
+				} else {
+					try!(res.write_u32::<BigEndian>(0));
+					try!(zstd::stream::write::Encoder::new(res, ZSTD_LEVEL))
+				};
+				try!(enc.write_all(blob));
+				return enc.finish();
+			},
+		}
+		Ok(res)
+	}
+}
+
+/// Returns the id of the dictionary needed to decompress the blob, if any
+pub fn needed_dictionary(blob :&[u8]) -> Option<u32> {
+	let mut rdr = blob;
+	match rdr.read_u8() {
+		Ok(CODEC_ZSTD) => match rdr.read_u32::<BigEndian>() {
+			Ok(0) | Err(_) => None,
+			Ok(id) => Some(id),
+		},
+		_ => None,
+	}
+}
+
+/// Changes the id of the dictionary the blob was compressed with
+///
+/// This is needed when a dictionary gets a new id, e.g. because
+/// its id is already used in the storage it's copied to.
+/// Blobs that don't need a dictionary are left alone.
+pub fn replace_dictionary_id(blob :&mut [u8], id :u32) {
+	if needed_dictionary(blob).is_some() {
+		BigEndian::write_u32(&mut blob[1 .. 5], id);
+	}
+}
+
+/// Decompresses a blob
+///
+/// If the blob needs a dictionary (see `needed_dictionary`),
+/// it has to be passed.
+pub fn decompress(blob :&[u8], dict :Option<&[u8]>) -> io::Result<Vec<u8>> {
+	let mut res = Vec::new();
+	try!(try!(decompressing_reader(blob, dict)).read_to_end(&mut res));
+	Ok(res)
+}
+
+/// Returns a reader that decompresses the blob read from `rdr`
+///
+/// Like for `decompress`, the dictionary has to be passed if the blob needs one.
+pub fn decompressing_reader<'a, R :Read + 'a>(mut rdr :R,
+		dict :Option<&[u8]>) -> io::Result<Box<dyn Read + 'a>> {
+	match try!(rdr.read_u8()) {
+		GZIP_MAGIC => {
+			// Legacy blob without codec byte
+			Ok(Box::new(GzDecoder::new((&[GZIP_MAGIC][..]).chain(rdr))))
+		},
+		CODEC_GZIP => Ok(Box::new(GzDecoder::new(rdr))),
+		CODEC_ZSTD => {
+			let dict_id = try!(rdr.read_u32::<BigEndian>());
+			let dict = match (dict_id, dict) {
+				(0, _) => &[][..],
+				(_, Some(dict)) => dict,
+				(_, None) => return Err(io::Error::new(io::ErrorKind::InvalidInput,
+					format!("Dictionary {} needed to decompress blob", dict_id))),
+			};
+			let dec = try!(zstd::stream::read::Decoder::with_dictionary(BufReader::new(rdr), dict));
+			Ok(Box::new(dec))
+		},
+		c => Err(io::Error::new(io::ErrorKind::InvalidData,
+			format!("Unknown blob codec {}", c))),
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	fn samples() -> Vec<Vec<u8>> {
+		(0 .. 400)
+			.map(|i| format!("fn function_{}() -> u32 {{\n\tlet v = {};\n\tv * 2\n}}\n", i, i * 7)
+				.into_bytes())
+			.collect()
+	}
+
+	#[test]
+	fn roundtrip() {
+		let samples = samples();
+		let dict = Dictionary::train(1, &samples).unwrap();
+		let compressors = [
+			BlobCompressor::new(Codec::Gzip, None),
+			BlobCompressor::new(Codec::Zstd, None),
+			BlobCompressor::new(Codec::Zstd, Some(dict.clone())),
+		];
+		for compressor in compressors.iter() {
+			for sample in samples.iter().take(20) {
+				let compressed = compressor.compress(sample).unwrap();
+				let needed = needed_dictionary(&compressed);
+				let d = needed.map(|id| {
+					assert_eq!(id, dict.id);
+					&dict.content[..]
+				});
+				assert_eq!(&decompress(&compressed, d).unwrap(), sample);
+			}
+		}
+	}
+
+	#[test]
+	fn replace_dict_id() {
+		let samples = samples();
+		let dict = Dictionary::train(1, &samples).unwrap();
+		let compressor = BlobCompressor::new(Codec::Zstd, Some(dict.clone()));
+		let mut compressed = compressor.compress(&samples[0]).unwrap();
+		replace_dictionary_id(&mut compressed, 7);
+		assert_eq!(needed_dictionary(&compressed), Some(7));
+		assert_eq!(decompress(&compressed, Some(&dict.content)).unwrap(), samples[0]);
+	}
+
+	#[test]
+	fn legacy_gzip() {
+		let blob = b"Hello World";
+		let mut gz_enc = GzBuilder::new().read(&blob[..], Compression::best());
+		let mut compressed = Vec::new();
+		io::copy(&mut gz_enc, &mut compressed).unwrap();
+		assert_eq!(needed_dictionary(&compressed), None);
+		assert_eq!(&decompress(&compressed, None).unwrap()[..], &blob[..]);
+	}
+}
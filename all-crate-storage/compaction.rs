@@ -0,0 +1,266 @@
+/*!
+Compaction of blob crate storages
+
+Crates can't be removed from a storage file in place.
+Instead, a new storage is written that only contains
+the crates to keep, and the blobs they reference.
+Which blobs are live is determined by counting the
+references from the metadata blobs of the kept crates.
+
+Blobs are copied over as they are, without recompressing them.
+Multi blobs are copied as a whole if all of their blobs are live.
+Otherwise they are split up, and only their live blobs are stored,
+so that the content of removed crates doesn't survive inside a diff.
+*/
+
+use std::collections::{HashMap, HashSet};
+use semver::{Version, SemVerError};
+use failure::ResultExt;
+use super::blob_crate_storage::BlobCrateStorage;
+use super::blob_storage::{StorageError, StorageErrorKind};
+use super::blob_store::{BlobStore, BlobStoreMut};
+use super::crate_storage::CrateSpec;
+use super::hash_ctx::Digest;
+use super::multi_blob::MultiBlob;
+use super::reconstruction::CrateRecMetadata;
+use super::registry::registry::AllCratesJson;
+
+/// Decides which crates are kept when compacting
+#[derive(Default, Debug)]
+pub struct RetentionPolicy {
+	/// Crates whose versions are all removed
+	pub denied_crates :HashSet<String>,
+	/// Single versions that are removed
+	pub denied_versions :HashSet<(String, Version)>,
+}
+
+impl RetentionPolicy {
+	/// Adds the entries of a deny list
+	///
+	/// The list has one crate name per line, optionally followed
+	/// by a version to only remove that version.
+	/// Empty lines and lines starting with `#` are ignored.
+	pub fn add_deny_list(&mut self, list :&str) -> Result<(), SemVerError> {
+		for line in list.lines() {
+			let line = line.trim();
+			if line.is_empty() || line.starts_with('#') {
+				continue;
+			}
+			let mut components = line.split_whitespace();
+			let name = components.next().unwrap().to_owned();
+			match components.next() {
+				Some(version) => {
+					let version = try!(Version::parse(version));
+					self.denied_versions.insert((name, version));
+				},
+				None => {
+					self.denied_crates.insert(name);
+				},
+			}
+		}
+		Ok(())
+	}
+	/// Removes all versions that are yanked in the given index
+	pub fn deny_yanked(&mut self, acj :&AllCratesJson) {
+		for (name, versions) in acj.iter() {
+			for v in versions.iter().filter(|v| v.yanked) {
+				self.denied_versions.insert((name.clone(), v.version.clone()));
+			}
+		}
+	}
+	pub fn keeps(&self, spec :&CrateSpec) -> bool {
+		!self.denied_crates.contains(&spec.name) &&
+			!self.denied_versions.contains(&(spec.name.clone(), spec.version.clone()))
+	}
+}
+
+/// Statistics about a compaction run
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct CompactionStats {
+	pub crates_kept :usize,
+	pub crates_removed :usize,
+	pub blobs_kept :usize,
+	pub blobs_removed :usize,
+}
+
+/// Counts the references to each blob from the given crates
+///
+/// Each crate references its metadata blob, as well as
+/// the blobs of its files. Crates that are stored as they are
+/// reference the blob of their .crate file.
+pub fn reference_counts<B :BlobStore>(src :&mut BlobCrateStorage<B>,
+		names :&[(String, Digest)], raw_crates :&[(String, Digest)])
+		-> Result<HashMap<Digest, usize>, StorageError> {
+	let mut counts = HashMap::new();
+	for (_, meta_d) in names.iter() {
+		*counts.entry(*meta_d).or_insert(0) += 1;
+		let meta_blob = match src.get_blob(meta_d) {
+			Ok(b) => b,
+			Err(_) => return Err(StorageErrorKind::MissingBlob.into()),
+		};
+		let meta = try!(CrateRecMetadata::deserialize(meta_blob.as_slice())
+			.context(StorageErrorKind::CorruptIndex));
+		for (_, d) in meta.entry_metadata.iter() {
+			*counts.entry(*d).or_insert(0) += 1;
+		}
+	}
+	for (_, d) in raw_crates.iter() {
+		*counts.entry(*d).or_insert(0) += 1;
+	}
+	Ok(counts)
+}
+
+fn is_kept<F :FnMut(&CrateSpec) -> bool>(name :&str, keep :&mut F) -> bool {
+	match CrateSpec::from_file_name(name) {
+		Some(spec) => keep(&spec),
+		// Not a crate we know how to name, so we can't decide about it
+		None => true,
+	}
+}
+
+/// Writes the crates of `src` that `keep` returns true for into `dst`
+///
+/// Only blobs that are referenced by the kept crates are copied over.
+/// Afterwards, the index of `dst` is written.
+pub fn compact<B :BlobStore, C :BlobStoreMut, F :FnMut(&CrateSpec) -> bool>(
+		src :&mut BlobCrateStorage<B>, dst :&mut BlobCrateStorage<C>,
+		mut keep :F) -> Result<CompactionStats, StorageError> {
+	let mut stats = CompactionStats::default();
+	let mut names = Vec::new();
+	for (name, d) in try!(src.b.names()) {
+		if is_kept(&name, &mut keep) {
+			names.push((name, d));
+		} else {
+			stats.crates_removed += 1;
+		}
+	}
+	let mut raw_crates = Vec::new();
+	for (name, d) in try!(src.b.raw_crates()) {
+		if is_kept(&name, &mut keep) {
+			raw_crates.push((name, d));
+		} else {
+			stats.crates_removed += 1;
+		}
+	}
+	stats.crates_kept = names.len() + raw_crates.len();
+	let counts = try!(reference_counts(src, &names, &raw_crates));
+
+	try!(dst.copy_dictionaries_from(src));
+	try!(dst.add_features_of(src));
+	let compressor = try!(dst.obtain_compressor(&[]));
+
+	// Copy the blobs that are stored directly,
+	// and find out which multi blobs are needed
+	let mut multi_blobs = HashMap::new();
+	for d in counts.keys() {
+		if try!(src.b.has(d)) {
+			let blob = try!(src.b.get(d)).unwrap();
+			try!(dst.b.insert(*d, &blob));
+			continue;
+		}
+		let mblob_d = match try!(src.b.get_multi_blob(d)) {
+			Some(mblob_d) => mblob_d,
+			None => return Err(StorageErrorKind::MissingBlob.into()),
+		};
+		multi_blobs.entry(mblob_d).or_insert_with(Vec::new).push(*d);
+	}
+	for (mblob_d, live) in multi_blobs {
+		let mblob = match try!(src.b.get(&mblob_d)) {
+			Some(b) => b,
+			None => return Err(StorageErrorKind::MissingBlob.into()),
+		};
+		let members = match src.get_blob(&mblob_d).ok()
+				.and_then(|b| MultiBlob::deserialize(b.as_slice(), src.diff_kinds()).ok()) {
+			Some(mb) => mb.digests(),
+			None => return Err(StorageErrorKind::MissingBlob.into()),
+		};
+		if members.len() == live.len() {
+			try!(dst.b.insert(mblob_d, &mblob));
+			for d in members {
+				dst.b.insert_multi_blob(d, mblob_d);
+			}
+			continue;
+		}
+		for d in live {
+			let blob = match src.get_blob(&d) {
+				Ok(b) => b,
+				Err(_) => return Err(StorageErrorKind::MissingBlob.into()),
+			};
+			let compressed = try!(compressor.compress(&blob).context(StorageErrorKind::Io));
+			try!(dst.b.insert(d, &compressed));
+		}
+	}
+
+	for (name, d) in names {
+		dst.b.insert_name(name, d);
+	}
+	for (name, d) in raw_crates {
+		dst.b.insert_raw_crate(name, d);
+	}
+	try!(dst.store());
+
+	// Dictionaries and multi blobs themselves are not counted
+	let mut stored = try!(src.b.digests()).into_iter().collect::<HashSet<_>>();
+	for (d, mblob_d) in try!(src.b.multi_blob_entries()) {
+		stored.remove(&mblob_d);
+		stored.insert(d);
+	}
+	for d in src.b.dictionaries().values() {
+		stored.remove(d);
+	}
+	stats.blobs_kept = counts.len();
+	stats.blobs_removed = stored.iter().filter(|&d| !counts.contains_key(d)).count();
+	Ok(stats)
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use std::io::Cursor;
+	use codec::Codec;
+	use crate_storage::CrateSource;
+	use hash_ctx::digest_of;
+	use test_util::{test_crate, spec, storage_with};
+
+	#[test]
+	fn deny_list() {
+		let mut policy = RetentionPolicy::default();
+		policy.add_deny_list("# Takedowns\nleaked-internal\n\nfoo 1.0.0\n").unwrap();
+		assert!(!policy.keeps(&spec("leaked-internal", "0.1.0")));
+		assert!(!policy.keeps(&spec("foo", "1.0.0")));
+		assert!(policy.keeps(&spec("foo", "1.0.1")));
+		assert!(policy.add_deny_list("foo bar").is_err());
+	}
+
+	#[test]
+	fn compact_storage() {
+		let shared = b"The MIT License\n";
+		let foo = test_crate("foo", "1.0.0", &[
+			("LICENSE", shared),
+			("src/lib.rs", b"pub fn foo() {}\n"),
+		]);
+		let secret = b"const PASSWORD :&str = \"hunter2\";\n";
+		let leaked = test_crate("leaked-internal", "0.1.0", &[
+			("LICENSE", shared),
+			("src/lib.rs", secret),
+		]);
+		let mut src = storage_with(Codec::Gzip, &[foo.clone(), leaked]);
+
+		let mut policy = RetentionPolicy::default();
+		policy.add_deny_list("leaked-internal").unwrap();
+		let mut dst = BlobCrateStorage::empty(Cursor::new(Vec::new()));
+		let stats = compact(&mut src, &mut dst, |s| policy.keeps(s)).unwrap();
+		assert_eq!(stats, CompactionStats {
+			crates_kept : 1,
+			crates_removed : 1,
+			// The metadata blob, LICENSE and src/lib.rs
+			blobs_kept : 3,
+			// The metadata blob and src/lib.rs of the removed crate
+			blobs_removed : 2,
+		});
+		assert_eq!(dst.get_crate(&foo.0).ok(), Some(foo.1));
+		assert_eq!(dst.get_crate(&spec("leaked-internal", "0.1.0")).ok(), None);
+		assert!(!dst.b.has(&digest_of(secret)).unwrap());
+		assert!(dst.b.has(&digest_of(shared)).unwrap());
+	}
+}
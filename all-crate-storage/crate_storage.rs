@@ -2,16 +2,31 @@ use semver::Version;
 use super::hash_ctx::{HashCtx, Digest};
 use super::registry::registry::{CrateIndexJson, AllCratesJson};
 use super::blob_crate_storage::{BlobCrateStorage, StorageFileHandle};
+use super::blob_storage::{BlobStorage, SyncAll};
+use super::dir_blob_storage::DirBlobStorage;
+use super::remote::RemoteFile;
+use super::gz_index::GzIndex;
+use super::extraction::{ExtractionLimits, ExtractionError, ExtractionErrorKind, EntryChecker,
+	SizeLimitedReader, limited_gz_decoder, extraction_error, error_kind, normalize_path,
+	listed_path};
+use super::error::{self, CrateError, CrateErrorKind};
+use super::read_at::ReadAt;
+use super::extracted_dir_storage::{ExtractedDirStorage, ExtractedCrateHandle};
+use super::reconstruction::PathResolver;
 use flate2::read::GzDecoder;
-use tar::Archive;
+use tar::{Archive, Header};
 use std::path::{Path, PathBuf};
 use std::cell::RefCell;
-use std::fs::File;
-use std::io::{self, Read, Seek};
+use std::rc::Rc;
+use std::sync::{RwLock, RwLockReadGuard};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, Write, Cursor, BufReader, BufWriter};
 use std::ops::Deref;
 use registry::registry::obtain_crate_name_path;
 
-#[derive(Clone, PartialEq, Eq)]
+#[derive(Clone, PartialEq, Eq, Hash)]
 pub struct CrateSpec {
 	pub name :String,
 	pub version :Version,
@@ -21,78 +36,231 @@ impl CrateSpec {
 	pub fn file_name(&self) -> String {
 		format!("{}-{}.crate", self.name, self.version)
 	}
+	/// Parses a file name as returned by `file_name`
+	pub fn from_file_name(file_name :&str) -> Option<Self> {
+		if !file_name.ends_with(".crate") {
+			return None;
+		}
+		let stem = &file_name[.. file_name.len() - ".crate".len()];
+		// Crate names can contain dashes, but no dots,
+		// so the first dash followed by a valid version
+		// separates the name from the version.
+		for (i, _) in stem.match_indices('-') {
+			if let Ok(version) = Version::parse(&stem[i + 1 ..]) {
+				return Some(CrateSpec {
+					name : stem[.. i].to_owned(),
+					version,
+				});
+			}
+		}
+		None
+	}
+}
+
+/// Sorts crate specs by name, then by version
+pub(crate) fn sort_specs(specs :&mut Vec<CrateSpec>) {
+	specs.sort_by(|a, b| (&a.name, &a.version).cmp(&(&b.name, &b.version)));
+	specs.dedup();
+}
+
+/// Metadata of a file inside a crate
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FileMetadata {
+	pub path :String,
+	pub size :u64,
+	/// The unix permission bits
+	pub mode :u32,
+	/// The modification time, in seconds since the unix epoch
+	pub mtime :u64,
+	/// The digest of the content
+	pub digest :Digest,
+	/// Whether the entry is a directory
+	pub is_dir :bool,
+}
+
+impl FileMetadata {
+	/// Obtains the metadata from the header of the file
+	///
+	/// The path is passed separately, as for long paths,
+	/// the header only contains a truncated version.
+	pub(crate) fn from_header(hdr :&Header, path :String, digest :Digest) -> Option<Self> {
+		Some(FileMetadata {
+			path,
+			size : hdr.size().ok()?,
+			mode : hdr.mode().ok()?,
+			mtime : hdr.mtime().ok()?,
+			digest,
+			is_dir : hdr.entry_type().is_dir(),
+		})
+	}
 }
 
 pub struct CrateHandle<'a, S :CrateSource + 'a, C :CrateFileHandle<S>> {
-	pub source :&'a mut S,
+	pub source :&'a S,
 	pub crate_file_handle :C,
 }
 
 impl<'a, S :CrateSource + 'a, C :CrateFileHandle<S>> CrateHandle<'a, S, C> {
-	pub fn get_file_list(&mut self) -> Vec<String> {
-		self.crate_file_handle.get_file_list(&mut self.source)
+	pub fn get_file_list(&self) -> Result<Vec<String>, CrateError> {
+		self.crate_file_handle.get_file_list(self.source)
+	}
+	pub fn get_file(&self, path :&str) -> Result<Vec<u8>, CrateError> {
+		self.crate_file_handle.get_file(self.source, path)
 	}
-	pub fn get_file(&mut self, path :&str) -> Option<Vec<u8>> {
-		self.crate_file_handle.get_file(&mut self.source, path)
+	pub fn get_file_metadata_list(&self) -> Result<Vec<FileMetadata>, CrateError> {
+		self.crate_file_handle.get_file_metadata_list(self.source)
+	}
+	pub fn get_file_reader<'b>(&'b self, path :&str) -> Result<Box<dyn Read + 'b>, CrateError> {
+		self.crate_file_handle.get_file_reader(self.source, path)
+	}
+	pub fn has_file(&self, path :&str) -> Result<bool, CrateError> {
+		self.crate_file_handle.has_file(self.source, path)
+	}
+	pub fn map_all_files<F :FnMut(Option<String>, Option<Vec<u8>>)>(&self,
+			f :F) -> Result<(), CrateError> {
+		self.crate_file_handle.map_all_files(self.source, f)
 	}
 }
 
 pub trait CrateFileHandle<S :CrateSource> {
-	fn get_file_list(&self, source :&mut S) -> Vec<String>;
-	fn get_file(&self, source :&mut S, path :&str) -> Option<Vec<u8>>;
+	/// Returns the normalized paths of the entries of the crate, in archive order
+	///
+	/// The paths of directories end with a `/`. Entries that only hold
+	/// the long names of other entries are resolved and not listed.
+	fn get_file_list(&self, source :&S) -> Result<Vec<String>, CrateError>;
+	fn get_file(&self, source :&S, path :&str) -> Result<Vec<u8>, CrateError>;
+	/// Returns the metadata of all files of the crate, in archive order
+	fn get_file_metadata_list(&self, source :&S) -> Result<Vec<FileMetadata>, CrateError>;
+	/// Returns a reader for the content of the file at the given path
+	///
+	/// The default implementation obtains the whole file via `get_file` first.
+	fn get_file_reader<'a>(&'a self, source :&'a S,
+			path :&str) -> Result<Box<dyn Read + 'a>, CrateError> {
+		let content = try!(self.get_file(source, path));
+		Ok(Box::new(Cursor::new(content)))
+	}
+	/// Returns whether the crate has a file with the given path, without reading it
+	///
+	/// The default implementation looks for the path in `get_file_list`.
+	fn has_file(&self, source :&S, path :&str) -> Result<bool, CrateError> {
+		Ok(try!(self.get_file_list(source)).iter().any(|p| p == path))
+	}
+	/// Calls `f` with the path and the content of each file of the crate
+	///
+	/// Fails if the file list can't be obtained, e.g. if the files
+	/// are read from an archive that exceeds the extraction limits.
+	fn map_all_files<F :FnMut(Option<String>, Option<Vec<u8>>)>(&self,
+			source :&S, mut f :F) -> Result<(), CrateError> where Self :Sized {
+		for path in try!(self.get_file_list(source)) {
+			let content = self.get_file(source, &path).ok();
+			f(Some(path), content);
+		}
+		Ok(())
+	}
 }
 
 impl<S :CrateSource> CrateFileHandle<S> for Box<dyn CrateFileHandle<S>> {
-	fn get_file_list(&self, source :&mut S) -> Vec<String> {
+	fn get_file_list(&self, source :&S) -> Result<Vec<String>, CrateError> {
 		<Box<_> as Deref>::deref(self).get_file_list(source)
 	}
-	fn get_file(&self, source :&mut S, path :&str) -> Option<Vec<u8>> {
+	fn get_file(&self, source :&S, path :&str) -> Result<Vec<u8>, CrateError> {
 		<Box<_> as Deref>::deref(self).get_file(source, path)
 	}
+	fn get_file_metadata_list(&self, source :&S) -> Result<Vec<FileMetadata>, CrateError> {
+		<Box<_> as Deref>::deref(self).get_file_metadata_list(source)
+	}
+	fn get_file_reader<'a>(&'a self, source :&'a S,
+			path :&str) -> Result<Box<dyn Read + 'a>, CrateError> {
+		<Box<_> as Deref>::deref(self).get_file_reader(source, path)
+	}
+	fn has_file(&self, source :&S, path :&str) -> Result<bool, CrateError> {
+		<Box<_> as Deref>::deref(self).has_file(source, path)
+	}
 }
 
 pub trait CrateSource :Sized {
 	type CrateHandle :CrateFileHandle<Self>;
-	fn get_crate_handle_nv(&mut self,
-			name :String, version :Version) -> Option<CrateHandle<Self, Self::CrateHandle>>;
+	fn get_crate_handle_nv(&self,
+			name :String, version :Version) -> Result<CrateHandle<'_, Self, Self::CrateHandle>, CrateError>;
 	// TODO maybe use CrateSpec here?
-	fn get_crate_nv(&mut self, name :String, version :Version) -> Option<Vec<u8>> {
+	fn get_crate_nv(&self, name :String, version :Version) -> Result<Vec<u8>, CrateError> {
 		self.get_crate(&CrateSpec {
 			name,
 			version,
 		})
 	}
-	fn get_crate(&mut self, spec :&CrateSpec) -> Option<Vec<u8>>;
+	fn get_crate(&self, spec :&CrateSpec) -> Result<Vec<u8>, CrateError>;
+	/// Returns a reader for the .crate file
+	///
+	/// The default implementation obtains the whole file via `get_crate` first.
+	fn get_crate_reader<'a>(&'a self, spec :&CrateSpec) -> Result<Box<dyn Read + 'a>, CrateError> {
+		let content = try!(self.get_crate(spec));
+		Ok(Box::new(Cursor::new(content)))
+	}
+	/// Returns whether the .crate file of the crate can be obtained, without reading it
+	///
+	/// The default implementation opens a reader via `get_crate_reader`.
+	fn has_crate(&self, spec :&CrateSpec) -> Result<bool, CrateError> {
+		match self.get_crate_reader(spec) {
+			Ok(_) => Ok(true),
+			Err(ref e) if *e.get_context() == CrateErrorKind::NotFound => Ok(false),
+			Err(e) => Err(e),
+		}
+	}
+	/// Returns the versions of the given crate that are present, in ascending order
+	fn get_crate_versions(&self, name :&str) -> Result<Vec<Version>, CrateError>;
+	/// Returns all crates that are present, sorted by name and version
+	fn get_crate_list(&self) -> Result<Vec<CrateSpec>, CrateError>;
+}
+
+/// A crate source that `OverlayCrateSource` can repair crates of
+pub trait RepairableCrateSource :CrateSource {
+	/// Re-stores a crate from an intact copy of its .crate file
+	///
+	/// Returns whether anything has been repaired.
+	/// Sources that can't be repaired return false.
+	fn repair_crate(&mut self, _spec :&CrateSpec, _crate_file :&[u8]) -> Result<bool, CrateError> {
+		Ok(false)
+	}
 }
 
 pub trait CrateStorage {
 	fn store_parallel_iter<I :Iterator<Item = (CrateSpec, Vec<u8>, Digest)>>(
-			&mut self, thread_count :u16, crate_iter :I);
+			&mut self, thread_count :u16, crate_iter :I) -> Result<(), CrateError>;
+
+	/// Returns the file names of the crates present in the storage
+	///
+	/// Those crates are skipped by `fill_crate_storage_from_source`,
+	/// so that an interrupted run can be resumed.
+	fn stored_crate_names(&self) -> Result<HashSet<String>, CrateError> {
+		Ok(HashSet::new())
+	}
 
+	/// Stores the crates of the index that the source has
+	///
+	/// Crates the source can't provide are skipped.
 	fn fill_crate_storage_from_source<S :CrateSource>(&mut self,
-			thread_count :u16, acj :&AllCratesJson, source :&mut S,
-			progress_callback :fn(&str, &CrateIndexJson)) {
-		// Iterators are cool they told me.
-		// Iterators are idiomatic they told me.
-		// THEN WHY THE FUCK DO I NEED THIS REFCELL CRAP?!?!?!
-		// https://stackoverflow.com/a/28521985
-		let source_cell = RefCell::new(source);
+			thread_count :u16, acj :&AllCratesJson, source :&S,
+			progress_callback :fn(&str, &CrateIndexJson)) -> Result<(), CrateError> {
+		let stored = try!(self.stored_crate_names());
 		let crate_iter = acj.iter()
 			.flat_map(|&(ref name, ref versions)| {
 				let name = name.clone();
-				let source_cell = &source_cell;
+				let stored = &stored;
 				versions.iter().filter_map(move |v| {
 					let name = name.clone();
-					let mut source = source_cell.borrow_mut();
-					progress_callback(&name, &v);
-
 					let spec = CrateSpec {
 						name : name.to_owned(),
 						version : v.version.clone(),
 					};
+					if stored.contains(&spec.file_name()) {
+						return None;
+					}
+					progress_callback(&name, &v);
+
 					let crate_file_buf = match source.get_crate(&spec) {
-						Some(cfb) => cfb,
-						None => return None,
+						Ok(cfb) => cfb,
+						Err(_) => return None,
 					};
 
 					let mut hctx = HashCtx::new();
@@ -101,192 +269,527 @@ pub trait CrateStorage {
 					Some((spec, crate_file_buf, d))
 				})
 			});
-		self.store_parallel_iter(thread_count, crate_iter);
+		self.store_parallel_iter(thread_count, crate_iter)
 	}
 }
 
-pub enum DynCrateSource<S :Read + Seek> {
+pub enum DynCrateSource<S :ReadAt + Seek + Write + SyncAll> {
 	FileTreeStorage(FileTreeStorage),
 	CacheStorage(CacheStorage),
-	BlobCrateStorage(BlobCrateStorage<S>),
+	BlobCrateStorage(Box<BlobCrateStorage<BlobStorage<S>>>),
+	BlobDirectory(Box<BlobCrateStorage<DirBlobStorage>>),
+	RemoteStorageFile(Box<BlobCrateStorage<BlobStorage<RemoteFile>>>),
 	OverlayCrateSource(Box<OverlayCrateSource<DynCrateSource<S>, DynCrateSource<S>>>),
+	ExtractedDirStorage(ExtractedDirStorage),
 }
 
-pub enum DynCrateHandle<S :Read + Seek> {
+pub enum DynCrateHandle<S :ReadAt + Seek + Write + SyncAll> {
 	BlobCrateHandle(BlobCrateHandle),
 	StorageFileHandle(StorageFileHandle),
 	OverlayCrateHandle(Box<OverlayCrateHandle<DynCrateSource<S>, DynCrateSource<S>>>),
+	ExtractedCrateHandle(ExtractedCrateHandle),
 }
 
-impl<S :Read + Seek> DynCrateHandle<S> {
+impl<S :ReadAt + Seek + Write + SyncAll> DynCrateHandle<S> {
 	fn blob(&self) -> Option<&BlobCrateHandle> {
-		match self {
-			&DynCrateHandle::BlobCrateHandle(ref h) => Some(h),
+		match *self {
+			DynCrateHandle::BlobCrateHandle(ref h) => Some(h),
 			_ => None,
 		}
 	}
 	fn storage(&self) -> Option<&StorageFileHandle> {
-		match self {
-			&DynCrateHandle::StorageFileHandle(ref h) => Some(h),
+		match *self {
+			DynCrateHandle::StorageFileHandle(ref h) => Some(h),
 			_ => None,
 		}
 	}
 	fn overlay(&self) -> Option<&OverlayCrateHandle<DynCrateSource<S>, DynCrateSource<S>>> {
-		match self {
-			&DynCrateHandle::OverlayCrateHandle(ref h) => Some(h),
+		match *self {
+			DynCrateHandle::OverlayCrateHandle(ref h) => Some(h),
+			_ => None,
+		}
+	}
+	fn extracted(&self) -> Option<&ExtractedCrateHandle> {
+		match *self {
+			DynCrateHandle::ExtractedCrateHandle(ref h) => Some(h),
 			_ => None,
 		}
 	}
 }
 
-impl<S :Read + Seek> CrateSource for DynCrateSource<S> {
+impl<S :ReadAt + Seek + Write + SyncAll> DynCrateSource<S> {
+	/// Sets the limits for the crate archives the source reads
+	///
+	/// Extracted directories contain no archives, so they ignore them.
+	pub fn set_extraction_limits(&mut self, limits :ExtractionLimits) {
+		match *self {
+			DynCrateSource::FileTreeStorage(ref mut s) => s.set_extraction_limits(limits),
+			DynCrateSource::CacheStorage(ref mut s) => s.set_extraction_limits(limits),
+			DynCrateSource::BlobCrateStorage(ref mut s) => s.set_extraction_limits(limits),
+			DynCrateSource::BlobDirectory(ref mut s) => s.set_extraction_limits(limits),
+			DynCrateSource::RemoteStorageFile(ref mut s) => s.set_extraction_limits(limits),
+			DynCrateSource::OverlayCrateSource(ref mut s) => {
+				s.0.get_mut().unwrap_or_else(|e| e.into_inner())
+					.set_extraction_limits(limits);
+				s.1.set_extraction_limits(limits);
+				s.set_extraction_limits(limits);
+			},
+			DynCrateSource::ExtractedDirStorage(_) => (),
+		}
+	}
+}
+
+impl<S :ReadAt + Seek + Write + SyncAll> CrateSource for DynCrateSource<S> {
 	type CrateHandle = DynCrateHandle<S>;
-	fn get_crate_handle_nv(&mut self,
-			name :String, version :Version) -> Option<CrateHandle<Self, Self::CrateHandle>> {
-		let ch = match self {
-			&mut DynCrateSource::FileTreeStorage(ref mut s) => {
+	fn get_crate_handle_nv(&self,
+			name :String, version :Version) -> Result<CrateHandle<'_, Self, Self::CrateHandle>, CrateError> {
+		let ch = match *self {
+			DynCrateSource::FileTreeStorage(ref s) => {
 				s.get_crate_handle_nv(name, version)
 					.map(|h| DynCrateHandle::BlobCrateHandle(h.crate_file_handle))
 			},
-			&mut DynCrateSource::CacheStorage(ref mut s) => {
+			DynCrateSource::CacheStorage(ref s) => {
 				s.get_crate_handle_nv(name, version)
 					.map(|h| DynCrateHandle::BlobCrateHandle(h.crate_file_handle))
 			},
-			&mut DynCrateSource::BlobCrateStorage(ref mut s) => {
+			DynCrateSource::BlobCrateStorage(ref s) => {
+				s.get_crate_handle_nv(name, version)
+					.map(|h| DynCrateHandle::StorageFileHandle(h.crate_file_handle))
+			},
+			DynCrateSource::BlobDirectory(ref s) => {
+				s.get_crate_handle_nv(name, version)
+					.map(|h| DynCrateHandle::StorageFileHandle(h.crate_file_handle))
+			},
+			DynCrateSource::RemoteStorageFile(ref s) => {
 				s.get_crate_handle_nv(name, version)
 					.map(|h| DynCrateHandle::StorageFileHandle(h.crate_file_handle))
 			},
-			&mut DynCrateSource::OverlayCrateSource(ref mut s) => {
+			DynCrateSource::OverlayCrateSource(ref s) => {
 				s.get_crate_handle_nv(name, version)
 					.map(|h| DynCrateHandle::OverlayCrateHandle(Box::new(h.crate_file_handle)))
 			},
+			DynCrateSource::ExtractedDirStorage(ref s) => {
+				s.get_crate_handle_nv(name, version)
+					.map(|h| DynCrateHandle::ExtractedCrateHandle(h.crate_file_handle))
+			},
 		};
-		if let Some(ch) = ch {
-			Some(CrateHandle {
-				source : self,
-				crate_file_handle : ch,
-			})
-		} else {
-			None
-		}
+		Ok(CrateHandle {
+			source : self,
+			crate_file_handle : try!(ch),
+		})
 	}
-	fn get_crate(&mut self, spec :&CrateSpec) -> Option<Vec<u8>> {
-		match self {
-			&mut DynCrateSource::FileTreeStorage(ref mut s) => {
+	fn get_crate(&self, spec :&CrateSpec) -> Result<Vec<u8>, CrateError> {
+		match *self {
+			DynCrateSource::FileTreeStorage(ref s) => {
+				s.get_crate(spec)
+			},
+			DynCrateSource::CacheStorage(ref s) => {
+				s.get_crate(spec)
+			},
+			DynCrateSource::BlobCrateStorage(ref s) => {
 				s.get_crate(spec)
 			},
-			&mut DynCrateSource::CacheStorage(ref mut s) => {
+			DynCrateSource::BlobDirectory(ref s) => {
 				s.get_crate(spec)
 			},
-			&mut DynCrateSource::BlobCrateStorage(ref mut s) => {
+			DynCrateSource::RemoteStorageFile(ref s) => {
 				s.get_crate(spec)
 			},
-			&mut DynCrateSource::OverlayCrateSource(ref mut s) => {
+			DynCrateSource::OverlayCrateSource(ref s) => {
 				s.get_crate(spec)
 			},
+			DynCrateSource::ExtractedDirStorage(ref s) => {
+				s.get_crate(spec)
+			},
+		}
+	}
+	fn get_crate_reader<'a>(&'a self, spec :&CrateSpec) -> Result<Box<dyn Read + 'a>, CrateError> {
+		match *self {
+			DynCrateSource::FileTreeStorage(ref s) => s.get_crate_reader(spec),
+			DynCrateSource::CacheStorage(ref s) => s.get_crate_reader(spec),
+			DynCrateSource::BlobCrateStorage(ref s) => s.get_crate_reader(spec),
+			DynCrateSource::BlobDirectory(ref s) => s.get_crate_reader(spec),
+			DynCrateSource::RemoteStorageFile(ref s) => s.get_crate_reader(spec),
+			DynCrateSource::OverlayCrateSource(ref s) => s.get_crate_reader(spec),
+			DynCrateSource::ExtractedDirStorage(ref s) => s.get_crate_reader(spec),
+		}
+	}
+	fn has_crate(&self, spec :&CrateSpec) -> Result<bool, CrateError> {
+		match *self {
+			DynCrateSource::FileTreeStorage(ref s) => s.has_crate(spec),
+			DynCrateSource::CacheStorage(ref s) => s.has_crate(spec),
+			DynCrateSource::BlobCrateStorage(ref s) => s.has_crate(spec),
+			DynCrateSource::BlobDirectory(ref s) => s.has_crate(spec),
+			DynCrateSource::RemoteStorageFile(ref s) => s.has_crate(spec),
+			DynCrateSource::OverlayCrateSource(ref s) => s.has_crate(spec),
+			DynCrateSource::ExtractedDirStorage(ref s) => s.has_crate(spec),
+		}
+	}
+	fn get_crate_versions(&self, name :&str) -> Result<Vec<Version>, CrateError> {
+		match *self {
+			DynCrateSource::FileTreeStorage(ref s) => s.get_crate_versions(name),
+			DynCrateSource::CacheStorage(ref s) => s.get_crate_versions(name),
+			DynCrateSource::BlobCrateStorage(ref s) => s.get_crate_versions(name),
+			DynCrateSource::BlobDirectory(ref s) => s.get_crate_versions(name),
+			DynCrateSource::RemoteStorageFile(ref s) => s.get_crate_versions(name),
+			DynCrateSource::OverlayCrateSource(ref s) => s.get_crate_versions(name),
+			DynCrateSource::ExtractedDirStorage(ref s) => s.get_crate_versions(name),
+		}
+	}
+	fn get_crate_list(&self) -> Result<Vec<CrateSpec>, CrateError> {
+		match *self {
+			DynCrateSource::FileTreeStorage(ref s) => s.get_crate_list(),
+			DynCrateSource::CacheStorage(ref s) => s.get_crate_list(),
+			DynCrateSource::BlobCrateStorage(ref s) => s.get_crate_list(),
+			DynCrateSource::BlobDirectory(ref s) => s.get_crate_list(),
+			DynCrateSource::RemoteStorageFile(ref s) => s.get_crate_list(),
+			DynCrateSource::OverlayCrateSource(ref s) => s.get_crate_list(),
+			DynCrateSource::ExtractedDirStorage(ref s) => s.get_crate_list(),
 		}
 	}
 }
 
-impl<S :Read + Seek> CrateFileHandle<DynCrateSource<S>> for DynCrateHandle<S> {
-	fn get_file_list(&self, source :&mut DynCrateSource<S>) -> Vec<String> {
-		match source {
-			&mut DynCrateSource::FileTreeStorage(ref mut s) => {
+impl<S :ReadAt + Seek + Write + SyncAll> RepairableCrateSource for DynCrateSource<S> {
+	fn repair_crate(&mut self, spec :&CrateSpec, crate_file :&[u8]) -> Result<bool, CrateError> {
+		match *self {
+			DynCrateSource::FileTreeStorage(ref mut s) => s.repair_crate(spec, crate_file),
+			DynCrateSource::CacheStorage(ref mut s) => s.repair_crate(spec, crate_file),
+			DynCrateSource::BlobCrateStorage(ref mut s) => s.repair_crate(spec, crate_file),
+			DynCrateSource::BlobDirectory(ref mut s) => s.repair_crate(spec, crate_file),
+			DynCrateSource::RemoteStorageFile(ref mut s) => s.repair_crate(spec, crate_file),
+			DynCrateSource::OverlayCrateSource(ref mut s) => s.repair_crate(spec, crate_file),
+			DynCrateSource::ExtractedDirStorage(ref mut s) => s.repair_crate(spec, crate_file),
+		}
+	}
+}
+
+impl<S :ReadAt + Seek + Write + SyncAll> CrateFileHandle<DynCrateSource<S>> for DynCrateHandle<S> {
+	fn get_file_list(&self, source :&DynCrateSource<S>) -> Result<Vec<String>, CrateError> {
+		match *source {
+			DynCrateSource::FileTreeStorage(ref s) => {
 				self.blob().unwrap().get_file_list(s)
 			},
-			&mut DynCrateSource::CacheStorage(ref mut s) => {
+			DynCrateSource::CacheStorage(ref s) => {
 				self.blob().unwrap().get_file_list(s)
 			},
-			&mut DynCrateSource::BlobCrateStorage(ref mut s) => {
+			DynCrateSource::BlobCrateStorage(ref s) => {
+				self.storage().unwrap().get_file_list(s)
+			},
+			DynCrateSource::BlobDirectory(ref s) => {
+				self.storage().unwrap().get_file_list(s)
+			},
+			DynCrateSource::RemoteStorageFile(ref s) => {
 				self.storage().unwrap().get_file_list(s)
 			},
-			&mut DynCrateSource::OverlayCrateSource(ref mut s) => {
+			DynCrateSource::OverlayCrateSource(ref s) => {
 				self.overlay().unwrap().get_file_list(s)
 			},
+			DynCrateSource::ExtractedDirStorage(ref s) => {
+				self.extracted().unwrap().get_file_list(s)
+			},
 		}
 	}
-	fn get_file(&self, source :&mut DynCrateSource<S>,
-			path :&str) -> Option<Vec<u8>> {
-		match source {
-			&mut DynCrateSource::FileTreeStorage(ref mut s) => {
+	fn get_file(&self, source :&DynCrateSource<S>,
+			path :&str) -> Result<Vec<u8>, CrateError> {
+		match *source {
+			DynCrateSource::FileTreeStorage(ref s) => {
 				self.blob().unwrap().get_file(s, path)
 			},
-			&mut DynCrateSource::CacheStorage(ref mut s) => {
+			DynCrateSource::CacheStorage(ref s) => {
 				self.blob().unwrap().get_file(s, path)
 			},
-			&mut DynCrateSource::BlobCrateStorage(ref mut s) => {
+			DynCrateSource::BlobCrateStorage(ref s) => {
+				self.storage().unwrap().get_file(s, path)
+			},
+			DynCrateSource::BlobDirectory(ref s) => {
+				self.storage().unwrap().get_file(s, path)
+			},
+			DynCrateSource::RemoteStorageFile(ref s) => {
 				self.storage().unwrap().get_file(s, path)
 			},
-			&mut DynCrateSource::OverlayCrateSource(ref mut s) => {
+			DynCrateSource::OverlayCrateSource(ref s) => {
 				self.overlay().unwrap().get_file(s, path)
 			},
+			DynCrateSource::ExtractedDirStorage(ref s) => {
+				self.extracted().unwrap().get_file(s, path)
+			},
+		}
+	}
+	fn get_file_metadata_list(&self, source :&DynCrateSource<S>) -> Result<Vec<FileMetadata>, CrateError> {
+		match *source {
+			DynCrateSource::FileTreeStorage(ref s) => {
+				self.blob().unwrap().get_file_metadata_list(s)
+			},
+			DynCrateSource::CacheStorage(ref s) => {
+				self.blob().unwrap().get_file_metadata_list(s)
+			},
+			DynCrateSource::BlobCrateStorage(ref s) => {
+				self.storage().unwrap().get_file_metadata_list(s)
+			},
+			DynCrateSource::BlobDirectory(ref s) => {
+				self.storage().unwrap().get_file_metadata_list(s)
+			},
+			DynCrateSource::RemoteStorageFile(ref s) => {
+				self.storage().unwrap().get_file_metadata_list(s)
+			},
+			DynCrateSource::OverlayCrateSource(ref s) => {
+				self.overlay().unwrap().get_file_metadata_list(s)
+			},
+			DynCrateSource::ExtractedDirStorage(ref s) => {
+				self.extracted().unwrap().get_file_metadata_list(s)
+			},
+		}
+	}
+	fn get_file_reader<'a>(&'a self, source :&'a DynCrateSource<S>,
+			path :&str) -> Result<Box<dyn Read + 'a>, CrateError> {
+		match *source {
+			DynCrateSource::FileTreeStorage(ref s) => {
+				self.blob().unwrap().get_file_reader(s, path)
+			},
+			DynCrateSource::CacheStorage(ref s) => {
+				self.blob().unwrap().get_file_reader(s, path)
+			},
+			DynCrateSource::BlobCrateStorage(ref s) => {
+				self.storage().unwrap().get_file_reader(&**s, path)
+			},
+			DynCrateSource::BlobDirectory(ref s) => {
+				self.storage().unwrap().get_file_reader(&**s, path)
+			},
+			DynCrateSource::RemoteStorageFile(ref s) => {
+				self.storage().unwrap().get_file_reader(&**s, path)
+			},
+			DynCrateSource::OverlayCrateSource(ref s) => {
+				self.overlay().unwrap().get_file_reader(&**s, path)
+			},
+			DynCrateSource::ExtractedDirStorage(ref s) => {
+				self.extracted().unwrap().get_file_reader(s, path)
+			},
 		}
 	}
+	fn has_file(&self, source :&DynCrateSource<S>, path :&str) -> Result<bool, CrateError> {
+		match *source {
+			DynCrateSource::FileTreeStorage(ref s) => {
+				self.blob().unwrap().has_file(s, path)
+			},
+			DynCrateSource::CacheStorage(ref s) => {
+				self.blob().unwrap().has_file(s, path)
+			},
+			DynCrateSource::BlobCrateStorage(ref s) => {
+				self.storage().unwrap().has_file(s, path)
+			},
+			DynCrateSource::BlobDirectory(ref s) => {
+				self.storage().unwrap().has_file(s, path)
+			},
+			DynCrateSource::RemoteStorageFile(ref s) => {
+				self.storage().unwrap().has_file(s, path)
+			},
+			DynCrateSource::OverlayCrateSource(ref s) => {
+				self.overlay().unwrap().has_file(s, path)
+			},
+			DynCrateSource::ExtractedDirStorage(ref s) => {
+				self.extracted().unwrap().has_file(s, path)
+			},
+		}
+	}
+	fn map_all_files<F :FnMut(Option<String>, Option<Vec<u8>>)>(&self,
+			source :&DynCrateSource<S>, mut f :F) -> Result<(), CrateError> {
+		if let Some(h) = self.blob() {
+			return h.map_all_files(f);
+		}
+		for path in try!(self.get_file_list(source)) {
+			let content = self.get_file(source, &path).ok();
+			f(Some(path), content);
+		}
+		Ok(())
+	}
 }
 
-pub struct OverlayCrateSource<S :CrateSource, T :CrateSource>(S, T);
+/// A source that falls back to a second source for crates missing in the first one
+///
+/// If the first source holds a corrupt copy of a crate,
+/// the crate is obtained from the fallback and the
+/// first source is repaired with it. For this, crates
+/// and files are read as a whole, even via the reader methods.
+/// Errors while repairing are returned, so the first source
+/// needs to be writable, e.g. a storage file opened for writing.
+///
+/// The first source is behind a lock, so that it can
+/// be repaired while the overlay is shared.
+pub struct OverlayCrateSource<S :CrateSource, T :CrateSource>(RwLock<S>, T, ExtractionLimits);
 
-impl<S :CrateSource, T :CrateSource> OverlayCrateSource<S, T> {
+impl<S :RepairableCrateSource, T :CrateSource> OverlayCrateSource<S, T> {
 	pub fn new(default :S, fallback :T) -> Self {
-		OverlayCrateSource(default, fallback)
+		OverlayCrateSource(RwLock::new(default), fallback, ExtractionLimits::default())
 	}
-	fn get_overlay_crate_handle_nv(&mut self,
-			name :String, version :Version) -> Option<OverlayCrateHandle<S, T>> {
-		if let Some(v) = self.0.get_crate_handle_nv(name.clone(), version.clone()) {
-			return Some(OverlayCrateHandle::DefaultFound(v.crate_file_handle));
+	/// Sets the limits that files are extracted from the crates of the fallback with
+	///
+	/// The limits of the two sources themselves need to be set separately.
+	pub fn set_extraction_limits(&mut self, limits :ExtractionLimits) {
+		self.2 = limits;
+	}
+	fn default_source(&self) -> RwLockReadGuard<'_, S> {
+		// A panic while repairing doesn't leave the source in an invalid state
+		self.0.read().unwrap_or_else(|e| e.into_inner())
+	}
+	fn get_overlay_crate_handle_nv(&self,
+			name :String, version :Version) -> Result<OverlayCrateHandle<S, T>, CrateError> {
+		let spec = CrateSpec {
+			name : name.clone(),
+			version : version.clone(),
+		};
+		let e = match self.default_source().get_crate_handle_nv(name.clone(), version.clone()) {
+			Ok(v) => return Ok(OverlayCrateHandle::DefaultFound(v.crate_file_handle, spec)),
+			Err(e) => e,
+		};
+		if e.get_context().is_corruption() {
+			let repaired = match self.repair_from_fallback(&spec) {
+				Ok((_, repaired)) => repaired,
+				Err(fallback_e) => return Err(overlay_error(e, fallback_e)),
+			};
+			if repaired {
+				return match self.default_source().get_crate_handle_nv(name, version) {
+					Ok(v) => Ok(OverlayCrateHandle::DefaultFound(v.crate_file_handle, spec)),
+					Err(e) => Err(e),
+				};
+			}
 		}
-		if let Some(v) = self.1.get_crate_handle_nv(name, version) {
-			return Some(OverlayCrateHandle::FallbackFound(v.crate_file_handle));
+		match self.1.get_crate_handle_nv(name, version) {
+			Ok(v) => Ok(OverlayCrateHandle::FallbackFound(v.crate_file_handle)),
+			Err(fallback_e) => Err(overlay_error(e, fallback_e)),
 		}
-		return None;
+	}
+	/// Obtains the crate from the fallback and repairs the default source with it
+	///
+	/// Returns the crate and whether the default source has been repaired.
+	fn repair_from_fallback(&self, spec :&CrateSpec) -> Result<(Vec<u8>, bool), CrateError> {
+		let crate_file = try!(self.1.get_crate(spec));
+		let repaired = try!(self.0.write().unwrap_or_else(|e| e.into_inner())
+			.repair_crate(spec, &crate_file));
+		Ok((crate_file, repaired))
+	}
+}
+
+/// Picks the error to report if neither source of an overlay succeeded
+///
+/// A damaged crate in the default source is more
+/// interesting than the crate missing in the fallback.
+fn overlay_error(default_e :CrateError, fallback_e :CrateError) -> CrateError {
+	if *fallback_e.get_context() == CrateErrorKind::NotFound {
+		default_e
+	} else {
+		fallback_e
 	}
 }
 
-impl<S :CrateSource, T :CrateSource> CrateSource for OverlayCrateSource<S, T> {
+impl<S :RepairableCrateSource, T :CrateSource> CrateSource for OverlayCrateSource<S, T> {
 	type CrateHandle = OverlayCrateHandle<S, T>;
-	fn get_crate_handle_nv(&mut self,
-			name :String, version :Version) -> Option<CrateHandle<Self, Self::CrateHandle>> {
-		if let Some(ch) = self.get_overlay_crate_handle_nv(name, version) {
-			Some(CrateHandle {
-				source : self,
-				crate_file_handle : ch,
-			})
+	fn get_crate_handle_nv(&self,
+			name :String, version :Version) -> Result<CrateHandle<'_, Self, Self::CrateHandle>, CrateError> {
+		let ch = try!(self.get_overlay_crate_handle_nv(name, version));
+		Ok(CrateHandle {
+			source : self,
+			crate_file_handle : ch,
+		})
+	}
+	fn get_crate(&self, spec :&CrateSpec) -> Result<Vec<u8>, CrateError> {
+		let e = match self.default_source().get_crate(spec) {
+			Ok(v) => return Ok(v),
+			Err(e) => e,
+		};
+		let res = if e.get_context().is_corruption() {
+			self.repair_from_fallback(spec).map(|(crate_file, _)| crate_file)
 		} else {
-			None
-		}
+			self.1.get_crate(spec)
+		};
+		res.map_err(|fallback_e| overlay_error(e, fallback_e))
 	}
-	fn get_crate(&mut self, spec :&CrateSpec) -> Option<Vec<u8>> {
-		if let Some(v) = self.0.get_crate(spec) {
-			return Some(v);
+	fn has_crate(&self, spec :&CrateSpec) -> Result<bool, CrateError> {
+		let e = match self.default_source().has_crate(spec) {
+			Ok(true) => return Ok(true),
+			Ok(false) => return self.1.has_crate(spec),
+			Err(e) => e,
+		};
+		match self.1.has_crate(spec) {
+			Ok(true) => Ok(true),
+			Ok(false) => Err(e),
+			Err(fallback_e) => Err(overlay_error(e, fallback_e)),
 		}
-		return self.1.get_crate(spec);
+	}
+	fn get_crate_versions(&self, name :&str) -> Result<Vec<Version>, CrateError> {
+		let mut versions = try!(self.default_source().get_crate_versions(name));
+		versions.extend(try!(self.1.get_crate_versions(name)));
+		versions.sort();
+		versions.dedup();
+		Ok(versions)
+	}
+	fn get_crate_list(&self) -> Result<Vec<CrateSpec>, CrateError> {
+		let mut specs = try!(self.default_source().get_crate_list());
+		specs.extend(try!(self.1.get_crate_list()));
+		sort_specs(&mut specs);
+		Ok(specs)
+	}
+}
+
+impl<S :RepairableCrateSource, T :CrateSource> RepairableCrateSource for OverlayCrateSource<S, T> {
+	fn repair_crate(&mut self, spec :&CrateSpec, crate_file :&[u8]) -> Result<bool, CrateError> {
+		self.0.get_mut().unwrap_or_else(|e| e.into_inner())
+			.repair_crate(spec, crate_file)
 	}
 }
 
 pub enum OverlayCrateHandle<D :CrateSource, F :CrateSource> {
-	DefaultFound(D::CrateHandle),
+	DefaultFound(D::CrateHandle, CrateSpec),
 	FallbackFound(F::CrateHandle),
 }
 
-impl<S :CrateSource, T: CrateSource> CrateFileHandle<OverlayCrateSource<S, T>> for OverlayCrateHandle<S, T> {
-	fn get_file_list(&self, source :&mut OverlayCrateSource<S, T>) -> Vec<String> {
-		match self {
-			&OverlayCrateHandle::DefaultFound(ref s) => {
-				s.get_file_list(&mut source.0)
+impl<S :RepairableCrateSource, T: CrateSource> CrateFileHandle<OverlayCrateSource<S, T>> for OverlayCrateHandle<S, T> {
+	fn get_file_list(&self, source :&OverlayCrateSource<S, T>) -> Result<Vec<String>, CrateError> {
+		match *self {
+			OverlayCrateHandle::DefaultFound(ref s, _) => {
+				s.get_file_list(&source.default_source())
 			},
-			&OverlayCrateHandle::FallbackFound(ref s) => {
-				s.get_file_list(&mut source.1)
+			OverlayCrateHandle::FallbackFound(ref s) => {
+				s.get_file_list(&source.1)
 			},
 		}
 	}
-	fn get_file(&self, source :&mut OverlayCrateSource<S, T>,
-			path :&str) -> Option<Vec<u8>> {
-		match self {
-			&OverlayCrateHandle::DefaultFound(ref s) => {
-				s.get_file(&mut source.0, path)
+	fn get_file(&self, source :&OverlayCrateSource<S, T>,
+			path :&str) -> Result<Vec<u8>, CrateError> {
+		match *self {
+			OverlayCrateHandle::DefaultFound(ref s, ref spec) => {
+				let e = match s.get_file(&source.default_source(), path) {
+					Ok(content) => return Ok(content),
+					Err(e) => e,
+				};
+				if !e.get_context().is_corruption() {
+					return Err(e);
+				}
+				let (crate_file, _) = try!(source.repair_from_fallback(spec)
+					.map_err(|fallback_e| overlay_error(e, fallback_e)));
+				extract_file(crate_file.as_slice(), path, &source.2)
 			},
-			&OverlayCrateHandle::FallbackFound(ref s) => {
-				s.get_file(&mut source.1, path)
+			OverlayCrateHandle::FallbackFound(ref s) => {
+				s.get_file(&source.1, path)
+			},
+		}
+	}
+	fn get_file_metadata_list(&self, source :&OverlayCrateSource<S, T>) -> Result<Vec<FileMetadata>, CrateError> {
+		match *self {
+			OverlayCrateHandle::DefaultFound(ref s, _) => {
+				s.get_file_metadata_list(&source.default_source())
+			},
+			OverlayCrateHandle::FallbackFound(ref s) => {
+				s.get_file_metadata_list(&source.1)
+			},
+		}
+	}
+	fn has_file(&self, source :&OverlayCrateSource<S, T>, path :&str) -> Result<bool, CrateError> {
+		match *self {
+			OverlayCrateHandle::DefaultFound(ref s, _) => {
+				s.has_file(&source.default_source(), path)
+			},
+			OverlayCrateHandle::FallbackFound(ref s) => {
+				s.has_file(&source.1, path)
 			},
 		}
 	}
@@ -294,170 +797,878 @@ impl<S :CrateSource, T: CrateSource> CrateFileHandle<OverlayCrateSource<S, T>> f
 
 pub struct FileTreeStorage {
 	storage_base :PathBuf,
+	index_dir :Option<PathBuf>,
+	limits :ExtractionLimits,
 }
 
 impl FileTreeStorage {
 	pub fn new(storage_base :&Path) -> Self {
 		FileTreeStorage {
 			storage_base : storage_base.to_path_buf(),
+			index_dir : None,
+			limits : ExtractionLimits::default(),
 		}
 	}
+	/// Sets the directory to cache the random access indices in
+	///
+	/// Without it, no indices are used, and files are found
+	/// by inflating the .crate file from its start.
+	pub fn set_index_dir(&mut self, index_dir :Option<&Path>) {
+		self.index_dir = index_dir.map(|d| d.to_path_buf());
+	}
+	/// Sets the limits that the .crate files are read with
+	pub fn set_extraction_limits(&mut self, limits :ExtractionLimits) {
+		self.limits = limits;
+	}
+	fn crate_file_path(&self, spec :&CrateSpec) -> PathBuf {
+		self.storage_base
+			.join(obtain_crate_name_path(&spec.name))
+			.join(spec.file_name())
+	}
 }
 
 
+/// Counter to give temporary files unique names
+static TMP_COUNTER :AtomicUsize = AtomicUsize::new(0);
+
+fn write_index_file(index :&GzIndex, path :&Path) -> io::Result<()> {
+	if let Some(dir) = path.parent() {
+		try!(fs::create_dir_all(dir));
+	}
+	let tmp_path = path.with_extension(format!("tmp{}-{}",
+		std::process::id(), TMP_COUNTER.fetch_add(1, Ordering::Relaxed)));
+	{
+		let mut wtr = BufWriter::new(try!(File::create(&tmp_path)));
+		try!(index.serialize(&mut wtr));
+		try!(wtr.flush());
+	}
+	fs::rename(&tmp_path, path)
+}
+
+/// Handle for a crate in the form of its .crate file
+///
+/// To access single files without inflating the archive from the start,
+/// a `GzIndex` is built when a file is accessed first, if
+/// there is a place to cache it. Without one, building the index
+/// on each access would cost more than inflating up to the file.
 pub struct BlobCrateHandle {
 	content :Vec<u8>,
+	index :RefCell<Option<Rc<GzIndex>>>,
+	/// Where the index is cached on disk
+	index_path :Option<PathBuf>,
+	limits :ExtractionLimits,
 }
 
 impl BlobCrateHandle {
 	pub fn new(content :Vec<u8>) -> Self {
 		BlobCrateHandle {
-			content
+			content,
+			index : RefCell::new(None),
+			index_path : None,
+			limits : ExtractionLimits::default(),
+		}
+	}
+	/// Sets the limits that the archive is read with
+	///
+	/// Archives exceeding them are treated as invalid.
+	pub fn set_extraction_limits(&mut self, limits :ExtractionLimits) {
+		self.limits = limits;
+	}
+	/// Creates a handle that caches its index at the given path
+	pub fn with_index_path(content :Vec<u8>, index_path :PathBuf) -> Self {
+		BlobCrateHandle {
+			index_path : Some(index_path),
+			.. BlobCrateHandle::new(content)
 		}
 	}
-	pub fn map_all_files<F :FnMut(Option<String>, Option<Vec<u8>>)>(&self, mut f :F) {
+	/// Obtains the index, or `None` if it can't be cached or the archive is invalid
+	fn index(&self) -> Option<Rc<GzIndex>> {
+		let path = self.index_path.as_ref()?;
+		if let Some(ref index) = *self.index.borrow() {
+			return Some(index.clone());
+		}
+		let index = Rc::new(self.load_or_build_index(path)?);
+		*self.index.borrow_mut() = Some(index.clone());
+		Some(index)
+	}
+	fn load_or_build_index(&self, path :&Path) -> Option<GzIndex> {
+		let cached = File::open(path).ok()
+			.and_then(|f| GzIndex::deserialize(BufReader::new(f)).ok())
+			.filter(|index| index.matches(Cursor::new(&self.content)).unwrap_or(false));
+		if cached.is_some() {
+			return cached;
+		}
+		let index = GzIndex::build(Cursor::new(&self.content), &self.limits).ok()?;
+		// Caching the index is best effort
+		let _ = write_index_file(&index, path);
+		Some(index)
+	}
+	/// Calls `f` with the path and the content of each file of the crate
+	///
+	/// Stops at the first entry that violates the limits.
+	/// Entries with unsafe paths are passed without a path.
+	pub fn map_all_files<F :FnMut(Option<String>, Option<Vec<u8>>)>(&self,
+			f :F) -> Result<(), CrateError> {
+		self.map_all_files_inner(f).map_err(error::from_extraction)
+	}
+	fn map_all_files_inner<F :FnMut(Option<String>, Option<Vec<u8>>)>(&self,
+			mut f :F) -> Result<(), ExtractionError> {
 		let r = self.content.as_slice();
-		let decoded = GzDecoder::new(r);
-		let mut archive = Archive::new(decoded);
-		for entry in archive.entries().unwrap() {
-			let mut entry = if let Ok(entry) = entry {
-				entry
-			} else {
-				continue;
-			};
-			let path :Option<String> = entry.path().ok()
-				.and_then(|s| if let Some(s) = s.to_str() {
-					Some(s.to_owned())
-				} else {
-					None
-				});
+		let mut archive = Archive::new(limited_gz_decoder(r, &self.limits));
+		let mut checker = EntryChecker::new(&self.limits);
+		for entry in try!(archive.entries().map_err(extraction_error)) {
+			let mut entry = try!(entry.map_err(extraction_error));
+			try!(checker.check(try!(entry.header().size().map_err(extraction_error))));
+			let is_dir = entry.header().entry_type().is_dir();
+			let path = normalize_path(&entry.path_bytes())
+				.map(|p| listed_path(p, is_dir));
 			let mut v = Vec::new();
-			let v = if entry.read_to_end(&mut v).is_ok() {
-				Some(v)
-			} else {
-				None
+			let v = match entry.read_to_end(&mut v) {
+				Ok(_) => Some(v),
+				Err(e) => match error_kind(&e) {
+					ExtractionErrorKind::InvalidArchive => None,
+					kind => return Err(kind.into()),
+				},
 			};
 			f(path, v);
 		}
+		Ok(())
+	}
+	/// Walks through the archive, without using the index
+	///
+	/// Entries with unsafe paths are left out.
+	fn list_files(&self) -> Result<Vec<FileMetadata>, ExtractionError> {
+		let decoded = limited_gz_decoder(self.content.as_slice(), &self.limits);
+		let mut archive = Archive::new(decoded);
+		let mut checker = EntryChecker::new(&self.limits);
+		let mut l = Vec::new();
+		for entry in try!(archive.entries().map_err(extraction_error)) {
+			let mut entry = try!(entry.map_err(extraction_error));
+			try!(checker.check(try!(entry.header().size().map_err(extraction_error))));
+			let mut hctx = HashCtx::new();
+			try!(io::copy(&mut entry, &mut hctx).map_err(extraction_error));
+			let digest = hctx.finish_and_get_digest();
+			let path = match normalize_path(&entry.path_bytes()) {
+				Some(p) => p,
+				None => continue,
+			};
+			if let Some(m) = FileMetadata::from_header(entry.header(), path, digest) {
+				l.push(m);
+			}
+		}
+		Ok(l)
 	}
 }
 
 impl<S :CrateSource> CrateFileHandle<S> for BlobCrateHandle {
-	fn get_file_list(&self, _source :&mut S) -> Vec<String> {
-		let f = self.content.as_slice();
-		let mut l = Vec::new();
-		let decoded = GzDecoder::new(f);
-		let mut archive = Archive::new(decoded);
-		for entry in archive.entries().unwrap() {
-			let entry = entry.unwrap();
-			let path = entry.path().unwrap();
-			let s :String = path.to_str().unwrap().to_owned();
-			l.push(s);
+	fn get_file_list(&self, _source :&S) -> Result<Vec<String>, CrateError> {
+		if let Some(index) = self.index() {
+			return Ok(index.file_metadata()
+				.map(|m| listed_path(m.path.clone(), m.is_dir))
+				.collect());
+		}
+		let files = try!(self.list_files().map_err(error::from_extraction));
+		Ok(files.into_iter()
+			.map(|m| listed_path(m.path, m.is_dir))
+			.collect())
+	}
+	fn get_file(&self, _ :&S, path :&str) -> Result<Vec<u8>, CrateError> {
+		if let Some(index) = self.index() {
+			let content = try!(index.read_file(Cursor::new(&self.content), path)
+				.map_err(error::from_io));
+			return content.ok_or_else(|| CrateErrorKind::NotFound.into());
+		}
+		extract_file(self.content.as_slice(), path, &self.limits)
+	}
+	fn get_file_reader<'a>(&'a self, _ :&'a S,
+			path :&str) -> Result<Box<dyn Read + 'a>, CrateError> {
+		if let Some(index) = self.index() {
+			let rdr = try!(index.file_reader(Cursor::new(self.content.as_slice()), path)
+				.map_err(error::from_io));
+			return match rdr {
+				Some(rdr) => Ok(Box::new(rdr)),
+				None => Err(CrateErrorKind::NotFound.into()),
+			};
+		}
+		let rdr = try!(find_path_in_gz(self.content.as_slice(), path, &self.limits)
+			.map_err(error::from_extraction));
+		match rdr {
+			Some(rdr) => Ok(Box::new(rdr)),
+			None => Err(CrateErrorKind::NotFound.into()),
+		}
+	}
+	fn get_file_metadata_list(&self, _ :&S) -> Result<Vec<FileMetadata>, CrateError> {
+		if let Some(index) = self.index() {
+			return Ok(index.file_metadata().cloned().collect());
+		}
+		self.list_files().map_err(error::from_extraction)
+	}
+	fn has_file(&self, _ :&S, path :&str) -> Result<bool, CrateError> {
+		if let Some(index) = self.index() {
+			return Ok(index.file_metadata().any(|m| m.path == path));
 		}
-		l
+		let rdr = try!(find_path_in_gz(self.content.as_slice(), path, &self.limits)
+			.map_err(error::from_extraction));
+		Ok(rdr.is_some())
 	}
-	fn get_file(&self, _ :&mut S, path :&str) -> Option<Vec<u8>> {
-		extract_path_from_gz(self.content.as_slice(), path)
+	fn map_all_files<F :FnMut(Option<String>, Option<Vec<u8>>)>(&self,
+			_ :&S, f :F) -> Result<(), CrateError> {
+		BlobCrateHandle::map_all_files(self, f)
 	}
 }
 
-macro_rules! otry {
-	($v:expr) => {{
-		if let Some(v) = $v.ok() {
-			v
-		} else {
-			return None;
+/// Walks through the gzipped tar archive until the entry with the given path
+///
+/// Returns a reader for the content of the entry, which decompresses
+/// it while it is being read. The paths of the entries are resolved
+/// with a `PathResolver` and normalized before comparing them.
+fn find_path_in_gz<T :Read>(r :T, path_ex :&str, limits :&ExtractionLimits)
+		-> Result<Option<io::Take<SizeLimitedReader<GzDecoder<T>>>>, ExtractionError> {
+	let mut decoded = limited_gz_decoder(r, limits);
+	let mut checker = EntryChecker::new(limits);
+	let mut resolver = PathResolver::default();
+	loop {
+		let mut hdr = Header::new_old();
+		try!(decoded.read_exact(hdr.as_mut_bytes()).map_err(extraction_error));
+		if hdr.as_bytes().iter().all(|b| *b == 0) {
+			// End of the archive
+			return Ok(None);
+		}
+		let size = try!(hdr.entry_size().map_err(extraction_error));
+		try!(checker.check(size));
+		let padded_size = size.div_ceil(512) * 512;
+		if PathResolver::sets_name(&hdr) {
+			let mut content = Vec::new();
+			try!((&mut decoded).take(padded_size).read_to_end(&mut content)
+				.map_err(extraction_error));
+			content.truncate(size as usize);
+			let resolved = resolver.resolve(&hdr, || Some(content));
+			try!(resolved.ok_or(ExtractionErrorKind::InvalidArchive));
+			continue;
 		}
-	}};
+		let path = try!(resolver.resolve(&hdr, || None)
+			.ok_or(ExtractionErrorKind::InvalidArchive));
+		if let Some(path) = path {
+			if normalize_path(&path).as_deref() == Some(path_ex) {
+				return Ok(Some(decoded.take(size)));
+			}
+		}
+		try!(io::copy(&mut (&mut decoded).take(padded_size), &mut io::sink())
+			.map_err(extraction_error));
+	}
 }
 
-fn extract_path_from_gz<T :Read>(r :T,
-		path_ex :&str) -> Option<Vec<u8>> {
-	let decoded = GzDecoder::new(r);
-	let mut archive = Archive::new(decoded);
-	for entry in otry!(archive.entries()) {
-		let mut entry = otry!(entry);
-		let is_path_ex = if let Some(path) = otry!(entry.path()).to_str() {
-			path_ex == path
-		} else {
-			false
-		};
-		if is_path_ex {
-			// Extract the file
-			let mut v = Vec::new();
-			otry!(entry.read_to_end(&mut v));
-			return Some(v);
+/// Obtains the content of the file with the given path from the gzipped tar archive
+fn extract_file<T :Read>(r :T, path_ex :&str,
+		limits :&ExtractionLimits) -> Result<Vec<u8>, CrateError> {
+	let rdr = try!(find_path_in_gz(r, path_ex, limits).map_err(error::from_extraction));
+	let mut rdr = try!(rdr.ok_or(CrateErrorKind::NotFound));
+	let mut v = Vec::new();
+	try!(rdr.read_to_end(&mut v)
+		.map_err(|e| error::from_extraction(extraction_error(e))));
+	Ok(v)
+}
+
+
+/// Adds the crates of the .crate files inside the directory to `specs`
+///
+/// A missing directory is treated as empty.
+fn crate_files_in_dir(dir :&Path, recursive :bool,
+		specs :&mut Vec<CrateSpec>) -> Result<(), CrateError> {
+	let entries = match fs::read_dir(dir) {
+		Ok(e) => e,
+		Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+		Err(e) => return Err(error::from_io(e)),
+	};
+	for entry in entries {
+		let entry = try!(entry.map_err(error::from_io));
+		let path = entry.path();
+		if path.is_dir() {
+			if recursive {
+				try!(crate_files_in_dir(&path, true, specs));
+			}
+			continue;
+		}
+		let spec = entry.file_name().to_str()
+			.and_then(CrateSpec::from_file_name);
+		if let Some(spec) = spec {
+			specs.push(spec);
 		}
 	}
-	return None;
+	Ok(())
 }
 
+fn read_crate_file(path :&Path) -> Result<Vec<u8>, CrateError> {
+	let mut f = try!(File::open(path).map_err(error::from_io));
+	let mut file_buf = Vec::new();
+	try!(f.read_to_end(&mut file_buf).map_err(error::from_io));
+	Ok(file_buf)
+}
+
+/// Creates the handle for a .crate file of a `FileTreeStorage` or `CacheStorage`
+fn archive_handle(index_dir :&Option<PathBuf>, limits :&ExtractionLimits,
+		spec :&CrateSpec, content :Vec<u8>) -> BlobCrateHandle {
+	let mut handle = match *index_dir {
+		Some(ref dir) => {
+			let index_path = dir.join(format!("{}.gzidx", spec.file_name()));
+			BlobCrateHandle::with_index_path(content, index_path)
+		},
+		None => BlobCrateHandle::new(content),
+	};
+	handle.set_extraction_limits(*limits);
+	handle
+}
 
 impl CrateSource for FileTreeStorage {
 	type CrateHandle = BlobCrateHandle;
-	fn get_crate_handle_nv(&mut self,
-			name :String, version :Version) -> Option<CrateHandle<Self, Self::CrateHandle>> {
-		if let Some(content) = self.get_crate_nv(name, version) {
-			Some(CrateHandle {
-				source : self,
-				crate_file_handle : BlobCrateHandle::new(content),
-			})
-		} else {
-			None
-		}
-	}
-	fn get_crate(&mut self, spec :&CrateSpec) -> Option<Vec<u8>> {
-		let crate_file_path = self.storage_base
-			.join(obtain_crate_name_path(&spec.name))
-			.join(spec.file_name());
-		let mut f = match File::open(&crate_file_path) {
-			Ok(f) => f,
-			Err(_) => {
-				return None;
-			},
+	fn get_crate_handle_nv(&self,
+			name :String, version :Version) -> Result<CrateHandle<'_, Self, Self::CrateHandle>, CrateError> {
+		let spec = CrateSpec {
+			name,
+			version,
 		};
-		let mut file_buf = Vec::new();
-		io::copy(&mut f, &mut file_buf).unwrap();
-		Some(file_buf)
+		let content = try!(self.get_crate(&spec));
+		let handle = archive_handle(&self.index_dir, &self.limits, &spec, content);
+		Ok(CrateHandle {
+			source : self,
+			crate_file_handle : handle,
+		})
+	}
+	fn get_crate(&self, spec :&CrateSpec) -> Result<Vec<u8>, CrateError> {
+		read_crate_file(&self.crate_file_path(spec))
+	}
+	fn get_crate_reader<'a>(&'a self, spec :&CrateSpec) -> Result<Box<dyn Read + 'a>, CrateError> {
+		let f = try!(File::open(self.crate_file_path(spec)).map_err(error::from_io));
+		Ok(Box::new(f))
+	}
+	fn has_crate(&self, spec :&CrateSpec) -> Result<bool, CrateError> {
+		Ok(self.crate_file_path(spec).is_file())
+	}
+	fn get_crate_versions(&self, name :&str) -> Result<Vec<Version>, CrateError> {
+		let dir = self.storage_base.join(obtain_crate_name_path(name));
+		let mut specs = Vec::new();
+		try!(crate_files_in_dir(&dir, false, &mut specs));
+		let mut versions = specs.into_iter()
+			.filter(|s| s.name == name)
+			.map(|s| s.version)
+			.collect::<Vec<_>>();
+		versions.sort();
+		Ok(versions)
+	}
+	fn get_crate_list(&self) -> Result<Vec<CrateSpec>, CrateError> {
+		let mut specs = Vec::new();
+		try!(crate_files_in_dir(&self.storage_base, true, &mut specs));
+		sort_specs(&mut specs);
+		Ok(specs)
 	}
 }
 
+impl RepairableCrateSource for FileTreeStorage {}
+
 pub struct CacheStorage {
 	storage_base :PathBuf,
+	index_dir :Option<PathBuf>,
+	limits :ExtractionLimits,
 }
 
 impl CacheStorage {
 	pub fn new(storage_base :&Path) -> Self {
 		CacheStorage {
 			storage_base : storage_base.to_path_buf(),
+			index_dir : None,
+			limits : ExtractionLimits::default(),
 		}
 	}
+	/// Sets the directory to cache the random access indices in
+	///
+	/// Without it, no indices are used, and files are found
+	/// by inflating the .crate file from its start.
+	pub fn set_index_dir(&mut self, index_dir :Option<&Path>) {
+		self.index_dir = index_dir.map(|d| d.to_path_buf());
+	}
+	/// Sets the limits that the .crate files are read with
+	pub fn set_extraction_limits(&mut self, limits :ExtractionLimits) {
+		self.limits = limits;
+	}
+	fn crate_file_path(&self, spec :&CrateSpec) -> PathBuf {
+		self.storage_base.join(spec.file_name())
+	}
 }
 
 impl CrateSource for CacheStorage {
 	type CrateHandle = BlobCrateHandle;
-	fn get_crate_handle_nv(&mut self,
-			name :String, version :Version) -> Option<CrateHandle<Self, Self::CrateHandle>> {
-		if let Some(content) = self.get_crate_nv(name, version) {
-			Some(CrateHandle {
-				source : self,
-				crate_file_handle : BlobCrateHandle::new(content),
-			})
-		} else {
-			None
+	fn get_crate_handle_nv(&self,
+			name :String, version :Version) -> Result<CrateHandle<'_, Self, Self::CrateHandle>, CrateError> {
+		let spec = CrateSpec {
+			name,
+			version,
+		};
+		let content = try!(self.get_crate(&spec));
+		let handle = archive_handle(&self.index_dir, &self.limits, &spec, content);
+		Ok(CrateHandle {
+			source : self,
+			crate_file_handle : handle,
+		})
+	}
+	fn get_crate(&self, spec :&CrateSpec) -> Result<Vec<u8>, CrateError> {
+		read_crate_file(&self.crate_file_path(spec))
+	}
+	fn get_crate_reader<'a>(&'a self, spec :&CrateSpec) -> Result<Box<dyn Read + 'a>, CrateError> {
+		let f = try!(File::open(self.crate_file_path(spec)).map_err(error::from_io));
+		Ok(Box::new(f))
+	}
+	fn has_crate(&self, spec :&CrateSpec) -> Result<bool, CrateError> {
+		Ok(self.crate_file_path(spec).is_file())
+	}
+	fn get_crate_versions(&self, name :&str) -> Result<Vec<Version>, CrateError> {
+		Ok(try!(self.get_crate_list()).into_iter()
+			.filter(|s| s.name == name)
+			.map(|s| s.version)
+			.collect())
+	}
+	fn get_crate_list(&self) -> Result<Vec<CrateSpec>, CrateError> {
+		let mut specs = Vec::new();
+		try!(crate_files_in_dir(&self.storage_base, false, &mut specs));
+		sort_specs(&mut specs);
+		Ok(specs)
+	}
+}
+
+impl RepairableCrateSource for CacheStorage {}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use std::io::Cursor;
+	use hash_ctx::digest_of;
+	use codec::{Codec, BlobCompressor};
+	use test_util::{crate_archive, spec, storage_with, MemStorage};
+	use cache::CacheStats;
+	use reconstruction::{CrateContentBlobs, CrateRecMetaWithBlobs, CrateRecMetadata};
+	use std::collections::HashMap;
+	use failure::Fail;
+
+	#[test]
+	fn file_name_roundtrip() {
+		for &(name, version) in [("foo", "1.0.0"), ("foo-2d", "0.1.0-alpha.1"), ("a-b-c", "10.2.3")].iter() {
+			let spec = CrateSpec {
+				name : name.to_owned(),
+				version : Version::parse(version).unwrap(),
+			};
+			let parsed = CrateSpec::from_file_name(&spec.file_name()).unwrap();
+			assert!(parsed == spec, "{} {}", name, version);
 		}
+		assert!(CrateSpec::from_file_name("foo.crate").is_none());
+		assert!(CrateSpec::from_file_name("foo-1.0.0.tar.gz").is_none());
 	}
-	fn get_crate(&mut self, spec :&CrateSpec) -> Option<Vec<u8>> {
-		let crate_file_path = self.storage_base
-			.join(spec.file_name());
-		let mut f = match File::open(&crate_file_path) {
-			Ok(f) => f,
-			Err(_) => {
-				return None;
-			},
+
+	#[test]
+	fn list_crates_and_file_metadata() {
+		use std::env;
+		use std::process;
+
+		let lib_rs = b"pub fn foo() {}\n";
+		let foo_1 = crate_archive("foo", "1.0.0", &[("src/lib.rs", lib_rs)]);
+		let foo_2 = crate_archive("foo", "1.2.0", &[("src/lib.rs", lib_rs)]);
+		let bar = crate_archive("bar", "0.1.0", &[("src/lib.rs", b"")]);
+
+		let mut st :MemStorage = BlobCrateStorage::empty(Cursor::new(Vec::new()));
+		st.store_parallel_iter(1, vec![
+			(spec("foo", "1.0.0"), foo_1.clone(), digest_of(&foo_1)),
+			(spec("foo", "1.2.0"), foo_2.clone(), digest_of(&foo_2)),
+		].into_iter()).unwrap();
+		assert_eq!(st.get_crate_versions("foo").unwrap(), vec![
+			Version::parse("1.0.0").unwrap(), Version::parse("1.2.0").unwrap()]);
+		assert!(st.get_crate_versions("bar").unwrap().is_empty());
+
+		let dir = env::temp_dir().join(format!("crate-list-test-{}", process::id()));
+		fs::create_dir_all(&dir).unwrap();
+		fs::write(dir.join(spec("bar", "0.1.0").file_name()), &bar).unwrap();
+		fs::write(dir.join(spec("foo", "1.0.0").file_name()), &foo_1).unwrap();
+		let cache = CacheStorage::new(&dir);
+
+		let overlay = OverlayCrateSource::new(st, cache);
+		let list = overlay.get_crate_list().unwrap().into_iter()
+			.map(|s| s.file_name())
+			.collect::<Vec<_>>();
+		assert_eq!(list, ["bar-0.1.0.crate", "foo-1.0.0.crate", "foo-1.2.0.crate"]);
+
+		// The storage reads the metadata from its headers,
+		// the cache from the archive. Both need to agree.
+		let from_storage = overlay.default_source().get_crate_handle_nv("foo".to_owned(),
+			Version::parse("1.0.0").unwrap()).unwrap().get_file_metadata_list().unwrap();
+		let from_cache = overlay.1.get_crate_handle_nv("foo".to_owned(),
+			Version::parse("1.0.0").unwrap()).unwrap().get_file_metadata_list().unwrap();
+		fs::remove_dir_all(&dir).unwrap();
+		assert_eq!(from_storage, from_cache);
+		assert_eq!(from_storage, vec![FileMetadata {
+			path : "foo-1.0.0/src/lib.rs".to_owned(),
+			size : lib_rs.len() as u64,
+			mode : 0o644,
+			mtime : 0,
+			digest : digest_of(lib_rs),
+			is_dir : false,
+		}]);
+	}
+
+	#[test]
+	fn streaming_readers() {
+		let long_path = format!("src/{}.rs", "x".repeat(120));
+		let lib_rs = b"pub fn foo() {}\n";
+		let archive = crate_archive("foo", "1.0.0", &[
+			("Cargo.toml", b"[package]\nname = \"foo\"\n"),
+			(&long_path, &[7; 3000]),
+			("src/lib.rs", lib_rs),
+		]);
+		let spec = spec("foo", "1.0.0");
+		let mut st = storage_with(Codec::Gzip, &[(spec.clone(), archive.clone())]);
+		// The crate is stored as blobs, not as a whole
+		assert_eq!(st.b.get_raw_crate(&spec.file_name()).unwrap(), None);
+		let mut reconstructed = Vec::new();
+		st.get_crate_reader(&spec).unwrap().read_to_end(&mut reconstructed).unwrap();
+		assert!(reconstructed == archive);
+		assert!(st.has_crate(&spec).unwrap());
+
+		{
+			let ch = st.get_crate_handle_nv(spec.name.clone(), spec.version.clone()).unwrap();
+			let mut content = Vec::new();
+			ch.get_file_reader("foo-1.0.0/src/lib.rs").unwrap()
+				.read_to_end(&mut content).unwrap();
+			assert_eq!(content, lib_rs);
+			assert!(ch.has_file("foo-1.0.0/src/lib.rs").unwrap());
+			assert!(!ch.has_file("foo-1.0.0/src/main.rs").unwrap());
+		}
+
+		let ch = BlobCrateHandle::new(archive);
+		let mut read_file = |path :&str| {
+			let mut content = Vec::new();
+			ch.get_file_reader(&mut st, path).ok()?.read_to_end(&mut content).unwrap();
+			Some(content)
 		};
-		let mut file_buf = Vec::new();
-		io::copy(&mut f, &mut file_buf).unwrap();
-		Some(file_buf)
+		assert_eq!(read_file("foo-1.0.0/src/lib.rs"), Some(lib_rs.to_vec()));
+		assert_eq!(read_file(&format!("foo-1.0.0/{}", long_path)), Some(vec![7; 3000]));
+		assert_eq!(read_file("foo-1.0.0/src/main.rs"), None);
+	}
+
+	#[test]
+	fn pax_paths_without_index() {
+		use flate2::{Compression, GzBuilder};
+		use tar::Builder;
+
+		let long_path = format!("foo-1.0.0/src/{}.rs", "y".repeat(120));
+		let mut builder = Builder::new(Vec::new());
+		builder.append_pax_extensions([("path", long_path.as_bytes())].iter().cloned()).unwrap();
+		let mut hdr = Header::new_ustar();
+		hdr.set_size(4);
+		hdr.set_mode(0o644);
+		hdr.set_cksum();
+		builder.append_data(&mut hdr, "foo-1.0.0/short.rs", &b"pax\n"[..]).unwrap();
+		let tar = builder.into_inner().unwrap();
+		let mut archive = Vec::new();
+		io::copy(&mut GzBuilder::new().read(&tar[..], Compression::best()), &mut archive).unwrap();
+
+		let st = storage_with(Codec::Gzip, &[]);
+		let ch = BlobCrateHandle::new(archive);
+		assert_eq!(ch.get_file_list(&st).unwrap(), [long_path.clone()]);
+		assert_eq!(ch.get_file(&st, &long_path).ok(), Some(b"pax\n".to_vec()));
+		assert!(ch.has_file(&st, &long_path).unwrap());
+		assert!(!ch.has_file(&st, "foo-1.0.0/short.rs").unwrap());
+	}
+
+	#[test]
+	fn directories_in_file_lists() {
+		use std::env;
+		use std::process;
+		use flate2::{Compression, GzBuilder};
+		use tar::{Builder, EntryType};
+
+		let mut builder = Builder::new(Vec::new());
+		let mut hdr = Header::new_gnu();
+		hdr.set_entry_type(EntryType::Directory);
+		hdr.set_size(0);
+		hdr.set_mode(0o755);
+		hdr.set_cksum();
+		builder.append_data(&mut hdr, "foo-1.0.0/src/", io::empty()).unwrap();
+		let mut hdr = Header::new_gnu();
+		hdr.set_size(7);
+		hdr.set_mode(0o644);
+		hdr.set_cksum();
+		builder.append_data(&mut hdr, "foo-1.0.0/src/lib.rs", &b"mod x;\n"[..]).unwrap();
+		let tar = builder.into_inner().unwrap();
+		let mut archive = Vec::new();
+		io::copy(&mut GzBuilder::new().read(&tar[..], Compression::best()), &mut archive).unwrap();
+		let expected = ["foo-1.0.0/src/", "foo-1.0.0/src/lib.rs"];
+
+		// Directories keep their trailing slash, whatever the list comes from
+		let spec = spec("foo", "1.0.0");
+		let st = storage_with(Codec::Gzip, &[(spec.clone(), archive.clone())]);
+		let ch = st.get_crate_handle_nv(spec.name.clone(), spec.version.clone()).unwrap();
+		assert_eq!(ch.get_file_list().unwrap(), expected);
+		let meta = ch.get_file_metadata_list().unwrap();
+		assert_eq!(meta.iter().map(|m| m.is_dir).collect::<Vec<_>>(), [true, false]);
+
+		let handle = BlobCrateHandle::new(archive.clone());
+		assert_eq!(handle.get_file_list(&st).unwrap(), expected);
+		let mut paths = Vec::new();
+		handle.map_all_files(|path, _| paths.push(path.unwrap())).unwrap();
+		assert_eq!(paths, expected);
+
+		let dir = env::temp_dir().join(format!("dir-list-test-{}", process::id()));
+		fs::create_dir_all(&dir).unwrap();
+		let index_path = dir.join("foo.gzidx");
+		let indexed = BlobCrateHandle::with_index_path(archive.clone(), index_path.clone());
+		assert_eq!(indexed.get_file_list(&st).unwrap(), expected);
+		// The saved index has to retain it as well
+		let reloaded = BlobCrateHandle::with_index_path(archive, index_path);
+		assert_eq!(reloaded.get_file_list(&st).unwrap(), expected);
+		fs::remove_dir_all(&dir).unwrap();
+	}
+
+	#[test]
+	fn long_paths_in_storage() {
+		let long_path = format!("src/{}.rs", "x".repeat(120));
+		let archive = crate_archive("foo", "1.0.0", &[
+			("src/lib.rs", b"mod x;\n"),
+			(&long_path, b"pub fn x() {}\n"),
+		]);
+		let spec = spec("foo", "1.0.0");
+		let long_path = format!("foo-1.0.0/{}", long_path);
+		let st = storage_with(Codec::Gzip, &[(spec.clone(), archive.clone())]);
+		let ch = st.get_crate_handle_nv(spec.name.clone(), spec.version.clone()).unwrap();
+		assert_eq!(ch.get_file_list().unwrap(), ["foo-1.0.0/src/lib.rs", &long_path]);
+		assert_eq!(ch.get_file(&long_path).ok(), Some(b"pub fn x() {}\n".to_vec()));
+		assert_eq!(ch.get_file("foo-1.0.0/src/lib.rs").ok(), Some(b"mod x;\n".to_vec()));
+		let e = ch.get_file("././@LongLink").unwrap_err();
+		assert_eq!(*e.get_context(), CrateErrorKind::NotFound);
+		let meta = ch.get_file_metadata_list().unwrap();
+		assert_eq!(meta[1].path, long_path);
+		assert_eq!(meta[1].size, 14);
+
+		// Metadata stored without the path table gets it built when loading
+		let ccb = CrateContentBlobs::from_archive_file(archive.as_slice()).unwrap();
+		let CrateRecMetaWithBlobs { mut meta, blobs } = ccb.into_meta_with_blobs();
+		let table = meta.path_table.take();
+		let mut legacy = Vec::new();
+		meta.serialize(&mut legacy).unwrap();
+		let mut meta = CrateRecMetadata::deserialize(legacy.as_slice()).unwrap();
+		assert!(meta.path_table.is_none());
+		let blobs = blobs.into_iter().collect::<HashMap<_, _>>();
+		meta.resolve_path_table(|d| blobs.get(d).cloned()).unwrap();
+		assert_eq!(meta.path_table, table);
+		let mut with_table = Vec::new();
+		meta.serialize(&mut with_table).unwrap();
+		let meta = CrateRecMetadata::deserialize(with_table.as_slice()).unwrap();
+		assert_eq!(meta.path_table, table);
+	}
+
+	#[test]
+	fn missing_dictionary() {
+		use blob_storage::StorageErrorKind;
+
+		let mut st = storage_with(Codec::Zstd, &[]);
+		// A dictionary whose blob is missing must not get replaced silently
+		st.b.dictionaries.insert(1, [7; 32]);
+		let e = st.obtain_compressor(&[]).err().unwrap();
+		assert_eq!(*e.get_context(), StorageErrorKind::MissingBlob);
+	}
+
+	#[test]
+	fn metadata_cache() {
+		let archive = crate_archive("foo", "1.0.0", &[
+			("src/lib.rs", b"pub fn foo() {}\n"),
+		]);
+		let spec = spec("foo", "1.0.0");
+		let mut st = storage_with(Codec::Gzip, &[(spec.clone(), archive.clone())]);
+		st.get_crate_handle_nv(spec.name.clone(), spec.version.clone()).unwrap();
+		assert_eq!(st.metadata_cache_stats().misses, 0);
+
+		st.set_metadata_cache_capacity(10);
+		for _ in 0 .. 3 {
+			let ch = st.get_crate_handle_nv(spec.name.clone(),
+				spec.version.clone()).unwrap();
+			assert_eq!(ch.get_file_list().unwrap(), ["foo-1.0.0/src/lib.rs"]);
+		}
+		assert!(st.get_crate(&spec).unwrap() == archive);
+		assert_eq!(st.metadata_cache_stats(), CacheStats {
+			hits : 3,
+			misses : 1,
+			entries : 1,
+			capacity : 10,
+		});
+	}
+
+	#[test]
+	fn cached_archive_index() {
+		use std::env;
+		use std::process;
+
+		let spec = spec("foo", "1.0.0");
+		let dir = env::temp_dir().join(format!("archive-index-test-{}", process::id()));
+		let index_dir = dir.join("index");
+		fs::create_dir_all(&dir).unwrap();
+		let mut cache = CacheStorage::new(&dir);
+		cache.set_index_dir(Some(&index_dir));
+		let read_lib_rs = |content :&[u8]| {
+			fs::write(dir.join(spec.file_name()), content).unwrap();
+			let ch = cache.get_crate_handle_nv(spec.name.clone(),
+				spec.version.clone()).unwrap();
+			ch.get_file("foo-1.0.0/src/lib.rs").ok()
+		};
+		let lib_rs = |c :&[u8]| crate_archive("foo", "1.0.0", &[("src/lib.rs", c)]);
+
+		assert_eq!(read_lib_rs(&lib_rs(b"fn a() {}")), Some(b"fn a() {}".to_vec()));
+		assert!(index_dir.join("foo-1.0.0.crate.gzidx").exists());
+		// The index of the old archive must not be used for the new one
+		assert_eq!(read_lib_rs(&lib_rs(b"fn bb() {}")), Some(b"fn bb() {}".to_vec()));
+		fs::remove_dir_all(&dir).unwrap();
+	}
+
+	#[test]
+	fn repair_from_fallback() {
+		let lib_rs = b"pub fn foo() {}\n";
+		let archive = crate_archive("foo", "1.0.0", &[
+			("Cargo.toml", b"[package]\nname = \"foo\"\n"),
+			("src/lib.rs", lib_rs),
+		]);
+		let spec = spec("foo", "1.0.0");
+		let mut default = storage_with(Codec::Gzip, &[(spec.clone(), archive.clone())]);
+		let fallback = storage_with(Codec::Gzip, &[(spec.clone(), archive.clone())]);
+
+		// Corrupt the blob of src/lib.rs
+		let lib_rs_d = digest_of(lib_rs);
+		let bad = BlobCompressor::new(Codec::Gzip, None).compress(b"pub fn bar() {}\n").unwrap();
+		default.b.replace(lib_rs_d, &bad).unwrap();
+		let e = default.get_crate(&spec).unwrap_err();
+		assert_eq!(*e.get_context(), CrateErrorKind::ChecksumMismatch);
+
+		// Without verification, the corrupt content is returned
+		default.set_verify_digests(false);
+		assert!(default.get_crate(&spec).is_ok());
+		default.set_verify_digests(true);
+
+		let overlay = OverlayCrateSource::new(default, fallback);
+		{
+			let ch = overlay.get_crate_handle_nv(spec.name.clone(),
+				spec.version.clone()).unwrap();
+			assert_eq!(ch.get_file("foo-1.0.0/src/lib.rs").ok().as_ref().map(|v| &v[..]),
+				Some(&lib_rs[..]));
+		}
+		// The default source has been repaired
+		let default = overlay.0.into_inner().unwrap();
+		assert_eq!(default.get_crate(&spec).ok(), Some(archive));
+	}
+
+	/// Builds a gzipped tar archive without the path checks of `tar::Builder`
+	fn raw_archive(files :&[(&[u8], &[u8])]) -> Vec<u8> {
+		use flate2::Compression;
+		use flate2::write::GzEncoder;
+		let mut tar = Vec::new();
+		for &(path, content) in files.iter() {
+			let mut hdr = Header::new_ustar();
+			hdr.as_old_mut().name[.. path.len()].copy_from_slice(path);
+			hdr.set_size(content.len() as u64);
+			hdr.set_mode(0o644);
+			hdr.set_cksum();
+			tar.extend_from_slice(hdr.as_bytes());
+			tar.extend_from_slice(content);
+			let padding = (512 - content.len() % 512) % 512;
+			tar.extend(::std::iter::repeat(0).take(padding));
+		}
+		tar.extend_from_slice(&[0; 1024]);
+		let mut enc = GzEncoder::new(Vec::new(), Compression::best());
+		enc.write_all(&tar).unwrap();
+		enc.finish().unwrap()
+	}
+
+	fn extraction_kind(e :&CrateError) -> ExtractionErrorKind {
+		let cause = e.cause().and_then(|c| c.downcast_ref::<ExtractionError>());
+		*cause.unwrap().get_context()
+	}
+
+	#[test]
+	fn unsafe_archives() {
+		use std::env;
+		use std::process;
+
+		let evil = raw_archive(&[
+			(b"foo-1.0.0/src/lib.rs", b"pub fn foo() {}\n"),
+			(b"foo-1.0.0/../../.bashrc", b"rm -rf ~\n"),
+		]);
+		// Reading skips the entry with the unsafe path, and keeps the rest
+		let handle = BlobCrateHandle::new(evil.clone());
+		let mut files = Vec::new();
+		handle.map_all_files(|path, content| files.push((path, content))).unwrap();
+		assert_eq!(files, vec![
+			(Some("foo-1.0.0/src/lib.rs".to_owned()), Some(b"pub fn foo() {}\n".to_vec())),
+			(None, Some(b"rm -rf ~\n".to_vec())),
+		]);
+		let source = CacheStorage::new(Path::new("."));
+		let list = CrateFileHandle::<CacheStorage>::get_file_list(&handle, &source).unwrap();
+		assert_eq!(list, vec!["foo-1.0.0/src/lib.rs".to_owned()]);
+		let paths = handle.list_files().unwrap().into_iter()
+			.map(|m| m.path)
+			.collect::<Vec<_>>();
+		assert_eq!(paths, list);
+
+		// The same goes for the index
+		let dir = env::temp_dir().join(format!("unsafe-archive-test-{}", process::id()));
+		fs::create_dir_all(&dir).unwrap();
+		let indexed = BlobCrateHandle::with_index_path(evil.clone(), dir.join("foo.gzidx"));
+		assert!(indexed.index().is_some());
+		let indexed_list = CrateFileHandle::<CacheStorage>::get_file_list(&indexed,
+			&source).unwrap();
+		assert_eq!(indexed_list, list);
+		let lib_rs = CrateFileHandle::<CacheStorage>::get_file(&indexed, &source,
+			"foo-1.0.0/src/lib.rs").unwrap();
+		assert_eq!(lib_rs, b"pub fn foo() {}\n".to_vec());
+		fs::remove_dir_all(&dir).unwrap();
+
+		// Storing still rejects the archive
+		let e = CrateContentBlobs::from_archive_file(evil.as_slice()).err().unwrap();
+		assert_eq!(*e.get_context(), ExtractionErrorKind::UnsafePath);
+
+		let archive = crate_archive("foo", "1.0.0", &[
+			("Cargo.toml", b"[package]\nname = \"foo\"\n"),
+			("src/lib.rs", &[b'x'; 2000]),
+		]);
+		let limited = |limits :ExtractionLimits| {
+			let mut handle = BlobCrateHandle::new(archive.clone());
+			handle.set_extraction_limits(limits);
+			extraction_kind(&handle.map_all_files(|_, _| ()).unwrap_err())
+		};
+		assert_eq!(limited(ExtractionLimits {
+			max_entries : 1,
+			.. ExtractionLimits::default()
+		}), ExtractionErrorKind::TooManyEntries);
+		assert_eq!(limited(ExtractionLimits {
+			max_file_size : 1000,
+			.. ExtractionLimits::default()
+		}), ExtractionErrorKind::FileTooLarge);
+		assert_eq!(limited(ExtractionLimits {
+			max_total_size : 1500,
+			.. ExtractionLimits::default()
+		}), ExtractionErrorKind::TotalSizeExceeded);
+	}
+
+	#[test]
+	fn resume_with_shared_blobs() {
+		let license = b"Permission is hereby granted, free of charge\n";
+		let foo = crate_archive("foo", "1.0.0", &[("LICENSE", license), ("src/lib.rs", b"")]);
+		let bar = crate_archive("bar", "1.0.0", &[("LICENSE", license), ("src/main.rs", b"")]);
+		let mut c = Cursor::new(Vec::new());
+		// Two separate runs, with the second one storing a blob that is present already
+		for &(name, ref archive) in [("foo", &foo), ("bar", &bar)].iter() {
+			let mut st = BlobCrateStorage::new(&mut c).unwrap();
+			st.store_parallel_iter(2, vec![(spec(name, "1.0.0"), archive.to_vec(),
+				digest_of(archive))].into_iter()).unwrap();
+			st.store().unwrap();
+		}
+		let st = BlobCrateStorage::new(&mut c).unwrap();
+		for &(name, ref archive) in [("foo", &foo), ("bar", &bar)].iter() {
+			assert_eq!(st.get_crate(&spec(name, "1.0.0")).unwrap(), **archive);
+			let handle = st.get_crate_handle_nv(name.to_owned(),
+				Version::parse("1.0.0").unwrap()).unwrap();
+			assert_eq!(handle.get_file(&format!("{}-1.0.0/LICENSE", name)).unwrap(), &license[..]);
+		}
 	}
 }
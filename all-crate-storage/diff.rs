@@ -1,9 +1,16 @@
 use std::io::Result as IoResult;
-use std::io::{Read, Write};
+use std::io::{self, Read, Write};
 use difference::{Difference, Changeset};
 use byteorder::{ReadBytesExt, WriteBytesExt, BigEndian};
-use super::blob_storage::{write_delim_byte_slice, read_delim_byte_slice};
-use std::str;
+use super::blob_storage::{write_delim_byte_slice, read_delim_byte_slice_max};
+use std::collections::HashMap;
+
+/// Maximum length of the content added by a single instruction
+///
+/// Diffs are between files of crates, so an addition can't be
+/// longer than the largest file that the default
+/// `ExtractionLimits` allow. Longer lengths come from damaged data.
+const MAX_ADDITION_LEN :u64 = 256 * 1024 * 1024;
 
 #[derive(Debug)]
 pub enum DiffInstruction {
@@ -49,7 +56,6 @@ impl Diff {
 		let instructions = cset.diffs.into_iter()
 			.enumerate()
 			.map(|(i, d)| {
-				println!("{:?}", d);
 				if i + 1 < len {
 					DiffInstruction::from_difference(d, sep)
 				} else {
@@ -61,42 +67,46 @@ impl Diff {
 			instructions,
 		}
 	}
-	pub fn reconstruct_new(&self, old :&str) -> String {
+	/// Applies the diff to `old`
+	///
+	/// Returns `None` if the diff doesn't fit `old`.
+	pub fn reconstruct_new(&self, old :&str) -> Option<String> {
 		let mut res = String::new();
 		let mut oldsl = old;
 		for ins in self.instructions.iter() {
 			match ins {
 				&DiffInstruction::Same(l) => {
 					let adv = l as usize;
-					res += &oldsl[..adv];
+					res += oldsl.get(..adv)?;
 					oldsl = &oldsl[adv..];
 				},
 				&DiffInstruction::Addition(ref s) => {
 					res += s;
 				},
 				&DiffInstruction::Removal(l) => {
-					let adv = l as usize;
-					oldsl = &oldsl[adv..];
+					oldsl = oldsl.get(l as usize ..)?;
 				},
 			}
 		}
-		res
+		Some(res)
 	}
 	pub fn deserialize<R :Read>(mut rdr :R) -> IoResult<Self> {
 		let len = try!(rdr.read_u64::<BigEndian>());
-		let mut instructions = Vec::with_capacity(len as usize);
+		// The length is untrusted, so don't preallocate for it
+		let mut instructions = Vec::new();
 		for _ in 0 .. len {
 			let kind = try!(rdr.read_u8());
 			let ins = match kind {
 				1 => DiffInstruction::Same(try!(rdr.read_u64::<BigEndian>())),
 				2 => {
-					let sl = try!(read_delim_byte_slice(&mut rdr));
-					// TODO get rid of unwrap here
-					let s = str::from_utf8(&sl).unwrap();
-					DiffInstruction::Addition(s.to_owned())
+					let sl = try!(read_delim_byte_slice_max(&mut rdr, MAX_ADDITION_LEN));
+					let s = try!(String::from_utf8(sl)
+						.map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)));
+					DiffInstruction::Addition(s)
 				},
 				3 => DiffInstruction::Removal(try!(rdr.read_u64::<BigEndian>())),
-				_ => panic!("Invalid kind {}", kind),
+				_ => return Err(io::Error::new(io::ErrorKind::InvalidData,
+					format!("Invalid diff instruction kind {}", kind))),
 			};
 			instructions.push(ins);
 		}
@@ -125,6 +135,164 @@ impl Diff {
 	}
 }
 
+#[derive(Debug)]
+pub enum ByteDiffInstruction {
+	/// Copy the given range of the old blob
+	Copy(u64, u64),
+	Insertion(Vec<u8>),
+}
+
+/// A byte oriented delta between two blobs
+///
+/// Unlike `Diff`, this doesn't require the blobs to be text.
+/// The new blob is expressed as a sequence of ranges copied
+/// from the old blob, and of inserted bytes.
+#[derive(Debug)]
+pub struct ByteDiff {
+	instructions :Vec<ByteDiffInstruction>,
+}
+
+/// Size of the blocks of the old blob that we look for in the new one
+const BLOCK_LEN :usize = 16;
+/// Maximum number of positions in the old blob we remember per block hash
+const MAX_BLOCK_CANDIDATES :usize = 8;
+const HASH_BASE :u32 = 257;
+
+fn block_hash(block :&[u8]) -> u32 {
+	block.iter().fold(0u32, |h, b| h.wrapping_mul(HASH_BASE).wrapping_add(*b as u32))
+}
+
+impl ByteDiff {
+	pub fn from_bytes(old :&[u8], new :&[u8]) -> Self {
+		let mut instructions = Vec::new();
+		if old.len() < BLOCK_LEN || new.len() < BLOCK_LEN {
+			if !new.is_empty() {
+				instructions.push(ByteDiffInstruction::Insertion(new.to_vec()));
+			}
+			return ByteDiff { instructions };
+		}
+		let mut blocks = HashMap::<u32, Vec<usize>>::new();
+		for offs in (0 ..= old.len() - BLOCK_LEN).step_by(BLOCK_LEN) {
+			let candidates = blocks.entry(block_hash(&old[offs .. offs + BLOCK_LEN]))
+				.or_default();
+			if candidates.len() < MAX_BLOCK_CANDIDATES {
+				candidates.push(offs);
+			}
+		}
+		// The factor of the byte leaving the rolling hash window
+		let leave_factor = (1 .. BLOCK_LEN).fold(1u32, |f, _| f.wrapping_mul(HASH_BASE));
+
+		let mut insert_start = 0;
+		let mut i = 0;
+		let mut hash = block_hash(&new[.. BLOCK_LEN]);
+		while i + BLOCK_LEN <= new.len() {
+			let found = blocks.get(&hash).and_then(|candidates| {
+				candidates.iter()
+					.filter(|offs| old[**offs .. **offs + BLOCK_LEN] == new[i .. i + BLOCK_LEN])
+					.map(|offs| {
+						let len = old[*offs ..].iter()
+							.zip(new[i ..].iter())
+							.take_while(|(a, b)| a == b)
+							.count();
+						(*offs, len)
+					})
+					.max_by_key(|(_, len)| *len)
+			});
+			if let Some((mut offs, mut len)) = found {
+				// Extend the match backwards into the pending insertion
+				let mut start = i;
+				while start > insert_start && offs > 0 && old[offs - 1] == new[start - 1] {
+					start -= 1;
+					offs -= 1;
+					len += 1;
+				}
+				if start > insert_start {
+					instructions.push(ByteDiffInstruction::Insertion(new[insert_start .. start].to_vec()));
+				}
+				instructions.push(ByteDiffInstruction::Copy(offs as u64, len as u64));
+				i = start + len;
+				insert_start = i;
+				if i + BLOCK_LEN <= new.len() {
+					hash = block_hash(&new[i .. i + BLOCK_LEN]);
+				}
+			} else {
+				if i + BLOCK_LEN < new.len() {
+					hash = hash.wrapping_sub((new[i] as u32).wrapping_mul(leave_factor))
+						.wrapping_mul(HASH_BASE)
+						.wrapping_add(new[i + BLOCK_LEN] as u32);
+				}
+				i += 1;
+			}
+		}
+		if insert_start < new.len() {
+			instructions.push(ByteDiffInstruction::Insertion(new[insert_start ..].to_vec()));
+		}
+		ByteDiff {
+			instructions,
+		}
+	}
+	/// Applies the delta to `old`
+	///
+	/// Returns `None` if a copied range lies outside of `old`.
+	pub fn reconstruct_new(&self, old :&[u8]) -> Option<Vec<u8>> {
+		let mut res = Vec::new();
+		for ins in self.instructions.iter() {
+			match ins {
+				ByteDiffInstruction::Copy(offs, len) => {
+					let end = offs.checked_add(*len)?;
+					res.extend_from_slice(old.get(*offs as usize .. end as usize)?);
+				},
+				ByteDiffInstruction::Insertion(b) => {
+					res.extend_from_slice(b);
+				},
+			}
+		}
+		Some(res)
+	}
+	pub fn deserialize<R :Read>(mut rdr :R) -> IoResult<Self> {
+		let len = try!(rdr.read_u64::<BigEndian>());
+		// The length is untrusted, so don't preallocate for it
+		let mut instructions = Vec::new();
+		for _ in 0 .. len {
+			let kind = try!(rdr.read_u8());
+			let ins = match kind {
+				1 => {
+					let offs = try!(rdr.read_u64::<BigEndian>());
+					let len = try!(rdr.read_u64::<BigEndian>());
+					ByteDiffInstruction::Copy(offs, len)
+				},
+				2 => {
+					let b = try!(read_delim_byte_slice_max(&mut rdr, MAX_ADDITION_LEN));
+					ByteDiffInstruction::Insertion(b)
+				},
+				_ => return Err(io::Error::new(io::ErrorKind::InvalidData,
+					format!("Invalid delta instruction kind {}", kind))),
+			};
+			instructions.push(ins);
+		}
+		Ok(ByteDiff {
+			instructions
+		})
+	}
+	pub fn serialize<W :Write>(&self, mut wtr :W) -> IoResult<()> {
+		try!(wtr.write_u64::<BigEndian>(self.instructions.len() as u64));
+		for ins in self.instructions.iter() {
+			match ins {
+				ByteDiffInstruction::Copy(offs, len) => {
+					try!(wtr.write_u8(1));
+					try!(wtr.write_u64::<BigEndian>(*offs));
+					try!(wtr.write_u64::<BigEndian>(*len));
+				},
+				ByteDiffInstruction::Insertion(b) => {
+					try!(wtr.write_u8(2));
+					try!(write_delim_byte_slice(&mut wtr, b));
+				},
+			}
+		}
+		Ok(())
+	}
+}
+
 #[cfg(test)]
 mod test {
 	use super::*;
@@ -139,7 +307,7 @@ mod test {
 			Reader!
 			Nice to see you."#;
 		let diff = Diff::from_texts_nl(str_a, str_b);
-		let str_b_reconstructed = diff.reconstruct_new(str_a);
+		let str_b_reconstructed = diff.reconstruct_new(str_a).unwrap();
 		assert_eq!(str_b, str_b_reconstructed);
 	}
 	#[test]
@@ -156,7 +324,7 @@ mod test {
 		let mut v = Vec::new();
 		diff.serialize(&mut v).unwrap();
 		let diff_reconstructed = Diff::deserialize(v.as_slice()).unwrap();
-		let str_b_reconstructed = diff_reconstructed.reconstruct_new(str_a);
+		let str_b_reconstructed = diff_reconstructed.reconstruct_new(str_a).unwrap();
 		assert_eq!(str_b, str_b_reconstructed);
 	}
 	#[test]
@@ -168,7 +336,48 @@ mod test {
 			For this example,
 			we wonder about the first line."#;
 		let diff = Diff::from_texts_nl(str_a, str_b);
-		let str_b_reconstructed = diff.reconstruct_new(str_a);
+		let str_b_reconstructed = diff.reconstruct_new(str_a).unwrap();
 		assert_eq!(str_b, str_b_reconstructed);
 	}
+	#[test]
+	fn test_bytes_simple() {
+		let old = (0 .. 1000u32).map(|v| (v * 7 % 251) as u8).collect::<Vec<u8>>();
+		let mut new = old.clone();
+		new[500] = 42;
+		new.splice(100 .. 100, vec![1, 2, 3]);
+		new.truncate(900);
+		new.extend_from_slice(&old[20 .. 70]);
+		let diff = ByteDiff::from_bytes(&old, &new);
+		assert_eq!(diff.reconstruct_new(&old), Some(new.clone()));
+		let mut v = Vec::new();
+		diff.serialize(&mut v).unwrap();
+		assert!(v.len() < new.len() / 4);
+		let diff_reconstructed = ByteDiff::deserialize(v.as_slice()).unwrap();
+		assert_eq!(diff_reconstructed.reconstruct_new(&old), Some(new));
+	}
+	#[test]
+	fn test_bytes_short() {
+		for &(old, new) in [(&b""[..], &b"abc"[..]), (b"abc", b""), (b"abc", b"abd")].iter() {
+			let diff = ByteDiff::from_bytes(old, new);
+			assert_eq!(diff.reconstruct_new(old).as_ref().map(|v| &v[..]), Some(new));
+		}
+	}
+	#[test]
+	fn test_bytes_invalid() {
+		let old = (0 .. 100u8).collect::<Vec<u8>>();
+		let diff = ByteDiff::from_bytes(&old, &old);
+		assert!(diff.reconstruct_new(&old[.. 50]).is_none());
+		let copy = ByteDiff { instructions : vec![ByteDiffInstruction::Copy(u64::max_value(), 2)] };
+		assert!(copy.reconstruct_new(&old).is_none());
+		let mut v = Vec::new();
+		diff.serialize(&mut v).unwrap();
+		v[8] = 42;
+		let e = ByteDiff::deserialize(v.as_slice()).unwrap_err();
+		assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+		// An insertion with a huge length
+		let mut v = vec![0, 0, 0, 0, 0, 0, 0, 1, 2];
+		v.extend_from_slice(&[0xff; 8]);
+		let e = ByteDiff::deserialize(v.as_slice()).unwrap_err();
+		assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+	}
 }
@@ -0,0 +1,303 @@
+/*!
+Blob storage in a directory
+
+Each blob is stored in a file of its own, named after the hex
+of its digest and fanned out by the first byte of the digest:
+`objects/ab/cdef...`. This makes the storage easy to sync
+with tools like rsync.
+
+The indices are kept in memory and written as sorted text files
+next to the `objects` directory, one entry per line:
+
+* `names`: crate name, then the digest of its metadata blob
+* `raw_crates`: crate name, then the digest of the crate
+* `multi_blobs`: digest of a blob, then the digest of its multi blob
+* `dictionaries`: dictionary id, then the digest of the dictionary
+* `features`: the feature flags, in hex
+
+Blobs and index files are written to a temporary file first
+and then renamed into place, so that readers never see
+a partially written file. The index files are rewritten
+as a whole from the indices in memory though, so only
+one process may write to the storage at a time.
+Otherwise, the crates added by one of them get lost.
+*/
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Display;
+use std::fs::{self, File};
+use std::io::{self, Read, Write, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::process;
+use failure::ResultExt;
+use super::blob_storage::{StorageError, StorageErrorKind,
+	FEATURE_MULTI_BLOB, FEATURE_RAW_CRATES};
+use super::blob_store::{BlobStore, BlobStoreMut};
+use super::hash_ctx::{Digest, get_digest_hex, digest_from_hex};
+
+pub struct DirBlobStorage {
+	root :PathBuf,
+	names :BTreeMap<String, Digest>,
+	raw_crates :BTreeMap<String, Digest>,
+	digest_to_multi_blob :BTreeMap<Digest, Digest>,
+	dictionaries :HashMap<u32, Digest>,
+	features :u64,
+	/// Counter to make the names of temporary files unique
+	tmp_counter :u64,
+}
+
+impl DirBlobStorage {
+	/// Creates the directory of the storage if needed, and opens it
+	pub fn create(root :&Path) -> Result<Self, StorageError> {
+		try!(fs::create_dir_all(root.join("objects")).context(StorageErrorKind::Io));
+		DirBlobStorage::open(root)
+	}
+	/// Opens the storage in the given directory
+	pub fn open(root :&Path) -> Result<Self, StorageError> {
+		use self::StorageErrorKind::*;
+		if !root.join("objects").is_dir() {
+			try!(Err(io::Error::new(ErrorKind::NotFound,
+				"No objects directory found")).context(Io));
+		}
+		let names = try!(read_index(root, "names", |n| Some(n.to_owned())));
+		let raw_crates = try!(read_index(root, "raw_crates", |n| Some(n.to_owned())));
+		let digest_to_multi_blob = try!(read_index(root, "multi_blobs", digest_from_hex));
+		let dictionaries = try!(read_index(root, "dictionaries", |id| id.parse().ok()))
+			.into_iter().collect();
+		let features = match try!(read_file(&root.join("features"))) {
+			Some(s) => try!(u64::from_str_radix(s.trim(), 16).context(CorruptIndex)),
+			None => 0,
+		};
+		Ok(DirBlobStorage {
+			root : root.to_path_buf(),
+			names,
+			raw_crates,
+			digest_to_multi_blob,
+			dictionaries,
+			features,
+			tmp_counter : 0,
+		})
+	}
+	/// Returns the path of the file of the blob with the given digest
+	pub fn blob_path(&self, digest :&Digest) -> PathBuf {
+		let hex = get_digest_hex(*digest);
+		self.root.join("objects").join(&hex[.. 2]).join(&hex[2 ..])
+	}
+	/// Writes the content to the file at `path`, via a temporary file
+	fn write_atomically(&mut self, path :&Path, content :&[u8]) -> io::Result<()> {
+		self.tmp_counter += 1;
+		let tmp_path = self.root.join(format!("tmp-{}-{}", process::id(), self.tmp_counter));
+		{
+			let mut f = try!(File::create(&tmp_path));
+			try!(f.write_all(content));
+			try!(f.sync_all());
+		}
+		fs::rename(&tmp_path, path)
+	}
+	fn write_blob(&mut self, digest :&Digest, content :&[u8]) -> Result<(), StorageError> {
+		use self::StorageErrorKind::*;
+		let path = self.blob_path(digest);
+		try!(fs::create_dir_all(path.parent().unwrap()).context(Io));
+		try!(self.write_atomically(&path, content).context(Io));
+		Ok(())
+	}
+	fn write_index_file<K :Display, I :Iterator<Item = (K, Digest)>>(
+			&mut self, name :&str, entries :I) -> Result<(), StorageError> {
+		let mut content = String::new();
+		for (k, d) in entries {
+			content += &format!("{}\t{}\n", k, get_digest_hex(d));
+		}
+		let path = self.root.join(name);
+		try!(self.write_atomically(&path, content.as_bytes())
+			.context(StorageErrorKind::Io));
+		Ok(())
+	}
+}
+
+fn read_file(path :&Path) -> Result<Option<String>, StorageError> {
+	let mut f = match File::open(path) {
+		Ok(f) => f,
+		Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+		Err(e) => try!(Err(e).context(StorageErrorKind::Io)),
+	};
+	let mut s = String::new();
+	try!(f.read_to_string(&mut s).context(StorageErrorKind::Io));
+	Ok(Some(s))
+}
+
+/// Reads an index file, returning no entries if the file doesn't exist
+fn read_index<K :Ord, F :Fn(&str) -> Option<K>>(root :&Path, name :&str,
+		parse_key :F) -> Result<BTreeMap<K, Digest>, StorageError> {
+	let mut res = BTreeMap::new();
+	let content = match try!(read_file(&root.join(name))) {
+		Some(c) => c,
+		None => return Ok(res),
+	};
+	for line in content.lines() {
+		let mut components = line.split('\t');
+		let key = components.next().and_then(&parse_key);
+		let value = components.next().and_then(digest_from_hex);
+		match (key, value) {
+			(Some(k), Some(v)) => {
+				res.insert(k, v);
+			},
+			_ => return Err(StorageErrorKind::CorruptIndex.into()),
+		}
+	}
+	Ok(res)
+}
+
+impl BlobStore for DirBlobStorage {
+	fn has(&self, digest :&Digest) -> Result<bool, StorageError> {
+		Ok(self.blob_path(digest).is_file())
+	}
+	fn get(&self, digest :&Digest) -> Result<Option<Vec<u8>>, StorageError> {
+		let mut f = match File::open(self.blob_path(digest)) {
+			Ok(f) => f,
+			Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+			Err(e) => try!(Err(e).context(StorageErrorKind::Io)),
+		};
+		let mut content = Vec::new();
+		try!(f.read_to_end(&mut content).context(StorageErrorKind::Io));
+		Ok(Some(content))
+	}
+	fn digests(&self) -> Result<Vec<Digest>, StorageError> {
+		use self::StorageErrorKind::*;
+		let mut res = Vec::new();
+		for fan_out in try!(fs::read_dir(self.root.join("objects")).context(Io)) {
+			let fan_out = try!(fan_out.context(Io));
+			let prefix = fan_out.file_name().to_string_lossy().into_owned();
+			for entry in try!(fs::read_dir(fan_out.path()).context(Io)) {
+				let entry = try!(entry.context(Io));
+				let rest = entry.file_name().to_string_lossy().into_owned();
+				// Skip anything that isn't named like a blob
+				if let Some(d) = digest_from_hex(&(prefix.clone() + &rest)) {
+					res.push(d);
+				}
+			}
+		}
+		res.sort();
+		Ok(res)
+	}
+	fn get_named(&self, name :&str) -> Result<Option<Digest>, StorageError> {
+		Ok(self.names.get(name).cloned())
+	}
+	fn names(&self) -> Result<Vec<(String, Digest)>, StorageError> {
+		Ok(self.names.iter().map(|(n, d)| (n.clone(), *d)).collect())
+	}
+	fn get_multi_blob(&self, digest :&Digest) -> Result<Option<Digest>, StorageError> {
+		Ok(self.digest_to_multi_blob.get(digest).cloned())
+	}
+	fn multi_blob_entries(&self) -> Result<Vec<(Digest, Digest)>, StorageError> {
+		Ok(self.digest_to_multi_blob.iter().map(|(d, m)| (*d, *m)).collect())
+	}
+	fn get_raw_crate(&self, name :&str) -> Result<Option<Digest>, StorageError> {
+		Ok(self.raw_crates.get(name).cloned())
+	}
+	fn raw_crates(&self) -> Result<Vec<(String, Digest)>, StorageError> {
+		Ok(self.raw_crates.iter().map(|(n, d)| (n.clone(), *d)).collect())
+	}
+	fn dictionaries(&self) -> &HashMap<u32, Digest> {
+		&self.dictionaries
+	}
+	fn features(&self) -> u64 {
+		self.features
+	}
+}
+
+impl BlobStoreMut for DirBlobStorage {
+	fn insert(&mut self, digest :Digest, content :&[u8]) -> Result<bool, StorageError> {
+		if try!(self.has(&digest)) {
+			return Ok(false);
+		}
+		try!(self.write_blob(&digest, content));
+		Ok(true)
+	}
+	fn replace(&mut self, digest :Digest, content :&[u8]) -> Result<(), StorageError> {
+		self.write_blob(&digest, content)
+	}
+	fn insert_name(&mut self, name :String, digest :Digest) {
+		self.names.insert(name, digest);
+	}
+	fn insert_multi_blob(&mut self, digest :Digest, multi_blob_digest :Digest) {
+		self.digest_to_multi_blob.insert(digest, multi_blob_digest);
+	}
+	fn insert_raw_crate(&mut self, name :String, digest :Digest) {
+		self.raw_crates.insert(name, digest);
+	}
+	fn insert_dictionary(&mut self, id :u32, digest :Digest) {
+		self.dictionaries.insert(id, digest);
+	}
+	fn add_features(&mut self, features :u64) {
+		self.features |= features;
+	}
+	fn write_index(&mut self) -> Result<(), StorageError> {
+		if !self.digest_to_multi_blob.is_empty() {
+			self.features |= FEATURE_MULTI_BLOB;
+		}
+		if !self.raw_crates.is_empty() {
+			self.features |= FEATURE_RAW_CRATES;
+		}
+		let names = self.names.clone();
+		try!(self.write_index_file("names", names.into_iter()));
+		let raw_crates = self.raw_crates.clone();
+		try!(self.write_index_file("raw_crates", raw_crates.into_iter()));
+		let multi_blobs = self.digest_to_multi_blob.iter()
+			.map(|(d, m)| (get_digest_hex(*d), *m))
+			.collect::<Vec<_>>();
+		try!(self.write_index_file("multi_blobs", multi_blobs.into_iter()));
+		let mut dictionaries = self.dictionaries.iter()
+			.map(|(id, d)| (*id, *d))
+			.collect::<Vec<_>>();
+		dictionaries.sort_by_key(|&(id, _)| id);
+		try!(self.write_index_file("dictionaries", dictionaries.into_iter()));
+		// The features are written last, after everything they describe
+		let path = self.root.join("features");
+		let features = format!("{:x}\n", self.features);
+		try!(self.write_atomically(&path, features.as_bytes())
+			.context(StorageErrorKind::Io));
+		Ok(())
+	}
+	fn checkpoint(&mut self) -> Result<(), StorageError> {
+		// The index files are small enough to be rewritten as a whole
+		self.write_index()
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use std::env;
+	use semver::Version;
+	use blob_crate_storage::BlobCrateStorage;
+	use crate_storage::{CrateSource, CrateStorage, CrateSpec};
+	use hash_ctx::digest_of;
+	use test_util::crate_archive;
+
+	#[test]
+	fn crates_in_dir_storage() {
+		let root = env::temp_dir().join(format!("dir-blob-storage-test-{}", process::id()));
+		let spec = CrateSpec {
+			name : "foo".to_owned(),
+			version : Version::parse("1.0.0").unwrap(),
+		};
+		let archive = crate_archive("foo", "1.0.0", &[
+			("src/lib.rs", b"pub fn foo() {}\n"),
+		]);
+		{
+			let mut st = BlobCrateStorage::from_blob_store(DirBlobStorage::create(&root).unwrap());
+			st.store_parallel_iter(2, vec![(spec.clone(), archive.clone(),
+				digest_of(&archive))].into_iter()).unwrap();
+			st.store().unwrap();
+		}
+		let lib_rs = digest_of(b"pub fn foo() {}\n");
+		let hex = get_digest_hex(lib_rs);
+		assert!(root.join("objects").join(&hex[.. 2]).join(&hex[2 ..]).is_file());
+
+		let st = BlobCrateStorage::from_blob_store(DirBlobStorage::open(&root).unwrap());
+		assert_eq!(st.get_crate(&spec).ok(), Some(archive));
+		assert!(st.b.digests().unwrap().contains(&lib_rs));
+		assert_eq!(st.b.names().unwrap().len(), 1);
+		fs::remove_dir_all(&root).unwrap();
+	}
+}
@@ -0,0 +1,119 @@
+/*!
+Errors of crate sources
+
+The crate sources are backed by quite different things,
+from directories of .crate files to blob storages on
+remote servers. `CrateErrorKind` is what their errors
+have in common, so that users can tell a crate that is
+missing apart from one that is damaged. The underlying
+error is kept as cause of the `CrateError`.
+*/
+
+use std::fmt;
+use std::io;
+use failure::{Context, Fail};
+use super::blob_storage::{StorageError, StorageErrorKind};
+use super::extraction::{ExtractionError, ExtractionErrorKind};
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum CrateErrorKind {
+	NotFound,
+	Corrupt,
+	Io,
+	UnsupportedFormat,
+	ChecksumMismatch,
+}
+
+impl fmt::Display for CrateErrorKind {
+	fn fmt(&self, f :&mut fmt::Formatter) -> fmt::Result {
+		use self::CrateErrorKind::*;
+		match *self {
+			NotFound => write!(f, "Crate or file not found"),
+			Corrupt => write!(f, "Corrupt data in the crate source"),
+			Io => write!(f, "I/O error while accessing the crate source"),
+			UnsupportedFormat => write!(f, "Unsupported format"),
+			ChecksumMismatch => write!(f, "Content doesn't match its checksum"),
+		}
+	}
+}
+
+impl Fail for CrateErrorKind {}
+
+pub type CrateError = Context<CrateErrorKind>;
+
+impl CrateErrorKind {
+	/// Whether the data in the source is damaged
+	pub fn is_corruption(&self) -> bool {
+		matches!(*self, CrateErrorKind::Corrupt | CrateErrorKind::ChecksumMismatch)
+	}
+}
+
+impl From<StorageErrorKind> for CrateErrorKind {
+	fn from(kind :StorageErrorKind) -> Self {
+		use self::StorageErrorKind::*;
+		match kind {
+			Io => CrateErrorKind::Io,
+			InvalidMagic | UnsupportedVersion(_) | UnsupportedFeatures(_)
+				| MixedDiffFormats => {
+				CrateErrorKind::UnsupportedFormat
+			},
+			InvalidName | MissingBlob | CorruptHeader | CorruptIndex
+				| InvalidUpdatePack | InvalidInventory
+				| ConflictingDictionary(_) | CorruptBlob => CrateErrorKind::Corrupt,
+		}
+	}
+}
+
+impl From<ExtractionErrorKind> for CrateErrorKind {
+	fn from(kind :ExtractionErrorKind) -> Self {
+		use self::ExtractionErrorKind::*;
+		match kind {
+			InvalidArchive => CrateErrorKind::Corrupt,
+			// The archive might be fine, we just refuse to read it
+			TotalSizeExceeded | TooManyEntries | FileTooLarge
+				| UnsafePath => CrateErrorKind::UnsupportedFormat,
+		}
+	}
+}
+
+impl<'a> From<&'a io::Error> for CrateErrorKind {
+	fn from(e :&'a io::Error) -> Self {
+		match e.kind() {
+			io::ErrorKind::NotFound => CrateErrorKind::NotFound,
+			io::ErrorKind::InvalidData | io::ErrorKind::UnexpectedEof => CrateErrorKind::Corrupt,
+			_ => CrateErrorKind::Io,
+		}
+	}
+}
+
+pub fn from_storage(e :StorageError) -> CrateError {
+	let kind = CrateErrorKind::from(*e.get_context());
+	e.context(kind)
+}
+
+pub fn from_extraction(e :ExtractionError) -> CrateError {
+	let kind = CrateErrorKind::from(*e.get_context());
+	e.context(kind)
+}
+
+pub fn from_io(e :io::Error) -> CrateError {
+	let kind = CrateErrorKind::from(&e);
+	e.context(kind)
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn kinds() {
+		let e = from_io(io::Error::new(io::ErrorKind::NotFound, "gone"));
+		assert_eq!(*e.get_context(), CrateErrorKind::NotFound);
+		assert_eq!(e.cause().unwrap().to_string(), "gone");
+		let e = from_storage(StorageErrorKind::MissingBlob.into());
+		assert!(e.get_context().is_corruption());
+		let e = from_extraction(ExtractionErrorKind::UnsafePath.into());
+		assert_eq!(*e.get_context(), CrateErrorKind::UnsupportedFormat);
+		assert!(!CrateErrorKind::Io.is_corruption());
+	}
+}
@@ -0,0 +1,362 @@
+/*!
+Crates that are only available in extracted form
+
+Cargo extracts the crates it downloads to `~/.cargo/registry/src/<registry>/`,
+and `cargo vendor` writes them to a `vendor` directory. In both
+cases, there is one directory per crate, containing its files.
+Registry directories are named `<name>-<version>`, vendor
+directories usually just `<name>`, so the name and version of a crate
+are read from its Cargo.toml.
+
+The root directory of an `ExtractedDirStorage` is either such a
+directory of crate directories, or a directory containing
+multiple of them, like `~/.cargo/registry/src/`.
+
+Files are presented with the paths they have inside .crate files,
+so prefixed with `<name>-<version>/`. The marker files that
+cargo adds when extracting are left out. The original .crate
+files can't be recovered from the extracted files, so
+`get_crate` always fails with `NotFound`.
+*/
+
+use semver::Version;
+use toml;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Component, Path, PathBuf};
+use super::crate_storage::{CrateSource, RepairableCrateSource, CrateSpec, CrateHandle,
+	CrateFileHandle, FileMetadata, sort_specs};
+use super::hash_ctx::HashCtx;
+use super::error::{self, CrateError, CrateErrorKind};
+
+/// Files that cargo adds to the crate directories
+const MARKER_FILES :&[&str] = &[".cargo-ok", ".cargo-checksum.json"];
+
+pub struct ExtractedDirStorage {
+	root :PathBuf,
+}
+
+#[derive(Deserialize)]
+struct CargoTomlPackage {
+	name :String,
+	version :String,
+}
+
+#[derive(Deserialize)]
+struct CargoToml {
+	package :Option<CargoTomlPackage>,
+	/// Old name of the package section
+	project :Option<CargoTomlPackage>,
+}
+
+/// Obtains the name and version of the crate in the directory
+fn crate_spec_of_dir(dir :&Path) -> Option<CrateSpec> {
+	let mut content = Vec::new();
+	File::open(dir.join("Cargo.toml")).ok()?
+		.read_to_end(&mut content).ok()?;
+	let cargo_toml :CargoToml = toml::from_slice(&content).ok()?;
+	let package = cargo_toml.package.or(cargo_toml.project)?;
+	Some(CrateSpec {
+		name : package.name,
+		version : Version::parse(&package.version).ok()?,
+	})
+}
+
+fn sub_dirs(dir :&Path) -> io::Result<Vec<PathBuf>> {
+	let mut dirs = Vec::new();
+	for entry in try!(fs::read_dir(dir)) {
+		let entry = try!(entry);
+		if try!(entry.file_type()).is_dir() {
+			dirs.push(entry.path());
+		}
+	}
+	dirs.sort();
+	Ok(dirs)
+}
+
+impl ExtractedDirStorage {
+	pub fn new(root :&Path) -> Self {
+		ExtractedDirStorage {
+			root : root.to_path_buf(),
+		}
+	}
+	/// Returns the directories that contain crate directories
+	fn crate_dir_parents(&self) -> io::Result<Vec<PathBuf>> {
+		let mut res = vec![self.root.clone()];
+		for dir in try!(sub_dirs(&self.root)) {
+			if !dir.join("Cargo.toml").exists() {
+				res.push(dir);
+			}
+		}
+		Ok(res)
+	}
+	/// Calls `f` for all crate directories whose name passes `filter`
+	fn for_each_crate_dir<P :Fn(&str) -> bool, F :FnMut(CrateSpec, PathBuf)>(&self,
+			filter :P, mut f :F) -> io::Result<()> {
+		for parent in try!(self.crate_dir_parents()) {
+			for dir in try!(sub_dirs(&parent)) {
+				let passes = dir.file_name()
+					.and_then(|n| n.to_str())
+					.map(&filter)
+					.unwrap_or(false);
+				if !passes {
+					continue;
+				}
+				if let Some(spec) = crate_spec_of_dir(&dir) {
+					f(spec, dir);
+				}
+			}
+		}
+		Ok(())
+	}
+	fn find_crate_dir(&self, spec :&CrateSpec) -> io::Result<Option<PathBuf>> {
+		let dir_name = format!("{}-{}", spec.name, spec.version);
+		for parent in try!(self.crate_dir_parents()) {
+			// The layouts of the registry, and of the vendor directory
+			for candidate in [parent.join(&dir_name), parent.join(&spec.name)].iter() {
+				if crate_spec_of_dir(candidate).as_ref() == Some(spec) {
+					return Ok(Some(candidate.clone()));
+				}
+			}
+		}
+		Ok(None)
+	}
+}
+
+impl CrateSource for ExtractedDirStorage {
+	type CrateHandle = ExtractedCrateHandle;
+	fn get_crate_handle_nv(&self,
+			name :String, version :Version) -> Result<CrateHandle<'_, Self, Self::CrateHandle>, CrateError> {
+		let spec = CrateSpec {
+			name,
+			version,
+		};
+		let dir = try!(self.find_crate_dir(&spec).map_err(error::from_io));
+		let dir = try!(dir.ok_or(CrateErrorKind::NotFound));
+		Ok(CrateHandle {
+			source : self,
+			crate_file_handle : ExtractedCrateHandle {
+				dir,
+				prefix : format!("{}-{}", spec.name, spec.version),
+			},
+		})
+	}
+	fn get_crate(&self, _spec :&CrateSpec) -> Result<Vec<u8>, CrateError> {
+		// The original archives are not available
+		Err(CrateErrorKind::NotFound.into())
+	}
+	fn get_crate_versions(&self, name :&str) -> Result<Vec<Version>, CrateError> {
+		let dir_prefix = format!("{}-", name);
+		let mut versions = Vec::new();
+		try!(self.for_each_crate_dir(|n| n == name || n.starts_with(&dir_prefix), |spec, _| {
+			if spec.name == name {
+				versions.push(spec.version);
+			}
+		}).map_err(error::from_io));
+		versions.sort();
+		versions.dedup();
+		Ok(versions)
+	}
+	fn get_crate_list(&self) -> Result<Vec<CrateSpec>, CrateError> {
+		let mut specs = Vec::new();
+		try!(self.for_each_crate_dir(|_| true, |spec, _| specs.push(spec))
+			.map_err(error::from_io));
+		sort_specs(&mut specs);
+		Ok(specs)
+	}
+}
+
+impl RepairableCrateSource for ExtractedDirStorage {}
+
+/// Handle for the directory of an extracted crate
+pub struct ExtractedCrateHandle {
+	dir :PathBuf,
+	/// The directory name inside .crate files, `<name>-<version>`
+	prefix :String,
+}
+
+impl ExtractedCrateHandle {
+	/// Returns the paths inside the crate and in the file system of all files
+	///
+	/// Files whose names aren't valid UTF-8 are left out.
+	fn files(&self) -> io::Result<Vec<(String, PathBuf)>> {
+		let mut res = Vec::new();
+		let mut to_walk = vec![(String::new(), self.dir.clone())];
+		while let Some((rel, dir)) = to_walk.pop() {
+			for entry in try!(fs::read_dir(&dir)) {
+				let entry = try!(entry);
+				let name = match entry.file_name().into_string() {
+					Ok(n) => n,
+					Err(_) => continue,
+				};
+				let rel_path = if rel.is_empty() {
+					name.clone()
+				} else {
+					format!("{}/{}", rel, name)
+				};
+				// Symlinks are skipped so that no files outside are exposed
+				let file_type = try!(entry.file_type());
+				if file_type.is_dir() {
+					to_walk.push((rel_path, entry.path()));
+				} else if file_type.is_file() {
+					if rel.is_empty() && MARKER_FILES.contains(&&name[..]) {
+						continue;
+					}
+					res.push((format!("{}/{}", self.prefix, rel_path), entry.path()));
+				}
+			}
+		}
+		res.sort();
+		Ok(res)
+	}
+	/// Obtains the file system path of the file with the given path inside the crate
+	fn file_path(&self, path :&str) -> Option<PathBuf> {
+		let rel = path.strip_prefix(&self.prefix)?.strip_prefix('/')?;
+		let rel = Path::new(rel);
+		let inside = rel.components().all(|c| matches!(c, Component::Normal(_)));
+		let is_marker = MARKER_FILES.iter().any(|m| rel == Path::new(m));
+		if !inside || is_marker {
+			return None;
+		}
+		// Like in the listing, symlinks are not followed
+		let mut fs_path = self.dir.clone();
+		for c in rel.components() {
+			fs_path.push(c);
+			let is_symlink = fs::symlink_metadata(&fs_path)
+				.map(|m| m.file_type().is_symlink())
+				.unwrap_or(true);
+			if is_symlink {
+				return None;
+			}
+		}
+		if !fs_path.is_file() {
+			return None;
+		}
+		Some(fs_path)
+	}
+}
+
+#[cfg(unix)]
+fn file_mode(meta :&fs::Metadata) -> u32 {
+	use std::os::unix::fs::PermissionsExt;
+	meta.permissions().mode() & 0o7777
+}
+
+#[cfg(not(unix))]
+fn file_mode(meta :&fs::Metadata) -> u32 {
+	if meta.permissions().readonly() {
+		0o444
+	} else {
+		0o644
+	}
+}
+
+fn file_metadata(path :String, fs_path :&Path) -> io::Result<FileMetadata> {
+	let meta = try!(fs::metadata(fs_path));
+	let mtime = meta.modified().ok()
+		.and_then(|t| t.duration_since(::std::time::UNIX_EPOCH).ok())
+		.map(|d| d.as_secs())
+		.unwrap_or(0);
+	let mut hctx = HashCtx::new();
+	try!(io::copy(&mut try!(File::open(fs_path)), &mut hctx));
+	Ok(FileMetadata {
+		path,
+		size : meta.len(),
+		mode : file_mode(&meta),
+		mtime,
+		digest : hctx.finish_and_get_digest(),
+		is_dir : false,
+	})
+}
+
+impl<S :CrateSource> CrateFileHandle<S> for ExtractedCrateHandle {
+	fn get_file_list(&self, _source :&S) -> Result<Vec<String>, CrateError> {
+		let files = try!(self.files().map_err(error::from_io));
+		Ok(files.into_iter()
+			.map(|(path, _)| path)
+			.collect())
+	}
+	fn get_file(&self, _source :&S, path :&str) -> Result<Vec<u8>, CrateError> {
+		let fs_path = try!(self.file_path(path).ok_or(CrateErrorKind::NotFound));
+		let mut content = Vec::new();
+		let mut f = try!(File::open(fs_path).map_err(error::from_io));
+		try!(f.read_to_end(&mut content).map_err(error::from_io));
+		Ok(content)
+	}
+	fn get_file_metadata_list(&self, _source :&S) -> Result<Vec<FileMetadata>, CrateError> {
+		let files = try!(self.files().map_err(error::from_io));
+		files.into_iter()
+			.map(|(path, fs_path)| file_metadata(path, &fs_path).map_err(error::from_io))
+			.collect()
+	}
+	fn get_file_reader<'a>(&'a self, _source :&'a S,
+			path :&str) -> Result<Box<dyn Read + 'a>, CrateError> {
+		let fs_path = try!(self.file_path(path).ok_or(CrateErrorKind::NotFound));
+		let f = try!(File::open(fs_path).map_err(error::from_io));
+		Ok(Box::new(f))
+	}
+	fn has_file(&self, _source :&S, path :&str) -> Result<bool, CrateError> {
+		Ok(self.file_path(path).is_some())
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use std::env;
+	use std::process;
+
+	fn write_crate(dir :&Path, name :&str, version :&str, files :&[(&str, &[u8])]) {
+		fs::create_dir_all(dir).unwrap();
+		let cargo_toml = format!("[package]\nname = \"{}\"\nversion = \"{}\"\n", name, version);
+		fs::write(dir.join("Cargo.toml"), cargo_toml).unwrap();
+		for &(path, content) in files.iter() {
+			let path = dir.join(path);
+			fs::create_dir_all(path.parent().unwrap()).unwrap();
+			fs::write(path, content).unwrap();
+		}
+	}
+
+	#[test]
+	fn registry_and_vendor_layouts() {
+		let root = env::temp_dir().join(format!("extracted-test-{}", process::id()));
+		let registry = root.join("github.com-1ecc6299db9ec823");
+		write_crate(&registry.join("foo-1.0.0"), "foo", "1.0.0", &[
+			(".cargo-ok", b""),
+			("src/lib.rs", b"pub fn foo() {}\n"),
+		]);
+		write_crate(&registry.join("foo-1.1.0"), "foo", "1.1.0", &[]);
+		write_crate(&root.join("vendor").join("bar"), "bar", "0.2.0", &[
+			(".cargo-checksum.json", b"{}"),
+		]);
+		let st = ExtractedDirStorage::new(&root);
+
+		let list = st.get_crate_list().unwrap().iter()
+			.map(|s| s.file_name())
+			.collect::<Vec<_>>();
+		assert_eq!(list, ["bar-0.2.0.crate", "foo-1.0.0.crate", "foo-1.1.0.crate"]);
+		assert_eq!(st.get_crate_versions("foo").unwrap().len(), 2);
+		assert_eq!(st.get_crate_versions("bar").unwrap(), [Version::parse("0.2.0").unwrap()]);
+
+		let spec = CrateSpec {
+			name : "foo".to_owned(),
+			version : Version::parse("1.0.0").unwrap(),
+		};
+		let e = st.get_crate(&spec).unwrap_err();
+		assert_eq!(*e.get_context(), CrateErrorKind::NotFound);
+		{
+			let ch = st.get_crate_handle_nv("foo".to_owned(), spec.version.clone()).unwrap();
+			assert_eq!(ch.get_file_list().unwrap(), ["foo-1.0.0/Cargo.toml", "foo-1.0.0/src/lib.rs"]);
+			assert_eq!(ch.get_file("foo-1.0.0/src/lib.rs").ok(), Some(b"pub fn foo() {}\n".to_vec()));
+			assert!(ch.get_file("foo-1.0.0/.cargo-ok").is_err());
+			assert!(ch.get_file("foo-1.0.0/../foo-1.1.0/Cargo.toml").is_err());
+			let meta = ch.get_file_metadata_list().unwrap();
+			assert_eq!(meta[1].size, 16);
+		}
+		let ch = st.get_crate_handle_nv("bar".to_owned(),
+			Version::parse("0.2.0").unwrap()).unwrap();
+		assert_eq!(ch.get_file_list().unwrap(), ["bar-0.2.0/Cargo.toml"]);
+		drop(ch);
+		fs::remove_dir_all(&root).unwrap();
+	}
+}
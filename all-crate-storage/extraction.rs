@@ -0,0 +1,249 @@
+/*!
+Safe inflation of crate archives
+
+Crate archives can come from untrusted sources. A gzip stream
+of a few kilobytes can inflate to gigabytes, and tar entries can
+have paths like `../../.bashrc`. The code reading archives
+enforces `ExtractionLimits` on them, and only accepts
+paths that `normalize_path` accepts.
+*/
+
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Read};
+use failure::{Context, Fail};
+use flate2::read::GzDecoder;
+
+/// Limits for the content of a single archive
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct ExtractionLimits {
+	/// Maximum size of the inflated archive, in bytes
+	///
+	/// This includes the tar headers and padding.
+	pub max_total_size :u64,
+	/// Maximum number of tar entries
+	pub max_entries :u64,
+	/// Maximum size of a single file, in bytes
+	pub max_file_size :u64,
+}
+
+impl Default for ExtractionLimits {
+	fn default() -> Self {
+		ExtractionLimits {
+			max_total_size : 1024 * 1024 * 1024,
+			max_entries : 100_000,
+			max_file_size : 256 * 1024 * 1024,
+		}
+	}
+}
+
+/// Maximum length of a path that we read back from stored metadata
+///
+/// No sane archive has longer paths, so longer lengths
+/// in the metadata can only come from damaged data.
+pub(crate) const MAX_PATH_LEN :u64 = 64 * 1024;
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum ExtractionErrorKind {
+	InvalidArchive,
+	TotalSizeExceeded,
+	TooManyEntries,
+	FileTooLarge,
+	UnsafePath,
+}
+
+impl fmt::Display for ExtractionErrorKind {
+	fn fmt(&self, f :&mut fmt::Formatter) -> fmt::Result {
+		use self::ExtractionErrorKind::*;
+		match *self {
+			InvalidArchive => write!(f, "Invalid gzip or tar data"),
+			TotalSizeExceeded => write!(f, "Archive inflates to more than the allowed size"),
+			TooManyEntries => write!(f, "Archive has more entries than allowed"),
+			FileTooLarge => write!(f, "Archive contains a file larger than allowed"),
+			UnsafePath => write!(f, "Archive contains an unsafe path"),
+		}
+	}
+}
+
+impl Fail for ExtractionErrorKind {}
+
+pub type ExtractionError = Context<ExtractionErrorKind>;
+
+/// The error a `SizeLimitedReader` fails with
+#[derive(Debug)]
+struct SizeLimitExceeded;
+
+impl fmt::Display for SizeLimitExceeded {
+	fn fmt(&self, f :&mut fmt::Formatter) -> fmt::Result {
+		write!(f, "size limit exceeded")
+	}
+}
+
+impl Error for SizeLimitExceeded {}
+
+/// Reader that fails once more than a given number of bytes are read
+pub(crate) struct SizeLimitedReader<R> {
+	pub(crate) inner :R,
+	remaining :u64,
+}
+
+impl<R :Read> SizeLimitedReader<R> {
+	pub(crate) fn new(inner :R, limit :u64) -> Self {
+		SizeLimitedReader {
+			inner,
+			remaining : limit,
+		}
+	}
+}
+
+impl<R :Read> Read for SizeLimitedReader<R> {
+	fn read(&mut self, buf :&mut [u8]) -> io::Result<usize> {
+		if buf.is_empty() {
+			return Ok(0);
+		}
+		if self.remaining == 0 {
+			// Only fail if there actually is more data
+			let mut probe = [0; 1];
+			if try!(self.inner.read(&mut probe)) == 0 {
+				return Ok(0);
+			}
+			return Err(io::Error::other(SizeLimitExceeded));
+		}
+		let max = buf.len().min(self.remaining.min(usize::MAX as u64) as usize);
+		let read = try!(self.inner.read(&mut buf[.. max]));
+		self.remaining -= read as u64;
+		Ok(read)
+	}
+}
+
+/// Inflates the gzip stream, failing once it exceeds the limits
+pub(crate) fn limited_gz_decoder<R :Read>(r :R,
+		limits :&ExtractionLimits) -> SizeLimitedReader<GzDecoder<R>> {
+	SizeLimitedReader::new(GzDecoder::new(r), limits.max_total_size)
+}
+
+/// Obtains the kind of an error that happened while reading an archive
+pub(crate) fn error_kind(e :&io::Error) -> ExtractionErrorKind {
+	let exceeded = e.get_ref()
+		.map(|inner| inner.is::<SizeLimitExceeded>())
+		.unwrap_or(false);
+	if exceeded {
+		ExtractionErrorKind::TotalSizeExceeded
+	} else {
+		ExtractionErrorKind::InvalidArchive
+	}
+}
+
+pub(crate) fn extraction_error(e :io::Error) -> ExtractionError {
+	Context::new(error_kind(&e))
+}
+
+/// Counts the entries of an archive, checking them against the limits
+pub(crate) struct EntryChecker {
+	limits :ExtractionLimits,
+	entries :u64,
+}
+
+impl EntryChecker {
+	pub(crate) fn new(limits :&ExtractionLimits) -> Self {
+		EntryChecker {
+			limits : *limits,
+			entries : 0,
+		}
+	}
+	/// Checks the next entry, with the given size of its content
+	pub(crate) fn check(&mut self, size :u64) -> Result<(), ExtractionError> {
+		self.entries += 1;
+		if self.entries > self.limits.max_entries {
+			return Err(ExtractionErrorKind::TooManyEntries.into());
+		}
+		if size > self.limits.max_file_size {
+			return Err(ExtractionErrorKind::FileTooLarge.into());
+		}
+		Ok(())
+	}
+}
+
+/// Normalizes the path of an archive entry
+///
+/// Empty and `.` components are removed. Returns `None` if
+/// the path is unsafe to use, e.g. because it is absolute or
+/// contains `..` components, or if it isn't valid UTF-8.
+pub fn normalize_path(path :&[u8]) -> Option<String> {
+	let path = ::std::str::from_utf8(path).ok()?;
+	if path.starts_with('/') || path.contains('\\') || path.contains('\0') {
+		return None;
+	}
+	let mut components = Vec::new();
+	for component in path.split('/') {
+		match component {
+			"" | "." => (),
+			".." => return None,
+			c => components.push(c),
+		}
+	}
+	// Windows drive prefixes like C:
+	let drive = components.first()
+		.map(|c| c.len() == 2 && c.ends_with(':'))
+		.unwrap_or(false);
+	if components.is_empty() || drive {
+		return None;
+	}
+	Some(components.join("/"))
+}
+
+/// Returns the path of an entry as it appears in file lists
+///
+/// The paths of directories end with a `/`, like in the archive,
+/// so that they can be told apart from files.
+pub fn listed_path(path :String, is_dir :bool) -> String {
+	if is_dir {
+		path + "/"
+	} else {
+		path
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use std::io::Write;
+	use flate2::Compression;
+	use flate2::write::GzEncoder;
+
+	#[test]
+	fn paths() {
+		let n = |p :&str| normalize_path(p.as_bytes());
+		assert_eq!(n("foo-1.0.0/src/lib.rs").as_deref(), Some("foo-1.0.0/src/lib.rs"));
+		assert_eq!(n("./foo-1.0.0//src/./lib.rs").as_deref(), Some("foo-1.0.0/src/lib.rs"));
+		assert_eq!(n("foo-1.0.0/../../.bashrc"), None);
+		assert_eq!(n("/etc/passwd"), None);
+		assert_eq!(n("C:/Windows"), None);
+		assert_eq!(n("foo\\..\\bar"), None);
+		assert_eq!(n("./"), None);
+		assert_eq!(normalize_path(b"foo/\xff"), None);
+	}
+
+	#[test]
+	fn gzip_bomb() {
+		let mut enc = GzEncoder::new(Vec::new(), Compression::best());
+		enc.write_all(&vec![0; 1024 * 1024]).unwrap();
+		let bomb = enc.finish().unwrap();
+		let limits = ExtractionLimits {
+			max_total_size : 1000,
+			.. ExtractionLimits::default()
+		};
+		let mut rdr = limited_gz_decoder(bomb.as_slice(), &limits);
+		let e = rdr.read_to_end(&mut Vec::new()).unwrap_err();
+		assert_eq!(error_kind(&e), ExtractionErrorKind::TotalSizeExceeded);
+
+		// Reading exactly up to the limit is fine
+		let limits = ExtractionLimits {
+			max_total_size : 1024 * 1024,
+			.. ExtractionLimits::default()
+		};
+		let mut content = Vec::new();
+		limited_gz_decoder(bomb.as_slice(), &limits).read_to_end(&mut content).unwrap();
+		assert_eq!(content.len(), 1024 * 1024);
+	}
+}
@@ -0,0 +1,617 @@
+/*!
+Random access into gzip compressed tar archives
+
+Finding a file inside a .crate file normally means inflating
+the archive from the start up to that file. A `GzIndex`
+avoids this. It is built in a single pass over the archive and
+records the position of each tar entry inside the uncompressed
+data, along with restart points for the deflate stream.
+
+Like in zlib's zran example, a restart point is placed at a
+deflate block boundary every `SPAN` bytes of uncompressed output.
+It consists of the offsets in the compressed and uncompressed data,
+the bits of the partially consumed byte and the last 32 KiB of
+uncompressed data, which later blocks can refer back to.
+Inflation can then start at the restart point closest to
+the file, so only up to `SPAN` bytes need to be inflated
+in vain.
+
+The index can be saved to disk, in the following format,
+all numbers big endian:
+
+* the magic `GZIDX001`
+* the length of the archive (u64) and its last 8 bytes,
+  the gzip trailer, to tell whether the index belongs to it
+* the number of restart points (u64), then for each: the
+  uncompressed offset (u64), the compressed offset (u64),
+  the number of bits (u8) and the length delimited window
+* the number of entries (u64), then for each: the length
+  delimited path, the offset of the content (u64),
+  the size (u64), the mode (u32), the mtime (u64), the digest
+  and whether the entry is a directory (u8)
+*/
+
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::mem;
+use std::os::raw::{c_int, c_uint};
+use std::ptr;
+use byteorder::{ReadBytesExt, WriteBytesExt, BigEndian};
+use libc;
+use libz_sys as zlib;
+use tar::Archive;
+use super::blob_storage::{read_delim_byte_slice_max, write_delim_byte_slice};
+use super::crate_storage::FileMetadata;
+use super::hash_ctx::HashCtx;
+use super::extraction::{ExtractionLimits, ExtractionError,
+	EntryChecker, SizeLimitedReader, extraction_error, normalize_path, MAX_PATH_LEN};
+
+/// The distance between restart points, in bytes of uncompressed data
+pub const SPAN :u64 = 1024 * 1024;
+
+/// The size of the deflate window
+const WINDOW_SIZE :usize = 32 * 1024;
+
+const INDEX_MAGIC :&[u8; 8] = b"GZIDX002";
+
+struct RestartPoint {
+	/// The offset in the uncompressed data
+	out :u64,
+	/// The offset of the first byte in the compressed data
+	/// that hasn't been consumed fully
+	input :u64,
+	/// The number of bits of the byte before `input` that are still needed
+	bits :u8,
+	/// The uncompressed data before the point, up to `WINDOW_SIZE` bytes
+	window :Vec<u8>,
+}
+
+/// A tar entry, along with its position in the uncompressed data
+struct IndexEntry {
+	meta :FileMetadata,
+	offset :u64,
+}
+
+pub struct GzIndex {
+	archive_len :u64,
+	archive_trailer :[u8; 8],
+	/// Sorted by their offsets
+	points :Vec<RestartPoint>,
+	/// In archive order
+	entries :Vec<IndexEntry>,
+}
+
+fn zlib_err(ret :c_int) -> io::Error {
+	io::Error::new(io::ErrorKind::InvalidData, format!("zlib error {}", ret))
+}
+
+unsafe extern "C" fn zalloc(_opaque :zlib::voidpf, items :c_uint, size :c_uint) -> zlib::voidpf {
+	libc::calloc(items as libc::size_t, size as libc::size_t) as zlib::voidpf
+}
+
+unsafe extern "C" fn zfree(_opaque :zlib::voidpf, address :zlib::voidpf) {
+	libc::free(address)
+}
+
+/// An inflate stream of zlib
+///
+/// Boxed, as zlib keeps a pointer to the stream in its state.
+struct Inflater(Box<zlib::z_stream>);
+
+impl Inflater {
+	/// Creates the stream, see `inflateInit2` for the meaning of `window_bits`
+	fn new(window_bits :c_int) -> io::Result<Self> {
+		let mut strm = Box::new(zlib::z_stream {
+			next_in : ptr::null_mut(),
+			avail_in : 0,
+			total_in : 0,
+			next_out : ptr::null_mut(),
+			avail_out : 0,
+			total_out : 0,
+			msg : ptr::null_mut(),
+			state : ptr::null_mut(),
+			zalloc,
+			zfree,
+			opaque : ptr::null_mut(),
+			data_type : 0,
+			adler : 0,
+			reserved : 0,
+		});
+		let ret = unsafe {
+			zlib::inflateInit2_(&mut *strm, window_bits, zlib::zlibVersion(),
+				mem::size_of::<zlib::z_stream>() as c_int)
+		};
+		if ret != zlib::Z_OK {
+			return Err(zlib_err(ret));
+		}
+		Ok(Inflater(strm))
+	}
+	/// Inflates from `input` into `output`
+	///
+	/// Returns the number of consumed and produced bytes,
+	/// and whether the end of the stream has been reached.
+	fn inflate(&mut self, input :&[u8], output :&mut [u8],
+			flush :c_int) -> io::Result<(usize, usize, bool)> {
+		self.0.next_in = input.as_ptr() as *mut u8;
+		self.0.avail_in = input.len() as c_uint;
+		self.0.next_out = output.as_mut_ptr();
+		self.0.avail_out = output.len() as c_uint;
+		let ret = unsafe { zlib::inflate(&mut *self.0, flush) };
+		let consumed = input.len() - self.0.avail_in as usize;
+		let produced = output.len() - self.0.avail_out as usize;
+		self.0.next_in = ptr::null_mut();
+		self.0.next_out = ptr::null_mut();
+		match ret {
+			zlib::Z_OK | zlib::Z_BUF_ERROR => Ok((consumed, produced, false)),
+			zlib::Z_STREAM_END => Ok((consumed, produced, true)),
+			_ => Err(zlib_err(ret)),
+		}
+	}
+	/// Whether the stream is at a block boundary, and not after the last block
+	fn at_block_boundary(&self) -> bool {
+		self.0.data_type & 128 != 0 && self.0.data_type & 64 == 0
+	}
+	/// The number of bits of the last consumed byte that haven't been used yet
+	fn unused_bits(&self) -> u8 {
+		(self.0.data_type & 7) as u8
+	}
+	fn prime(&mut self, bits :u8, value :u8) -> io::Result<()> {
+		let ret = unsafe { zlib::inflatePrime(&mut *self.0, bits as c_int, value as c_int) };
+		if ret != zlib::Z_OK {
+			return Err(zlib_err(ret));
+		}
+		Ok(())
+	}
+	fn set_dictionary(&mut self, dict :&[u8]) -> io::Result<()> {
+		let ret = unsafe {
+			zlib::inflateSetDictionary(&mut *self.0, dict.as_ptr(), dict.len() as c_uint)
+		};
+		if ret != zlib::Z_OK {
+			return Err(zlib_err(ret));
+		}
+		Ok(())
+	}
+}
+
+impl Drop for Inflater {
+	fn drop(&mut self) {
+		unsafe {
+			zlib::inflateEnd(&mut *self.0);
+		}
+	}
+}
+
+/// Inflates a gzip stream while recording restart points
+struct IndexingReader<R :Read> {
+	inner :R,
+	inflater :Inflater,
+	span :u64,
+	in_buf :Vec<u8>,
+	in_pos :usize,
+	in_len :usize,
+	/// Bytes consumed from `inner`
+	total_in :u64,
+	/// Bytes produced
+	total_out :u64,
+	/// The last bytes produced, up to `WINDOW_SIZE`
+	window :Vec<u8>,
+	points :Vec<RestartPoint>,
+	done :bool,
+}
+
+impl<R :Read> IndexingReader<R> {
+	fn new(inner :R, span :u64) -> io::Result<Self> {
+		Ok(IndexingReader {
+			inner,
+			// Only accept gzip streams
+			inflater : try!(Inflater::new(15 + 16)),
+			span,
+			in_buf : vec![0; 16 * 1024],
+			in_pos : 0,
+			in_len : 0,
+			total_in : 0,
+			total_out : 0,
+			window : Vec::new(),
+			points : Vec::new(),
+			done : false,
+		})
+	}
+	fn add_to_window(&mut self, produced :&[u8]) {
+		self.window.extend_from_slice(produced);
+		if self.window.len() > 2 * WINDOW_SIZE {
+			let excess = self.window.len() - WINDOW_SIZE;
+			self.window.drain(.. excess);
+		}
+	}
+	fn maybe_add_point(&mut self) {
+		if !self.inflater.at_block_boundary() {
+			return;
+		}
+		let last_out = self.points.last().map(|p| p.out);
+		let due = match last_out {
+			None => true,
+			Some(last) => self.total_out - last > self.span,
+		};
+		if !due {
+			return;
+		}
+		let window_start = self.window.len().saturating_sub(WINDOW_SIZE);
+		self.points.push(RestartPoint {
+			out : self.total_out,
+			input : self.total_in,
+			bits : self.inflater.unused_bits(),
+			window : self.window[window_start ..].to_vec(),
+		});
+	}
+}
+
+impl<R :Read> Read for IndexingReader<R> {
+	fn read(&mut self, buf :&mut [u8]) -> io::Result<usize> {
+		if buf.is_empty() {
+			return Ok(0);
+		}
+		while !self.done {
+			if self.in_pos == self.in_len {
+				self.in_len = try!(self.inner.read(&mut self.in_buf));
+				self.in_pos = 0;
+				if self.in_len == 0 {
+					return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+						"gzip stream ends early"));
+				}
+			}
+			let (consumed, produced, end) = try!(self.inflater.inflate(
+				&self.in_buf[self.in_pos .. self.in_len], buf, zlib::Z_BLOCK));
+			self.in_pos += consumed;
+			self.total_in += consumed as u64;
+			self.total_out += produced as u64;
+			self.add_to_window(&buf[.. produced]);
+			self.done = end;
+			self.maybe_add_point();
+			if produced > 0 {
+				return Ok(produced);
+			}
+		}
+		Ok(0)
+	}
+}
+
+/// Reader for a range of the uncompressed data of an archive
+///
+/// Created by `GzIndex::file_reader`.
+pub struct RangeReader<R :Read> {
+	inner :R,
+	inflater :Inflater,
+	in_buf :Vec<u8>,
+	in_pos :usize,
+	in_len :usize,
+	out_buf :Vec<u8>,
+	out_pos :usize,
+	out_len :usize,
+	/// Uncompressed bytes before the start of the range that still need to be skipped
+	to_skip :u64,
+	/// Bytes of the range that haven't been read yet
+	remaining :u64,
+	stream_end :bool,
+}
+
+impl<R :Read> Read for RangeReader<R> {
+	fn read(&mut self, buf :&mut [u8]) -> io::Result<usize> {
+		loop {
+			if self.remaining == 0 || buf.is_empty() {
+				return Ok(0);
+			}
+			if self.out_pos < self.out_len {
+				let n = ((self.out_len - self.out_pos) as u64)
+					.min(buf.len() as u64)
+					.min(self.remaining) as usize;
+				buf[.. n].copy_from_slice(&self.out_buf[self.out_pos .. self.out_pos + n]);
+				self.out_pos += n;
+				self.remaining -= n as u64;
+				return Ok(n);
+			}
+			if self.stream_end {
+				return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+					"Archive ends before the file"));
+			}
+			if self.in_pos == self.in_len {
+				self.in_len = try!(self.inner.read(&mut self.in_buf));
+				self.in_pos = 0;
+				if self.in_len == 0 {
+					return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+						"Archive ends before the file"));
+				}
+			}
+			let (consumed, produced, end) = try!(self.inflater.inflate(
+				&self.in_buf[self.in_pos .. self.in_len], &mut self.out_buf, zlib::Z_NO_FLUSH));
+			self.in_pos += consumed;
+			self.stream_end = end;
+			let skipped = self.to_skip.min(produced as u64);
+			self.to_skip -= skipped;
+			self.out_pos = skipped as usize;
+			self.out_len = produced;
+		}
+	}
+}
+
+/// Reads the length and the gzip trailer of the archive
+fn archive_id<R :Read + Seek>(mut r :R) -> io::Result<(u64, [u8; 8])> {
+	let len = try!(r.seek(SeekFrom::End(0)));
+	let mut trailer = [0; 8];
+	if len >= 8 {
+		try!(r.seek(SeekFrom::End(-8)));
+		try!(r.read_exact(&mut trailer));
+	}
+	Ok((len, trailer))
+}
+
+impl GzIndex {
+	/// Builds the index of the given .crate file
+	///
+	/// Archives that exceed the limits are rejected.
+	/// The paths in the index are normalized,
+	/// and entries with unsafe paths are left out.
+	pub fn build<R :Read + Seek>(r :R, limits :&ExtractionLimits) -> Result<Self, ExtractionError> {
+		GzIndex::build_with_span(r, SPAN, limits)
+	}
+	fn build_with_span<R :Read + Seek>(mut r :R, span :u64,
+			limits :&ExtractionLimits) -> Result<Self, ExtractionError> {
+		let (archive_len, archive_trailer) = try!(archive_id(&mut r).map_err(extraction_error));
+		try!(r.seek(SeekFrom::Start(0)).map_err(extraction_error));
+		let indexing_rdr = try!(IndexingReader::new(r, span).map_err(extraction_error));
+		let mut archive = Archive::new(SizeLimitedReader::new(indexing_rdr,
+			limits.max_total_size));
+		let mut checker = EntryChecker::new(limits);
+		let mut entries = Vec::new();
+		for entry in try!(archive.entries().map_err(extraction_error)) {
+			let mut entry = try!(entry.map_err(extraction_error));
+			try!(checker.check(try!(entry.header().size().map_err(extraction_error))));
+			let offset = entry.raw_file_position();
+			let mut hctx = HashCtx::new();
+			try!(io::copy(&mut entry, &mut hctx).map_err(extraction_error));
+			let digest = hctx.finish_and_get_digest();
+			let path = match normalize_path(&entry.path_bytes()) {
+				Some(p) => p,
+				None => continue,
+			};
+			let header = entry.header();
+			let meta = FileMetadata {
+				path,
+				size : try!(header.size().map_err(extraction_error)),
+				mode : try!(header.mode().map_err(extraction_error)),
+				mtime : try!(header.mtime().map_err(extraction_error)),
+				digest,
+				is_dir : header.entry_type().is_dir(),
+			};
+			entries.push(IndexEntry {
+				meta,
+				offset,
+			});
+		}
+		let mut rdr = archive.into_inner();
+		// Inflate the rest, to obtain the restart points
+		// up to the end and to check the stream
+		try!(io::copy(&mut rdr, &mut io::sink()).map_err(extraction_error));
+		Ok(GzIndex {
+			archive_len,
+			archive_trailer,
+			points : rdr.inner.points,
+			entries,
+		})
+	}
+	/// Returns whether the index has been built from the given archive
+	///
+	/// This only compares the length and the gzip trailer,
+	/// which contains a crc32 of the uncompressed data.
+	pub fn matches<R :Read + Seek>(&self, r :R) -> io::Result<bool> {
+		let (len, trailer) = try!(archive_id(r));
+		Ok(len == self.archive_len && trailer == self.archive_trailer)
+	}
+	pub fn file_metadata(&self) -> impl Iterator<Item = &FileMetadata> {
+		self.entries.iter().map(|e| &e.meta)
+	}
+	/// Reads the content of the file at the given path in the archive
+	pub fn read_file<R :Read + Seek>(&self, r :R, path :&str) -> io::Result<Option<Vec<u8>>> {
+		let mut rdr = match try!(self.file_reader(r, path)) {
+			Some(rdr) => rdr,
+			None => return Ok(None),
+		};
+		let mut res = Vec::new();
+		try!(rdr.read_to_end(&mut res));
+		Ok(Some(res))
+	}
+	/// Returns a reader for the file at the given path in the archive
+	///
+	/// The file is inflated while it is being read,
+	/// starting at the closest restart point.
+	pub fn file_reader<R :Read + Seek>(&self, r :R, path :&str) -> io::Result<Option<RangeReader<R>>> {
+		let entry = match self.entries.iter().find(|e| e.meta.path == path) {
+			Some(e) => e,
+			None => return Ok(None),
+		};
+		self.reader_at(r, entry.offset, entry.meta.size).map(Some)
+	}
+	/// Returns a reader for `len` bytes of uncompressed data, starting at `offset`
+	fn reader_at<R :Read + Seek>(&self, mut r :R, offset :u64, len :u64) -> io::Result<RangeReader<R>> {
+		let idx = match self.points.iter().rposition(|p| p.out <= offset) {
+			Some(i) => i,
+			None => return Err(io::Error::new(io::ErrorKind::InvalidData,
+				"No restart point before the offset")),
+		};
+		let point = &self.points[idx];
+		// Raw deflate, the gzip header is behind us
+		let mut inflater = try!(Inflater::new(-15));
+		if point.bits > 0 {
+			try!(r.seek(SeekFrom::Start(point.input - 1)));
+			let byte = try!(r.read_u8());
+			try!(inflater.prime(point.bits, byte >> (8 - point.bits)));
+		} else {
+			try!(r.seek(SeekFrom::Start(point.input)));
+		}
+		if !point.window.is_empty() {
+			try!(inflater.set_dictionary(&point.window));
+		}
+		Ok(RangeReader {
+			inner : r,
+			inflater,
+			in_buf : vec![0; 16 * 1024],
+			in_pos : 0,
+			in_len : 0,
+			out_buf : vec![0; 32 * 1024],
+			out_pos : 0,
+			out_len : 0,
+			to_skip : offset - point.out,
+			remaining : len,
+			stream_end : false,
+		})
+	}
+	pub fn serialize<W :Write>(&self, mut wtr :W) -> io::Result<()> {
+		try!(wtr.write_all(INDEX_MAGIC));
+		try!(wtr.write_u64::<BigEndian>(self.archive_len));
+		try!(wtr.write_all(&self.archive_trailer));
+		try!(wtr.write_u64::<BigEndian>(self.points.len() as u64));
+		for p in self.points.iter() {
+			try!(wtr.write_u64::<BigEndian>(p.out));
+			try!(wtr.write_u64::<BigEndian>(p.input));
+			try!(wtr.write_u8(p.bits));
+			try!(write_delim_byte_slice(&mut wtr, &p.window));
+		}
+		try!(wtr.write_u64::<BigEndian>(self.entries.len() as u64));
+		for e in self.entries.iter() {
+			try!(write_delim_byte_slice(&mut wtr, e.meta.path.as_bytes()));
+			try!(wtr.write_u64::<BigEndian>(e.offset));
+			try!(wtr.write_u64::<BigEndian>(e.meta.size));
+			try!(wtr.write_u32::<BigEndian>(e.meta.mode));
+			try!(wtr.write_u64::<BigEndian>(e.meta.mtime));
+			try!(wtr.write_all(&e.meta.digest));
+			try!(wtr.write_u8(e.meta.is_dir as u8));
+		}
+		Ok(())
+	}
+	pub fn deserialize<R :Read>(mut rdr :R) -> io::Result<Self> {
+		let invalid = |msg| io::Error::new(io::ErrorKind::InvalidData, msg);
+		let mut magic = [0; 8];
+		try!(rdr.read_exact(&mut magic));
+		if &magic != INDEX_MAGIC {
+			return Err(invalid("Invalid magic"));
+		}
+		let archive_len = try!(rdr.read_u64::<BigEndian>());
+		let mut archive_trailer = [0; 8];
+		try!(rdr.read_exact(&mut archive_trailer));
+		let point_count = try!(rdr.read_u64::<BigEndian>());
+		let mut points = Vec::new();
+		for _ in 0 .. point_count {
+			let out = try!(rdr.read_u64::<BigEndian>());
+			let input = try!(rdr.read_u64::<BigEndian>());
+			// Only the bits of a partially consumed byte are kept
+			let bits = try!(rdr.read_u8());
+			if bits > 7 {
+				return Err(invalid("Too many bits"));
+			}
+			points.push(RestartPoint {
+				out,
+				input,
+				bits,
+				window : try!(read_delim_byte_slice_max(&mut rdr, WINDOW_SIZE as u64)),
+			});
+		}
+		let entry_count = try!(rdr.read_u64::<BigEndian>());
+		let mut entries = Vec::new();
+		for _ in 0 .. entry_count {
+			let path = try!(read_delim_byte_slice_max(&mut rdr, MAX_PATH_LEN));
+			let path = try!(String::from_utf8(path)
+				.map_err(|_| invalid("Path is not valid UTF-8")));
+			let offset = try!(rdr.read_u64::<BigEndian>());
+			let size = try!(rdr.read_u64::<BigEndian>());
+			let mode = try!(rdr.read_u32::<BigEndian>());
+			let mtime = try!(rdr.read_u64::<BigEndian>());
+			let mut digest = [0; 32];
+			try!(rdr.read_exact(&mut digest));
+			let is_dir = try!(rdr.read_u8()) != 0;
+			entries.push(IndexEntry {
+				meta : FileMetadata {
+					path,
+					size,
+					mode,
+					mtime,
+					digest,
+					is_dir,
+				},
+				offset,
+			});
+		}
+		Ok(GzIndex {
+			archive_len,
+			archive_trailer,
+			points,
+			entries,
+		})
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use std::io::Cursor;
+	use test_util::crate_archive;
+
+	/// Text that compresses, but not too well
+	fn pseudo_random_text(seed :u32, len :usize) -> Vec<u8> {
+		let mut state = seed;
+		(0 .. len).map(|_| {
+			state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+			b"abcdefghij \n"[(state >> 16) as usize % 12]
+		}).collect()
+	}
+
+	#[test]
+	fn random_access() {
+		let files = (0 .. 40u32)
+			.map(|i| (format!("src/file_{}.rs", i), pseudo_random_text(i, 10_000 + 997 * i as usize)))
+			.collect::<Vec<_>>();
+		let file_refs = files.iter()
+			.map(|(p, c)| (&p[..], &c[..]))
+			.collect::<Vec<_>>();
+		let archive = crate_archive("foo", "1.0.0", &file_refs);
+
+		let index = GzIndex::build_with_span(Cursor::new(&archive), 32 * 1024,
+			&ExtractionLimits::default()).unwrap();
+		assert!(index.points.len() > 4, "{} points", index.points.len());
+		assert!(index.points.iter().any(|p| p.bits != 0));
+
+		// Roundtrip through the serialized form
+		let mut serialized = Vec::new();
+		index.serialize(&mut serialized).unwrap();
+		let index = GzIndex::deserialize(serialized.as_slice()).unwrap();
+		assert!(index.matches(Cursor::new(&archive)).unwrap());
+		assert!(!index.matches(Cursor::new(&archive[1 ..])).unwrap());
+
+		// Restart points can't have more bits than a byte has
+		let mut bad_bits = serialized.clone();
+		bad_bits[48] = 8;
+		let e = GzIndex::deserialize(bad_bits.as_slice()).err().unwrap();
+		assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+
+		assert_eq!(index.file_metadata().count(), files.len());
+		for (path, content) in files.iter().rev() {
+			let path = format!("foo-1.0.0/{}", path);
+			let read = index.read_file(Cursor::new(&archive), &path).unwrap();
+			assert!(read.as_ref() == Some(content), "{}", path);
+		}
+		assert!(index.read_file(Cursor::new(&archive), "foo-1.0.0/src/nope.rs")
+			.unwrap().is_none());
+
+		// Reading in small pieces yields the same content
+		let (path, content) = &files[25];
+		let mut rdr = index.file_reader(Cursor::new(&archive),
+			&format!("foo-1.0.0/{}", path)).unwrap().unwrap();
+		let mut read = Vec::new();
+		let mut buf = [0; 100];
+		loop {
+			let n = rdr.read(&mut buf).unwrap();
+			if n == 0 {
+				break;
+			}
+			read.extend_from_slice(&buf[.. n]);
+		}
+		assert!(read == *content);
+	}
+}
@@ -1,6 +1,6 @@
 use hex;
 use ring::digest::{Context, SHA256};
-use std::io;
+use std::io::{self, Read, Write};
 
 pub type Digest = [u8; 32];
 
@@ -19,10 +19,17 @@ pub fn digest_from_hex(digest :&str) -> Option<Digest> {
 	}
 }
 
+/// Computes the SHA-256 digest of the given data
+pub fn digest_of(data :&[u8]) -> Digest {
+	let mut hctx = HashCtx::new();
+	io::copy(&mut &data[..], &mut hctx).unwrap();
+	hctx.finish_and_get_digest()
+}
+
 /// SHA-256 hash context that impls Write
 pub struct HashCtx(Context);
 
-impl io::Write for HashCtx {
+impl Write for HashCtx {
 	fn write(&mut self, data: &[u8]) -> Result<usize, io::Error> {
 		self.0.update(data);
 		Ok(data.len())
@@ -47,3 +54,59 @@ impl HashCtx {
 		res
 	}
 }
+
+/// Reader that checks the digest of the content it passes through
+///
+/// Once the end is reached, an `InvalidData` error is
+/// returned if the content doesn't match the digest.
+pub struct VerifyingReader<R :Read> {
+	inner :R,
+	hctx :Option<HashCtx>,
+	digest :Digest,
+}
+
+impl<R :Read> VerifyingReader<R> {
+	pub fn new(inner :R, digest :Digest) -> Self {
+		VerifyingReader {
+			inner,
+			hctx : Some(HashCtx::new()),
+			digest,
+		}
+	}
+}
+
+impl<R :Read> Read for VerifyingReader<R> {
+	fn read(&mut self, buf :&mut [u8]) -> io::Result<usize> {
+		let len = try!(self.inner.read(buf));
+		if len > 0 || buf.is_empty() {
+			if let Some(ref mut hctx) = self.hctx {
+				try!(hctx.write_all(&buf[.. len]));
+			}
+			return Ok(len);
+		}
+		if let Some(hctx) = self.hctx.take() {
+			if hctx.finish_and_get_digest() != self.digest {
+				return Err(io::Error::new(io::ErrorKind::InvalidData,
+					"Content doesn't match its digest"));
+			}
+		}
+		Ok(0)
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn verifying_reader() {
+		let data = b"Hello World";
+		let mut content = Vec::new();
+		VerifyingReader::new(&data[..], digest_of(data))
+			.read_to_end(&mut content).unwrap();
+		assert_eq!(&content[..], &data[..]);
+		let e = VerifyingReader::new(&data[..], [0; 32])
+			.read_to_end(&mut Vec::new()).unwrap_err();
+		assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+	}
+}
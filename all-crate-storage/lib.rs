@@ -16,18 +16,43 @@ extern crate multiqueue;
 extern crate hex;
 extern crate difference;
 extern crate petgraph;
+extern crate zstd;
+extern crate reqwest;
+extern crate libz_sys;
+extern crate libc;
+extern crate toml;
+extern crate lru_cache;
 #[macro_use]
 extern crate try;
 
 pub mod registry;
 pub mod crate_storage;
 pub mod blob_storage;
+pub mod blob_store;
+pub mod dir_blob_storage;
 pub mod blob_crate_storage;
 pub mod multi_blob_crate_storage;
 pub mod hash_ctx;
 pub mod reconstruction;
+pub mod extraction;
+pub mod error;
 pub mod diff;
 pub mod multi_blob;
+pub mod codec;
+mod sorted_table;
+pub mod verify;
+pub mod compaction;
+pub mod update_pack;
+pub mod merge;
+pub mod volumes;
+pub mod remote;
+pub mod sync;
+pub mod gz_index;
+pub mod read_at;
+pub mod cache;
+pub mod extracted_dir_storage;
 
 #[cfg(test)]
 mod blob_storage_test;
+#[cfg(test)]
+mod test_util;
@@ -0,0 +1,210 @@
+/*!
+Merging of blob crate storages
+
+Combines any number of storage files into one.
+Blobs are copied over as they are, and deduplicated by digest.
+Storages may use different codecs and dictionaries, or contain
+multi blobs. Dictionaries whose id is already taken by a different
+dictionary get a new id, and the blobs compressed with them are
+changed to refer to the new id.
+
+If storages disagree about the content of a crate,
+the crate is reconstructed from each of them, and the copy
+that matches the checksum from the registry index wins.
+*/
+
+use std::collections::{BTreeMap, HashMap};
+use super::blob_crate_storage::BlobCrateStorage;
+use super::blob_storage::StorageError;
+use super::blob_store::{BlobStore, BlobStoreMut};
+use super::codec;
+use super::crate_storage::{CrateSource, CrateSpec};
+use super::hash_ctx::{Digest, digest_of};
+
+/// Statistics about a merge
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct MergeStats {
+	pub crates :usize,
+	pub blobs :usize,
+	/// Number of crates the storages disagreed about
+	pub conflicts :usize,
+	/// Number of conflicts where no copy matched the checksum
+	///
+	/// For those, the copy of the first storage is used.
+	pub unresolved :usize,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+enum CrateEntry {
+	/// Metadata blob of a crate stored as separate blobs
+	Named(Digest),
+	/// Blob of a crate stored as it is
+	Raw(Digest),
+}
+
+/// Merges the dictionaries of the source into `dst`
+///
+/// Returns the ids that need to be changed in the blobs of the source.
+fn merge_dictionaries<B :BlobStore, C :BlobStoreMut>(
+		dst :&mut BlobCrateStorage<C>,
+		src :&BlobCrateStorage<B>) -> HashMap<u32, u32> {
+	let mut remap = HashMap::new();
+	for (&id, &d) in src.b.dictionaries().iter() {
+		if let Some((&dst_id, _)) = dst.b.dictionaries().iter().find(|&(_, dst_d)| *dst_d == d) {
+			// The dictionary is present already, possibly with another id
+			if dst_id != id {
+				remap.insert(id, dst_id);
+			}
+			continue;
+		}
+		let new_id = if dst.b.dictionaries().contains_key(&id) {
+			let new_id = dst.b.dictionaries().keys().max().unwrap() + 1;
+			remap.insert(id, new_id);
+			new_id
+		} else {
+			id
+		};
+		dst.b.insert_dictionary(new_id, d);
+	}
+	remap
+}
+
+/// Copies all blobs of the source that are not present in `dst` yet
+fn copy_blobs<B :BlobStore, C :BlobStoreMut>(
+		dst :&mut BlobCrateStorage<C>, src :&mut BlobCrateStorage<B>,
+		remap :&HashMap<u32, u32>) -> Result<usize, StorageError> {
+	let mut copied = 0;
+	for d in try!(src.b.digests()) {
+		if try!(dst.b.has(&d)) {
+			continue;
+		}
+		let mut blob = try!(src.b.get(&d)).unwrap();
+		if let Some(new_id) = codec::needed_dictionary(&blob).and_then(|id| remap.get(&id)) {
+			codec::replace_dictionary_id(&mut blob, *new_id);
+		}
+		try!(dst.b.insert(d, &blob));
+		copied += 1;
+	}
+	for (d, mblob_d) in try!(src.b.multi_blob_entries()) {
+		if !try!(dst.b.has(&d)) && try!(dst.b.get_multi_blob(&d)).is_none() {
+			dst.b.insert_multi_blob(d, mblob_d);
+		}
+	}
+	Ok(copied)
+}
+
+/// Merges the storages in `srcs` into `dst`
+///
+/// `checksum` is asked for the checksum of a crate
+/// if the storages disagree about its content.
+/// Afterwards, the index of `dst` is written.
+pub fn merge<B, C, F>(dst :&mut BlobCrateStorage<C>, srcs :&mut [BlobCrateStorage<B>],
+		mut checksum :F) -> Result<MergeStats, StorageError>
+		where B :BlobStore, C :BlobStoreMut,
+		F :FnMut(&CrateSpec) -> Option<Digest> {
+	let mut stats = MergeStats::default();
+	let mut entries = BTreeMap::new();
+	for (i, src) in srcs.iter_mut().enumerate() {
+		try!(dst.add_features_of(src));
+		let remap = merge_dictionaries(dst, src);
+		stats.blobs += try!(copy_blobs(dst, src, &remap));
+
+		let names = try!(src.b.names()).into_iter()
+			.map(|(name, d)| (name, CrateEntry::Named(d)));
+		let raw_crates = try!(src.b.raw_crates()).into_iter()
+			.map(|(name, d)| (name, CrateEntry::Raw(d)));
+		for (name, entry) in names.chain(raw_crates) {
+			entries.entry(name).or_insert_with(Vec::new).push((i, entry));
+		}
+	}
+	for (name, candidates) in entries {
+		let (_, mut chosen) = candidates[0];
+		if candidates.iter().any(|&(_, e)| e != chosen) {
+			stats.conflicts += 1;
+			let spec = CrateSpec::from_file_name(&name);
+			let expected = spec.as_ref().and_then(&mut checksum);
+			let matching = match (spec, expected) {
+				(Some(spec), Some(expected)) => candidates.iter()
+					.find(|&&(i, _)| {
+						srcs[i].get_crate(&spec).ok().map(|c| digest_of(&c)) == Some(expected)
+					})
+					.map(|&(_, e)| e),
+				_ => None,
+			};
+			match matching {
+				Some(e) => chosen = e,
+				None => stats.unresolved += 1,
+			}
+		}
+		match chosen {
+			CrateEntry::Named(d) => dst.b.insert_name(name, d),
+			CrateEntry::Raw(d) => dst.b.insert_raw_crate(name, d),
+		}
+		stats.crates += 1;
+	}
+	try!(dst.store());
+	Ok(stats)
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use std::io::Cursor;
+	use blob_storage::StorageErrorKind;
+	use codec::Codec;
+	use test_util::{test_crate, storage_with};
+
+	fn crate_with_files(name :&str, version :&str, file_count :usize) -> (CrateSpec, Vec<u8>) {
+		let files = (0 .. file_count)
+			.map(|i| (format!("src/m{}.rs", i),
+				format!("pub fn function_{}() -> u32 {{\n\t{}\n}}\n", i, i * 7)))
+			.collect::<Vec<_>>();
+		let files = files.iter()
+			.map(|(p, c)| (&p[..], c.as_bytes()))
+			.collect::<Vec<_>>();
+		test_crate(name, version, &files)
+	}
+
+	#[test]
+	fn merge_storages() {
+		let foo = crate_with_files("foo", "1.0.0", 300);
+		let bar = crate_with_files("bar", "1.0.0", 300);
+		let baz = crate_with_files("baz", "1.0.0", 3);
+		// Both zstd storages train a dictionary with id 1
+		let mut srcs = vec![
+			storage_with(Codec::Zstd, &[foo.clone(), baz.clone()]),
+			storage_with(Codec::Zstd, std::slice::from_ref(&bar)),
+			storage_with(Codec::Gzip, std::slice::from_ref(&baz)),
+		];
+		// Make the last storage disagree about baz
+		let bogus = srcs[0].b.get_named(&foo.0.file_name()).unwrap().unwrap();
+		srcs[2].b.insert_name(baz.0.file_name(), bogus);
+
+		let mut dst = BlobCrateStorage::empty(Cursor::new(Vec::new()));
+		let baz_checksum = digest_of(&baz.1);
+		let stats = merge(&mut dst, &mut srcs, |s| {
+			if s.name == "baz" { Some(baz_checksum) } else { None }
+		}).unwrap();
+		assert_eq!(stats.crates, 3);
+		assert_eq!(stats.conflicts, 1);
+		assert_eq!(stats.unresolved, 0);
+		assert_eq!(dst.b.dictionaries().len(), 2);
+		for (s, c) in [foo, bar, baz].iter() {
+			assert_eq!(dst.get_crate(s).ok().as_ref(), Some(c));
+		}
+	}
+
+	#[test]
+	fn mixed_diff_formats() {
+		// Multi blobs written before diffs were prefixed with their kind
+		let mut legacy = storage_with(Codec::Gzip, &[]);
+		legacy.b.insert_multi_blob([1; 32], [2; 32]);
+		let mut current = storage_with(Codec::Gzip, &[]);
+		current.use_diff_format(true).unwrap();
+		current.b.insert_multi_blob([3; 32], [4; 32]);
+
+		let mut dst = BlobCrateStorage::empty(Cursor::new(Vec::new()));
+		let e = merge(&mut dst, &mut [legacy, current], |_| None).unwrap_err();
+		assert_eq!(*e.get_context(), StorageErrorKind::MixedDiffFormats);
+	}
+}
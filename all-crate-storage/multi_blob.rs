@@ -5,69 +5,242 @@ This structure stores multiple blobs by storing the
 first blob directly, and then expressing the other
 blobs via a tree structure, only storing the edges
 between the vertices via diffs.
+
+The trees are obtained by computing a minimum spanning
+tree over the candidate edges, weighted by the size of
+the diff, and then splitting it up into units whose
+chains don't exceed a given depth. This way, obtaining
+any blob from a multiblob requires a bounded number
+of diff applications.
+
+Edges between two text blobs are stored as line based
+diffs, all other edges as byte oriented deltas.
 */
 
 use hash_ctx::Digest;
-use super::diff::Diff;
+use super::diff::{Diff, ByteDiff};
+use std::str;
 use std::io::Result as IoResult;
-use std::io::{Read, Write};
+use std::io::{self, Read, Write};
+use std::collections::{HashMap, HashSet, VecDeque};
 use byteorder::{ReadBytesExt, WriteBytesExt, BigEndian};
+use petgraph::graph::UnGraph;
+use petgraph::algo::min_spanning_tree;
+use petgraph::data::Element;
 use super::blob_storage::{write_delim_byte_slice, read_delim_byte_slice};
 
+macro_rules! optry {
+	($e:expr) => {
+		match $e {
+			Some(d) => d,
+			None => return None,
+		}
+	};
+}
+
+/// A diff between two blobs of a multiblob
+pub enum BlobDiff {
+	/// Line based diff between two text blobs
+	Text(Diff),
+	/// Byte oriented delta, usable for any blob
+	Bytes(ByteDiff),
+}
+
+impl BlobDiff {
+	/// Computes the diff between two blobs
+	///
+	/// A text diff is used if both blobs are valid UTF-8.
+	pub fn from_blobs(old :&[u8], new :&[u8]) -> Self {
+		match (str::from_utf8(old), str::from_utf8(new)) {
+			(Ok(old), Ok(new)) => BlobDiff::Text(Diff::from_texts_nl(old, new)),
+			_ => BlobDiff::Bytes(ByteDiff::from_bytes(old, new)),
+		}
+	}
+	pub fn reconstruct_new(&self, old :&[u8]) -> Option<Vec<u8>> {
+		match self {
+			BlobDiff::Text(diff) => {
+				let old = optry!(str::from_utf8(old).ok());
+				Some(optry!(diff.reconstruct_new(old)).into_bytes())
+			},
+			BlobDiff::Bytes(diff) => diff.reconstruct_new(old),
+		}
+	}
+	/// Deserializes a diff
+	///
+	/// Multi blobs written before the `FEATURE_DIFF_KINDS` flag only
+	/// contain text diffs without a kind byte. `diff_kinds`
+	/// tells whether the storage has that flag set.
+	pub fn deserialize<R :Read>(mut rdr :R, diff_kinds :bool) -> IoResult<Self> {
+		if !diff_kinds {
+			return Ok(BlobDiff::Text(try!(Diff::deserialize(rdr))));
+		}
+		let kind = try!(rdr.read_u8());
+		match kind {
+			1 => Ok(BlobDiff::Text(try!(Diff::deserialize(rdr)))),
+			2 => Ok(BlobDiff::Bytes(try!(ByteDiff::deserialize(rdr)))),
+			_ => Err(io::Error::new(io::ErrorKind::InvalidData,
+				format!("Invalid diff kind {}", kind))),
+		}
+	}
+	pub fn serialize<W :Write>(&self, mut wtr :W) -> IoResult<()> {
+		match self {
+			BlobDiff::Text(diff) => {
+				try!(wtr.write_u8(1));
+				diff.serialize(wtr)
+			},
+			BlobDiff::Bytes(diff) => {
+				try!(wtr.write_u8(2));
+				diff.serialize(wtr)
+			},
+		}
+	}
+}
+
 pub struct MultiBlob {
-	pub(crate) root_blob :(Digest, String),
-	/// The tree expressed in DFS traversal form.
+	pub(crate) root_blob :(Digest, Vec<u8>),
+	/// The tree expressed as a list of edges
 	///
-	/// In order to get the path to the root,
-	/// you should traverse this list in a reverse
-	/// fashion.
-	pub(crate) diff_list :Vec<(Digest, Digest, Diff)>,
+	/// Each entry contains the digest of the parent,
+	/// the digest of the child, and the diff that
+	/// obtains the child from the parent.
+	/// Parents are always listed before their children.
+	pub(crate) diff_list :Vec<(Digest, Digest, BlobDiff)>,
 }
 
+
 impl MultiBlob {
-	pub fn get_blob(&self, d :Digest) -> Option<String> {
+	pub fn get_blob(&self, d :Digest) -> Option<Vec<u8>> {
+		let parents = self.diff_list.iter()
+			.map(|(parent, child, diff)| (child, (parent, diff)))
+			.collect::<HashMap<_, _>>();
 		// Assemble the diffs
 		let mut needed = d;
 		let mut diffs = Vec::new();
-		for cr in self.diff_list.iter().rev() {
-			if cr.0 != needed {
-				continue;
+		while needed != self.root_blob.0 {
+			// A chain can't be longer than the list of edges,
+			// unless the deserialized list contains a cycle
+			if diffs.len() >= self.diff_list.len() {
+				return None;
 			}
-			needed = cr.1;
-			diffs.push(&cr.2);
+			let (parent, diff) = match parents.get(&needed) {
+				Some(p) => *p,
+				None => return None,
+			};
+			diffs.push(diff);
+			needed = *parent;
 		}
 		// Resolve the diffs
-		if needed != self.root_blob.0 {
-			return None;
-		}
 		let mut res = self.root_blob.1.clone();
-		for diff in diffs.iter() {
-			res = diff.reconstruct_new(&res);
+		for diff in diffs.iter().rev() {
+			res = optry!(diff.reconstruct_new(&res));
 		}
 		Some(res)
 	}
-	pub fn deserialize<R :Read>(mut rdr :R) -> IoResult<Self> {
+	/// Returns the digests of all blobs stored in the multiblob
+	pub fn digests(&self) -> Vec<Digest> {
+		let mut res = vec![self.root_blob.0];
+		res.extend(self.diff_list.iter().map(|&(_, child, _)| child));
+		res
+	}
+	/// Returns the length of the longest chain of diffs
+	pub fn chain_depth(&self) -> usize {
+		let mut depths = HashMap::new();
+		depths.insert(self.root_blob.0, 0);
+		let mut max_depth = 0;
+		for (parent, child, _) in self.diff_list.iter() {
+			let depth = depths[parent] + 1;
+			depths.insert(*child, depth);
+			max_depth = max_depth.max(depth);
+		}
+		max_depth
+	}
+	/// Creates multiblobs from a set of blobs and the candidate edges between them
+	///
+	/// The edges are given as indices into the blob list.
+	/// A minimum spanning forest is computed over the candidate
+	/// edges, with the size of the diff between two blobs as weight.
+	/// Each tree of the forest is then split up into units so that
+	/// no chain of diffs inside a unit is longer than `max_chain_depth`.
+	pub fn from_blobs(blobs :&[(Digest, Vec<u8>)], edges :&[(usize, usize)],
+			max_chain_depth :usize) -> Vec<MultiBlob> {
+		let mut graph = UnGraph::<usize, usize>::with_capacity(blobs.len(), edges.len());
+		let nodes = (0 .. blobs.len())
+			.map(|i| graph.add_node(i))
+			.collect::<Vec<_>>();
+		let mut diffs = HashMap::new();
+		for &(a, b) in edges.iter() {
+			if a == b || diffs.contains_key(&(a, b)) || diffs.contains_key(&(b, a)) {
+				continue;
+			}
+			let diff = BlobDiff::from_blobs(&blobs[a].1, &blobs[b].1);
+			let mut diff_blob = Vec::new();
+			diff.serialize(&mut diff_blob).unwrap();
+			graph.add_edge(nodes[a], nodes[b], diff_blob.len());
+			diffs.insert((a, b), diff);
+		}
+
+		let mut adjacency = vec![Vec::new(); blobs.len()];
+		for elem in min_spanning_tree(&graph) {
+			if let Element::Edge { source, target, .. } = elem {
+				adjacency[source].push(target);
+				adjacency[target].push(source);
+			}
+		}
+
+		let mut visited = vec![false; blobs.len()];
+		let mut res = Vec::new();
+		for start in 0 .. blobs.len() {
+			if visited[start] {
+				continue;
+			}
+			let root = tree_center(&adjacency, start);
+			let order = bfs_order(&adjacency, root);
+			for &(n, _) in order.iter() {
+				visited[n] = true;
+			}
+			for unit in split_tree(&order, max_chain_depth) {
+				let (unit_root, _) = unit[0];
+				let diff_list = unit[1 ..].iter()
+					.map(|&(child, parent)| {
+						let diff = match diffs.remove(&(parent, child)) {
+							Some(diff) => diff,
+							None => BlobDiff::from_blobs(&blobs[parent].1, &blobs[child].1),
+						};
+						(blobs[parent].0, blobs[child].0, diff)
+					})
+					.collect::<Vec<_>>();
+				res.push(MultiBlob {
+					root_blob : blobs[unit_root].clone(),
+					diff_list,
+				});
+			}
+		}
+		res
+	}
+	/// Deserializes a multi blob
+	///
+	/// See `BlobDiff::deserialize` for the meaning of `diff_kinds`.
+	pub fn deserialize<R :Read>(mut rdr :R, diff_kinds :bool) -> IoResult<Self> {
 		let mut root_digest :Digest = [0; 32];
 		try!(rdr.read_exact(&mut root_digest));
 		let root_sl = try!(read_delim_byte_slice(&mut rdr));
-		// TODO get rid of unwrap here
-		let root_s = String::from_utf8(root_sl).unwrap();
-		let root_blob = (root_digest, root_s);
+		let root_blob = (root_digest, root_sl);
 		let len = try!(rdr.read_u64::<BigEndian>());
-		let mut diff_list = Vec::with_capacity(len as usize);
+		// The length is untrusted, so don't preallocate for it
+		let mut diff_list = Vec::new();
 		for _ in 0 .. len {
 			let mut digest_a :Digest = [0; 32];
 			try!(rdr.read_exact(&mut digest_a));
 			let mut digest_b :Digest = [0; 32];
 			try!(rdr.read_exact(&mut digest_b));
-			let diff = try!(Diff::deserialize(&mut rdr));
+			let diff = try!(BlobDiff::deserialize(&mut rdr, diff_kinds));
 			diff_list.push((digest_a, digest_b, diff));
 		}
 		Ok(MultiBlob { root_blob, diff_list })
 	}
 	pub fn serialize<W :Write>(&self, mut wtr :W) -> IoResult<()> {
 		try!(wtr.write(&self.root_blob.0));
-		try!(write_delim_byte_slice(&mut wtr, self.root_blob.1.as_bytes()));
+		try!(write_delim_byte_slice(&mut wtr, &self.root_blob.1));
 		try!(wtr.write_u64::<BigEndian>(self.diff_list.len() as u64));
 		for d in self.diff_list.iter() {
 			try!(wtr.write(&d.0));
@@ -77,3 +250,195 @@ impl MultiBlob {
 		Ok(())
 	}
 }
+
+/// Returns the nodes of the tree containing `start` in BFS order,
+/// each together with its parent (the root is its own parent).
+fn bfs_order(adjacency :&[Vec<usize>], start :usize) -> Vec<(usize, usize)> {
+	let mut seen = HashSet::new();
+	let mut order = Vec::new();
+	let mut queue = VecDeque::new();
+	seen.insert(start);
+	queue.push_back((start, start));
+	while let Some((n, parent)) = queue.pop_front() {
+		order.push((n, parent));
+		for &neigh in adjacency[n].iter() {
+			if seen.insert(neigh) {
+				queue.push_back((neigh, n));
+			}
+		}
+	}
+	order
+}
+
+/// Finds the center of the tree containing `start`
+///
+/// Rooting the tree at its center minimizes its height,
+/// which keeps the number of units we split it into low.
+fn tree_center(adjacency :&[Vec<usize>], start :usize) -> usize {
+	// The last node in BFS order is one end of a longest path
+	let end_a = bfs_order(adjacency, start).last().unwrap().0;
+	let order = bfs_order(adjacency, end_a);
+	let parents = order.iter().cloned().collect::<HashMap<_, _>>();
+	let mut path = vec![order.last().unwrap().0];
+	while let Some(&last) = path.last() {
+		let parent = parents[&last];
+		if parent == last {
+			break;
+		}
+		path.push(parent);
+	}
+	path[path.len() / 2]
+}
+
+/// Splits a tree given in BFS order into units of bounded chain depth
+///
+/// The greedy bottom-up approach cuts off a subtree
+/// exactly when its height reaches `max_depth`, which
+/// yields the minimum number of units.
+/// Each unit is again in BFS order, its first entry being its root.
+fn split_tree(order :&[(usize, usize)], max_depth :usize) -> Vec<Vec<(usize, usize)>> {
+	let mut heights = HashMap::new();
+	let mut unit_roots = HashSet::new();
+	for &(n, parent) in order.iter().rev() {
+		let height = *heights.get(&n).unwrap_or(&0);
+		let is_root = n == parent;
+		if is_root || height >= max_depth {
+			unit_roots.insert(n);
+			continue;
+		}
+		let parent_height = heights.entry(parent).or_insert(0);
+		*parent_height = (*parent_height).max(height + 1);
+	}
+	let mut unit_of = HashMap::new();
+	let mut units :Vec<Vec<(usize, usize)>> = Vec::new();
+	for &(n, parent) in order.iter() {
+		if unit_roots.contains(&n) {
+			unit_of.insert(n, units.len());
+			units.push(vec![(n, n)]);
+		} else {
+			let unit = unit_of[&parent];
+			unit_of.insert(n, unit);
+			units[unit].push((n, parent));
+		}
+	}
+	units
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	fn blob(i :u8, s :String) -> (Digest, Vec<u8>) {
+		([i; 32], s.into_bytes())
+	}
+
+	#[test]
+	fn test_chain_depth_bounded() {
+		// A long chain of versions, each adding a line
+		let mut content = String::new();
+		let mut blobs = Vec::new();
+		for i in 0 .. 40 {
+			content += &format!("line number {}\n", i);
+			blobs.push(blob(i, content.clone()));
+		}
+		let edges = (1 .. blobs.len())
+			.map(|i| (i - 1, i))
+			.collect::<Vec<_>>();
+		for &max_depth in [1, 3, 8, 100].iter() {
+			let mbs = MultiBlob::from_blobs(&blobs, &edges, max_depth);
+			let mut seen = 0;
+			for mb in mbs.iter() {
+				assert!(mb.chain_depth() <= max_depth);
+				for d in mb.digests() {
+					let i = d[0] as usize;
+					assert_eq!(mb.get_blob(d).as_ref(), Some(&blobs[i].1));
+					seen += 1;
+				}
+			}
+			assert_eq!(seen, blobs.len());
+		}
+	}
+
+	#[test]
+	fn test_cyclic_diff_list() {
+		let blobs = vec![blob(0, "a\n".to_owned()), blob(1, "a\nb\n".to_owned()),
+			blob(2, "a\nb\nc\n".to_owned())];
+		let mut mb = MultiBlob::from_blobs(&blobs, &[(0, 1), (1, 2)], 8).pop().unwrap();
+		// Make the two non-root blobs each other's parents
+		let (c0, c1) = (mb.diff_list[0].1, mb.diff_list[1].1);
+		mb.diff_list[0].0 = c1;
+		mb.diff_list[1].0 = c0;
+		assert_eq!(mb.get_blob(c0), None);
+	}
+
+	#[test]
+	fn test_binary_blobs() {
+		let mut content = (0 .. 2000u32).map(|v| (v * 13 % 256) as u8).collect::<Vec<u8>>();
+		let mut blobs = Vec::new();
+		for i in 0 .. 10 {
+			content[i * 100] = 0xff;
+			blobs.push(([i as u8; 32], content.clone()));
+		}
+		// Mix in a text blob
+		blobs.push(blob(10, "text\n".to_owned()));
+		let edges = (1 .. blobs.len())
+			.map(|i| (i - 1, i))
+			.collect::<Vec<_>>();
+		let mbs = MultiBlob::from_blobs(&blobs, &edges, 16);
+		assert_eq!(mbs.len(), 1);
+		for mb in mbs.iter() {
+			let mut v = Vec::new();
+			mb.serialize(&mut v).unwrap();
+			let mb_reconstructed = MultiBlob::deserialize(v.as_slice(), true).unwrap();
+			for d in mb.digests() {
+				let i = d[0] as usize;
+				assert_eq!(mb_reconstructed.get_blob(d).as_ref(), Some(&blobs[i].1));
+			}
+		}
+		let total_len :usize = blobs.iter().map(|b| b.1.len()).sum();
+		let mut serialized = Vec::new();
+		for mb in mbs.iter() {
+			mb.serialize(&mut serialized).unwrap();
+		}
+		assert!(serialized.len() < total_len / 3);
+	}
+
+	#[test]
+	fn test_spanning_tree_ser_de() {
+		let blobs = vec![
+			blob(0, "a\nb\nc\n".to_owned()),
+			blob(1, "a\nb\nc\nd\n".to_owned()),
+			blob(2, "a\nc\nd\n".to_owned()),
+			blob(3, "unrelated\n".to_owned()),
+		];
+		let edges = [(0, 1), (1, 2), (0, 2)];
+		let mbs = MultiBlob::from_blobs(&blobs, &edges, 5);
+		// Two trees: one for the related blobs, one for the unrelated one
+		assert_eq!(mbs.len(), 2);
+		for mb in mbs.iter() {
+			let mut v = Vec::new();
+			mb.serialize(&mut v).unwrap();
+			let mb_reconstructed = MultiBlob::deserialize(v.as_slice(), true).unwrap();
+			for d in mb.digests() {
+				let i = d[0] as usize;
+				assert_eq!(mb_reconstructed.get_blob(d).as_ref(), Some(&blobs[i].1));
+			}
+		}
+	}
+
+	#[test]
+	fn test_legacy_text_diffs() {
+		let old = "a\nb\nc\n";
+		let new = "a\nc\nd\n";
+		// Before diff kinds, text diffs were stored without a kind byte
+		let mut v = Vec::new();
+		Diff::from_texts_nl(old, new).serialize(&mut v).unwrap();
+		let diff = BlobDiff::deserialize(v.as_slice(), false).unwrap();
+		assert_eq!(diff.reconstruct_new(old.as_bytes()), Some(new.as_bytes().to_vec()));
+
+		let mut v = vec![3];
+		Diff::from_texts_nl(old, new).serialize(&mut v).unwrap();
+		let e = BlobDiff::deserialize(v.as_slice(), true).err().unwrap();
+		assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+	}
+}
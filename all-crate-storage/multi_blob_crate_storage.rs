@@ -4,20 +4,21 @@ Embedding multi blob  into blob crate storages
 
 In the first step, we create a directed graph of blobs.
 In the second step, we determine minimum spanning trees
+for each connected component and split them up into
+units of bounded chain depth (see the multi_blob module).
 
 */
 
 use hash_ctx::Digest;
 use std::collections::{HashMap, HashSet};
 use std::collections::hash_map::Entry;
-use std::io::{self, Read, Seek};
 use semver::Version;
 use petgraph::graph::{Graph, NodeIndex};
 use registry::registry::AllCratesJson;
 use crate_storage::{CrateSource, CrateSpec};
 use blob_crate_storage::BlobCrateStorage;
+use blob_store::BlobStore;
 
-use super::hash_ctx::HashCtx;
 
 /**
 A grah of blobs
@@ -32,6 +33,20 @@ pub struct GraphOfBlobs {
 	pub roots :HashSet<NodeIndex>,
 }
 
+/// A connected component of the graph of blobs
+pub struct BlobComponent {
+	pub digests :Vec<Digest>,
+	/// The edges of the component, as indices into `digests`
+	pub edges :Vec<(usize, usize)>,
+}
+
+/// The number of prior versions of a file that each version is connected to
+///
+/// Connecting to more than just the directly preceding
+/// version gives the spanning tree computation something
+/// to choose from, e.g. if a change got reverted.
+const EDGE_WINDOW :usize = 3;
+
 macro_rules! optry {
 	($e:expr) => {
 		match $e {
@@ -42,8 +57,12 @@ macro_rules! optry {
 }
 
 impl GraphOfBlobs {
+	/// Builds the graph from the digests of the entries of each crate
+	///
+	/// Entries without a path, like the ones holding long names
+	/// of other entries, are added as nodes without edges.
 	pub fn from_func(acj :&AllCratesJson,
-			mut get_digest_list :impl FnMut(&str, &Version) -> Option<Vec<(Digest, String)>>)
+			mut get_digest_list :impl FnMut(&str, &Version) -> Option<Vec<(Digest, Option<String>)>>)
 			-> GraphOfBlobs {
 		/// Strips the first component of a path
 		fn strip_path<'a>(path :&'a str, name :&str, version :&Version) -> &'a str {
@@ -62,10 +81,10 @@ impl GraphOfBlobs {
 				let digest_list = get_digest_list(&krate.name, &krate.version);
 				if let Some(digest_list) = digest_list {
 					for (digest, path) in digest_list.iter() {
-						// TODO find a way to store these long file names
-						if path == "././@LongLink" {
-							continue;
-						}
+						let path = match *path {
+							Some(ref p) => p,
+							None => continue,
+						};
 						let path_stripped = strip_path(path, &krate.name, &krate.version)
 							.to_owned();
 						let digests = path_to_digests.entry(path_stripped)
@@ -95,14 +114,14 @@ impl GraphOfBlobs {
 				ordered_digests.sort_by_key(|digest| {
 					digest_to_version.get(*digest).unwrap()
 				});
-				let mut node_id_prior = None;
-				for digest in ordered_digests {
-					let node_id = *digest_to_node_id.get(digest).unwrap();
-					if let Some(prior) = node_id_prior {
-						roots.remove(&node_id);
-						graph.add_edge(prior, node_id, ());
+				let node_ids = ordered_digests.iter()
+					.map(|digest| *digest_to_node_id.get(*digest).unwrap())
+					.collect::<Vec<_>>();
+				for (i, node_id) in node_ids.iter().enumerate() {
+					for prior in node_ids[i.saturating_sub(EDGE_WINDOW) .. i].iter() {
+						roots.remove(node_id);
+						graph.add_edge(*prior, *node_id, ());
 					}
-					node_id_prior = Some(node_id);
 				}
 			}
 		}
@@ -112,39 +131,64 @@ impl GraphOfBlobs {
 		}
 	}
 
-	pub fn from_crate_source<C :CrateSource>(acj :&AllCratesJson, src :&mut C) -> GraphOfBlobs {
+	/// Obtains the connected components of the graph
+	///
+	/// The direction of the edges is ignored for this.
+	pub fn components(&self) -> Vec<BlobComponent> {
+		let graph = &self.graph;
+		let mut res = Vec::with_capacity(self.roots.len());
+		let mut visited = HashSet::new();
+		// Not every component needs to have a root as there
+		// may be cycles, so walk all the nodes.
+		for start in graph.node_indices() {
+			if visited.contains(&start) {
+				continue;
+			}
+			let mut index_in_component = HashMap::new();
+			let mut digests = Vec::new();
+			let mut to_walk = vec![start];
+			while let Some(n) = to_walk.pop() {
+				if !visited.insert(n) {
+					continue;
+				}
+				index_in_component.insert(n, digests.len());
+				digests.push(*graph.node_weight(n).unwrap());
+				to_walk.extend(graph.neighbors_undirected(n));
+			}
+			let edges = index_in_component.iter()
+				.flat_map(|(n, i)| {
+					let index_in_component = &index_in_component;
+					graph.neighbors(*n)
+						.map(move |neigh| (*i, index_in_component[&neigh]))
+				})
+				.collect::<Vec<_>>();
+			res.push(BlobComponent {
+				digests,
+				edges,
+			});
+		}
+		res
+	}
+
+	pub fn from_crate_source<C :CrateSource>(acj :&AllCratesJson, src :&C) -> GraphOfBlobs {
 		GraphOfBlobs::from_func(acj, |name :&str, version :&Version| {
 			println!("name {} v {}", name, version);
-			// TODO instead of obtaining the blobs,
-			//      extend the CrateSource trait or the CrateHandle trait
-			//      to include a way to obtain this directly.
-			//      Some format store the digests along the metadata.
-			// TODO don't use unwrap here
-			let mut handle = optry!(src.get_crate_handle_nv(name.to_string(), version.clone()));
-			let file_list = handle.get_file_list();
-			Some(file_list.into_iter()
-				.map(|path| {
-					let file = handle.get_file(&path).unwrap();
-					let mut file_rdr = &*file;
-					let mut hash_ctx = HashCtx::new();
-					io::copy(&mut file_rdr, &mut hash_ctx).unwrap();
-					let digest = hash_ctx.finish_and_get_digest();
-					(digest, path)
-				})
+			let handle = optry!(src.get_crate_handle_nv(name.to_string(), version.clone()).ok());
+			Some(optry!(handle.get_file_metadata_list().ok()).into_iter()
+				.map(|m| (m.digest, Some(m.path)))
 				.collect::<Vec<_>>())
 		})
 	}
 
-	pub fn from_blob_crate_storage<S :Read + Seek>(acj :&AllCratesJson,
-			src :&mut BlobCrateStorage<S>) -> GraphOfBlobs {
+	pub fn from_blob_crate_storage<B :BlobStore>(acj :&AllCratesJson,
+			src :&mut BlobCrateStorage<B>) -> GraphOfBlobs {
 		GraphOfBlobs::from_func(acj, |name :&str, version :&Version| {
 			//println!("name {} v {}", name, version);
 			let s = CrateSpec {
 				name : name.to_string(),
 				version : version.clone(),
 			};
-			// TODO treat LongLink
-			let meta = optry!(src.get_crate_rec_meta(&s));
+			let meta = optry!(src.get_crate_rec_meta(&s).ok());
 			Some(meta.get_file_digest_list())
 		})
 	}
@@ -0,0 +1,151 @@
+/*!
+Positional reads
+
+Reading through `Read` and `Seek` needs mutable access to the
+reader, as the position is part of it. So that a storage can be
+shared between threads, storages are read via positional reads
+instead, like `pread` does them: each read names the offset
+it starts at.
+
+The code parsing the storage works with `Read` and `Seek`,
+so `ReadAtCursor` provides them on top of a shared
+reference, with a position of its own.
+*/
+
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Cursor};
+
+pub trait ReadAt {
+	/// Reads bytes starting at `offset`, returning how many have been read
+	///
+	/// Like `Read::read`, it may read less than requested,
+	/// and zero bytes are read at the end of the content.
+	fn read_at(&self, buf :&mut [u8], offset :u64) -> io::Result<usize>;
+	/// Returns the length of the content
+	fn content_len(&self) -> io::Result<u64>;
+}
+
+impl ReadAt for File {
+	#[cfg(unix)]
+	fn read_at(&self, buf :&mut [u8], offset :u64) -> io::Result<usize> {
+		use std::os::unix::fs::FileExt;
+		FileExt::read_at(self, buf, offset)
+	}
+	#[cfg(windows)]
+	fn read_at(&self, buf :&mut [u8], offset :u64) -> io::Result<usize> {
+		// Note that this moves the cursor of the file
+		use std::os::windows::fs::FileExt;
+		self.seek_read(buf, offset)
+	}
+	fn content_len(&self) -> io::Result<u64> {
+		Ok(try!(self.metadata()).len())
+	}
+}
+
+impl<T :AsRef<[u8]>> ReadAt for Cursor<T> {
+	fn read_at(&self, buf :&mut [u8], offset :u64) -> io::Result<usize> {
+		let content = self.get_ref().as_ref();
+		let start = (offset as usize).min(content.len());
+		let len = buf.len().min(content.len() - start);
+		buf[.. len].copy_from_slice(&content[start .. start + len]);
+		Ok(len)
+	}
+	fn content_len(&self) -> io::Result<u64> {
+		Ok(self.get_ref().as_ref().len() as u64)
+	}
+}
+
+impl<R :ReadAt + ?Sized> ReadAt for &R {
+	fn read_at(&self, buf :&mut [u8], offset :u64) -> io::Result<usize> {
+		(**self).read_at(buf, offset)
+	}
+	fn content_len(&self) -> io::Result<u64> {
+		(**self).content_len()
+	}
+}
+
+impl<R :ReadAt + ?Sized> ReadAt for &mut R {
+	fn read_at(&self, buf :&mut [u8], offset :u64) -> io::Result<usize> {
+		(**self).read_at(buf, offset)
+	}
+	fn content_len(&self) -> io::Result<u64> {
+		(**self).content_len()
+	}
+}
+
+/// A reader with a position of its own on top of a `ReadAt`
+pub struct ReadAtCursor<'a, R :?Sized + 'a> {
+	inner :&'a R,
+	pos :u64,
+}
+
+impl<'a, R :ReadAt + ?Sized + 'a> ReadAtCursor<'a, R> {
+	pub fn new(inner :&'a R) -> Self {
+		ReadAtCursor {
+			inner,
+			pos : 0,
+		}
+	}
+}
+
+impl<'a, R :ReadAt + ?Sized + 'a> Read for ReadAtCursor<'a, R> {
+	fn read(&mut self, buf :&mut [u8]) -> io::Result<usize> {
+		let read = try!(self.inner.read_at(buf, self.pos));
+		self.pos += read as u64;
+		Ok(read)
+	}
+}
+
+impl<'a, R :ReadAt + ?Sized + 'a> Seek for ReadAtCursor<'a, R> {
+	fn seek(&mut self, pos :SeekFrom) -> io::Result<u64> {
+		let new_pos = match pos {
+			SeekFrom::Start(p) => Some(p),
+			SeekFrom::Current(d) => self.pos.checked_add_signed(d),
+			SeekFrom::End(d) => try!(self.inner.content_len()).checked_add_signed(d),
+		};
+		self.pos = match new_pos {
+			Some(p) => p,
+			None => return Err(io::Error::new(io::ErrorKind::InvalidInput,
+				"Seek to a negative position")),
+		};
+		Ok(self.pos)
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use std::env;
+	use std::fs;
+	use std::io::Write;
+	use std::process;
+	use std::sync::Arc;
+	use std::thread;
+
+	#[test]
+	fn shared_file_reads() {
+		let path = env::temp_dir().join(format!("read-at-test-{}", process::id()));
+		let content = (0 .. 10_000u32).map(|v| v as u8).collect::<Vec<u8>>();
+		File::create(&path).unwrap().write_all(&content).unwrap();
+		let f = Arc::new(File::open(&path).unwrap());
+		let threads = (0 .. 4u64).map(|t| {
+			let f = f.clone();
+			thread::spawn(move || {
+				let mut rdr = ReadAtCursor::new(&*f);
+				rdr.seek(SeekFrom::Start(t * 1000)).unwrap();
+				let mut buf = vec![0; 1000];
+				rdr.read_exact(&mut buf).unwrap();
+				buf
+			})
+		}).collect::<Vec<_>>();
+		for (t, th) in threads.into_iter().enumerate() {
+			assert_eq!(th.join().unwrap(), &content[t * 1000 .. (t + 1) * 1000]);
+		}
+		let mut rdr = ReadAtCursor::new(&*f);
+		assert_eq!(rdr.seek(SeekFrom::End(-10)).unwrap(), 9990);
+		let mut rest = Vec::new();
+		rdr.read_to_end(&mut rest).unwrap();
+		assert_eq!(rest, &content[9990 ..]);
+		fs::remove_file(&path).unwrap();
+	}
+}
@@ -8,18 +8,31 @@ files.
 */
 
 use super::hash_ctx::{Digest, HashCtx};
+use super::blob_storage::{read_delim_byte_slice_max, write_delim_byte_slice};
+use super::extraction::{ExtractionLimits, ExtractionError, ExtractionErrorKind,
+	EntryChecker, limited_gz_decoder, extraction_error, normalize_path, listed_path,
+	MAX_PATH_LEN};
 use flate2::{Compression, GzBuilder};
-use flate2::read::GzDecoder;
-use tar::{Archive, Header, Builder as TarBuilder};
+use tar::{Archive, Header, Builder as TarBuilder, EntryType, PaxExtensions};
 use byteorder::{ReadBytesExt, WriteBytesExt, BigEndian};
 use std::mem;
 use std::u64;
 use std::io;
 
+/// The tar header of an entry, with the digest of its content
+pub(crate) type EntryMetadata = (Box<[u8; 512]>, Digest);
+
+#[derive(Clone)]
 pub(crate) struct CrateRecMetadata {
 	pub(crate) gz_file_name :Option<Vec<u8>>,
 	pub(crate) gz_os :u8,
-	pub(crate) entry_metadata :Vec<(Box<[u8; 512]>, Digest)>,
+	pub(crate) entry_metadata :Vec<EntryMetadata>,
+	/// The paths of the files, sorted, with the index of their entry
+	///
+	/// Long names are resolved. It is `None` for metadata that has
+	/// been stored without the table, until `resolve_path_table`
+	/// gets called.
+	pub(crate) path_table :Option<Vec<(String, usize)>>,
 }
 
 pub(crate) struct CrateRecMetaWithBlobs {
@@ -33,28 +46,151 @@ pub struct CrateContentBlobs {
 	entries :Vec<(Box<[u8; 512]>, Vec<u8>)>,
 }
 
+/// Obtains the gzip settings that reproduce the original archive
+pub(crate) fn archive_gz_builder(gz_file_name :Option<Vec<u8>>, gz_os :u8) -> GzBuilder {
+	let gz_bld = GzBuilder::new()
+		.operating_system(gz_os);
+	if let Some(filen) = gz_file_name {
+		gz_bld.filename(filen)
+	} else {
+		gz_bld
+	}
+}
+
 pub(crate) fn hdr_from_ptr(p :&[u8; 512]) -> &Header {
 	unsafe {
 		mem::transmute(p)
 	}
 }
 
+/// Resolves the paths of tar entries, one entry after the other
+///
+/// Entries that set the name of the next entry, like GNU
+/// `././@LongLink` entries or pax headers, are resolved and
+/// don't have a path themselves.
+#[derive(Default)]
+pub(crate) struct PathResolver {
+	long_name :Option<Vec<u8>>,
+}
+
+impl PathResolver {
+	/// Whether the entry sets the name of the next entry
+	///
+	/// The content of such entries is needed to resolve the path.
+	pub(crate) fn sets_name(hdr :&Header) -> bool {
+		matches!(hdr.entry_type(), EntryType::GNULongName | EntryType::XHeader)
+	}
+	/// Returns the path of the entry with the given header
+	///
+	/// Returns `Some(None)` for entries without a path of their own,
+	/// and `None` if the entry can't be resolved. `get_content`
+	/// obtains the content of the entry, and is only called
+	/// for entries where `sets_name` returns true.
+	pub(crate) fn resolve<F :FnOnce() -> Option<Vec<u8>>>(&mut self,
+			hdr :&Header, get_content :F) -> Option<Option<Vec<u8>>> {
+		match hdr.entry_type() {
+			EntryType::GNULongName => {
+				let mut name = get_content()?;
+				// The name is terminated by a null byte
+				while name.last() == Some(&0) {
+					name.pop();
+				}
+				self.long_name = Some(name);
+			},
+			EntryType::XHeader => {
+				let content = get_content()?;
+				for ext in PaxExtensions::new(&content) {
+					let ext = ext.ok()?;
+					if ext.key_bytes() == b"path" {
+						self.long_name = Some(ext.value_bytes().to_vec());
+					}
+				}
+			},
+			EntryType::GNULongLink | EntryType::XGlobalHeader => (),
+			_ => {
+				let path = match self.long_name.take() {
+					Some(name) => name,
+					None => hdr.path_bytes().into_owned(),
+				};
+				return Some(Some(path));
+			},
+		}
+		Some(None)
+	}
+}
+
+/// Obtains the paths of the given tar entries, with their index
+///
+/// The paths are resolved with a `PathResolver`.
+/// `get_content` obtains the content of an entry by its index.
+fn resolve_paths<'a, I, F>(headers :I, mut get_content :F)
+		-> Option<Vec<(Vec<u8>, usize)>>
+		where I :Iterator<Item = &'a [u8; 512]>, F :FnMut(usize) -> Option<Vec<u8>> {
+	let mut paths = Vec::new();
+	let mut resolver = PathResolver::default();
+	for (i, hdr) in headers.enumerate() {
+		if let Some(path) = resolver.resolve(hdr_from_ptr(hdr), || get_content(i))? {
+			paths.push((path, i));
+		}
+	}
+	Some(paths)
+}
+
+/// Builds the sorted table of paths of the given tar entries
+///
+/// The paths are resolved like `resolve_paths` does it,
+/// and normalized. Entries with unsafe paths are left out.
+/// If a path appears multiple times, the last entry wins,
+/// like it does when extracting the archive.
+fn build_path_table<'a, I, F>(headers :I, get_content :F)
+		-> Option<Vec<(String, usize)>>
+		where I :Iterator<Item = &'a [u8; 512]>, F :FnMut(usize) -> Option<Vec<u8>> {
+	let mut table = resolve_paths(headers, get_content)?.into_iter()
+		.filter_map(|(path, i)| Some((normalize_path(&path)?, i)))
+		.collect::<Vec<_>>();
+	// Among equal paths, the last entry comes first and is kept
+	table.sort_by(|a, b| a.0.cmp(&b.0).then(b.1.cmp(&a.1)));
+	table.dedup_by(|a, b| a.0 == b.0);
+	Some(table)
+}
+
 impl CrateContentBlobs {
 	/// Creates the CrateContentBlobs structure from a given .crate file
-	pub fn from_archive_file<R :io::Read>(archive_rdr :R) -> io::Result<Self> {
-		let gz_dec = GzDecoder::new(archive_rdr);
-		let gz_file_name = gz_dec.header().unwrap()
-			.filename().map(|v| v.to_vec());
-		let gz_os = gz_dec.header().unwrap().operating_system();
+	///
+	/// The default `ExtractionLimits` are enforced.
+	pub fn from_archive_file<R :io::Read>(archive_rdr :R) -> Result<Self, ExtractionError> {
+		CrateContentBlobs::from_archive_file_limited(archive_rdr, &ExtractionLimits::default())
+	}
+	/// Creates the CrateContentBlobs structure from a given .crate file
+	///
+	/// Archives exceeding the limits or containing unsafe paths are rejected.
+	pub fn from_archive_file_limited<R :io::Read>(archive_rdr :R,
+			limits :&ExtractionLimits) -> Result<Self, ExtractionError> {
+		let gz_dec = limited_gz_decoder(archive_rdr, limits);
+		let (gz_file_name, gz_os) = match gz_dec.inner.header() {
+			Some(hdr) => (hdr.filename().map(|v| v.to_vec()), hdr.operating_system()),
+			None => return Err(ExtractionErrorKind::InvalidArchive.into()),
+		};
 		let mut archive = Archive::new(gz_dec);
+		let mut checker = EntryChecker::new(limits);
 		let mut entries = Vec::new();
-		for entry in archive.entries().unwrap().raw(true) {
-			let mut entry = try!(entry);
-			let hdr_box = Box::new(entry.header().as_bytes().clone());
+		for entry in try!(archive.entries().map_err(extraction_error)).raw(true) {
+			let mut entry = try!(entry.map_err(extraction_error));
+			let size = try!(entry.header().entry_size().map_err(extraction_error));
+			try!(checker.check(size));
+			let hdr_box = Box::new(*entry.header().as_bytes());
 			let mut content = Vec::new();
-			try!(io::copy(&mut entry, &mut content));
+			try!(io::copy(&mut entry, &mut content).map_err(extraction_error));
 			entries.push((hdr_box, content));
 		}
+		let paths = resolve_paths(entries.iter().map(|e| &*e.0),
+			|i| Some(entries[i].1.clone()));
+		let safe = paths.map(|paths| {
+			paths.iter().all(|(path, _)| normalize_path(path).is_some())
+		});
+		if safe != Some(true) {
+			return Err(ExtractionErrorKind::UnsafePath.into());
+		}
 		Ok(CrateContentBlobs {
 			gz_file_name,
 			gz_os,
@@ -65,13 +201,7 @@ impl CrateContentBlobs {
 	/// Reconstructs the .crate file from the CrateContentBlobs structure
 	pub fn to_archive_file(&self) -> Vec<u8> {
 		let mut res = Vec::new();
-		let gz_bld = GzBuilder::new()
-			.operating_system(self.gz_os);
-		let gz_bld = if let Some(filen) = self.gz_file_name.clone() {
-			gz_bld.filename(filen)
-		} else {
-			gz_bld
-		};
+		let gz_bld = archive_gz_builder(self.gz_file_name.clone(), self.gz_os);
 		{
 			let mut gz_enc = gz_bld.write(&mut res, Compression::best());
 			let mut bld = TarBuilder::new(&mut gz_enc);
@@ -94,6 +224,11 @@ impl CrateContentBlobs {
 	}
 
 	pub(crate) fn into_meta_with_blobs(self) -> CrateRecMetaWithBlobs {
+		let path_table = {
+			let entries = &self.entries;
+			build_path_table(entries.iter().map(|e| &*e.0),
+				|i| Some(entries[i].1.clone()))
+		};
 		let mut entry_metadata = Vec::new();
 		let mut blobs = Vec::new();
 		for entry in self.entries {
@@ -109,6 +244,7 @@ impl CrateContentBlobs {
 				gz_file_name : self.gz_file_name,
 				gz_os : self.gz_os,
 				entry_metadata,
+				path_table,
 			},
 			blobs,
 		}
@@ -132,6 +268,9 @@ impl CrateRecMetadata {
 		let gz_file_name_len = try!(rdr.read_u64::<BigEndian>());
 		let gz_file_name = if gz_file_name_len == u64::MAX {
 			None
+		} else if gz_file_name_len > MAX_PATH_LEN {
+			return Err(io::Error::new(io::ErrorKind::InvalidData,
+				"File name in gzip header too long"));
 		} else {
 			let mut gfn = vec![0; gz_file_name_len as usize];
 			try!(rdr.read_exact(&mut gfn));
@@ -139,7 +278,8 @@ impl CrateRecMetadata {
 		};
 		let gz_os = try!(rdr.read_u8());
 		let entry_count = try!(rdr.read_u64::<BigEndian>()) as usize;
-		let mut entry_metadata = Vec::with_capacity(entry_count);
+		// The count might be damaged, so don't trust it too much when allocating
+		let mut entry_metadata = Vec::with_capacity(entry_count.min(4096));
 		for _ in 0 .. entry_count {
 			let mut hdr = Box::new([0; 512]);
 			{
@@ -150,10 +290,31 @@ impl CrateRecMetadata {
 			try!(rdr.read_exact(&mut digest));
 			entry_metadata.push((hdr, digest));
 		}
+		// Metadata stored without the path table ends here
+		let mut first = [0; 1];
+		let path_table = if try!(rdr.read(&mut first)) == 0 {
+			None
+		} else {
+			let mut rdr = io::Read::chain(&first[..], &mut rdr);
+			let count = try!(rdr.read_u64::<BigEndian>()) as usize;
+			let mut table = Vec::with_capacity(count.min(entry_count));
+			for _ in 0 .. count {
+				let idx = try!(rdr.read_u64::<BigEndian>()) as usize;
+				let path = try!(read_delim_byte_slice_max(&mut rdr, MAX_PATH_LEN));
+				let path = match String::from_utf8(path) {
+					Ok(p) if idx < entry_count => p,
+					_ => return Err(io::Error::new(io::ErrorKind::InvalidData,
+						"Invalid path table entry")),
+				};
+				table.push((path, idx));
+			}
+			Some(table)
+		};
 		Ok(CrateRecMetadata {
 			gz_file_name,
 			gz_os,
 			entry_metadata,
+			path_table,
 		})
 	}
 	pub fn serialize<W :io::Write>(&self, mut wtr :W) -> io::Result<()> {
@@ -170,26 +331,64 @@ impl CrateRecMetadata {
 			try!(wtr.write(hdr_ref));
 			try!(wtr.write(&entry.1));
 		}
+		if let Some(ref table) = self.path_table {
+			try!(wtr.write_u64::<BigEndian>(table.len() as u64));
+			for &(ref path, idx) in table.iter() {
+				try!(wtr.write_u64::<BigEndian>(idx as u64));
+				try!(write_delim_byte_slice(&mut wtr, path.as_bytes()));
+			}
+		}
 		Ok(())
 	}
+	/// Builds the path table if the metadata has been stored without it
+	///
+	/// `get_blob` obtains the content of an entry by its digest.
+	pub(crate) fn resolve_path_table<F>(&mut self, mut get_blob :F) -> Option<()>
+			where F :FnMut(&Digest) -> Option<Vec<u8>> {
+		if self.path_table.is_none() {
+			let entries = &self.entry_metadata;
+			let table = build_path_table(entries.iter().map(|e| &*e.0),
+				|i| get_blob(&entries[i].1))?;
+			self.path_table = Some(table);
+		}
+		Some(())
+	}
+	fn path_table(&self) -> &[(String, usize)] {
+		self.path_table.as_ref().map(|t| &t[..]).unwrap_or(&[])
+	}
+	/// Finds the entry of the file with the given path
+	pub(crate) fn find_entry(&self, path :&str) -> Option<&EntryMetadata> {
+		let table = self.path_table();
+		let pos = table.binary_search_by(|e| e.0.as_str().cmp(path)).ok()?;
+		Some(&self.entry_metadata[table[pos].1])
+	}
+	/// Returns the paths of the files with their entries, in archive order
+	pub(crate) fn files(&self) -> Vec<(&str, &EntryMetadata)> {
+		let mut table = self.path_table().iter().collect::<Vec<_>>();
+		table.sort_by_key(|e| e.1);
+		table.into_iter()
+			.map(|&(ref path, idx)| (path.as_str(), &self.entry_metadata[idx]))
+			.collect()
+	}
 	pub fn get_file_list(&self) -> Vec<String> {
-		self.entry_metadata.iter()
-			.map(|&(ref h, _)| {
-				let hdr = hdr_from_ptr(h);
-				let path = hdr.path().unwrap();
-				let s :String = path.to_str().unwrap().to_owned();
-				s
+		self.files().into_iter()
+			.map(|(path, (hdr, _))| {
+				listed_path(path.to_owned(), hdr_from_ptr(hdr).entry_type().is_dir())
 			})
-			.collect::<Vec<_>>()
+			.collect()
 	}
-	pub(crate) fn get_file_digest_list(&self) -> Vec<(Digest, String)> {
+	/// Returns the digests of all entries, in archive order
+	///
+	/// Entries that aren't files themselves, like the ones
+	/// holding long names of other entries, have no path.
+	pub(crate) fn get_file_digest_list(&self) -> Vec<(Digest, Option<String>)> {
+		let mut paths = vec![None; self.entry_metadata.len()];
+		for &(ref path, idx) in self.path_table().iter() {
+			paths[idx] = Some(path.clone());
+		}
 		self.entry_metadata.iter()
-			.map(|&(ref h, d)| {
-				let hdr = hdr_from_ptr(h);
-				let path = hdr.path().unwrap();
-				let s :String = path.to_str().unwrap().to_owned();
-				(d, s)
-			})
-			.collect::<Vec<_>>()
+			.map(|e| e.1)
+			.zip(paths)
+			.collect()
 	}
 }
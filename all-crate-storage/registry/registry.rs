@@ -9,6 +9,7 @@ use failure::{Context, ResultExt};
 use semver::Version;
 use git2::{self, Repository};
 use super::super::crate_storage::CacheStorage;
+use super::super::extracted_dir_storage::ExtractedDirStorage;
 
 #[derive(Serialize, Debug)]
 pub struct Dependency {
@@ -80,6 +81,7 @@ pub struct CrateIndexJson {
 pub struct Registry {
 	cache_path :PathBuf,
 	index_path :PathBuf,
+	src_path :PathBuf,
 }
 
 pub fn obtain_crate_name_path(name :&str) -> String {
@@ -133,9 +135,11 @@ impl Registry {
 		let cache_path = base_path.join("cache").join(name);
 		//let cache_path = env::current_dir().unwrap().join("crate-archives");
 		let index_path = base_path.join("index").join(name);
+		let src_path = base_path.join("src").join(name);
 		Ok(Registry {
 			cache_path,
 			index_path,
+			src_path,
 		})
 	}
 	pub fn get_crate_json(&self, crate_name :&str)
@@ -207,4 +211,7 @@ impl Registry {
 	pub fn get_cache_storage(&self) -> CacheStorage {
 		CacheStorage::new(&self.cache_path)
 	}
+	pub fn get_extracted_dir_storage(&self) -> ExtractedDirStorage {
+		ExtractedDirStorage::new(&self.src_path)
+	}
 }
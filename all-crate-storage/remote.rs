@@ -0,0 +1,360 @@
+/*!
+Storage files on a remote server
+
+A `RemoteFile` reads a storage file that is served via HTTP,
+without downloading it as a whole. The file is split into
+blocks of `BLOCK_SIZE` bytes, which are fetched with HTTP range
+requests when they are first read. As reading a blob
+only touches the blocks of the index it needs and the blocks
+of the blob itself, only a small part of the file is transferred.
+
+Fetched blocks are cached, either in memory, or in a
+cache directory so that they survive restarts.
+The first block contains the header of the storage, which changes
+whenever the remote storage gets a new index. The first block
+is always fetched when opening the file, and if it differs from
+the cached one, the cache is discarded.
+
+A `RemoteFile` can be shared between threads. The lock of the
+block cache isn't held while blocks are fetched, so that reads
+of cached blocks don't wait for the fetches of other threads.
+Threads that miss the same block may both fetch it.
+*/
+
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+use reqwest::{Client, StatusCode};
+use reqwest::header::{RANGE, CONTENT_RANGE};
+use super::read_at::ReadAt;
+use super::blob_storage::SyncAll;
+
+/// Size of the blocks the file is fetched in
+pub const BLOCK_SIZE :u64 = 64 * 1024;
+
+/// Maximum number of blocks kept in memory if there is no cache directory
+const MAX_MEMORY_BLOCKS :usize = 1024;
+
+pub struct RemoteFile {
+	url :String,
+	client :Client,
+	/// The length of the remote file
+	len :u64,
+	pos :u64,
+	blocks :Mutex<HashMap<u64, Vec<u8>>>,
+	cache_dir :Option<PathBuf>,
+}
+
+fn other_err<E :ToString>(e :E) -> io::Error {
+	io::Error::other(e.to_string())
+}
+
+/// Obtains the length of the whole file from a Content-Range header value
+fn parse_content_range_len(v :&str) -> Option<u64> {
+	if !v.starts_with("bytes ") {
+		return None;
+	}
+	v.rsplit('/').next().and_then(|l| l.parse().ok())
+}
+
+/// Removes the cached blocks from the directory
+fn clear_cache_dir(dir :&Path) -> io::Result<()> {
+	for entry in try!(fs::read_dir(dir)) {
+		let entry = try!(entry);
+		let name = entry.file_name().to_string_lossy().into_owned();
+		let is_block = name.trim_end_matches(".tmp").parse::<u64>().is_ok();
+		if is_block {
+			try!(fs::remove_file(entry.path()));
+		}
+	}
+	Ok(())
+}
+
+impl RemoteFile {
+	/// Opens the file at the given URL
+	///
+	/// If a cache directory is given, fetched blocks are stored
+	/// there. It is created if it doesn't exist.
+	pub fn open(url :&str, cache_dir :Option<&Path>) -> io::Result<Self> {
+		let mut rf = RemoteFile {
+			url : url.to_owned(),
+			client : Client::new(),
+			len : 0,
+			pos : 0,
+			blocks : Mutex::new(HashMap::new()),
+			cache_dir : cache_dir.map(|d| d.to_path_buf()),
+		};
+		let (first, len) = try!(rf.fetch_range(0, BLOCK_SIZE));
+		rf.len = len;
+		if let Some(dir) = rf.cache_dir.clone() {
+			try!(fs::create_dir_all(&dir));
+			let cached_first = try!(rf.read_cached_block(0));
+			if cached_first.as_ref() != Some(&first) {
+				// The remote file has changed, so the cache is stale
+				try!(clear_cache_dir(&dir));
+			}
+		}
+		try!(rf.store_block(&mut rf.lock_blocks(), 0, first));
+		Ok(rf)
+	}
+	pub fn len(&self) -> u64 {
+		self.len
+	}
+	pub fn is_empty(&self) -> bool {
+		self.len == 0
+	}
+	/// Fetches the bytes starting at `offset`, at most `count` many
+	///
+	/// Returns the bytes and the length of the whole file.
+	fn fetch_range(&self, offset :u64, count :u64) -> io::Result<(Vec<u8>, u64)> {
+		let range = format!("bytes={}-{}", offset, offset + count - 1);
+		let mut resp = try!(self.client.get(&self.url)
+			.header(RANGE, range)
+			.send()
+			.map_err(other_err));
+		if resp.status() != StatusCode::PARTIAL_CONTENT {
+			return Err(other_err(format!("Range request to {} failed with status {}",
+				self.url, resp.status())));
+		}
+		let len = resp.headers().get(CONTENT_RANGE)
+			.and_then(|v| v.to_str().ok())
+			.and_then(parse_content_range_len);
+		let len = try!(len.ok_or_else(|| other_err("Invalid Content-Range header")));
+		let mut content = Vec::new();
+		try!(resp.read_to_end(&mut content));
+		if content.len() as u64 != count.min(len - offset) {
+			return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+				"Range response has wrong length"));
+		}
+		Ok((content, len))
+	}
+	fn lock_blocks(&self) -> MutexGuard<'_, HashMap<u64, Vec<u8>>> {
+		// The cache is valid even if a thread has panicked while holding the lock
+		self.blocks.lock().unwrap_or_else(|e| e.into_inner())
+	}
+	fn block_path(&self, idx :u64) -> Option<PathBuf> {
+		self.cache_dir.as_ref().map(|d| d.join(format!("{}", idx)))
+	}
+	fn read_cached_block(&self, idx :u64) -> io::Result<Option<Vec<u8>>> {
+		let path = match self.block_path(idx) {
+			Some(p) => p,
+			None => return Ok(None),
+		};
+		let mut f = match File::open(path) {
+			Ok(f) => f,
+			Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+			Err(e) => return Err(e),
+		};
+		let mut content = Vec::new();
+		try!(f.read_to_end(&mut content));
+		Ok(Some(content))
+	}
+	fn store_block(&self, blocks :&mut HashMap<u64, Vec<u8>>,
+			idx :u64, content :Vec<u8>) -> io::Result<()> {
+		if let Some(path) = self.block_path(idx) {
+			let tmp_path = path.with_extension("tmp");
+			try!(try!(File::create(&tmp_path)).write_all(&content));
+			try!(fs::rename(&tmp_path, &path));
+			// Only keep the current block in memory
+			blocks.clear();
+		} else if blocks.len() >= MAX_MEMORY_BLOCKS {
+			blocks.clear();
+		}
+		blocks.insert(idx, content);
+		Ok(())
+	}
+	fn is_cached(&self, blocks :&HashMap<u64, Vec<u8>>, idx :u64) -> bool {
+		blocks.contains_key(&idx) ||
+			self.block_path(idx).map(|p| p.exists()).unwrap_or(false)
+	}
+	/// Obtains the content of a block that isn't in memory
+	///
+	/// If the block needs to be fetched, up to `count`
+	/// following blocks are fetched along with it,
+	/// as long as they aren't cached already.
+	fn load_block(&self, idx :u64, count :u64) -> io::Result<Vec<u8>> {
+		if let Some(content) = try!(self.read_cached_block(idx)) {
+			let mut blocks = self.lock_blocks();
+			blocks.clear();
+			blocks.insert(idx, content.clone());
+			return Ok(content);
+		}
+		let fetch_count = {
+			let blocks = self.lock_blocks();
+			let mut fetch_count = 1;
+			while fetch_count < count && !self.is_cached(&blocks, idx + fetch_count) {
+				fetch_count += 1;
+			}
+			fetch_count
+		};
+		// The lock is released during the fetch
+		let (content, _) = try!(self.fetch_range(idx * BLOCK_SIZE, fetch_count * BLOCK_SIZE));
+		// Store the first block last, so that it stays in memory
+		let mut chunks = content.chunks(BLOCK_SIZE as usize)
+			.map(|c| c.to_vec())
+			.enumerate()
+			.collect::<Vec<_>>();
+		let (_, first) = chunks.remove(0);
+		let mut blocks = self.lock_blocks();
+		for (i, chunk) in chunks {
+			try!(self.store_block(&mut blocks, idx + i as u64, chunk));
+		}
+		try!(self.store_block(&mut blocks, idx, first.clone()));
+		Ok(first)
+	}
+}
+
+/// Copies the content of the block from `offset` on into `buf`
+fn copy_from_block(block :&[u8], buf :&mut [u8], offset :usize) -> usize {
+	let len = buf.len().min(block.len() - offset);
+	buf[.. len].copy_from_slice(&block[offset .. offset + len]);
+	len
+}
+
+impl ReadAt for RemoteFile {
+	fn read_at(&self, buf :&mut [u8], pos :u64) -> io::Result<usize> {
+		if pos >= self.len || buf.is_empty() {
+			return Ok(0);
+		}
+		let idx = pos / BLOCK_SIZE;
+		let offset = (pos % BLOCK_SIZE) as usize;
+		let end = (pos + buf.len() as u64).min(self.len);
+		let count = (end - 1) / BLOCK_SIZE - idx + 1;
+		if let Some(block) = self.lock_blocks().get(&idx) {
+			return Ok(copy_from_block(block, buf, offset));
+		}
+		let block = try!(self.load_block(idx, count));
+		Ok(copy_from_block(&block, buf, offset))
+	}
+	fn content_len(&self) -> io::Result<u64> {
+		Ok(self.len)
+	}
+}
+
+impl Read for RemoteFile {
+	fn read(&mut self, buf :&mut [u8]) -> io::Result<usize> {
+		let read = try!(self.read_at(buf, self.pos));
+		self.pos += read as u64;
+		Ok(read)
+	}
+}
+
+impl Write for RemoteFile {
+	fn write(&mut self, _buf :&[u8]) -> io::Result<usize> {
+		Err(io::Error::new(io::ErrorKind::PermissionDenied,
+			"Remote files are read only"))
+	}
+	fn flush(&mut self) -> io::Result<()> {
+		Ok(())
+	}
+}
+
+impl SyncAll for RemoteFile {
+	fn sync_all(&mut self) -> io::Result<()> {
+		Ok(())
+	}
+}
+
+impl Seek for RemoteFile {
+	fn seek(&mut self, pos :SeekFrom) -> io::Result<u64> {
+		let new_pos = match pos {
+			SeekFrom::Start(p) => Some(p),
+			SeekFrom::Current(d) => self.pos.checked_add_signed(d),
+			SeekFrom::End(d) => self.len.checked_add_signed(d),
+		};
+		self.pos = match new_pos {
+			Some(p) => p,
+			None => return Err(io::Error::new(io::ErrorKind::InvalidInput,
+				"Seek to a negative position")),
+		};
+		Ok(self.pos)
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use std::env;
+	use std::io::{BufRead, BufReader};
+	use std::net::TcpListener;
+	use std::process;
+	use std::sync::{Arc, Mutex};
+	use std::thread;
+	use blob_storage::BlobStorage;
+
+	/// Serves `content` via HTTP, supporting range requests
+	///
+	/// Returns the URL and the list of requested ranges.
+	fn serve(content :Vec<u8>) -> (String, Arc<Mutex<Vec<String>>>) {
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let url = format!("http://{}/crate_storage", listener.local_addr().unwrap());
+		let ranges = Arc::new(Mutex::new(Vec::new()));
+		let ranges_ = ranges.clone();
+		thread::spawn(move || {
+			for stream in listener.incoming() {
+				let mut stream = stream.unwrap();
+				let mut range = None;
+				for line in BufReader::new(&stream).lines() {
+					let line = line.unwrap();
+					if line.is_empty() {
+						break;
+					}
+					if line.to_lowercase().starts_with("range: bytes=") {
+						range = Some(line["range: bytes=".len() ..].to_owned());
+					}
+				}
+				let range = range.unwrap();
+				ranges_.lock().unwrap().push(range.clone());
+				let mut bounds = range.split('-').map(|b| b.parse::<usize>().unwrap());
+				let start = bounds.next().unwrap();
+				let end = bounds.next().unwrap().min(content.len() - 1);
+				let body = &content[start ..= end];
+				write!(stream, "HTTP/1.1 206 Partial Content\r\n\
+					Content-Range: bytes {}-{}/{}\r\n\
+					Content-Length: {}\r\n\
+					Connection: close\r\n\r\n",
+					start, end, content.len(), body.len()).unwrap();
+				stream.write_all(body).unwrap();
+			}
+		});
+		(url, ranges)
+	}
+
+	#[test]
+	fn blob_storage_via_http() {
+		let blobs = (0 .. 20u8)
+			.map(|i| ([i; 32], vec![i; 20_000]))
+			.collect::<Vec<_>>();
+		let mut content = io::Cursor::new(Vec::new());
+		{
+			let mut st = BlobStorage::empty(&mut content);
+			for (d, blob) in blobs.iter() {
+				st.insert(*d, blob).unwrap();
+			}
+			st.write_header_and_index().unwrap();
+		}
+		let (url, ranges) = serve(content.into_inner());
+
+		let cache_dir = env::temp_dir().join(format!("remote-test-{}", process::id()));
+		{
+			let rf = RemoteFile::open(&url, Some(&cache_dir)).unwrap();
+			let st = BlobStorage::load(rf).unwrap();
+			assert_eq!(st.get(&[7; 32]).unwrap(), Some(vec![7; 20_000]));
+			assert_eq!(st.get(&[19; 32]).unwrap(), Some(vec![19; 20_000]));
+			assert_eq!(st.get(&[42; 32]).unwrap(), None);
+		}
+		let request_count = ranges.lock().unwrap().len();
+		assert!(request_count > 1);
+		{
+			// Everything is served from the cache now,
+			// apart from the first block
+			let rf = RemoteFile::open(&url, Some(&cache_dir)).unwrap();
+			let st = BlobStorage::load(rf).unwrap();
+			assert_eq!(st.get(&[7; 32]).unwrap(), Some(vec![7; 20_000]));
+		}
+		assert_eq!(ranges.lock().unwrap().len(), request_count + 1);
+		fs::remove_dir_all(&cache_dir).unwrap();
+	}
+}
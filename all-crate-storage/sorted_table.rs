@@ -0,0 +1,423 @@
+/*!
+Sorted on-disk tables
+
+The index sections of the blob storage are stored as sorted
+tables so that lookups can be done with a binary search
+directly on the storage, without loading the index into
+memory first.
+
+A table section has the following layout:
+
+* the number of entries (u64)
+* the length of the key area (u64)
+* the entries, sorted by key, each with the same length
+* the key area
+
+Keys with a fixed length are stored in the entry directly.
+Keys with variable length (names) are stored in the key area,
+and the entry only contains their offset relative to the
+start of the key area.
+*/
+
+use std::io::{Read, Write, Seek, SeekFrom, Result as IoResult};
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::cmp::Ordering;
+use byteorder::{ReadBytesExt, WriteBytesExt, BigEndian};
+use failure::ResultExt;
+use super::blob_storage::{StorageError, StorageErrorKind,
+	read_index_name, write_delim_byte_slice};
+use super::extraction::MAX_PATH_LEN;
+use super::hash_ctx::Digest;
+
+pub(crate) trait TableKey :Ord + Hash + Clone + Sized {
+	/// The number of bytes the key takes up inside an entry
+	const ENTRY_LEN :u64;
+	fn write_key<W :Write>(&self, entry :W, key_area :&mut Vec<u8>) -> IoResult<()>;
+	fn read_key<R :Read + Seek>(entry :&[u8], rdr :R,
+			key_area_offset :u64, key_area_len :u64) -> Result<Self, StorageError>;
+}
+
+pub(crate) trait TableValue :Copy + Sized {
+	/// The number of bytes the value takes up inside an entry
+	const ENTRY_LEN :u64;
+	fn write_value<W :Write>(&self, entry :W) -> IoResult<()>;
+	fn read_value(entry :&[u8]) -> Self;
+}
+
+impl TableKey for Digest {
+	const ENTRY_LEN :u64 = 32;
+	fn write_key<W :Write>(&self, mut entry :W, _key_area :&mut Vec<u8>) -> IoResult<()> {
+		entry.write_all(self)
+	}
+	fn read_key<R :Read + Seek>(entry :&[u8], _rdr :R,
+			_key_area_offset :u64, _key_area_len :u64) -> Result<Self, StorageError> {
+		let mut d :Digest = [0; 32];
+		d.copy_from_slice(&entry[.. 32]);
+		Ok(d)
+	}
+}
+
+impl TableKey for String {
+	const ENTRY_LEN :u64 = 8;
+	fn write_key<W :Write>(&self, mut entry :W, key_area :&mut Vec<u8>) -> IoResult<()> {
+		try!(entry.write_u64::<BigEndian>(key_area.len() as u64));
+		try!(write_delim_byte_slice(key_area, self.as_bytes()));
+		Ok(())
+	}
+	fn read_key<R :Read + Seek>(mut entry :&[u8], mut rdr :R,
+			key_area_offset :u64, key_area_len :u64) -> Result<Self, StorageError> {
+		use self::StorageErrorKind::*;
+		let offs = try!(entry.read_u64::<BigEndian>().context(Io));
+		// The key must lie inside the key area
+		let max_len = match key_area_len.checked_sub(offs).and_then(|l| l.checked_sub(8)) {
+			Some(l) => l.min(MAX_PATH_LEN),
+			None => return Err(CorruptIndex.into()),
+		};
+		try!(rdr.seek(SeekFrom::Start(key_area_offset + offs)).context(Io));
+		read_index_name(&mut rdr, max_len)
+	}
+}
+
+impl TableValue for u64 {
+	const ENTRY_LEN :u64 = 8;
+	fn write_value<W :Write>(&self, mut entry :W) -> IoResult<()> {
+		entry.write_u64::<BigEndian>(*self)
+	}
+	fn read_value(mut entry :&[u8]) -> Self {
+		entry.read_u64::<BigEndian>().unwrap()
+	}
+}
+
+impl TableValue for Digest {
+	const ENTRY_LEN :u64 = 32;
+	fn write_value<W :Write>(&self, mut entry :W) -> IoResult<()> {
+		entry.write_all(self)
+	}
+	fn read_value(entry :&[u8]) -> Self {
+		let mut d :Digest = [0; 32];
+		d.copy_from_slice(&entry[.. 32]);
+		d
+	}
+}
+
+/// Location of a table section inside the storage
+#[derive(Copy, Clone)]
+struct OnDisk {
+	offset :u64,
+	len :u64,
+	key_area_len :u64,
+}
+
+impl OnDisk {
+	fn entries_offset(&self) -> u64 {
+		self.offset + 16
+	}
+	fn key_area_offset<K :TableKey, V :TableValue>(&self) -> u64 {
+		self.entries_offset() + self.len * (K::ENTRY_LEN + V::ENTRY_LEN)
+	}
+	fn end<K :TableKey, V :TableValue>(&self) -> u64 {
+		self.key_area_offset::<K, V>() + self.key_area_len
+	}
+}
+
+/// A sorted table, optionally backed by a section inside the storage
+///
+/// Entries that were not part of the section are kept in memory.
+/// Of those, the ones that were added since the last call to
+/// `take_new_entries` are kept apart, so that they can be
+/// written out as an incremental section.
+pub(crate) struct SortedTable<K, V> {
+	on_disk :Option<OnDisk>,
+	saved :HashMap<K, V>,
+	new :HashMap<K, V>,
+}
+
+impl<K :TableKey, V :TableValue> SortedTable<K, V> {
+	pub fn empty() -> Self {
+		SortedTable::from_map(HashMap::new())
+	}
+	/// Creates a table that only has in-memory entries
+	pub fn from_map(saved :HashMap<K, V>) -> Self {
+		SortedTable {
+			on_disk : None,
+			saved,
+			new : HashMap::new(),
+		}
+	}
+	/// Reads the table section starting at the given offset
+	///
+	/// Only the section header is read.
+	/// Returns the table and the offset where the section ends.
+	/// Sections that would end after `content_len` are rejected.
+	pub fn read_section<R :Read + Seek>(mut rdr :R, offset :u64,
+			content_len :u64) -> Result<(Self, u64), StorageError> {
+		use self::StorageErrorKind::*;
+		try!(rdr.seek(SeekFrom::Start(offset)).context(Io));
+		let len = try!(rdr.read_u64::<BigEndian>().context(Io));
+		let key_area_len = try!(rdr.read_u64::<BigEndian>().context(Io));
+		let end = len.checked_mul(K::ENTRY_LEN + V::ENTRY_LEN)
+			.and_then(|l| l.checked_add(offset + 16))
+			.and_then(|o| o.checked_add(key_area_len));
+		match end {
+			Some(end) if end <= content_len => (),
+			_ => return Err(CorruptIndex.into()),
+		}
+		let on_disk = OnDisk {
+			offset,
+			len,
+			key_area_len,
+		};
+		let tbl = SortedTable {
+			on_disk : Some(on_disk),
+			saved : HashMap::new(),
+			new : HashMap::new(),
+		};
+		Ok((tbl, on_disk.end::<K, V>()))
+	}
+	pub fn is_empty(&self) -> bool {
+		self.saved.is_empty() && self.new.is_empty() &&
+			self.on_disk.map(|d| d.len == 0).unwrap_or(true)
+	}
+	pub fn insert(&mut self, key :K, value :V) {
+		self.new.insert(key, value);
+	}
+	pub fn has_new_entries(&self) -> bool {
+		!self.new.is_empty()
+	}
+	/// Adds entries that are already saved in some section
+	pub fn extend_saved<I :IntoIterator<Item = (K, V)>>(&mut self, entries :I) {
+		self.saved.extend(entries);
+	}
+	/// Returns the entries added since the last call, sorted by key
+	///
+	/// Afterwards, they are regarded as saved.
+	pub fn take_new_entries(&mut self) -> Vec<(K, V)> {
+		let mut res = self.new.drain().collect::<Vec<_>>();
+		res.sort_by(|a, b| a.0.cmp(&b.0));
+		self.saved.extend(res.iter().cloned());
+		res
+	}
+	fn read_entry<R :Read + Seek>(on_disk :&OnDisk, mut rdr :R, idx :u64,
+			entry :&mut [u8]) -> Result<K, StorageError> {
+		let entry_len = K::ENTRY_LEN + V::ENTRY_LEN;
+		try!(rdr.seek(SeekFrom::Start(on_disk.entries_offset() + idx * entry_len))
+			.context(StorageErrorKind::Io));
+		try!(rdr.read_exact(entry).context(StorageErrorKind::Io));
+		K::read_key(entry, rdr, on_disk.key_area_offset::<K, V>(), on_disk.key_area_len)
+	}
+	/// Looks up the key, doing a binary search on the storage
+	pub fn get<R :Read + Seek>(&self, mut rdr :R, key :&K) -> Result<Option<V>, StorageError> {
+		if let Some(v) = self.new.get(key).or_else(|| self.saved.get(key)) {
+			return Ok(Some(*v));
+		}
+		let on_disk = match self.on_disk {
+			Some(d) => d,
+			None => return Ok(None),
+		};
+		let mut entry = vec![0; (K::ENTRY_LEN + V::ENTRY_LEN) as usize];
+		let (mut lo, mut hi) = (0, on_disk.len);
+		while lo < hi {
+			let mid = lo + (hi - lo) / 2;
+			let mid_key = try!(Self::read_entry(&on_disk, &mut rdr, mid, &mut entry));
+			match mid_key.cmp(key) {
+				Ordering::Less => lo = mid + 1,
+				Ordering::Greater => hi = mid,
+				Ordering::Equal => {
+					let v = V::read_value(&entry[K::ENTRY_LEN as usize ..]);
+					return Ok(Some(v));
+				},
+			}
+		}
+		Ok(None)
+	}
+	/// Obtains all entries of the table, sorted by key
+	pub fn entries<R :Read + Seek>(&self, mut rdr :R) -> Result<Vec<(K, V)>, StorageError> {
+		let mut in_memory = self.saved.clone();
+		in_memory.extend(self.new.iter().map(|(k, v)| (k.clone(), *v)));
+		let mut res = Vec::new();
+		if let Some(on_disk) = self.on_disk {
+			let mut entry = vec![0; (K::ENTRY_LEN + V::ENTRY_LEN) as usize];
+			for idx in 0 .. on_disk.len {
+				let key = try!(Self::read_entry(&on_disk, &mut rdr, idx, &mut entry));
+				if in_memory.contains_key(&key) {
+					continue;
+				}
+				res.push((key, V::read_value(&entry[K::ENTRY_LEN as usize ..])));
+			}
+		}
+		res.extend(in_memory);
+		res.sort_by(|a, b| a.0.cmp(&b.0));
+		Ok(res)
+	}
+}
+
+impl<V :TableValue> SortedTable<String, V> {
+	/// Obtains the entries whose key starts with the given prefix, sorted by key
+	///
+	/// Only the range of the prefix is read from the storage.
+	pub fn entries_with_prefix<R :Read + Seek>(&self, mut rdr :R,
+			prefix :&str) -> Result<Vec<(String, V)>, StorageError> {
+		let mut in_memory = self.saved.iter()
+			.filter(|(k, _)| k.starts_with(prefix))
+			.map(|(k, v)| (k.clone(), *v))
+			.collect::<HashMap<_, _>>();
+		in_memory.extend(self.new.iter()
+			.filter(|(k, _)| k.starts_with(prefix))
+			.map(|(k, v)| (k.clone(), *v)));
+		let mut res = Vec::new();
+		if let Some(on_disk) = self.on_disk {
+			let mut entry = vec![0; (String::ENTRY_LEN + V::ENTRY_LEN) as usize];
+			// Find the first key that is not less than the prefix
+			let (mut lo, mut hi) = (0, on_disk.len);
+			while lo < hi {
+				let mid = lo + (hi - lo) / 2;
+				let mid_key = try!(Self::read_entry(&on_disk, &mut rdr, mid, &mut entry));
+				if mid_key.as_str() < prefix {
+					lo = mid + 1;
+				} else {
+					hi = mid;
+				}
+			}
+			for idx in lo .. on_disk.len {
+				let key = try!(Self::read_entry(&on_disk, &mut rdr, idx, &mut entry));
+				if !key.starts_with(prefix) {
+					break;
+				}
+				if in_memory.contains_key(&key) {
+					continue;
+				}
+				res.push((key, V::read_value(&entry[String::ENTRY_LEN as usize ..])));
+			}
+		}
+		res.extend(in_memory);
+		res.sort_by(|a, b| a.0.cmp(&b.0));
+		Ok(res)
+	}
+}
+
+/// Writes a table section with the given entries
+///
+/// The entries need to be sorted by key.
+pub(crate) fn write_table<K :TableKey, V :TableValue, W :Write>(mut wtr :W,
+		entries :&[(K, V)]) -> IoResult<()> {
+	let mut entry_bytes = Vec::new();
+	let mut key_area = Vec::new();
+	for (k, v) in entries.iter() {
+		try!(k.write_key(&mut entry_bytes, &mut key_area));
+		try!(v.write_value(&mut entry_bytes));
+	}
+	try!(wtr.write_u64::<BigEndian>(entries.len() as u64));
+	try!(wtr.write_u64::<BigEndian>(key_area.len() as u64));
+	try!(wtr.write_all(&entry_bytes));
+	try!(wtr.write_all(&key_area));
+	Ok(())
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use std::io::Cursor;
+
+	#[test]
+	fn lookup() {
+		let names = (0 .. 100u64)
+			.map(|i| (format!("crate-{}", i * 3), i))
+			.collect::<HashMap<String, u64>>();
+		let mut entries = names.iter()
+			.map(|(k, v)| (k.clone(), *v))
+			.collect::<Vec<_>>();
+		entries.sort_by(|a, b| a.0.cmp(&b.0));
+
+		let mut c = Cursor::new(vec![0; 7]);
+		c.seek(SeekFrom::End(0)).unwrap();
+		write_table(&mut c, &entries).unwrap();
+		let end = c.get_ref().len() as u64;
+
+		let (mut tbl, tbl_end) = SortedTable::<String, u64>::read_section(&mut c, 7, end).unwrap();
+		assert_eq!(tbl_end, end);
+		for (k, v) in names.iter() {
+			assert_eq!(tbl.get(&mut c, k).unwrap(), Some(*v));
+		}
+		assert_eq!(tbl.get(&mut c, &"crate-1".to_owned()).unwrap(), None);
+		assert_eq!(tbl.get(&mut c, &"zzz".to_owned()).unwrap(), None);
+
+		// In-memory entries take precedence over the on-disk ones
+		tbl.insert("crate-3".to_owned(), 1000);
+		tbl.insert("crate-4".to_owned(), 1001);
+		assert_eq!(tbl.get(&mut c, &"crate-3".to_owned()).unwrap(), Some(1000));
+		let all = tbl.entries(&mut c).unwrap();
+		assert_eq!(all.len(), 101);
+		assert!(all.windows(2).all(|w| w[0].0 < w[1].0));
+
+		let new = tbl.take_new_entries();
+		assert_eq!(new, vec![("crate-3".to_owned(), 1000), ("crate-4".to_owned(), 1001)]);
+		assert!(tbl.take_new_entries().is_empty());
+		assert_eq!(tbl.get(&mut c, &"crate-4".to_owned()).unwrap(), Some(1001));
+		assert_eq!(tbl.get(&mut c, &"crate-6".to_owned()).unwrap(), Some(2));
+	}
+
+	#[test]
+	fn empty_table() {
+		let mut c = Cursor::new(Vec::new());
+		write_table::<Digest, Digest, _>(&mut c, &[]).unwrap();
+		let (tbl, end) = SortedTable::<Digest, Digest>::read_section(&mut c, 0, 16).unwrap();
+		assert_eq!(end, 16);
+		assert!(tbl.is_empty());
+		assert_eq!(tbl.get(&mut c, &[0; 32]).unwrap(), None);
+	}
+
+	#[test]
+	fn prefix_range() {
+		let names = ["bar-1.0.0", "foo-0.1.0", "foo-1.0.0", "foo-bar-0.2.0", "foobar-1.0.0"];
+		let entries = names.iter()
+			.enumerate()
+			.map(|(i, n)| (n.to_string(), i as u64))
+			.collect::<Vec<_>>();
+		let mut c = Cursor::new(Vec::new());
+		write_table(&mut c, &entries).unwrap();
+		let len = c.get_ref().len() as u64;
+		let (mut tbl, _) = SortedTable::<String, u64>::read_section(&mut c, 0, len).unwrap();
+		tbl.insert("foo-2.0.0".to_owned(), 7);
+		tbl.insert("foo-0.1.0".to_owned(), 8);
+		let keys = |prefix| tbl.entries_with_prefix(&mut c.clone(), prefix).unwrap();
+		assert_eq!(keys("foo-"), [
+			("foo-0.1.0".to_owned(), 8),
+			("foo-1.0.0".to_owned(), 2),
+			("foo-2.0.0".to_owned(), 7),
+			("foo-bar-0.2.0".to_owned(), 3),
+		]);
+		assert_eq!(keys("bar-").len(), 1);
+		assert!(keys("baz-").is_empty());
+		assert!(keys("zzz").is_empty());
+	}
+
+	#[test]
+	fn corrupt_len() {
+		let entries = [("foo".to_owned(), 1u64), ("bar".to_owned(), 2)];
+		let mut c = Cursor::new(Vec::new());
+		write_table(&mut c, &entries).unwrap();
+		let len = c.get_ref().len() as u64;
+		// Entries reaching beyond the end
+		c.get_mut()[7] = 3;
+		let res = SortedTable::<String, u64>::read_section(&mut c, 0, len);
+		assert_eq!(*res.err().unwrap().get_context(), StorageErrorKind::CorruptIndex);
+		// Entry count that would overflow
+		c.get_mut()[0] = 0xff;
+		let res = SortedTable::<String, u64>::read_section(&mut c, 0, len);
+		assert_eq!(*res.err().unwrap().get_context(), StorageErrorKind::CorruptIndex);
+	}
+
+	#[test]
+	fn corrupt_key_len() {
+		let entries = [("foo".to_owned(), 1u64)];
+		let mut c = Cursor::new(Vec::new());
+		write_table(&mut c, &entries).unwrap();
+		let len = c.get_ref().len() as u64;
+		// The length prefix of the key, at the start of the key area
+		c.get_mut()[32] = 0xff;
+		let (tbl, _) = SortedTable::<String, u64>::read_section(&mut c, 0, len).unwrap();
+		let res = tbl.get(&mut c, &"foo".to_owned());
+		assert_eq!(*res.err().unwrap().get_context(), StorageErrorKind::CorruptIndex);
+	}
+}
@@ -0,0 +1,272 @@
+/*!
+Synchronization of mirrors
+
+A satellite mirror keeps its storage up to date with
+a central one. It sends an `Inventory` of its storage: the
+digests of the blobs it has, its crate names and the other
+index entries. The central mirror answers with an update pack
+that contains everything the satellite lacks, which the satellite
+then applies to its storage, see the `update_pack` module.
+
+As the inventory is made of blob digests, blobs that the
+satellite has already are never transferred again,
+even if they are used by new crates.
+
+The inventory is encoded as follows, all numbers big endian:
+
+* the magic `SYNCINV1`
+* the digests of the blobs: their number (u64), then the digests
+* the name index: the number of entries (u64), then the
+  length delimited name and the digest of each entry
+* the raw crate index, in the same way
+* the multi blob index: the number of entries (u64),
+  then pairs of digests
+* the dictionaries: the number of entries (u64),
+  then the id (u32) and the digest of each entry
+
+Inventories are sent by other instances without
+any authentication, so their size is limited,
+see `MAX_INVENTORY_LEN`.
+*/
+
+use std::collections::{HashMap, HashSet};
+use std::io::{Read, Write, Result as IoResult};
+use byteorder::{ReadBytesExt, WriteBytesExt, BigEndian};
+use failure::ResultExt;
+use super::blob_crate_storage::BlobCrateStorage;
+use super::blob_storage::{StorageError, StorageErrorKind,
+	read_delim_byte_slice_max, write_delim_byte_slice,
+	read_dictionaries, write_dictionaries};
+use super::blob_store::BlobStore;
+use super::hash_ctx::Digest;
+use super::update_pack::PackBase;
+
+const INVENTORY_MAGIC :&[u8; 8] = b"SYNCINV1";
+
+/// Maximum length of a serialized inventory, in bytes
+///
+/// Readers of inventories from untrusted sources should stop
+/// reading after this many bytes, e.g. via `Read::take`.
+pub const MAX_INVENTORY_LEN :u64 = 1 << 30;
+/// Maximum number of entries of a name index in an inventory
+const MAX_NAME_COUNT :u64 = 1 << 24;
+/// Maximum number of blobs in an inventory
+///
+/// Both the blobs stored directly and the entries
+/// of the multi blob index count towards it.
+const MAX_BLOB_COUNT :u64 = 1 << 24;
+/// Maximum length of a crate file name in an inventory
+const MAX_NAME_LEN :u64 = 1024;
+
+/// The blobs and index entries a storage has
+#[derive(Default, Debug)]
+pub struct Inventory {
+	digests :HashSet<Digest>,
+	names :HashMap<String, Digest>,
+	raw_crates :HashMap<String, Digest>,
+	multi_blobs :HashMap<Digest, Digest>,
+	dictionaries :HashMap<u32, Digest>,
+}
+
+fn read_digest<R :Read>(mut rdr :R) -> IoResult<Digest> {
+	let mut d :Digest = [0; 32];
+	try!(rdr.read_exact(&mut d));
+	Ok(d)
+}
+
+fn write_name_idx<W :Write>(mut wtr :W, idx :&HashMap<String, Digest>) -> IoResult<()> {
+	let mut entries = idx.iter().collect::<Vec<_>>();
+	entries.sort();
+	try!(wtr.write_u64::<BigEndian>(entries.len() as u64));
+	for (name, d) in entries {
+		try!(write_delim_byte_slice(&mut wtr, name.as_bytes()));
+		try!(wtr.write_all(d));
+	}
+	Ok(())
+}
+
+fn read_name_idx<R :Read>(mut rdr :R) -> Result<HashMap<String, Digest>, StorageError> {
+	use self::StorageErrorKind::*;
+	let len = try!(rdr.read_u64::<BigEndian>().context(Io));
+	if len > MAX_NAME_COUNT {
+		return Err(InvalidInventory.into());
+	}
+	let mut res = HashMap::new();
+	for _ in 0 .. len {
+		let name = try!(read_delim_byte_slice_max(&mut rdr, MAX_NAME_LEN)
+			.context(InvalidInventory));
+		let name = try!(String::from_utf8(name).context(InvalidName));
+		let d = try!(read_digest(&mut rdr).context(Io));
+		res.insert(name, d);
+	}
+	Ok(res)
+}
+
+impl Inventory {
+	/// Obtains the inventory of the given storage
+	pub fn of_storage<B :BlobStore>(storage :&mut BlobCrateStorage<B>)
+			-> Result<Self, StorageError> {
+		Ok(Inventory {
+			digests : try!(storage.b.digests()).into_iter().collect(),
+			names : try!(storage.b.names()).into_iter().collect(),
+			raw_crates : try!(storage.b.raw_crates()).into_iter().collect(),
+			multi_blobs : try!(storage.b.multi_blob_entries()).into_iter().collect(),
+			dictionaries : storage.b.dictionaries().clone(),
+		})
+	}
+	pub fn crate_count(&self) -> usize {
+		self.names.len() + self.raw_crates.len()
+	}
+	pub fn serialize<W :Write>(&self, mut wtr :W) -> IoResult<()> {
+		try!(wtr.write_all(INVENTORY_MAGIC));
+		let mut digests = self.digests.iter().collect::<Vec<_>>();
+		digests.sort();
+		try!(wtr.write_u64::<BigEndian>(digests.len() as u64));
+		for d in digests {
+			try!(wtr.write_all(d));
+		}
+		try!(write_name_idx(&mut wtr, &self.names));
+		try!(write_name_idx(&mut wtr, &self.raw_crates));
+		let mut multi_blobs = self.multi_blobs.iter().collect::<Vec<_>>();
+		multi_blobs.sort();
+		try!(wtr.write_u64::<BigEndian>(multi_blobs.len() as u64));
+		for (d, mblob_d) in multi_blobs {
+			try!(wtr.write_all(d));
+			try!(wtr.write_all(mblob_d));
+		}
+		try!(write_dictionaries(&mut wtr, &self.dictionaries));
+		Ok(())
+	}
+	pub fn deserialize<R :Read>(mut rdr :R) -> Result<Self, StorageError> {
+		use self::StorageErrorKind::*;
+		let mut magic = [0; 8];
+		try!(rdr.read_exact(&mut magic).context(Io));
+		if &magic != INVENTORY_MAGIC {
+			return Err(InvalidMagic.into());
+		}
+		let mut inv = Inventory::default();
+		let digest_count = try!(rdr.read_u64::<BigEndian>().context(Io));
+		if digest_count > MAX_BLOB_COUNT {
+			return Err(InvalidInventory.into());
+		}
+		for _ in 0 .. digest_count {
+			inv.digests.insert(try!(read_digest(&mut rdr).context(Io)));
+		}
+		inv.names = try!(read_name_idx(&mut rdr));
+		inv.raw_crates = try!(read_name_idx(&mut rdr));
+		let len = try!(rdr.read_u64::<BigEndian>().context(Io));
+		if len > MAX_BLOB_COUNT - digest_count {
+			return Err(InvalidInventory.into());
+		}
+		for _ in 0 .. len {
+			let d = try!(read_digest(&mut rdr).context(Io));
+			let mblob_d = try!(read_digest(&mut rdr).context(Io));
+			inv.multi_blobs.insert(d, mblob_d);
+		}
+		inv.dictionaries = try!(read_dictionaries(&mut rdr).context(Io));
+		Ok(inv)
+	}
+}
+
+impl PackBase for Inventory {
+	fn has_blob(&mut self, digest :&Digest) -> Result<bool, StorageError> {
+		Ok(self.digests.contains(digest))
+	}
+	fn get_named(&mut self, name :&str) -> Result<Option<Digest>, StorageError> {
+		Ok(self.names.get(name).cloned())
+	}
+	fn get_raw_crate(&mut self, name :&str) -> Result<Option<Digest>, StorageError> {
+		Ok(self.raw_crates.get(name).cloned())
+	}
+	fn get_multi_blob(&mut self, digest :&Digest) -> Result<Option<Digest>, StorageError> {
+		Ok(self.multi_blobs.get(digest).cloned())
+	}
+	fn get_dictionary(&self, id :u32) -> Option<Digest> {
+		self.dictionaries.get(&id).cloned()
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use std::io::Cursor;
+	use codec::Codec;
+	use crate_storage::CrateSource;
+	use update_pack::{create_update_pack, apply_update_pack, PackStats};
+	use test_util::{test_crate, storage_with, MemStorage};
+
+	/// Makes the satellite catch up with the central storage
+	fn sync(central :&mut MemStorage, satellite :&mut MemStorage) -> PackStats {
+		let mut request = Vec::new();
+		Inventory::of_storage(satellite).unwrap().serialize(&mut request).unwrap();
+		let mut inv = Inventory::deserialize(request.as_slice()).unwrap();
+		let mut pack = BlobCrateStorage::empty(Cursor::new(Vec::new()));
+		let created = create_update_pack(&mut inv, central, &mut pack).unwrap();
+		let applied = apply_update_pack(satellite, &mut pack).unwrap();
+		assert_eq!(created, applied);
+		applied
+	}
+
+	#[test]
+	fn sync_satellite() {
+		let foo = test_crate("foo", "1.0.0", &[
+			("LICENSE", b"The MIT License\n"),
+			("src/lib.rs", b"pub fn foo() {}\n"),
+		]);
+		let bar = test_crate("bar", "1.0.0", &[
+			("LICENSE", b"The MIT License\n"),
+			("src/lib.rs", b"pub fn bar() {}\n"),
+		]);
+		let mut central = storage_with(Codec::Gzip, &[foo.clone(), bar.clone()]);
+		let mut satellite = storage_with(Codec::Gzip, std::slice::from_ref(&foo));
+
+		// The LICENSE blob is present on the satellite already
+		let stats = sync(&mut central, &mut satellite);
+		assert_eq!(stats, PackStats { crates : 1, blobs : 2 });
+		for (s, c) in [foo, bar].iter() {
+			assert_eq!(satellite.get_crate(s).ok().as_ref(), Some(c));
+		}
+		// Nothing is transferred if the satellite is up to date
+		let stats = sync(&mut central, &mut satellite);
+		assert_eq!(stats, PackStats { crates : 0, blobs : 0 });
+	}
+
+	#[test]
+	fn oversized_inventory() {
+		use byteorder::{WriteBytesExt, BigEndian};
+		let inventory = |name_count :u64, name_len :u64| {
+			let mut inv = INVENTORY_MAGIC.to_vec();
+			// No digests
+			inv.write_u64::<BigEndian>(0).unwrap();
+			inv.write_u64::<BigEndian>(name_count).unwrap();
+			inv.write_u64::<BigEndian>(name_len).unwrap();
+			inv
+		};
+		let e = Inventory::deserialize(inventory(u64::MAX, 3).as_slice()).unwrap_err();
+		assert_eq!(*e.get_context(), StorageErrorKind::InvalidInventory);
+		let e = Inventory::deserialize(inventory(1, u64::MAX).as_slice()).unwrap_err();
+		assert_eq!(*e.get_context(), StorageErrorKind::InvalidInventory);
+
+		let mut inv = INVENTORY_MAGIC.to_vec();
+		inv.write_u64::<BigEndian>(u64::MAX).unwrap();
+		let e = Inventory::deserialize(inv.as_slice()).unwrap_err();
+		assert_eq!(*e.get_context(), StorageErrorKind::InvalidInventory);
+
+		let blobs = |digests :&[Digest], multi_blob_count :u64| {
+			let mut inv = INVENTORY_MAGIC.to_vec();
+			inv.write_u64::<BigEndian>(digests.len() as u64).unwrap();
+			for d in digests {
+				inv.extend_from_slice(d);
+			}
+			// No names and raw crates
+			inv.write_u64::<BigEndian>(0).unwrap();
+			inv.write_u64::<BigEndian>(0).unwrap();
+			inv.write_u64::<BigEndian>(multi_blob_count).unwrap();
+			inv
+		};
+		let e = Inventory::deserialize(blobs(&[], u64::MAX).as_slice()).unwrap_err();
+		assert_eq!(*e.get_context(), StorageErrorKind::InvalidInventory);
+		let e = Inventory::deserialize(blobs(&[[0; 32]], MAX_BLOB_COUNT).as_slice()).unwrap_err();
+		assert_eq!(*e.get_context(), StorageErrorKind::InvalidInventory);
+	}
+}
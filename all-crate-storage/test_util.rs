@@ -0,0 +1,61 @@
+/*!
+Fixtures shared by the tests of the modules
+
+Crate archives get created in memory, and
+stored into in-memory blob crate storages.
+*/
+
+use std::io::{self, Cursor};
+use flate2::{Compression, GzBuilder};
+use tar::{Builder, Header};
+use semver::Version;
+use blob_crate_storage::BlobCrateStorage;
+use blob_storage::BlobStorage;
+use codec::Codec;
+use crate_storage::{CrateSpec, CrateStorage};
+use hash_ctx::digest_of;
+
+pub(crate) type MemStorage = BlobCrateStorage<BlobStorage<Cursor<Vec<u8>>>>;
+
+pub(crate) fn spec(name :&str, version :&str) -> CrateSpec {
+	CrateSpec {
+		name : name.to_owned(),
+		version : Version::parse(version).unwrap(),
+	}
+}
+
+/// Creates an in-memory storage that contains the given crates
+pub(crate) fn storage_with(codec :Codec, crates :&[(CrateSpec, Vec<u8>)]) -> MemStorage {
+	let mut st = BlobCrateStorage::empty(Cursor::new(Vec::new()));
+	st.set_codec(codec);
+	st.store_parallel_iter(2, crates.iter()
+		.map(|(s, c)| (s.clone(), c.clone(), digest_of(c)))).unwrap();
+	st.store().unwrap();
+	st
+}
+
+/// Creates a .crate file with the given files
+///
+/// The paths are relative to the directory of the crate.
+pub(crate) fn crate_archive(name :&str, version :&str, files :&[(&str, &[u8])]) -> Vec<u8> {
+	let mut builder = Builder::new(Vec::new());
+	for &(path, content) in files.iter() {
+		let mut hdr = Header::new_gnu();
+		hdr.set_size(content.len() as u64);
+		hdr.set_mode(0o644);
+		hdr.set_cksum();
+		let path = format!("{}-{}/{}", name, version, path);
+		builder.append_data(&mut hdr, path, content).unwrap();
+	}
+	let tar = builder.into_inner().unwrap();
+	let mut gz_enc = GzBuilder::new().read(&tar[..], Compression::best());
+	let mut res = Vec::new();
+	io::copy(&mut gz_enc, &mut res).unwrap();
+	res
+}
+
+/// Creates a crate with the given files, together with its spec
+pub(crate) fn test_crate(name :&str, version :&str,
+		files :&[(&str, &[u8])]) -> (CrateSpec, Vec<u8>) {
+	(spec(name, version), crate_archive(name, version, files))
+}
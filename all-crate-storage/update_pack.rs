@@ -0,0 +1,221 @@
+/*!
+Update packs between two storage files
+
+An update pack contains what a newer storage file has
+and an older one lacks: blobs, dictionaries, and entries
+of the name, multi blob and raw crate indices.
+It is a storage file itself, so it can be inspected
+with the usual tools.
+
+Instead of an older storage file, a pack can also be made
+against anything else that knows which blobs and crates
+its receiver has, see `PackBase`.
+
+Applying a pack appends its blobs to the old storage
+and writes a new index. Before the header is updated
+to point to the new index, the crates of the pack are
+checked to be complete, so a pack made for a different
+storage file is rejected without harm.
+*/
+
+use super::blob_crate_storage::BlobCrateStorage;
+use super::blob_storage::{StorageError, StorageErrorKind};
+use super::blob_store::{BlobStore, BlobStoreMut};
+use super::crate_storage::{CrateSource, CrateSpec};
+use super::hash_ctx::Digest;
+use super::verify;
+
+/// What the receiver of an update pack has already
+///
+/// Only what the receiver lacks is put into the pack.
+pub trait PackBase {
+	fn has_blob(&mut self, digest :&Digest) -> Result<bool, StorageError>;
+	fn get_named(&mut self, name :&str) -> Result<Option<Digest>, StorageError>;
+	fn get_raw_crate(&mut self, name :&str) -> Result<Option<Digest>, StorageError>;
+	fn get_multi_blob(&mut self, digest :&Digest) -> Result<Option<Digest>, StorageError>;
+	fn get_dictionary(&self, id :u32) -> Option<Digest>;
+}
+
+impl<B :BlobStore> PackBase for BlobCrateStorage<B> {
+	fn has_blob(&mut self, digest :&Digest) -> Result<bool, StorageError> {
+		self.b.has(digest)
+	}
+	fn get_named(&mut self, name :&str) -> Result<Option<Digest>, StorageError> {
+		self.b.get_named(name)
+	}
+	fn get_raw_crate(&mut self, name :&str) -> Result<Option<Digest>, StorageError> {
+		self.b.get_raw_crate(name)
+	}
+	fn get_multi_blob(&mut self, digest :&Digest) -> Result<Option<Digest>, StorageError> {
+		self.b.get_multi_blob(digest)
+	}
+	fn get_dictionary(&self, id :u32) -> Option<Digest> {
+		self.b.dictionaries().get(&id).cloned()
+	}
+}
+
+/// Statistics about a created or applied update pack
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct PackStats {
+	/// Number of crates added or changed
+	pub crates :usize,
+	/// Number of blobs added
+	pub blobs :usize,
+}
+
+/// Writes the differences between `old` and `new` into `pack`
+pub fn create_update_pack<A :PackBase, B :BlobStore, C :BlobStoreMut>(
+		old :&mut A, new :&BlobCrateStorage<B>,
+		pack :&mut BlobCrateStorage<C>) -> Result<PackStats, StorageError> {
+	let mut stats = PackStats::default();
+	for (id, d) in new.b.dictionaries().iter() {
+		match old.get_dictionary(*id) {
+			Some(old_d) if old_d != *d => return Err(StorageErrorKind::InvalidUpdatePack.into()),
+			Some(_) => (),
+			None => {
+				pack.b.insert_dictionary(*id, *d);
+			},
+		}
+	}
+	for d in try!(new.b.digests()) {
+		if try!(old.has_blob(&d)) {
+			continue;
+		}
+		let blob = try!(new.b.get(&d)).unwrap();
+		try!(pack.b.insert(d, &blob));
+		stats.blobs += 1;
+	}
+	for (d, mblob_d) in try!(new.b.multi_blob_entries()) {
+		if try!(old.get_multi_blob(&d)) != Some(mblob_d) {
+			pack.b.insert_multi_blob(d, mblob_d);
+		}
+	}
+	for (name, d) in try!(new.b.names()) {
+		if try!(old.get_named(&name)) != Some(d) {
+			pack.b.insert_name(name, d);
+			stats.crates += 1;
+		}
+	}
+	for (name, d) in try!(new.b.raw_crates()) {
+		if try!(old.get_raw_crate(&name)) != Some(d) {
+			pack.b.insert_raw_crate(name, d);
+			stats.crates += 1;
+		}
+	}
+	try!(pack.add_features_of(new));
+	try!(pack.store());
+	Ok(stats)
+}
+
+/// Checks that all crates of the pack can be obtained from `storage`
+fn check_pack_crates<B :BlobStoreMut, C :BlobStore>(
+		storage :&mut BlobCrateStorage<B>,
+		pack :&mut BlobCrateStorage<C>) -> Result<(), StorageError> {
+	for (name, d) in try!(pack.b.names()) {
+		let spec = match CrateSpec::from_file_name(&name) {
+			Some(spec) => spec,
+			None => continue,
+		};
+		let complete = try!(storage.b.get_named(&name)) == Some(d) &&
+			storage.get_crate_rec_meta(&spec).is_ok() &&
+			verify::verify_crate_blobs(storage, &spec).is_empty();
+		if !complete {
+			return Err(StorageErrorKind::InvalidUpdatePack.into());
+		}
+	}
+	for (name, d) in try!(pack.b.raw_crates()) {
+		let spec = match CrateSpec::from_file_name(&name) {
+			Some(spec) => spec,
+			None => continue,
+		};
+		let complete = try!(storage.b.get_raw_crate(&name)) == Some(d) &&
+			storage.get_crate(&spec).is_ok();
+		if !complete {
+			return Err(StorageErrorKind::InvalidUpdatePack.into());
+		}
+	}
+	Ok(())
+}
+
+/// Applies the update pack to `storage`, in place
+///
+/// If the crates of the pack turn out to be incomplete,
+/// e.g. because the pack was made for a different storage file,
+/// an error is returned and the header of `storage` is left alone.
+pub fn apply_update_pack<B :BlobStoreMut, C :BlobStore>(
+		storage :&mut BlobCrateStorage<B>,
+		pack :&mut BlobCrateStorage<C>) -> Result<PackStats, StorageError> {
+	let mut stats = PackStats::default();
+	for (id, d) in pack.b.dictionaries().iter() {
+		match storage.b.dictionaries().get(id) {
+			Some(old_d) if old_d != d => return Err(StorageErrorKind::InvalidUpdatePack.into()),
+			_ => (),
+		}
+	}
+	try!(storage.add_features_of(pack));
+	for d in try!(pack.b.digests()) {
+		let blob = try!(pack.b.get(&d)).unwrap();
+		if try!(storage.b.insert(d, &blob)) {
+			stats.blobs += 1;
+		}
+	}
+	let dictionaries = pack.b.dictionaries().clone();
+	for (id, d) in dictionaries {
+		storage.b.insert_dictionary(id, d);
+	}
+	for (d, mblob_d) in try!(pack.b.multi_blob_entries()) {
+		storage.b.insert_multi_blob(d, mblob_d);
+	}
+	for (name, d) in try!(pack.b.names()) {
+		storage.b.insert_name(name, d);
+		stats.crates += 1;
+	}
+	for (name, d) in try!(pack.b.raw_crates()) {
+		storage.b.insert_raw_crate(name, d);
+		stats.crates += 1;
+	}
+	try!(check_pack_crates(storage, pack));
+	try!(storage.store());
+	// Check again, now with the index that has been written
+	try!(check_pack_crates(storage, pack));
+	Ok(stats)
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use std::io::Cursor;
+	use codec::Codec;
+	use test_util::{test_crate, storage_with};
+
+	#[test]
+	fn create_and_apply() {
+		let foo_1 = test_crate("foo", "1.0.0", &[
+			("src/lib.rs", b"pub fn foo() {}\n"),
+		]);
+		let foo_2 = test_crate("foo", "2.0.0", &[
+			("src/lib.rs", b"pub fn foo() {}\npub fn bar() {}\n"),
+		]);
+		let bar_1 = test_crate("bar", "1.0.0", &[
+			("src/lib.rs", b"pub fn foo() {}\n"),
+		]);
+		let mut old = storage_with(Codec::Gzip, std::slice::from_ref(&foo_1));
+		let new = storage_with(Codec::Gzip, &[foo_1.clone(), foo_2.clone(), bar_1.clone()]);
+
+		let mut pack = BlobCrateStorage::empty(Cursor::new(Vec::new()));
+		let stats = create_update_pack(&mut old, &new, &mut pack).unwrap();
+		// The src/lib.rs of bar is the same as the one of foo 1.0.0,
+		// so only the metadata blobs and one file blob are new
+		assert_eq!(stats, PackStats { crates : 2, blobs : 3 });
+
+		// A pack that doesn't fit is rejected
+		let mut unrelated = storage_with(Codec::Gzip, &[]);
+		assert!(apply_update_pack(&mut unrelated, &mut pack).is_err());
+
+		let stats = apply_update_pack(&mut old, &mut pack).unwrap();
+		assert_eq!(stats, PackStats { crates : 2, blobs : 3 });
+		for (s, c) in [foo_1, foo_2, bar_1].iter() {
+			assert_eq!(old.get_crate(s).ok().as_ref(), Some(c));
+		}
+	}
+}
@@ -0,0 +1,180 @@
+/*!
+Verification of crate sources
+
+Checks that the crates of a source can be obtained
+and match the checksums of the registry index.
+For blob crate storages, the digest of each blob
+is checked as well.
+*/
+
+use super::crate_storage::{CrateSource, CrateSpec};
+use super::error::CrateErrorKind;
+use super::blob_crate_storage::BlobCrateStorage;
+use super::blob_store::BlobStore;
+use super::hash_ctx::{digest_of, get_digest_hex};
+use super::registry::registry::CrateIndexJson;
+
+/// A problem found during verification
+#[derive(Serialize, Debug, PartialEq, Eq)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum Problem {
+	/// The crate isn't present in the source
+	Missing,
+	/// The crate is present in the source but couldn't be obtained
+	Corrupt {
+		error :String,
+	},
+	/// The checksum of the obtained crate doesn't match the index
+	ChecksumMismatch {
+		expected :String,
+		actual :String,
+	},
+	/// A blob of the crate is missing in the storage
+	MissingBlob {
+		digest :String,
+	},
+	/// A blob of the crate is present but its content doesn't match its digest
+	///
+	/// If the blob couldn't be decompressed, `actual` is `None`.
+	CorruptBlob {
+		digest :String,
+		actual :Option<String>,
+	},
+}
+
+/// A problem, together with the crate it was found for
+#[derive(Serialize, Debug)]
+pub struct Finding {
+	pub name :String,
+	pub version :String,
+	#[serde(flatten)]
+	pub problem :Problem,
+}
+
+/// Obtains the crate from the source and compares its checksum with the index
+pub fn verify_crate<S :CrateSource>(source :&mut S, v :&CrateIndexJson) -> Option<Problem> {
+	let spec = CrateSpec {
+		name : v.name.clone(),
+		version : v.version.clone(),
+	};
+	let crate_file = match source.get_crate(&spec) {
+		Ok(f) => f,
+		Err(ref e) if *e.get_context() == CrateErrorKind::NotFound => {
+			return Some(Problem::Missing);
+		},
+		Err(e) => return Some(Problem::Corrupt {
+			error : e.to_string(),
+		}),
+	};
+	let actual = get_digest_hex(digest_of(&crate_file));
+	if actual != v.checksum {
+		return Some(Problem::ChecksumMismatch {
+			expected : v.checksum.clone(),
+			actual,
+		});
+	}
+	None
+}
+
+/// Checks the digests of all blobs of the crate
+///
+/// Crates that are stored as they are don't have any blobs.
+pub fn verify_crate_blobs<B :BlobStore>(storage :&mut BlobCrateStorage<B>,
+		spec :&CrateSpec) -> Vec<Problem> {
+	let meta = match storage.get_crate_rec_meta(spec) {
+		Ok(m) => m,
+		Err(_) => return Vec::new(),
+	};
+	let mut problems = Vec::new();
+	for (_, d) in meta.entry_metadata.iter() {
+		let digest = get_digest_hex(*d);
+		let present = storage.b.has(d).unwrap_or(false) ||
+			storage.b.get_multi_blob(d).ok().and_then(|m| m).is_some();
+		if !present {
+			problems.push(Problem::MissingBlob { digest });
+			continue;
+		}
+		let actual = storage.get_blob_verify(d, false).ok().map(|blob| digest_of(&blob));
+		if actual != Some(*d) {
+			problems.push(Problem::CorruptBlob {
+				digest,
+				actual : actual.map(get_digest_hex),
+			});
+		}
+	}
+	problems
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use std::io::Cursor;
+	use semver::Version;
+	use codec::{Codec, BlobCompressor};
+	use crate_storage::CrateStorage;
+	use test_util::{crate_archive, spec, storage_with};
+
+	fn index_json(name :&str, version :&str, archive :&[u8]) -> CrateIndexJson {
+		CrateIndexJson {
+			name : name.to_owned(),
+			version : Version::parse(version).unwrap(),
+			dependencies : Vec::new(),
+			checksum : get_digest_hex(digest_of(archive)),
+			yanked : false,
+		}
+	}
+
+	#[test]
+	fn verify_storage() {
+		let archive = crate_archive("foo", "1.0.0", &[
+			("Cargo.toml", b"[package]\nname = \"foo\"\n"),
+			("src/lib.rs", b"pub fn foo() {}\n"),
+		]);
+		let v = index_json("foo", "1.0.0", &archive);
+		let spec = CrateSpec {
+			name : v.name.clone(),
+			version : v.version.clone(),
+		};
+		let mut st = BlobCrateStorage::empty(Cursor::new(Vec::new()));
+		let d = digest_of(&archive);
+		st.store_parallel_iter(2, vec![(spec.clone(), archive, d)].into_iter()).unwrap();
+
+		assert_eq!(verify_crate(&mut st, &v), None);
+		assert_eq!(verify_crate_blobs(&mut st, &spec), Vec::new());
+
+		let mut wrong = v.clone();
+		wrong.checksum = get_digest_hex([0; 32]);
+		match verify_crate(&mut st, &wrong) {
+			Some(Problem::ChecksumMismatch { actual, .. }) => assert_eq!(actual, v.checksum),
+			p => panic!("Unexpected result {:?}", p),
+		}
+
+		let missing = index_json("bar", "0.1.0", &[]);
+		assert_eq!(verify_crate(&mut st, &missing), Some(Problem::Missing));
+	}
+
+	#[test]
+	fn verify_corrupt_blob() {
+		let lib_rs = b"pub fn foo() {}\n";
+		let archive = crate_archive("foo", "1.0.0", &[
+			("Cargo.toml", b"[package]\nname = \"foo\"\n"),
+			("src/lib.rs", lib_rs),
+		]);
+		let v = index_json("foo", "1.0.0", &archive);
+		let spec = spec("foo", "1.0.0");
+		let mut st = storage_with(Codec::Gzip, &[(spec.clone(), archive)]);
+
+		let lib_rs_d = digest_of(lib_rs);
+		let bad = BlobCompressor::new(Codec::Gzip, None).compress(b"pub fn bar() {}\n").unwrap();
+		st.b.replace(lib_rs_d, &bad).unwrap();
+
+		match verify_crate(&mut st, &v) {
+			Some(Problem::Corrupt { .. }) => (),
+			p => panic!("Unexpected result {:?}", p),
+		}
+		assert_eq!(verify_crate_blobs(&mut st, &spec), vec![Problem::CorruptBlob {
+			digest : get_digest_hex(lib_rs_d),
+			actual : Some(get_digest_hex(digest_of(b"pub fn bar() {}\n"))),
+		}]);
+	}
+}
@@ -0,0 +1,253 @@
+/*!
+Storage files split into size bounded volumes
+
+Some filesystems and media can't hold a storage file
+of the size of the whole crates.io registry.
+A `VolumeSet` spreads the storage over numbered volume files
+`<base>.000`, `<base>.001`, ... of a maximum size each.
+
+The storage sees the volumes as one stream. An offset in the
+index addresses volume `offset / volume_size` at position
+`offset % volume_size` inside it. All volumes but the last one
+are filled up to the maximum size, so the volume size can be
+obtained from the first volume when reading.
+*/
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use super::read_at::ReadAt;
+use super::blob_storage::SyncAll;
+
+pub struct VolumeSet {
+	base :PathBuf,
+	volumes :Vec<File>,
+	/// Maximum size of a single volume
+	volume_size :u64,
+	writable :bool,
+	pos :u64,
+}
+
+/// Returns the path of the volume with the given number
+pub fn volume_path(base :&Path, idx :usize) -> PathBuf {
+	let mut name = base.file_name().map(|n| n.to_os_string()).unwrap_or_default();
+	name.push(format!(".{:03}", idx));
+	base.with_file_name(name)
+}
+
+fn open_volumes(base :&Path, writable :bool) -> io::Result<Vec<File>> {
+	let mut volumes = Vec::new();
+	loop {
+		let path = volume_path(base, volumes.len());
+		if !path.exists() {
+			break;
+		}
+		volumes.push(try!(OpenOptions::new()
+			.read(true)
+			.write(writable)
+			.open(path)));
+	}
+	Ok(volumes)
+}
+
+impl VolumeSet {
+	/// Opens the storage at the given path for reading
+	///
+	/// If there is a file at the path itself, it's used as the
+	/// only volume. Otherwise, the numbered volume files are opened.
+	pub fn open(base :&Path) -> io::Result<Self> {
+		if base.is_file() {
+			return Ok(VolumeSet {
+				base : base.to_path_buf(),
+				volumes : vec![try!(File::open(base))],
+				volume_size : u64::MAX,
+				writable : false,
+				pos : 0,
+			});
+		}
+		let volumes = try!(open_volumes(base, false));
+		if volumes.is_empty() {
+			return Err(io::Error::new(io::ErrorKind::NotFound,
+				format!("No volumes found for {}", base.display())));
+		}
+		let volume_size = if volumes.len() > 1 {
+			try!(volumes[0].metadata()).len()
+		} else {
+			u64::MAX
+		};
+		Ok(VolumeSet {
+			base : base.to_path_buf(),
+			volumes,
+			volume_size,
+			writable : false,
+			pos : 0,
+		})
+	}
+	/// Opens the volumes at the given path for reading and writing
+	///
+	/// If there are no volumes yet, the first one is created.
+	pub fn open_writable(base :&Path, volume_size :u64) -> io::Result<Self> {
+		if volume_size == 0 {
+			return Err(io::Error::new(io::ErrorKind::InvalidInput,
+				"Volume size must not be zero"));
+		}
+		let mut volumes = try!(open_volumes(base, true));
+		let mismatch = match volumes.split_last() {
+			Some((last, full)) => {
+				let mut mismatch = try!(last.metadata()).len() > volume_size;
+				for v in full.iter() {
+					mismatch |= try!(v.metadata()).len() != volume_size;
+				}
+				mismatch
+			},
+			None => {
+				volumes.push(try!(OpenOptions::new()
+					.read(true)
+					.write(true)
+					.create_new(true)
+					.open(volume_path(base, 0))));
+				false
+			},
+		};
+		if mismatch {
+			return Err(io::Error::new(io::ErrorKind::InvalidInput,
+				format!("Volumes of {} don't have a size of {} bytes",
+					base.display(), volume_size)));
+		}
+		Ok(VolumeSet {
+			base : base.to_path_buf(),
+			volumes,
+			volume_size,
+			writable : true,
+			pos : 0,
+		})
+	}
+	pub fn volume_count(&self) -> usize {
+		self.volumes.len()
+	}
+	/// Returns the volume and the offset inside it for a position
+	fn locate(&self, pos :u64) -> (usize, u64) {
+		((pos / self.volume_size) as usize, pos % self.volume_size)
+	}
+	fn len(&self) -> io::Result<u64> {
+		let last = self.volumes.len() - 1;
+		let last_len = try!(self.volumes[last].metadata()).len();
+		Ok(last as u64 * self.volume_size + last_len)
+	}
+}
+
+impl ReadAt for VolumeSet {
+	fn read_at(&self, buf :&mut [u8], pos :u64) -> io::Result<usize> {
+		let (idx, offset) = self.locate(pos);
+		let volume = match self.volumes.get(idx) {
+			Some(v) => v,
+			None => return Ok(0),
+		};
+		let len = (buf.len() as u64).min(self.volume_size - offset) as usize;
+		volume.read_at(&mut buf[.. len], offset)
+	}
+	fn content_len(&self) -> io::Result<u64> {
+		self.len()
+	}
+}
+
+impl Read for VolumeSet {
+	fn read(&mut self, buf :&mut [u8]) -> io::Result<usize> {
+		let read = try!(self.read_at(buf, self.pos));
+		self.pos += read as u64;
+		Ok(read)
+	}
+}
+
+impl Write for VolumeSet {
+	fn write(&mut self, buf :&[u8]) -> io::Result<usize> {
+		if !self.writable {
+			return Err(io::Error::new(io::ErrorKind::PermissionDenied,
+				"Volumes are opened read only"));
+		}
+		let (idx, offset) = self.locate(self.pos);
+		while self.volumes.len() <= idx {
+			let path = volume_path(&self.base, self.volumes.len());
+			self.volumes.push(try!(OpenOptions::new()
+				.read(true)
+				.write(true)
+				.create_new(true)
+				.open(path)));
+		}
+		let len = (buf.len() as u64).min(self.volume_size - offset) as usize;
+		let volume = &mut self.volumes[idx];
+		try!(volume.seek(SeekFrom::Start(offset)));
+		let written = try!(volume.write(&buf[.. len]));
+		self.pos += written as u64;
+		Ok(written)
+	}
+	fn flush(&mut self) -> io::Result<()> {
+		for v in self.volumes.iter_mut() {
+			try!(v.flush());
+		}
+		Ok(())
+	}
+}
+
+impl SyncAll for VolumeSet {
+	fn sync_all(&mut self) -> io::Result<()> {
+		for v in self.volumes.iter_mut() {
+			try!(v.sync_all());
+		}
+		Ok(())
+	}
+}
+
+impl Seek for VolumeSet {
+	fn seek(&mut self, pos :SeekFrom) -> io::Result<u64> {
+		let new_pos = match pos {
+			SeekFrom::Start(p) => Some(p),
+			SeekFrom::Current(d) => self.pos.checked_add_signed(d),
+			SeekFrom::End(d) => try!(self.len()).checked_add_signed(d),
+		};
+		self.pos = match new_pos {
+			Some(p) => p,
+			None => return Err(io::Error::new(io::ErrorKind::InvalidInput,
+				"Seek to a negative position")),
+		};
+		Ok(self.pos)
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use std::env;
+	use std::fs;
+	use std::process;
+	use blob_storage::BlobStorage;
+
+	#[test]
+	fn blob_storage_on_volumes() {
+		let dir = env::temp_dir().join(format!("volumes-test-{}", process::id()));
+		fs::create_dir_all(&dir).unwrap();
+		let base = dir.join("crate_storage");
+		let blobs = (0 .. 20u8)
+			.map(|i| ([i; 32], vec![i; 50 + i as usize * 10]))
+			.collect::<Vec<_>>();
+		{
+			let volumes = VolumeSet::open_writable(&base, 256).unwrap();
+			let mut st = BlobStorage::empty(volumes);
+			for (d, blob) in blobs.iter() {
+				st.insert(*d, blob).unwrap();
+			}
+			st.write_header_and_index().unwrap();
+		}
+		let volumes = VolumeSet::open(&base).unwrap();
+		assert!(volumes.volume_count() > 1);
+		for idx in 0 .. volumes.volume_count() {
+			assert!(fs::metadata(volume_path(&base, idx)).unwrap().len() <= 256);
+		}
+		let st = BlobStorage::load(volumes).unwrap();
+		for (d, blob) in blobs.iter() {
+			assert_eq!(st.get(d).unwrap().as_ref(), Some(blob));
+		}
+		assert!(VolumeSet::open_writable(&base, 512).is_err());
+		fs::remove_dir_all(&dir).unwrap();
+	}
+}
@@ -19,6 +19,7 @@ extern crate syntect;
 #[macro_use]
 extern crate lazy_static;
 extern crate failure;
+extern crate libc;
 #[macro_use]
 extern crate try;
 
@@ -26,7 +27,10 @@ extern crate all_crate_storage;
 
 use iron::prelude::*;
 use iron::{AfterMiddleware, Handler, status};
-use iron::headers::{ContentEncoding, Encoding, Location};
+use iron::headers::{ContentEncoding, ContentType, Encoding, Location};
+use iron::headers::{AcceptRanges, ByteRangeSpec, ContentLength, ContentRange,
+	ContentRangeSpec, Range, RangeUnit};
+use iron::response::{BodyReader, WriteBody};
 use hbs::{Template, HandlebarsEngine, DirectorySource};
 use hbs::handlebars::to_json;
 use serde_json::value::{Value, Map};
@@ -34,12 +38,13 @@ use serde_json::value::{Value, Map};
 use iron::headers::Referer;
 
 use std::time::Duration;
+use std::env;
 use std::path::Path;
-use std::fs::File;
-use std::io::Read;
-use std::cell::RefCell;
-use std::sync::RwLock;
-use std::fmt::Display;
+use std::process;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 use flate2::Compression;
 use flate2::write::GzEncoder;
@@ -56,7 +61,17 @@ use all_crate_storage::registry::registry::Registry;
 use all_crate_storage::registry::statistics::{compute_crate_statistics, CrateStats};
 use all_crate_storage::crate_storage::{DynCrateSource, FileTreeStorage, CrateSource};
 use all_crate_storage::blob_crate_storage::BlobCrateStorage;
+use all_crate_storage::dir_blob_storage::DirBlobStorage;
+use all_crate_storage::volumes::VolumeSet;
+use all_crate_storage::read_at::ReadAt;
+use all_crate_storage::remote::RemoteFile;
+use all_crate_storage::extracted_dir_storage::ExtractedDirStorage;
 use all_crate_storage::crate_storage::CrateSpec;
+use all_crate_storage::cache::CacheStats;
+use all_crate_storage::extraction::ExtractionLimits;
+use all_crate_storage::error::{CrateError, CrateErrorKind};
+use all_crate_storage::blob_storage::SyncAll;
+use failure::Fail;
 
 mod registry_data;
 mod markdown_render;
@@ -64,20 +79,29 @@ mod escape;
 mod code_format;
 mod syntect_format;
 
-#[derive(Debug)]
-pub struct StrErr(String);
+use registry_data::{FILE_CACHE, PageError};
+use markdown_render::MARKDOWN_CACHE;
 
-impl<T :Display> From<T> for StrErr {
-	fn from(v :T) -> Self {
-		StrErr(format!("{}", v))
-	}
+/// Creates an error that is shown to the user on the error page
+fn error_page(status :status::Status, msg :String) -> IronError {
+	let mut m = Map::new();
+	m.insert("error".to_string(), to_json(&msg));
+	IronError::new(io::Error::other(msg), (status, Template::new("error", m)))
 }
 
-impl StrErr {
-	fn as_map(&self) -> Map<String, Value> {
-		let mut m = Map::new();
-		m.insert("error".to_string(), to_json(&self.0));
-		m
+/// Creates the error response for a page whose data couldn't be obtained
+fn page_error(e :PageError) -> IronError {
+	match e {
+		PageError::NotFound(msg) => error_page(status::NotFound, msg),
+		PageError::NotMirrored(data) => {
+			IronError::new(io::Error::other("Version not mirrored"),
+				(status::NotFound, Template::new("not-mirrored", data)))
+		},
+		PageError::Source(spec, e) => {
+			let status = crate_error_status(&spec, &e);
+			error_page(status, format!("Couldn't obtain version {} of crate {}: {}",
+				spec.version, spec.name, e))
+		},
 	}
 }
 
@@ -90,22 +114,121 @@ impl AfterMiddleware for GzMiddleware {
 			return Ok(resp);
 		}
 
-		let compressed_bytes = resp.body.as_mut().map(|b| {
-			let mut encoder = GzEncoder::new(Vec::new(), Compression::best());
-			{
-				let _ = b.write_body(&mut encoder);
-			}
-			encoder.finish().unwrap()
-		});
-
-		if let Some(b) = compressed_bytes {
+		if let Some(b) = resp.body.take() {
+			resp.headers.remove::<ContentLength>();
 			resp.headers.set(ContentEncoding(vec![Encoding::Gzip]));
-			resp.set_mut(b);
+			resp.body = Some(Box::new(GzBody(b)));
 		}
 		Ok(resp)
 	}
 }
 
+/// Compresses a body while it is being written
+struct GzBody(Box<dyn WriteBody>);
+
+impl WriteBody for GzBody {
+	fn write_body(&mut self, res :&mut dyn Write) -> io::Result<()> {
+		let mut encoder = GzEncoder::new(res, Compression::best());
+		try!(self.0.write_body(&mut encoder));
+		try!(encoder.finish());
+		Ok(())
+	}
+}
+
+/// Streams a crate, or a file of it, from the crate source
+///
+/// The body opens the content only when it gets written,
+/// as the crate source can only be borrowed for that long.
+/// Before, `exists` checks for it without reading it.
+struct CrateBody {
+	spec :CrateSpec,
+	/// The path of the file inside the crate, or `None` for the .crate file
+	path :Option<String>,
+}
+
+impl CrateBody {
+	/// Calls `f` with a reader for the content
+	fn with_reader<T, F :FnOnce(&mut dyn Read) -> T>(&self, f :F) -> Result<T, CrateError> {
+		with_crate_source(|s| {
+			match self.path {
+				None => {
+					let mut rdr = try!(s.get_crate_reader(&self.spec));
+					Ok(f(&mut rdr))
+				},
+				Some(ref path) => {
+					let ch = try!(s.get_crate_handle_nv(self.spec.name.clone(),
+						self.spec.version.clone()));
+					let mut rdr = try!(ch.get_file_reader(path));
+					Ok(f(&mut rdr))
+				},
+			}
+		})
+	}
+	/// Checks whether the content is present, without reading it
+	fn exists(&self) -> Result<bool, CrateError> {
+		with_crate_source(|s| {
+			match self.path {
+				None => s.has_crate(&self.spec),
+				Some(ref path) => {
+					let ch = match s.get_crate_handle_nv(self.spec.name.clone(),
+							self.spec.version.clone()) {
+						Ok(ch) => ch,
+						Err(ref e) if *e.get_context() == CrateErrorKind::NotFound => return Ok(false),
+						Err(e) => return Err(e),
+					};
+					ch.has_file(path)
+				},
+			}
+		})
+	}
+	/// Whether the files of the crate can be found
+	fn files_exist(&self) -> bool {
+		with_crate_source(|s| {
+			s.get_crate_handle_nv(self.spec.name.clone(),
+				self.spec.version.clone()).is_ok()
+		})
+	}
+}
+
+impl WriteBody for CrateBody {
+	fn write_body(&mut self, res :&mut dyn Write) -> io::Result<()> {
+		let copied = self.with_reader(|rdr| io::copy(rdr, res));
+		match copied {
+			Ok(r) => r.map(|_| ()),
+			Err(e) => Err(io::Error::other(e.to_string())),
+		}
+	}
+}
+
+/// Returns the status to respond with for the error, and logs damaged data
+fn crate_error_status(spec :&CrateSpec, e :&CrateError) -> status::Status {
+	match *e.get_context() {
+		CrateErrorKind::NotFound => status::NotFound,
+		kind => {
+			let cause = e.cause().map(|c| c.to_string()).unwrap_or_default();
+			if kind.is_corruption() {
+				eprintln!("Corrupt data for {} {}: {} {}", spec.name, spec.version, e, cause);
+			} else {
+				eprintln!("Error obtaining {} {}: {} {}", spec.name, spec.version, e, cause);
+			}
+			status::InternalServerError
+		},
+	}
+}
+
+/// Responds with the given body, or with an error status if it isn't present
+///
+/// `exists` is the result of `CrateBody::exists`.
+fn crate_body_response(body :CrateBody, exists :Result<bool, CrateError>) -> Response {
+	match exists {
+		Ok(true) => (),
+		Ok(false) => return Response::with(status::NotFound),
+		Err(e) => return Response::with(crate_error_status(&body.spec, &e)),
+	}
+	let body :Box<dyn WriteBody> = Box::new(body);
+	Response::with((status::Ok, body))
+}
+
 struct FallbackHandler(Box<dyn Handler>);
 
 impl Handler for FallbackHandler {
@@ -114,6 +237,10 @@ impl Handler for FallbackHandler {
 
 		match resp {
 			Err(err) => {
+				if err.response.extensions.contains::<HandlebarsEngine>() {
+					// The handler has already chosen a page for the error
+					return Ok(err.response);
+				}
 				match err.response.status {
 					Some(status) => {
 						let mut m = Map::new();
@@ -134,14 +261,22 @@ lazy_static! {
 		Registry::from_name("github.com-1ecc6299db9ec823").unwrap();
 	static ref CRATE_STATS :CrateStats =
 		compute_crate_statistics(&REGISTRY.get_all_crates_json().unwrap());
-	static ref CRATE_SOURCE_GEN :RwLock<Option<Box<dyn Fn() -> DynCrateSource<File> + Send + Sync>>> = RwLock::new(None);
+	/// The storage file that is served for remote instances, if any
+	static ref STORAGE_FILE :RwLock<Option<Arc<VolumeSet>>> = RwLock::new(None);
+	static ref SYNC_PACK_COUNTER :AtomicUsize = AtomicUsize::new(0);
+	/// Number of sync requests that are being answered right now
+	static ref RUNNING_SYNCS :AtomicUsize = AtomicUsize::new(0);
+	/// The crate source, shared by all request handling threads
+	static ref CRATE_SOURCE :RwLock<Option<DynCrateSource<VolumeSet>>> = RwLock::new(None);
 }
 
-thread_local!(static CRATE_SOURCE :RefCell<DynCrateSource<File>> = {
-	let csg = CRATE_SOURCE_GEN.read().unwrap();
-	let dcs = csg.as_ref().unwrap()();
-	RefCell::new(dcs)
-});
+/// Calls `f` with the crate source
+fn with_crate_source<T, F :FnOnce(&DynCrateSource<VolumeSet>) -> T>(f :F) -> T {
+	// The source is only written to once, at startup,
+	// so the lock can't be poisoned by a panicking reader
+	let cs = CRATE_SOURCE.read().unwrap_or_else(|e| e.into_inner());
+	f(cs.as_ref().expect("crate source not initialized"))
+}
 
 header! { (ContentSecurityPolicy, "Content-Security-Policy") => [String] }
 
@@ -179,20 +314,11 @@ fn krate(r: &mut Request) -> IronResult<Response> {
 	let name = path[0];
 	let opt_version = path.get(1).map(|v| *v);
 	let mut resp = Response::new();
-	CRATE_SOURCE.with(|s| {
-		let data = registry_data::get_crate_data(name.to_string(),
-			&REGISTRY, &mut *s.borrow_mut(), opt_version);
-		match data {
-			Ok(d) => {
-				resp.set_mut(Template::new("crate", d))
-					.set_mut(status::Ok);
-			},
-			Err(e) => {
-				resp.set_mut(Template::new("error", e.as_map()))
-					.set_mut(status::Ok);
-			},
-		}
-	});
+	let data = try!(with_crate_source(|s| {
+		registry_data::get_crate_data(name.to_string(), &REGISTRY, s, opt_version)
+	}).map_err(page_error));
+	resp.set_mut(Template::new("crate", data))
+		.set_mut(status::Ok);
 	Ok(resp)
 }
 
@@ -204,7 +330,8 @@ fn versions(r: &mut Request) -> IronResult<Response> {
 	let refferer = r.headers.get::<Referer>()
 		.map(|s| s.as_str().to_string());
 
-	let crate_data = registry_data::get_versions_data(name, &REGISTRY, refferer);
+	let crate_data = try!(registry_data::get_versions_data(name, &REGISTRY, refferer)
+		.map_err(page_error));
 	resp.set_mut(Template::new("versions", crate_data))
 		.set_mut(status::Ok);
 	Ok(resp)
@@ -220,8 +347,8 @@ fn reverse_dependencies(r: &mut Request) -> IronResult<Response> {
 
 	let only_latest_versions = false;
 
-	let crate_data = registry_data::get_reverse_dependencies(name,
-		only_latest_versions, &CRATE_STATS, refferer);
+	let crate_data = try!(registry_data::get_reverse_dependencies(name,
+		only_latest_versions, &CRATE_STATS, refferer).map_err(page_error));
 	resp.set_mut(Template::new("reverse_dependencies", crate_data))
 		.set_mut(status::Ok);
 	Ok(resp)
@@ -255,20 +382,22 @@ fn crate_files(req :&mut Request) -> IronResult<Response> {
 	use self::registry_data::CrateFileData::*;
 
 	let path = req.url.path();
+	if path.len() < 2 {
+		return Err(error_page(status::NotFound, "No crate version given".to_owned()));
+	}
 	let name = path[0];
 	let version = path[1];
 	let mut resp = Response::new();
 
-	CRATE_SOURCE.with(|s| {
-		let crate_file_data = registry_data::get_crate_file_data(
-			&mut *s.borrow_mut(), name, version, &path[2..]);
-		let template = match crate_file_data {
-			FileListing(data) => Template::new("file-listing", data),
-			FileContent(data) => Template::new("file-content", data),
-		};
-		resp.set_mut(template)
-			.set_mut(status::Ok);
-	});
+	let crate_file_data = try!(with_crate_source(|s| {
+		registry_data::get_crate_file_data(s, name, version, &path[2..])
+	}).map_err(page_error));
+	let template = match crate_file_data {
+		FileListing(data) => Template::new("file-listing", data),
+		FileContent(data) => Template::new("file-content", data),
+	};
+	resp.set_mut(template)
+		.set_mut(status::Ok);
 	Ok(resp)
 }
 
@@ -276,49 +405,405 @@ fn api_crate(req :&mut Request) -> IronResult<Response> {
 
 	println!("{:?}", req.url.path());
 	let path = req.url.path();
+	if path.len() < 2 {
+		return Ok(Response::with(status::NotFound));
+	}
 	let name = path[0];
-	let version = path[1];
-	let mut resp = Response::new();
-
-	let sv_version = SvVersion::parse(version).unwrap();
+	let sv_version = match SvVersion::parse(path[1]) {
+		Ok(v) => v,
+		Err(_) => return Ok(Response::with(status::NotFound)),
+	};
 	let crate_spec = CrateSpec {
 		name : name.to_string(),
 		version : sv_version,
 	};
-	CRATE_SOURCE.with(|s| {
-		let s = &mut *s.borrow_mut();
-		let crate_opt = s.get_crate(&crate_spec);
-		if let Some(crate_data) = crate_opt {
-			use all_crate_storage::reconstruction::CrateContentBlobs;
-			let ccb = CrateContentBlobs::from_archive_file(&crate_data as &[u8]).unwrap();
-			let crate_data = ccb.to_archive_file();
-			resp.set_mut(crate_data)
+	let body = CrateBody {
+		spec : crate_spec,
+		path : None,
+	};
+	let exists = body.exists();
+	if let Ok(false) = exists {
+		if body.files_exist() {
+			// Sources of extracted crates have the files but not the archive
+			let msg = format!("The .crate archive of {} {} is not available on this mirror, \
+				only its extracted files are.", body.spec.name, body.spec.version);
+			return Ok(Response::with((status::NotFound, msg)));
+		}
+	}
+	Ok(crate_body_response(body, exists))
+}
+
+/// Serves the raw content of a file of a crate
+fn raw_file(req :&mut Request) -> IronResult<Response> {
+	let path = req.url.path();
+	if path.len() < 3 {
+		return Ok(Response::with(status::NotFound));
+	}
+	let version = match SvVersion::parse(path[1]) {
+		Ok(v) => v,
+		Err(_) => return Ok(Response::with(status::NotFound)),
+	};
+	let body = CrateBody {
+		spec : CrateSpec {
+			name : path[0].to_string(),
+			version,
+		},
+		path : Some(path[2..].join("/")),
+	};
+	let exists = body.exists();
+	let mut resp = crate_body_response(body, exists);
+	resp.headers.set(ContentType::plaintext());
+	Ok(resp)
+}
+
+/// Maximum number of sync requests that are answered at the same time
+const MAX_CONCURRENT_SYNCS :usize = 2;
+
+/// Free space of the temporary directory that update packs may not use
+const SYNC_SPACE_RESERVE :u64 = 1024 * 1024 * 1024;
+
+/// A slot among the `MAX_CONCURRENT_SYNCS`, freed when dropped
+struct SyncSlot;
+
+impl SyncSlot {
+	fn acquire() -> Option<Self> {
+		if RUNNING_SYNCS.fetch_add(1, Ordering::SeqCst) >= MAX_CONCURRENT_SYNCS {
+			RUNNING_SYNCS.fetch_sub(1, Ordering::SeqCst);
+			return None;
+		}
+		Some(SyncSlot)
+	}
+}
+
+impl Drop for SyncSlot {
+	fn drop(&mut self) {
+		RUNNING_SYNCS.fetch_sub(1, Ordering::SeqCst);
+	}
+}
+
+/// An update pack being sent, which holds its `SyncSlot` until it is dropped
+///
+/// The pack keeps taking up space until it has been sent.
+struct SyncPackReader {
+	file :File,
+	_slot :SyncSlot,
+}
+
+impl Read for SyncPackReader {
+	fn read(&mut self, buf :&mut [u8]) -> io::Result<usize> {
+		self.file.read(buf)
+	}
+}
+
+/// Returns the number of bytes that can be written to the file system of `path`
+#[cfg(unix)]
+fn available_space(path :&Path) -> io::Result<u64> {
+	use std::ffi::CString;
+	use std::mem;
+	use std::os::unix::ffi::OsStrExt;
+
+	let path = try!(CString::new(path.as_os_str().as_bytes())
+		.map_err(io::Error::other));
+	let mut stat :libc::statvfs = unsafe { mem::zeroed() };
+	if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+		return Err(io::Error::last_os_error());
+	}
+	Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+/// Returns the number of bytes that can be written to the file system of `path`
+#[cfg(not(unix))]
+fn available_space(_path :&Path) -> io::Result<u64> {
+	// Not determined, update packs are only limited by the number of syncs
+	Ok(u64::MAX)
+}
+
+/// A file that refuses writes beyond a given length
+struct SizeLimitedFile {
+	file :File,
+	limit :u64,
+}
+
+impl ReadAt for SizeLimitedFile {
+	fn read_at(&self, buf :&mut [u8], offset :u64) -> io::Result<usize> {
+		self.file.read_at(buf, offset)
+	}
+	fn content_len(&self) -> io::Result<u64> {
+		self.file.content_len()
+	}
+}
+
+impl Write for SizeLimitedFile {
+	fn write(&mut self, buf :&[u8]) -> io::Result<usize> {
+		let pos = try!(self.file.stream_position());
+		if pos + buf.len() as u64 > self.limit {
+			return Err(io::Error::other("Update pack exceeds the free space"));
+		}
+		self.file.write(buf)
+	}
+	fn flush(&mut self) -> io::Result<()> {
+		self.file.flush()
+	}
+}
+
+impl Seek for SizeLimitedFile {
+	fn seek(&mut self, pos :SeekFrom) -> io::Result<u64> {
+		self.file.seek(pos)
+	}
+}
+
+impl SyncAll for SizeLimitedFile {
+	fn sync_all(&mut self) -> io::Result<()> {
+		self.file.sync_all()
+	}
+}
+
+/// Answers a sync request of another instance
+///
+/// The request body is the inventory of the other instance's
+/// storage. The response is an update pack with
+/// everything in our storage that the other instance lacks.
+///
+/// The pack is written to the temporary directory first. Only
+/// `MAX_CONCURRENT_SYNCS` requests are answered at a time, and
+/// each pack may only take its share of the free space there.
+fn sync(req :&mut Request) -> IronResult<Response> {
+	use all_crate_storage::sync::{Inventory, MAX_INVENTORY_LEN};
+	use all_crate_storage::update_pack::create_update_pack;
+
+	let io_err = |e| IronError::new(e, status::InternalServerError);
+	let storage_err = |e :all_crate_storage::blob_storage::StorageError| {
+		io_err(io::Error::other(e.to_string()))
+	};
+	let slot = match SyncSlot::acquire() {
+		Some(slot) => slot,
+		None => return Ok(Response::with(status::ServiceUnavailable)),
+	};
+	let space = try!(available_space(&env::temp_dir()).map_err(io_err));
+	let limit = space.saturating_sub(SYNC_SPACE_RESERVE) / MAX_CONCURRENT_SYNCS as u64;
+	if limit == 0 {
+		return Ok(Response::with(status::ServiceUnavailable));
+	}
+	// Oversized inventories are cut off, making them invalid
+	let mut inventory = match Inventory::deserialize((&mut req.body).take(MAX_INVENTORY_LEN)) {
+		Ok(inv) => inv,
+		Err(e) => return Err(IronError::new(io::Error::other(e.to_string()),
+			status::BadRequest)),
+	};
+	let pack_path = env::temp_dir().join(format!("sync-pack-{}-{}",
+		process::id(), SYNC_PACK_COUNTER.fetch_add(1, Ordering::Relaxed)));
+	let mut pack_file = try!(OpenOptions::new().read(true).write(true)
+		.create_new(true).open(&pack_path).map_err(io_err));
+	let limited = SizeLimitedFile {
+		file : try!(pack_file.try_clone().map_err(io_err)),
+		limit,
+	};
+	let mut pack = BlobCrateStorage::empty(limited);
+	let res = with_crate_source(|s| {
+		match *s {
+			DynCrateSource::BlobCrateStorage(ref s) => {
+				create_update_pack(&mut inventory, s, &mut pack).map(Some)
+			},
+			DynCrateSource::BlobDirectory(ref s) => {
+				create_update_pack(&mut inventory, s, &mut pack).map(Some)
+			},
+			_ => Ok(None),
+		}
+	});
+	let res = res.map_err(storage_err);
+	drop(pack);
+	// The file stays accessible through the handle
+	// after it has been removed (on unix at least)
+	let _ = fs::remove_file(&pack_path);
+	if try!(res).is_none() {
+		// Only blob based sources can be synced from
+		return Ok(Response::with(status::NotFound));
+	}
+	let len = try!(pack_file.seek(SeekFrom::End(0)).map_err(io_err));
+	try!(pack_file.seek(SeekFrom::Start(0)).map_err(io_err));
+	let mut resp = Response::new();
+	let rdr = SyncPackReader {
+		file : pack_file,
+		_slot : slot,
+	};
+	resp.headers.set(ContentLength(len));
+	resp.set_mut(BodyReader(rdr))
+		.set_mut(status::Ok);
+	Ok(resp)
+}
+
+/// Returns the first and last byte of the range, if it can be satisfied
+fn resolve_byte_range(spec :&ByteRangeSpec, len :u64) -> Option<(u64, u64)> {
+	let (from, to) = match *spec {
+		ByteRangeSpec::FromTo(from, to) => (from, to.min(len.saturating_sub(1))),
+		ByteRangeSpec::AllFrom(from) => (from, len.saturating_sub(1)),
+		ByteRangeSpec::Last(count) => (len.saturating_sub(count), len.saturating_sub(1)),
+	};
+	if from < len && from <= to {
+		Some((from, to))
+	} else {
+		None
+	}
+}
+
+/// Reads the served storage file, from a position of its own
+struct StorageFileReader {
+	file :Arc<VolumeSet>,
+	pos :u64,
+}
+
+impl Read for StorageFileReader {
+	fn read(&mut self, buf :&mut [u8]) -> io::Result<usize> {
+		let read = try!(self.file.read_at(buf, self.pos));
+		self.pos += read as u64;
+		Ok(read)
+	}
+}
+
+/// Serves the storage file that the crates are obtained from
+///
+/// Range requests are supported, so other instances
+/// can use it as `RemoteStorageFile` source.
+fn storage_file(req :&mut Request) -> IronResult<Response> {
+	let file = match *STORAGE_FILE.read().unwrap() {
+		Some(ref f) => f.clone(),
+		None => return Ok(Response::with(status::NotFound)),
+	};
+	let io_err = |e| IronError::new(e, status::InternalServerError);
+	let len = try!(file.content_len().map_err(io_err));
+	let range = match req.headers.get::<Range>() {
+		Some(Range::Bytes(specs)) if specs.len() == 1 => {
+			Some(resolve_byte_range(&specs[0], len))
+		},
+		// Multiple ranges are not supported, so the whole file is sent
+		_ => None,
+	};
+	let mut resp = Response::new();
+	resp.headers.set(AcceptRanges(vec![RangeUnit::Bytes]));
+	match range {
+		None => {
+			let rdr = StorageFileReader { file, pos : 0 };
+			resp.headers.set(ContentLength(len));
+			resp.set_mut(BodyReader(rdr.take(len)))
 				.set_mut(status::Ok);
-		} else {
-			resp.set_mut(status::NotFound);
+		},
+		Some(None) => {
+			resp.headers.set(ContentRange(ContentRangeSpec::Bytes {
+				range : None,
+				instance_length : Some(len),
+			}));
+			resp.set_mut(status::RangeNotSatisfiable);
+		},
+		Some(Some((from, to))) => {
+			let rdr = StorageFileReader { file, pos : from };
+			resp.headers.set(ContentLength(to - from + 1));
+			resp.headers.set(ContentRange(ContentRangeSpec::Bytes {
+				range : Some((from, to)),
+				instance_length : Some(len),
+			}));
+			resp.set_mut(BodyReader(rdr.take(to - from + 1)))
+				.set_mut(status::PartialContent);
+		},
+	}
+	Ok(resp)
+}
+
+/// Responds with the hit and miss counts of the caches, as JSON
+fn cache_stats(_req :&mut Request) -> IronResult<Response> {
+	#[derive(Serialize)]
+	struct Stats {
+		/// `None` if the source doesn't have a metadata cache
+		metadata :Option<CacheStats>,
+		files :CacheStats,
+		markdown :CacheStats,
+	}
+	let metadata = with_crate_source(|s| {
+		match *s {
+			DynCrateSource::BlobCrateStorage(ref s) => Some(s.metadata_cache_stats()),
+			DynCrateSource::BlobDirectory(ref s) => Some(s.metadata_cache_stats()),
+			DynCrateSource::RemoteStorageFile(ref s) => Some(s.metadata_cache_stats()),
+			_ => None,
 		}
 	});
+	let stats = Stats {
+		metadata,
+		files : FILE_CACHE.stats(),
+		markdown : MARKDOWN_CACHE.stats(),
+	};
+	let mut resp = Response::with((status::Ok, serde_json::to_string(&stats).unwrap()));
+	resp.headers.set(ContentType::json());
 	Ok(resp)
 }
 
 #[derive(Deserialize, Debug)]
 #[serde(tag = "kind")]
 enum CrateSourceCfg {
-	Cache,
+	Cache {
+		index_dir :Option<String>,
+	},
 	ArchiveTree {
 		path :Option<String>,
+		index_dir :Option<String>,
 	},
 	StorageFile {
 		path :Option<String>,
+		verify_digests :Option<bool>,
+	},
+	BlobDirectory {
+		path :Option<String>,
+		verify_digests :Option<bool>,
+	},
+	RemoteStorageFile {
+		url :String,
+		cache_dir :Option<String>,
+		verify_digests :Option<bool>,
+	},
+	ExtractedDirectory {
+		path :Option<String>,
 	},
 }
 
+/// Capacities of the in memory caches, in numbers of entries
+#[derive(Deserialize, Debug, Default)]
+struct CacheCfg {
+	/// Crates whose metadata is kept, for blob based sources
+	metadata_entries :Option<usize>,
+	/// Files of crates, like Cargo.toml or README files
+	file_entries :Option<usize>,
+	/// Rendered markdown files
+	markdown_entries :Option<usize>,
+}
+
+/// Limits for inflating crate archives
+#[derive(Deserialize, Debug, Default)]
+struct LimitsCfg {
+	/// Maximum inflated size of an archive, in bytes
+	max_total_size :Option<u64>,
+	/// Maximum number of entries of an archive
+	max_entries :Option<u64>,
+	/// Maximum size of a single file, in bytes
+	max_file_size :Option<u64>,
+}
+
+impl LimitsCfg {
+	fn extraction_limits(&self) -> ExtractionLimits {
+		let default = ExtractionLimits::default();
+		ExtractionLimits {
+			max_total_size : self.max_total_size.unwrap_or(default.max_total_size),
+			max_entries : self.max_entries.unwrap_or(default.max_entries),
+			max_file_size : self.max_file_size.unwrap_or(default.max_file_size),
+		}
+	}
+}
+
 #[derive(Deserialize, Debug)]
 struct AppConfigOpt {
 	site_dir :Option<String>,
 	listen_host :Option<String>,
 	listen_port :Option<u32>,
 	source :Option<CrateSourceCfg>,
+	cache :Option<CacheCfg>,
+	limits :Option<LimitsCfg>,
 }
 
 // This construct with AppConfig and AppConfigOpt
@@ -329,6 +814,8 @@ struct AppConfig {
 	listen_host :String,
 	listen_port :u32,
 	source :CrateSourceCfg,
+	cache :CacheCfg,
+	limits :LimitsCfg,
 }
 
 impl AppConfig {
@@ -337,7 +824,11 @@ impl AppConfig {
 			site_dir : o.site_dir,
 			listen_host : o.listen_host.unwrap_or("localhost".to_owned()),
 			listen_port : o.listen_port.unwrap_or(3000),
-			source : o.source.unwrap_or(CrateSourceCfg::Cache),
+			source : o.source.unwrap_or(CrateSourceCfg::Cache {
+				index_dir : None,
+			}),
+			cache : o.cache.unwrap_or_default(),
+			limits : o.limits.unwrap_or_default(),
 		}
 	}
 }
@@ -383,38 +874,84 @@ fn main() {
 	let template_dir = site_dir.to_owned() + "templates/";
 	let static_dir = site_dir.to_owned() + "/static/";
 
-	{
-		let mut csg = CRATE_SOURCE_GEN.write().unwrap();
-		let b = match cfg.source {
-			CrateSourceCfg::Cache => Box::new(|| {
-				DynCrateSource::CacheStorage(REGISTRY.get_cache_storage())
-			}) as Box<dyn Fn() -> DynCrateSource<File> + Send + Sync>,
-			CrateSourceCfg::ArchiveTree { path } => {
-				let p = if let Some(p) = path {
-					p
-				} else {
-					String::from("crate-archives")
-				};
-				Box::new(move || {
-					DynCrateSource::FileTreeStorage(FileTreeStorage::new(Path::new(&p)))
-				}) as Box<dyn Fn() -> DynCrateSource<File> + Send + Sync>
-			},
-			CrateSourceCfg::StorageFile { path } => {
-				let p = if let Some(p) = path {
-					p
-				} else {
-					String::from("crate-constr-archives/crate_storage")
-				};
-				Box::new(move || {
-					let f = File::open(&p).unwrap();
-					let bcs = BlobCrateStorage::new(f).unwrap();
-					let dcs :DynCrateSource<File> =  DynCrateSource::BlobCrateStorage(bcs);
-					dcs
-				}) as Box<dyn Fn() -> DynCrateSource<File> + Send + Sync>
-			},
-		};
-		*csg = Some(b);
-	}
+	let metadata_entries = cfg.cache.metadata_entries.unwrap_or(1000);
+	FILE_CACHE.set_capacity(cfg.cache.file_entries.unwrap_or(1000));
+	MARKDOWN_CACHE.set_capacity(cfg.cache.markdown_entries.unwrap_or(200));
+
+	let mut crate_source = match cfg.source {
+		CrateSourceCfg::Cache { index_dir } => {
+			let mut cs = REGISTRY.get_cache_storage();
+			cs.set_index_dir(index_dir.as_ref().map(Path::new));
+			DynCrateSource::CacheStorage(cs)
+		},
+		CrateSourceCfg::ArchiveTree { path, index_dir } => {
+			let p = if let Some(p) = path {
+				p
+			} else {
+				String::from("crate-archives")
+			};
+			let mut fts = FileTreeStorage::new(Path::new(&p));
+			fts.set_index_dir(index_dir.as_ref().map(Path::new));
+			DynCrateSource::FileTreeStorage(fts)
+		},
+		CrateSourceCfg::StorageFile { path, verify_digests } => {
+			let p = if let Some(p) = path {
+				p
+			} else {
+				String::from("crate-constr-archives/crate_storage")
+			};
+			// Either a single file or numbered volumes.
+			// It is opened read-only, so it never gets repaired.
+			let open = || VolumeSet::open(Path::new(&p)).unwrap_or_else(|e| {
+				panic!("Couldn't open storage file {}: {}", p, e)
+			});
+			// A handle of its own for serving the file, so that
+			// requests for it don't need the crate source
+			*STORAGE_FILE.write().unwrap() = Some(Arc::new(open()));
+			let f = open();
+			let mut bcs = BlobCrateStorage::new(f).unwrap_or_else(|e| {
+				panic!("Couldn't open storage file {}: {}", p, e)
+			});
+			bcs.set_verify_digests(verify_digests.unwrap_or(true));
+			bcs.set_metadata_cache_capacity(metadata_entries);
+			DynCrateSource::BlobCrateStorage(Box::new(bcs))
+		},
+		CrateSourceCfg::BlobDirectory { path, verify_digests } => {
+			let p = if let Some(p) = path {
+				p
+			} else {
+				String::from("crate-constr-archives/blobs")
+			};
+			let dbs = DirBlobStorage::open(Path::new(&p)).unwrap_or_else(|e| {
+				panic!("Couldn't open blob directory {}: {}", p, e)
+			});
+			let mut bcs = BlobCrateStorage::from_blob_store(dbs);
+			bcs.set_verify_digests(verify_digests.unwrap_or(true));
+			bcs.set_metadata_cache_capacity(metadata_entries);
+			DynCrateSource::BlobDirectory(Box::new(bcs))
+		},
+		CrateSourceCfg::RemoteStorageFile { url, cache_dir, verify_digests } => {
+			let f = RemoteFile::open(&url, cache_dir.as_ref().map(Path::new))
+				.unwrap_or_else(|e| {
+					panic!("Couldn't open remote storage file {}: {}", url, e)
+				});
+			let mut bcs = BlobCrateStorage::new(f).unwrap_or_else(|e| {
+				panic!("Couldn't open remote storage file {}: {}", url, e)
+			});
+			bcs.set_verify_digests(verify_digests.unwrap_or(true));
+			bcs.set_metadata_cache_capacity(metadata_entries);
+			DynCrateSource::RemoteStorageFile(Box::new(bcs))
+		},
+		CrateSourceCfg::ExtractedDirectory { path } => {
+			let eds = match path {
+				Some(ref p) => ExtractedDirStorage::new(Path::new(p)),
+				None => REGISTRY.get_extracted_dir_storage(),
+			};
+			DynCrateSource::ExtractedDirStorage(eds)
+		},
+	};
+	crate_source.set_extraction_limits(cfg.limits.extraction_limits());
+	*CRATE_SOURCE.write().unwrap() = Some(crate_source);
 
 	// add a directory source, all files with .hbs suffix will be loaded as template
 	let template_dir :&str = &template_dir;
@@ -433,7 +970,11 @@ fn main() {
 		.cache(Duration::from_secs(30 * 24 * 60 * 60)));
 	mount.mount("/search", search);
 	mount.mount("/files", crate_files);
+	mount.mount("/raw", raw_file);
 	mount.mount("/api/v1/crates", api_crate);
+	mount.mount("/api/v1/storage_file", storage_file);
+	mount.mount("/api/v1/sync", sync);
+	mount.mount("/api/v1/cache_stats", cache_stats);
 	mount.mount("/", index);
 	let mut chain = Chain::new(FallbackHandler(Box::new(mount)));
 	chain.link_after(hbse);
@@ -1,6 +1,8 @@
 use pulldown_cmark::{html, Parser, Event, Tag, CodeBlockKind};
 use ammonia::Builder;
 use syntect_format::SyntectFormatter;
+use all_crate_storage::cache::LruCache;
+use all_crate_storage::hash_ctx::{Digest, digest_of};
 
 struct EventIter<'a> {
 	p :Parser<'a>,
@@ -16,6 +18,8 @@ impl<'a> EventIter<'a> {
 
 lazy_static! {
 	static ref AMMONIA_BUILDER :Builder<'static> = construct_ammonia_builder();
+	/// Rendered markdown, by digest of the markdown source
+	pub static ref MARKDOWN_CACHE :LruCache<Digest, String> = LruCache::new(0);
 }
 
 impl<'a> Iterator for EventIter<'a> {
@@ -69,7 +73,19 @@ fn construct_ammonia_builder() -> Builder<'static> {
 
 /// Renders a given markdown string to sanitized HTML
 /// with formatted code blocks.
+///
+/// The result is kept in `MARKDOWN_CACHE`.
 pub fn render_markdown(markdown :&str) -> String {
+	let d = digest_of(markdown.as_bytes());
+	if let Some(html) = MARKDOWN_CACHE.get(&d) {
+		return html;
+	}
+	let html = render_markdown_uncached(markdown);
+	MARKDOWN_CACHE.insert(d, html.clone());
+	html
+}
+
+fn render_markdown_uncached(markdown :&str) -> String {
 	let p = Parser::new(&markdown);
 	let ev_it = EventIter::new(p);
 	let mut unsafe_html = String::new();
@@ -7,9 +7,95 @@ use urlencoded::QueryMap;
 
 use all_crate_storage::registry::registry::{Dependency, Registry, DependencyKind};
 use all_crate_storage::registry::statistics::CrateStats;
-use all_crate_storage::crate_storage::CrateSource;
+use all_crate_storage::crate_storage::{CrateSource, CrateHandle, CrateSpec};
+use all_crate_storage::cache::LruCache;
+use all_crate_storage::error::{CrateError, CrateErrorKind};
 use super::markdown_render::render_markdown;
-use crate::StrErr;
+
+lazy_static! {
+	/// Contents of crate files, by crate and path inside the crate
+	pub static ref FILE_CACHE :LruCache<(CrateSpec, String), Vec<u8>> = LruCache::new(0);
+}
+
+/// Files larger than this are not put into `FILE_CACHE`
+const MAX_CACHED_FILE_LEN :usize = 256 * 1024;
+
+/// Obtains a file of the crate, using `FILE_CACHE`
+fn get_file_cached<C :CrateSource>(fh :&CrateHandle<C, C::CrateHandle>,
+		spec :&CrateSpec, path :&str) -> Result<Vec<u8>, CrateError> {
+	let key = (spec.clone(), path.to_owned());
+	if let Some(content) = FILE_CACHE.get(&key) {
+		return Ok(content);
+	}
+	let content = try!(fh.get_file(path));
+	if content.len() <= MAX_CACHED_FILE_LEN {
+		FILE_CACHE.insert(key, content.clone());
+	}
+	Ok(content)
+}
+
+/// Obtains a file of the crate that might be missing, using `FILE_CACHE`
+fn get_optional_file<C :CrateSource>(fh :&CrateHandle<C, C::CrateHandle>,
+		spec :&CrateSpec, path :&str) -> Result<Option<Vec<u8>>, PageError> {
+	match get_file_cached(fh, spec, path) {
+		Ok(content) => Ok(Some(content)),
+		Err(ref e) if *e.get_context() == CrateErrorKind::NotFound => Ok(None),
+		Err(e) => Err(PageError::Source(Box::new(spec.clone()), e)),
+	}
+}
+
+/// Reasons why the data for a page can't be obtained
+pub enum PageError {
+	/// The crate, the version or the file doesn't exist
+	NotFound(String),
+	/// The version exists, but isn't present in the crate source
+	///
+	/// Contains the data for the "not-mirrored" template.
+	NotMirrored(Map<String, Value>),
+	/// The crate source failed to provide the crate
+	Source(Box<CrateSpec>, CrateError),
+}
+
+/// Obtains the handle of the crate from the source
+///
+/// Versions that aren't present in the source result in `None`.
+fn get_optional_crate_handle<'a, C :CrateSource>(st :&'a C, name :&str,
+		version :&SvVersion) -> Result<Option<CrateHandle<'a, C, C::CrateHandle>>, PageError> {
+	match st.get_crate_handle_nv(name.to_owned(), version.clone()) {
+		Ok(fh) => Ok(Some(fh)),
+		Err(ref e) if *e.get_context() == CrateErrorKind::NotFound => Ok(None),
+		Err(e) => Err(source_error(name, version, e)),
+	}
+}
+
+/// Obtains the handle of the crate from the source
+///
+/// Versions that aren't present in the source result in the "not-mirrored" page.
+fn get_crate_handle<'a, C :CrateSource>(st :&'a C, name :&str,
+		version :&SvVersion) -> Result<CrateHandle<'a, C, C::CrateHandle>, PageError> {
+	if let Some(fh) = try!(get_optional_crate_handle(st, name, version)) {
+		return Ok(fh);
+	}
+	match get_not_mirrored_data(st, name, version) {
+		Ok(data) => Err(PageError::NotMirrored(data)),
+		Err(e) => Err(source_error(name, version, e)),
+	}
+}
+
+fn source_error(name :&str, version :&SvVersion, e :CrateError) -> PageError {
+	let spec = CrateSpec {
+		name : name.to_owned(),
+		version : version.clone(),
+	};
+	PageError::Source(Box::new(spec), e)
+}
+
+/// Parses the version from the url
+fn parse_version(name :&str, version :&str) -> Result<SvVersion, PageError> {
+	SvVersion::parse(version).map_err(|_| {
+		PageError::NotFound(format!("Invalid version '{}' of crate '{}'", version, name))
+	})
+}
 
 #[derive(Serialize, Debug)]
 pub struct Crate {
@@ -17,6 +103,10 @@ pub struct Crate {
 	version :String,
 	de :Option<CrateDetails>,
 	err_msg :Option<String>,
+	/// Whether the version is present in the crate source
+	mirrored :bool,
+	/// The versions present in the crate source, if this one isn't
+	mirrored_versions :Vec<Version>,
 	documentation :String,
 	versions :Vec<Version>,
 	versions_limited :Option<usize>,
@@ -89,6 +179,8 @@ pub fn winapi_crate_data() -> Map<String, Value> {
 			license : "MIT".to_string(),
 		}),
 		err_msg : None,
+		mirrored : true,
+		mirrored_versions : vec![],
 		documentation : "https://docs.rs/winapi".to_string(),
 		versions : vec![
 			Version {
@@ -116,29 +208,47 @@ pub fn winapi_crate_data() -> Map<String, Value> {
 	data
 }
 
-pub fn get_crate_data<C :CrateSource>(name :String, reg :&Registry, st :&mut C,
-		version :Option<&str>) -> Result<Map<String, Value>, StrErr> {
+pub fn get_crate_data<C :CrateSource>(name :String, reg :&Registry, st :&C,
+		requested_version :Option<&str>) -> Result<Map<String, Value>, PageError> {
 
 	let mut data = Map::new();
 
 	// First step: find the path to the crate.
-	let crate_json = reg.get_crate_json(&name)
-		.map_err(|_| format!("Couldn't get crate json for crate '{}'", name))?;
-	let version = if let Some(v) = version {
-		SvVersion::parse(v).unwrap()
+	let crate_json = try!(reg.get_crate_json(&name).map_err(|_| {
+		PageError::NotFound(format!("Couldn't get crate json for crate '{}'", name))
+	}));
+	let version = if let Some(v) = requested_version {
+		try!(parse_version(&name, v))
 	} else {
 		// Finds the latest version
-		crate_json.iter()
+		try!(crate_json.iter()
 			.map(|v| &v.version)
 			.max()
-			.ok_or_else(|| format!("No version present of crate '{}'", name))?
+			.ok_or_else(|| PageError::NotFound(format!("No version present of crate '{}'", name))))
 			.clone()
 	};
-
-	let dtls = get_crate_details(&name, version.clone(), st);
-	let (de, err_msg) = match dtls {
-		Ok(d) => (Some(d), None),
-		Err(msg) => (None, Some(msg)),
+	let json_for_version = try!(crate_json.iter()
+		.find(|v| v.version == version)
+		.ok_or_else(|| PageError::NotFound(format!("Crate '{}' has no version {}", name, version))));
+
+	// The page of the latest version still shows the data from the
+	// registry if that version isn't present in the crate source.
+	// Explicitly requested versions get the "not-mirrored" page.
+	let fh = if requested_version.is_some() {
+		Some(try!(get_crate_handle(st, &name, &version)))
+	} else {
+		try!(get_optional_crate_handle(st, &name, &version))
+	};
+	let (de, err_msg, mirrored_versions) = if let Some(fh) = fh {
+		match try!(get_crate_details(&name, version.clone(), &fh)) {
+			Ok(d) => (Some(d), None, None),
+			Err(msg) => (None, Some(msg), None),
+		}
+	} else {
+		let mirrored_versions = try!(get_mirrored_versions(st, &name)
+			.map_err(|e| source_error(&name, &version, e)));
+		let msg = format!("Version {} of crate {} is not mirrored", version, name);
+		(None, Some(msg), Some(mirrored_versions))
 	};
 
 	let versions = crate_json.iter()
@@ -149,9 +259,6 @@ pub fn get_crate_data<C :CrateSource>(name :String, reg :&Registry, st :&mut C,
 	} else {
 		(0, false)
 	};
-	let json_for_version = crate_json.iter()
-		.filter(|v| v.version == version).next().unwrap();
-
 	let dev_deps :Vec<Dependency> = json_for_version.dependencies.iter()
 			.filter(|d| d.kind == DependencyKind::Dev)
 			.map(|d| d.to_crate_dep())
@@ -162,6 +269,8 @@ pub fn get_crate_data<C :CrateSource>(name :String, reg :&Registry, st :&mut C,
 		version : version.to_string(),
 		de,
 		err_msg,
+		mirrored : mirrored_versions.is_none(),
+		mirrored_versions : mirrored_versions.unwrap_or_default(),
 		documentation : format!("https://docs.rs/{}/{}", name.clone(), version.to_string()),
 		versions : versions[v_start ..].iter().map(|v|
 			Version {
@@ -199,8 +308,12 @@ struct CrateDetails {
 	license :String,
 }
 
+/// Obtains the details of the crate from its files
+///
+/// Problems with the files themselves result in a message to show on
+/// the crate page, only failures of the crate source in a `PageError`.
 fn get_crate_details<C :CrateSource>(name :&str, version :SvVersion,
-		st :&mut C) -> Result<CrateDetails, String> {
+		fh :&CrateHandle<C, C::CrateHandle>) -> Result<Result<CrateDetails, String>, PageError> {
 	#[derive(Deserialize)]
 	struct CratePackage {
 		repository :Option<String>,
@@ -216,26 +329,27 @@ fn get_crate_details<C :CrateSource>(name :&str, version :SvVersion,
 		package :CratePackage,
 	}
 
-	let mut fh = match st.get_crate_handle_nv(name.to_owned(), version.clone()) {
-		Some(f) => f,
-		None => return Err(
-			format!("Version {} of crate {} not mirrored", version, name)
-		),
+	let spec = CrateSpec {
+		name : name.to_owned(),
+		version : version.clone(),
 	};
-	let cargo_toml_extracted = fh.get_file(
-		&format!("{}-{}/Cargo.toml", name, version));
+	let cargo_toml_extracted = try!(get_optional_file(fh, &spec,
+		&format!("{}-{}/Cargo.toml", name, version)));
 
 	let cargo_toml_file = if let Some(toml_file) = cargo_toml_extracted {
 		toml_file
 	} else {
-		return Err("Cargo.toml file does not exist".into());
+		return Ok(Err("Cargo.toml file does not exist".into()));
 	};
 
-	let info :CrateInfo = toml::from_slice(&cargo_toml_file).unwrap();
+	let info :CrateInfo = match toml::from_slice(&cargo_toml_file) {
+		Ok(info) => info,
+		Err(e) => return Ok(Err(format!("Invalid Cargo.toml file: {}", e))),
+	};
 
 	let readme_html = if let Some(filename) = info.package.readme {
-		if let Some(c) = fh.get_file(
-				&format!("{}-{}/{}", name, version, filename)) {
+		if let Some(c) = try!(get_optional_file(fh, &spec,
+				&format!("{}-{}/{}", name, version, filename))) {
 			if let Ok(s) = String::from_utf8(c) {
 				Some(render_markdown(&s))
 			} else {
@@ -248,8 +362,8 @@ fn get_crate_details<C :CrateSource>(name :&str, version :SvVersion,
 		None
 	};
 
-	let vcs_commit = if let Some(c) = fh.get_file(
-				&format!("{}-{}/{}", name, version, ".cargo_vcs_info.json")) {
+	let vcs_commit = if let Some(c) = try!(get_optional_file(fh, &spec,
+				&format!("{}-{}/{}", name, version, ".cargo_vcs_info.json"))) {
 		if let Ok(s) = String::from_utf8(c) {
 			#[derive(Deserialize)]
 			struct Git {
@@ -279,11 +393,11 @@ fn get_crate_details<C :CrateSource>(name :&str, version :SvVersion,
 		license : info.package.license,
 	};
 
-	Ok(r)
+	Ok(Ok(r))
 }
 
 pub fn get_versions_data(name :&str, reg :&Registry, refferer :Option<String>)
-		-> Map<String, Value> {
+		-> Result<Map<String, Value>, PageError> {
 
 	#[derive(Serialize, Debug)]
 	struct Versions {
@@ -295,7 +409,9 @@ pub fn get_versions_data(name :&str, reg :&Registry, refferer :Option<String>)
 
 	let mut data = Map::new();
 
-	let crate_json = reg.get_crate_json(&name).unwrap();
+	let crate_json = try!(reg.get_crate_json(&name).map_err(|_| {
+		PageError::NotFound(format!("Couldn't get crate json for crate '{}'", name))
+	}));
 
 	let version_list = crate_json.iter()
 		.map(|jl| Version {
@@ -311,12 +427,51 @@ pub fn get_versions_data(name :&str, reg :&Registry, refferer :Option<String>)
 		versions : version_list,
 	};
 	data.insert("c".to_string(), to_json(&versions));
-	data
+	Ok(data)
+}
+
+/// The versions of the crate that are present in the crate source
+fn get_mirrored_versions<C :CrateSource>(st :&C, name :&str) -> Result<Vec<Version>, CrateError> {
+	let versions = try!(st.get_crate_versions(name)).iter()
+		.map(|v| Version {
+			v : format!("{}", v),
+			date : None,
+		})
+		.collect::<Vec<Version>>();
+	Ok(versions)
+}
+
+/// Data for the page shown if a version of a crate is not in the crate source
+///
+/// Lists the versions of the crate that the source has.
+pub fn get_not_mirrored_data<C :CrateSource>(st :&C, name :&str,
+		version :&SvVersion) -> Result<Map<String, Value>, CrateError> {
+
+	#[derive(Serialize, Debug)]
+	struct NotMirrored {
+		name :String,
+		version :String,
+		versions_length :usize,
+		versions :Vec<Version>,
+	}
+
+	let mut data = Map::new();
+
+	let version_list = try!(get_mirrored_versions(st, name));
+
+	let not_mirrored = NotMirrored {
+		name : name.to_string(),
+		version : version.to_string(),
+		versions_length : version_list.len(),
+		versions : version_list,
+	};
+	data.insert("c".to_string(), to_json(&not_mirrored));
+	Ok(data)
 }
 
 pub fn get_reverse_dependencies(name :&str,
 		only_latest_versions :bool,
-		stats :&CrateStats, refferer :Option<String>) -> Map<String, Value> {
+		stats :&CrateStats, refferer :Option<String>) -> Result<Map<String, Value>, PageError> {
 
 	#[derive(Serialize, Debug)]
 	struct RevDep {
@@ -335,11 +490,14 @@ pub fn get_reverse_dependencies(name :&str,
 
 	let mut data = Map::new();
 
-	// TODO don't use unwrap, and use "checked" getting below.
-	// Give an error instead in both cases!
-	let name_i = stats.crate_names_interner.get(name).unwrap();
+	let name_i = try!(stats.crate_names_interner.get(name).ok_or_else(|| {
+		PageError::NotFound(format!("Crate '{}' doesn't exist", name))
+	}));
 	let mut rev_d_list = Vec::new();
-	for (vreq, dlist) in stats.reverse_dependencies[&name_i].iter() {
+	// Crates that nothing depends on have no entry
+	let rev_deps = stats.reverse_dependencies.get(&name_i).into_iter()
+		.flat_map(|r| r.iter());
+	for (vreq, dlist) in rev_deps {
 		for &(rev_d_name, ref rev_d_version) in dlist.iter() {
 			if only_latest_versions &&
 					rev_d_version != &stats.latest_crate_versions[&rev_d_name] {
@@ -362,7 +520,7 @@ pub fn get_reverse_dependencies(name :&str,
 		rev_d : rev_d_list,
 	};
 	data.insert("c".to_string(), to_json(&rev_deps));
-	data
+	Ok(data)
 }
 
 pub fn get_index_data(stats :&CrateStats) -> Map<String, Value> {
@@ -460,20 +618,21 @@ pub enum CrateFileData {
 	FileContent(Map<String, Value>),
 }
 
-pub fn get_crate_file_data<C :CrateSource>(st :&mut C,
+pub fn get_crate_file_data<C :CrateSource>(st :&C,
 	name :&str, version_str :&str, path :&[&str])
-		-> CrateFileData {
+		-> Result<CrateFileData, PageError> {
 	use std::str;
 	use syntect_format::SyntectFormatter;
 
 	let mut data = Map::new();
 
 	// First step: find the path to the crate.
-	let version = SvVersion::parse(version_str).unwrap();
-	let mut fh = match st.get_crate_handle_nv(name.to_owned(), version.clone()) {
-		Some(f) => f,
-		None => panic!("Version {} of crate {} not mirrored", version, name),
+	let version = try!(parse_version(name, version_str));
+	let spec = CrateSpec {
+		name : name.to_owned(),
+		version : version.clone(),
 	};
+	let fh = try!(get_crate_handle(st, name, &version));
 	let file_path_str = path.iter().fold(String::new(), |s, u| s + "/" + u);
 
 	if file_path_str.len() <= 1 {
@@ -481,6 +640,8 @@ pub fn get_crate_file_data<C :CrateSource>(st :&mut C,
 		#[derive(Serialize, Debug)]
 		struct FileEntry {
 			name :String,
+			/// Directories have no page of their own
+			is_dir :bool,
 		}
 
 		#[derive(Serialize, Debug)]
@@ -491,19 +652,21 @@ pub fn get_crate_file_data<C :CrateSource>(st :&mut C,
 			files :Vec<FileEntry>,
 		}
 
-		let file_list = fh.get_file_list();
+		let file_list = try!(fh.get_file_list()
+			.map_err(|e| PageError::Source(Box::new(spec), e)));
 
 		let listing = CrateFileListing {
 			name : name.to_owned(),
 			version : version_str.to_owned(),
 			file_count : file_list.len(),
 			files : file_list.into_iter().map(|s| FileEntry {
+				is_dir : s.ends_with('/'),
 				name : s,
 			}).collect::<Vec<_>>(),
 		};
 		data.insert("c".to_string(), to_json(&listing));
 
-		CrateFileData::FileListing(data)
+		Ok(CrateFileData::FileListing(data))
 	} else {
 		#[derive(Serialize, Debug)]
 		struct CrateFileContent {
@@ -512,8 +675,11 @@ pub fn get_crate_file_data<C :CrateSource>(st :&mut C,
 			file_path :String,
 			content_html :String,
 		}
-		let content_raw = fh.get_file(&file_path_str[1..])
-			.expect("Path not found in crate file");
+		let content_raw = match try!(get_optional_file(&fh, &spec, &file_path_str[1..])) {
+			Some(content) => content,
+			None => return Err(PageError::NotFound(format!(
+				"No file '{}' in version {} of crate '{}'", file_path_str, version, name))),
+		};
 		let content_html = match str::from_utf8(&content_raw) {
 			Ok(content_str) => {
 				let extension = if file_path_str.contains(".") {
@@ -544,7 +710,7 @@ pub fn get_crate_file_data<C :CrateSource>(st :&mut C,
 			content_html
 		};
 		data.insert("c".to_string(), to_json(&content));
-		CrateFileData::FileContent(data)
+		Ok(CrateFileData::FileContent(data))
 	}
 
 